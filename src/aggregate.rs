@@ -8,6 +8,7 @@ use num_traits::{AsPrimitive, Float};
 use serde::{Deserialize, Serialize};
 
 use crate::metric::{FromF64, Metric, MetricTypeName, MetricValue};
+use crate::timer::TimerUnit;
 
 /// Percentile counter. Not safe against all edge cases:
 ///
@@ -88,6 +89,10 @@ where
     /// and must be set explicitly when aggregated, considering a correct number of buckets
     /// known externally
     Bucket(Option<usize>),
+
+    /// Calculated over timer values by the `aggregate` hook of a WASM plugin, see `WasmPlugin::aggregates`,
+    /// there is no value without the plugin
+    Custom,
 }
 
 impl<F> TryFrom<String> for Aggregate<F>
@@ -97,6 +102,18 @@ where
     type Error = String;
 
     fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::try_from(s.as_str())
+    }
+}
+
+/// Percentiles may be specified either as `percentile-99` or in short form as `p99`
+impl<F> TryFrom<&str> for Aggregate<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize>,
+{
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
         match s.to_lowercase().as_str() {
             "value" => Ok(Aggregate::Value),
             "count" => Ok(Aggregate::Count),
@@ -108,27 +125,61 @@ where
             "mean" => Ok(Aggregate::Mean),
             "updates" => Ok(Aggregate::UpdateCount),
             "rate" => Ok(Aggregate::Rate(None)),
-            s if s.starts_with("percentile-") => {
-                // check in match guarantees minus char exists
-                let pos = s.chars().position(|c| c == '-').unwrap() + 1;
-                let num: u64 = u64::from_str(&s[pos..]).map_err(|_| "percentile value is not unsigned integer".to_owned())?;
-                let mut divider = 10f64;
-
-                let numf = num as f64;
-                // divider is f64, so it's always bigger than u64:MAX and therefore never
-                // overflow
-                while numf > divider {
-                    divider *= 10.0;
-                }
-
-                Ok(Aggregate::Percentile(F::from_f64(numf / divider), num))
-            }
+            s if s.starts_with("percentile-") => parse_percentile(&s["percentile-".len()..]),
+            s if s.len() > 1 && s.starts_with('p') && s[1..].bytes().all(|c| c.is_ascii_digit()) => parse_percentile(&s[1..]),
             "bucket" => Ok(Aggregate::Bucket(None)),
+            "custom" => Ok(Aggregate::Custom),
             _ => Err("unknown aggregate name".into()),
         }
     }
 }
 
+fn parse_percentile<F>(s: &str) -> Result<Aggregate<F>, String>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize>,
+{
+    let num: u64 = u64::from_str(s).map_err(|_| "percentile value is not unsigned integer".to_owned())?;
+    Ok(percentile_from_num(num))
+}
+
+// the name of the aggregate as it is parsed from config, unlike `to_string` giving the one for metric names,
+// values of rates and buckets are not a part of it
+fn config_name<F>(aggregate: &Aggregate<F>) -> String
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize>,
+{
+    match aggregate {
+        Aggregate::Value => "value".to_string(),
+        Aggregate::Percentile(_, num) => format!("percentile-{}", num),
+        Aggregate::Rate(_) => "rate".to_string(),
+        Aggregate::Bucket(_) => "bucket".to_string(),
+        other => other.to_string(),
+    }
+}
+
+// makes a percentile from the integer number as it is written in config, i.e. 99 is 0.99 and 999 is 0.999
+pub(crate) fn percentile_from_num<F>(num: u64) -> Aggregate<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize>,
+{
+    Aggregate::Percentile(F::from_f64(quantile_from_num(num)), num)
+}
+
+/// The quantile the percentile number stands for, i.e. 0.999 for 999, calculated as f64
+/// regardless of the float type used for metrics
+pub(crate) fn quantile_from_num(num: u64) -> f64 {
+    let mut divider = 10f64;
+
+    let numf = num as f64;
+    // divider is f64, so it's always bigger than u64:MAX and therefore never
+    // overflow
+    while numf > divider {
+        divider *= 10.0;
+    }
+
+    numf / divider
+}
+
 impl<F> ToString for Aggregate<F>
 where
     F: Float + Debug + FromF64 + AsPrimitive<usize>,
@@ -149,6 +200,7 @@ where
             Aggregate::Percentile(_, num) => format!("percentile.{}", num),
             Aggregate::Bucket(None) => "bad_bucket".to_string(),
             Aggregate::Bucket(Some(nth)) => format!("bucket.{}", nth),
+            Aggregate::Custom => "custom".to_string(),
         }
     }
 }
@@ -187,6 +239,7 @@ where
                 13usize.hash(state);
                 nth.hash(state);
             }
+            Aggregate::Custom => 14usize.hash(state),
         }
     }
 }
@@ -215,6 +268,7 @@ where
             // sake of correctness we'd better do this
             (Aggregate::Percentile(_, num), Aggregate::Percentile(_, o)) => num == o,
             (Aggregate::Bucket(b), Aggregate::Bucket(o)) => b == o,
+            (Aggregate::Custom, Aggregate::Custom) => true,
             _ => false,
         }
     }
@@ -228,13 +282,29 @@ where
     /// returns None in all inapplicable cases, like zero-length vector or metric type mismatch
     /// cached_sum must relate to the same metric between calls, giving incorrect results or
     /// panics otherwise
+    /// compact timers are only counted for updates and rate, they must be converted to vec storage
+    /// for other aggregates, `AggregateCalculator` does it automatically
     pub fn calculate(&self, metric: &Metric<F>, cached_sum: &mut Option<F>, timer_last: Option<F>) -> Option<F> {
         match (metric.value(), self) {
+            // the gauge has expired, its tombstone has no values to export
+            (MetricValue::Gauge(_), _) if metric.is_gauge_unset() => None,
             // for sets calculate only count
             (MetricValue::Set(ref hs), &Aggregate::Count) => Some(F::from_f64(hs.len() as f64) / metric.sampling()),
+            (MetricValue::SortedSet(ref ss), &Aggregate::Count) => Some(F::from_f64(ss.len() as f64) / metric.sampling()),
             // don't count values for timers and sets
             (MetricValue::Set(_), &Aggregate::Value) => None,
+            (MetricValue::SortedSet(_), &Aggregate::Value) => None,
             (MetricValue::Timer(_), &Aggregate::Value) => None,
+            // empty timers have no values to calculate order based aggregates and mean from
+            (MetricValue::Timer(ref agg), &Aggregate::Min)
+            | (MetricValue::Timer(ref agg), &Aggregate::Max)
+            | (MetricValue::Timer(ref agg), &Aggregate::Median)
+            | (MetricValue::Timer(ref agg), &Aggregate::Mean)
+            | (MetricValue::Timer(ref agg), &Aggregate::Percentile(_, _))
+                if agg.is_empty() =>
+            {
+                None
+            }
             // for timers calculate all aggregates
             (MetricValue::Timer(ref agg), &s) => match s {
                 Aggregate::Value => None,
@@ -265,7 +335,7 @@ where
                 Aggregate::Rate(Some(secs)) => Some(metric.updates() / secs / metric.sampling()),
                 Aggregate::Rate(None) => None,
                 Aggregate::Percentile(ref p, _) => Some(percentile(agg, *p)),
-                Aggregate::Bucket(_) => None,
+                Aggregate::Bucket(_) | Aggregate::Custom => None,
             },
             (MetricValue::CustomHistogram(left, buckets), &Aggregate::Bucket(Some(nth))) => {
                 let value = if nth == 0 {
@@ -295,16 +365,36 @@ where
     }
 }
 
+impl<F> Aggregate<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize> + AsPrimitive<f64>,
+{
+    /// Converts the aggregate of timer from milliseconds to the unit, the aggregates
+    /// not measured in time units, like count or rate, are returned as is
+    pub fn timer_value_in(&self, value: F, unit: TimerUnit) -> F {
+        match self {
+            Aggregate::Last | Aggregate::Min | Aggregate::Max | Aggregate::Sum | Aggregate::Median | Aggregate::Mean | Aggregate::Percentile(_, _) => {
+                F::from_f64(unit.convert_millis(value.as_()))
+            }
+            _ => value,
+        }
+    }
+}
+
 /// A state for calculating all aggregates over metric
 /// Implements iterator returning the index of aggregate in the input and the aggregate value
 /// if such value should exist for an aggregate
+///
+/// Aggregates are calculated lazily: timer values are only sorted when the first aggregate requiring
+/// the order is reached, and the sum is only counted when needed
 pub struct AggregateCalculator<'a, F>
 where
     F: Float + Debug + FromF64 + AsPrimitive<usize>,
 {
-    metric: &'a Metric<F>,
+    metric: &'a mut Metric<F>,
     timer_sum: Option<F>,
     timer_last: Option<F>,
+    sorted: bool,
     aggregates: &'a [Aggregate<F>],
     current: usize,
 }
@@ -314,28 +404,21 @@ where
     F: Float + Debug + FromF64 + AsPrimitive<f64> + AsPrimitive<usize>,
 {
     pub fn new(metric: &'a mut Metric<F>, aggregates: &'a [Aggregate<F>]) -> Self {
-        let timer_last = if let MetricValue::Timer(ref agg) = metric.value() {
-            if let Some(last) = agg.last() {
-                Some(*last)
-            } else {
-                None
-            }
-        } else {
-            None
-        };
+        // compact timers are expanded, since aggregates are calculated over plain vectors
+        metric.expand_timer();
 
-        let timer_sum = if let MetricValue::Timer(ref agg) = metric.value() {
-            let first = agg.first().unwrap();
-            Some(agg.iter().skip(1).fold(*first, |acc, &v| acc + v))
+        // the last value must be taken before values are sorted
+        let timer_last = if let MetricValue::Timer(ref agg) = metric.value() {
+            agg.last().copied()
         } else {
             None
         };
 
-        metric.sort_timer();
         Self {
             metric,
-            timer_sum,
+            timer_sum: None,
             timer_last,
+            sorted: false,
             aggregates,
             current: 0,
         }
@@ -354,6 +437,11 @@ where
         }
 
         let agg = &self.aggregates[self.current];
+        if !self.sorted && matches!(agg, Aggregate::Min | Aggregate::Max | Aggregate::Median | Aggregate::Percentile(_, _)) {
+            self.metric.sort_timer();
+            self.sorted = true;
+        }
+
         let calc = agg
             .calculate(self.metric, &mut self.timer_sum, self.timer_last)
             .map(|result| (self.current, result));
@@ -362,6 +450,162 @@ where
     }
 }
 
+/// Lazily calculates the requested aggregates over the metric, returning only the applicable ones
+/// along with their values. The aggregates not consumed from the iterator are not calculated at all.
+pub fn aggregates<'a, F>(metric: &'a mut Metric<F>, aggregates: &'a [Aggregate<F>]) -> impl Iterator<Item = (Aggregate<F>, F)> + 'a
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize> + AsPrimitive<f64>,
+{
+    AggregateCalculator::new(metric, aggregates)
+        .flatten()
+        .map(move |(idx, value)| (aggregates[idx], value))
+}
+
+/// Same as `aggregates`, but timer aggregates are given in the specified unit instead of milliseconds.
+/// Other metric types are not converted.
+pub fn aggregates_in<'a, F>(metric: &'a mut Metric<F>, aggregates: &'a [Aggregate<F>], unit: TimerUnit) -> impl Iterator<Item = (Aggregate<F>, F)> + 'a
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize> + AsPrimitive<f64>,
+{
+    let is_timer = metric.timer_len().is_some();
+    AggregateCalculator::new(metric, aggregates).flatten().map(move |(idx, value)| {
+        let agg = aggregates[idx];
+        if is_timer {
+            (agg, agg.timer_value_in(value, unit))
+        } else {
+            (agg, value)
+        }
+    })
+}
+
+/// A set of percentiles fixed at compile time. Neither the set itself nor the calculated values
+/// are allocated on heap, which suits agents and embedded use. Servers getting percentiles
+/// from config should use the dynamic `Aggregate` slices instead.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Percentiles<F, const N: usize>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize>,
+{
+    aggregates: [Aggregate<F>; N],
+}
+
+impl<F, const N: usize> Percentiles<F, N>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize> + AsPrimitive<f64>,
+{
+    /// Creates percentiles from their integer form like in config, i.e. `[50, 99, 999]`
+    /// stand for the 50th, 99th and 99.9th percentiles
+    pub fn new(nums: [u64; N]) -> Self {
+        Self {
+            aggregates: nums.map(percentile_from_num),
+        }
+    }
+
+    /// The percentiles as aggregates, so they can be passed to the dynamic calculation too
+    pub fn aggregates(&self) -> &[Aggregate<F>; N] {
+        &self.aggregates
+    }
+
+    /// Calculates all percentiles of the timer, in the same order they were specified.
+    /// Timer values are sorted in place. Returns None for empty timers and other metric types.
+    /// Compact timers are expanded to vector, so they are the only case allocating memory
+    pub fn calculate(&self, metric: &mut Metric<F>) -> Option<[F; N]> {
+        metric.expand_timer();
+        metric.sort_timer();
+        let agg = match metric.value() {
+            MetricValue::Timer(agg) if !agg.is_empty() => agg,
+            _ => return None,
+        };
+
+        let mut result = [F::zero(); N];
+        for (value, aggregate) in result.iter_mut().zip(self.aggregates.iter()) {
+            if let Aggregate::Percentile(p, _) = aggregate {
+                *value = percentile(agg, *p);
+            }
+        }
+        Some(result)
+    }
+}
+
+/// Bounds for an aggregate value, a value outside of them is flagged as exceeded.
+/// NaN values never exceed the bounds.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Threshold<F> {
+    pub min: Option<F>,
+    pub max: Option<F>,
+}
+
+impl<F: Float> Threshold<F> {
+    pub fn exceeded(&self, value: F) -> bool {
+        self.min.map(|min| value < min).unwrap_or(false) || self.max.map(|max| value > max).unwrap_or(false)
+    }
+}
+
+/// An aggregate value with the threshold check result
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AggregateResult<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize>,
+{
+    pub aggregate: Aggregate<F>,
+    pub value: F,
+    pub exceeded: bool,
+}
+
+/// Thresholds for aggregates, as they come in the aggregation config, i.e.
+/// `{ "max": { "max": 100.0 }, "percentile-99": { "min": 0.0, "max": 50.0 } }`
+/// Aggregates without a threshold are never flagged. Aggregates are serialized by their names,
+/// so they can be the map keys in any format.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Thresholds<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize>,
+{
+    #[serde(serialize_with = "serialize_thresholds", bound(serialize = "F: Serialize"))]
+    thresholds: HashMap<Aggregate<F>, Threshold<F>>,
+}
+
+impl<F> Thresholds<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize>,
+{
+    pub fn new() -> Self {
+        Self { thresholds: HashMap::new() }
+    }
+
+    pub fn insert(&mut self, aggregate: Aggregate<F>, threshold: Threshold<F>) -> Option<Threshold<F>> {
+        self.thresholds.insert(aggregate, threshold)
+    }
+
+    pub fn get(&self, aggregate: &Aggregate<F>) -> Option<&Threshold<F>> {
+        self.thresholds.get(aggregate)
+    }
+
+    pub fn check(&self, aggregate: Aggregate<F>, value: F) -> AggregateResult<F> {
+        let exceeded = self.get(&aggregate).map(|threshold| threshold.exceeded(value)).unwrap_or(false);
+        AggregateResult { aggregate, value, exceeded }
+    }
+
+    /// Annotates aggregation results, i.e. the ones coming from `aggregates`
+    pub fn annotate<'a, I>(&'a self, results: I) -> impl Iterator<Item = AggregateResult<F>> + 'a
+    where
+        I: IntoIterator<Item = (Aggregate<F>, F)>,
+        I::IntoIter: 'a,
+    {
+        results.into_iter().map(move |(aggregate, value)| self.check(aggregate, value))
+    }
+}
+
+fn serialize_thresholds<F, S>(thresholds: &HashMap<Aggregate<F>, Threshold<F>>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize> + Serialize,
+    S: serde::Serializer,
+{
+    serializer.collect_map(thresholds.iter().map(|(aggregate, threshold)| (config_name(aggregate), threshold)))
+}
+
 /// A helper function giving all possible aggregates for each metric type name.
 /// Includes ony one, 99th percentile for the sake of complenetes
 /// `interval` paremeter is only used to set the rate aggregation interval
@@ -404,7 +648,44 @@ mod tests {
     use super::*;
 
     use crate::metric::{StatsdMetric, StatsdType};
+    use crate::timer::TimerStorage;
     use std::collections::{HashMap, HashSet};
+    use std::time::Duration;
+
+    #[test]
+    fn aggregate_thresholds() {
+        let mut thresholds = Thresholds::<f64>::new();
+        thresholds.insert(Aggregate::Max, Threshold { min: None, max: Some(10f64) });
+        thresholds.insert(
+            Aggregate::Percentile(0.99, 99),
+            Threshold {
+                min: Some(1f64),
+                max: Some(5f64),
+            },
+        );
+
+        let mut metric = Metric::new(MetricValue::Timer(vec![1f64, 3f64, 12f64]), None, 1f32);
+        let aggs = vec![Aggregate::Count, Aggregate::Min, Aggregate::Max, Aggregate::Percentile(0.99, 99)];
+        let results = thresholds.annotate(aggregates(&mut metric, &aggs)).collect::<Vec<_>>();
+        assert_eq!(results.len(), 4);
+        assert_eq!(results.iter().map(|r| r.exceeded).collect::<Vec<_>>(), vec![false, false, true, true]);
+        assert_eq!(results[2].value, 12f64);
+
+        assert!(!thresholds.check(Aggregate::Percentile(0.99, 99), 1f64).exceeded);
+        assert!(thresholds.check(Aggregate::Percentile(0.99, 99), 0.5f64).exceeded);
+        assert!(!thresholds.check(Aggregate::Max, f64::NAN).exceeded);
+
+        // thresholds are serialized with aggregate names as keys, they must parse back
+        let mut all = possible_aggregates::<f64>(Some(1f64), Some(2)).into_values().flatten().collect::<Vec<_>>();
+        all.push(Aggregate::Custom);
+        for aggregate in all {
+            let parsed = Aggregate::<f64>::try_from(config_name(&aggregate)).unwrap();
+            assert_eq!(std::mem::discriminant(&parsed), std::mem::discriminant(&aggregate));
+            if let Aggregate::Percentile(..) = aggregate {
+                assert_eq!(parsed, aggregate);
+            }
+        }
+    }
 
     #[test]
     fn aggregates_eq_and_hashing_f32() {
@@ -438,6 +719,20 @@ mod tests {
         assert_eq!(hm.len(), 100000 - 1);
     }
 
+    #[test]
+    fn aggregate_from_str() {
+        assert_eq!(Aggregate::<f64>::try_from("mean"), Ok(Aggregate::Mean));
+        assert_eq!(Aggregate::<f64>::try_from("Count"), Ok(Aggregate::Count));
+        assert_eq!(Aggregate::<f64>::try_from("percentile-95"), Ok(Aggregate::Percentile(0.95, 95)));
+        assert_eq!(Aggregate::<f64>::try_from("p99"), Ok(Aggregate::Percentile(0.99, 99)));
+        assert_eq!(Aggregate::<f64>::try_from("p999"), Ok(Aggregate::Percentile(0.999, 999)));
+        assert_eq!(Aggregate::<f64>::try_from("P75").unwrap().to_string(), "percentile.75");
+        assert!(Aggregate::<f64>::try_from("p").is_err());
+        assert!(Aggregate::<f64>::try_from("p-1").is_err());
+        assert!(Aggregate::<f64>::try_from("percentile-x").is_err());
+        assert!(Aggregate::<f64>::try_from("pmax").is_err());
+    }
+
     #[test]
     fn percentile_to_string() {
         assert_eq!(&Aggregate::Percentile(0.75f64, 75).to_string(), "percentile.75");
@@ -568,10 +863,7 @@ mod tests {
             //if ec == &Aggregate::Percentile(0.85f64, 85) || ec == &td.rate_agg {
             ev.iter()
                 .zip(rv.iter())
-                .map(|((_, e), (_, r))| {
-                    let diff = (e - r).abs();
-                    assert!(diff < 0.0001, "(expected){} ~= {}: {}", e, r, diff);
-                })
+                .map(|((_, e), (_, r))| crate::assert_metric_approx_eq!(*e, *r, 0.0001))
             .last();
             //} else {
             //assert_eq!(ev, rv, "\non {:?} expected: \n {:?} \n not equal to \n {:?}", &ec, &ev, &rv);
@@ -707,6 +999,73 @@ mod tests {
         test_aggregation(td);
     }
 
+    #[test]
+    fn aggregate_compact_timer() {
+        let mut td = TestData::new(1.);
+        // compact timers should give the same aggregates as the usual ones
+        let mut timer = Metric::new(MetricValue::Timer(vec![1f64]), None, 1.);
+        timer.timer_storage(TimerStorage::Compact(0.01)).unwrap();
+
+        td.samples
+            .iter()
+            .map(|t| {
+                let timer2 = Metric::new(MetricValue::Timer(vec![*t]), None, 1.);
+                timer.accumulate(timer2).unwrap();
+            })
+            .last();
+
+        td.expected.insert(Aggregate::Count, vec![(timer.clone(), 10f64)]);
+        td.expected.insert(Aggregate::Min, vec![(timer.clone(), 1f64)]);
+        td.expected.insert(Aggregate::UpdateCount, vec![(timer.clone(), td.num_samples + 1f64)]);
+        td.expected.insert(Aggregate::Max, vec![(timer.clone(), 84f64)]);
+        td.expected.insert(Aggregate::Sum, vec![(timer.clone(), 316f64)]);
+        td.expected.insert(Aggregate::Median, vec![(timer.clone(), 23.5f64)]);
+        td.expected.insert(td.rate_agg, vec![(timer.clone(), td.rate)]);
+        td.expected.insert(Aggregate::Percentile(0.85, 85), vec![(timer.clone(), 60.85f64)]);
+        td.expected.insert(Aggregate::Mean, vec![(timer.clone(), 31.6f64)]);
+        td.expected.insert(Aggregate::Last, vec![(timer.clone(), 64f64)]);
+
+        td.to_aggregate.push(timer);
+
+        test_aggregation(td);
+    }
+
+    #[test]
+    fn aggregate_lazily() {
+        let mut timer = Metric::new(MetricValue::Timer(vec![3f64, 1f64, 2f64]), None, 1.);
+        let requested = vec![Aggregate::Count, Aggregate::Last, Aggregate::Value, Aggregate::Min, Aggregate::Sum];
+
+        let mut iter = aggregates(&mut timer, &requested);
+        assert_eq!(iter.next(), Some((Aggregate::Count, 3f64)));
+        assert_eq!(iter.next(), Some((Aggregate::Last, 2f64)));
+        drop(iter);
+        // values are not sorted until an aggregate requires it
+        assert_eq!(timer.value(), &MetricValue::Timer(vec![3f64, 1f64, 2f64]));
+
+        let result = aggregates(&mut timer, &requested).collect::<Vec<_>>();
+        assert_eq!(
+            result,
+            vec![
+                (Aggregate::Count, 3f64),
+                (Aggregate::Last, 2f64),
+                (Aggregate::Min, 1f64),
+                (Aggregate::Sum, 6f64)
+            ]
+        );
+
+        let mut empty = Metric::new(MetricValue::Timer(Vec::new()), None, 1.);
+        let requested = vec![
+            Aggregate::Count,
+            Aggregate::Min,
+            Aggregate::Median,
+            Aggregate::Mean,
+            Aggregate::Sum,
+            Aggregate::UpdateCount,
+        ];
+        let result = aggregates(&mut empty, &requested).collect::<Vec<_>>();
+        assert_eq!(result, vec![(Aggregate::Count, 0f64), (Aggregate::UpdateCount, 1f64)]);
+    }
+
     #[test]
     fn aggregate_timer_sampled() {
         let mut td = TestData::new(0.1);
@@ -852,4 +1211,42 @@ mod tests {
 
         test_aggregation(td);
     }
+
+    #[test]
+    fn static_percentiles() {
+        const PERCENTILES: [u64; 3] = [50, 90, 999];
+        let percentiles = Percentiles::<f64, 3>::new(PERCENTILES);
+        assert_eq!(percentiles.aggregates()[2], Aggregate::Percentile(0.999, 999));
+
+        let mut timer = Metric::<f64>::new(MetricValue::Timer((0..=10).rev().map(f64::from).collect()), None, 1f32);
+        let values = percentiles.calculate(&mut timer).unwrap();
+        assert_eq!(values[0], 5f64);
+        assert_eq!(values[1], 9f64);
+        assert!((values[2] - 9.99).abs() < 1e-9);
+
+        // results must match the dynamic calculation
+        let dynamic = aggregates(&mut timer, percentiles.aggregates()).map(|(_, value)| value).collect::<Vec<_>>();
+        assert_eq!(&dynamic[..], &values[..]);
+
+        assert_eq!(percentiles.calculate(&mut Metric::new(MetricValue::Timer(Vec::new()), None, 1f32)), None);
+        assert_eq!(percentiles.calculate(&mut Metric::new(MetricValue::Gauge(1f64), None, 1f32)), None);
+    }
+
+    #[test]
+    fn duration_timers_in_units() {
+        let mut timer = Metric::<f64>::timer_from_duration(Duration::from_micros(1500));
+        timer.accumulate(Metric::timer_from_duration(Duration::from_millis(3))).unwrap();
+        assert_eq!(timer.timer_samples(), Some(&[1.5f64, 3f64][..]));
+
+        let aggs = [Aggregate::Count, Aggregate::Min, Aggregate::Sum, Aggregate::Percentile(0.5, 5)];
+        let values = aggregates_in(&mut timer, &aggs, TimerUnit::Micros).map(|(_, value)| value).collect::<Vec<_>>();
+        assert_eq!(values, vec![2f64, 1500f64, 4500f64, 2250f64]);
+        let values = aggregates_in(&mut timer, &aggs, TimerUnit::Seconds).map(|(_, value)| value).collect::<Vec<_>>();
+        assert_eq!(values, vec![2f64, 0.0015, 0.0045, 0.00225]);
+
+        // counters keep their values
+        let mut counter = Metric::new(MetricValue::Counter(5f64), None, 1f32);
+        let values = aggregates_in(&mut counter, &[Aggregate::Value], TimerUnit::Seconds).collect::<Vec<_>>();
+        assert_eq!(values, vec![(Aggregate::Value, 5f64)]);
+    }
 }
@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+use num_traits::{AsPrimitive, Float};
+use serde::{Deserialize, Serialize};
+
+use crate::aggregate::Aggregate;
+use crate::cache::Snapshot;
+use crate::metric::FromF64;
+use crate::name::MetricName;
+use crate::query::TagSelector;
+
+/// The way the aggregate value is compared to the threshold
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Comparison {
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Comparison {
+    /// NaN values never match
+    pub fn matches<F: Float>(self, value: F, threshold: F) -> bool {
+        if value.is_nan() {
+            return false;
+        }
+        match self {
+            Comparison::Greater => value > threshold,
+            Comparison::GreaterOrEqual => value >= threshold,
+            Comparison::Less => value < threshold,
+            Comparison::LessOrEqual => value <= threshold,
+            Comparison::Equal => value == threshold,
+            Comparison::NotEqual => value != threshold,
+        }
+    }
+}
+
+/// A condition on an aggregate of the metrics selected by name globs and tag selectors,
+/// like in `SnapshotQuery`. Every matching series is alerted on separately
+#[derive(Debug, Clone)]
+pub struct AlertRule<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize>,
+{
+    pub name: String,
+    pub names: Vec<String>,
+    pub tags: Vec<TagSelector>,
+    pub aggregate: Aggregate<F>,
+    pub comparison: Comparison,
+    pub threshold: F,
+    /// the number of successive intervals the condition must hold before the alert fires,
+    /// the alert is pending until then, 0 and 1 mean firing right away
+    pub for_intervals: u32,
+}
+
+impl<F> AlertRule<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64> + AsPrimitive<usize>,
+{
+    pub fn new(name: &str, aggregate: Aggregate<F>, comparison: Comparison, threshold: F) -> Self {
+        Self {
+            name: name.to_string(),
+            names: Vec::new(),
+            tags: Vec::new(),
+            aggregate,
+            comparison,
+            threshold,
+            for_intervals: 0,
+        }
+    }
+
+    /// Adds a glob for the name without tags, rules without globs select all the metrics
+    pub fn metric(mut self, glob: &str) -> Self {
+        self.names.push(glob.to_string());
+        self
+    }
+
+    pub fn tag(mut self, selector: TagSelector) -> Self {
+        self.tags.push(selector);
+        self
+    }
+
+    pub fn for_intervals(mut self, intervals: u32) -> Self {
+        self.for_intervals = intervals;
+        self
+    }
+
+    /// The values of the aggregate of every series selected, series without the aggregate are skipped
+    fn values<'a>(&self, snapshot: &'a Snapshot<F>) -> Vec<(&'a MetricName, F)> {
+        let query = self.names.iter().fold(snapshot.query(), |query, glob| query.name(glob));
+        let query = self.tags.iter().fold(query, |query, selector| query.tag(selector.clone()));
+        let query = query.aggregates(&[self.aggregate]);
+        query
+            .iter()
+            .filter_map(|item| item.aggregates.first().map(|(_, value)| (item.name, *value)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AlertState {
+    Inactive,
+    /// the condition holds, but not for long enough yet
+    Pending,
+    Firing,
+}
+
+/// A change of the alert state of a series
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlertTransition<F> {
+    /// the name of the rule
+    pub rule: String,
+    pub series: MetricName,
+    pub from: AlertState,
+    pub to: AlertState,
+    /// the aggregate value, None if the series has disappeared
+    pub value: Option<F>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SeriesAlert {
+    state: AlertState,
+    intervals: u32,
+}
+
+/// Evaluates alert rules against snapshots of successive intervals, keeping the state of every
+/// series. Series disappeared from the snapshot or no more matching the condition become inactive
+#[derive(Debug, Clone)]
+pub struct AlertEvaluator<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize>,
+{
+    rules: Vec<AlertRule<F>>,
+    // only the series not inactive are kept, by rule index
+    active: HashMap<(usize, MetricName), SeriesAlert>,
+}
+
+impl<F> AlertEvaluator<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64> + AsPrimitive<usize>,
+{
+    pub fn new(rules: Vec<AlertRule<F>>) -> Self {
+        Self { rules, active: HashMap::new() }
+    }
+
+    pub fn rules(&self) -> &[AlertRule<F>] {
+        &self.rules
+    }
+
+    /// The current state of the series for the rule with the index
+    pub fn state(&self, rule: usize, series: &MetricName) -> AlertState {
+        self.active
+            .get(&(rule, series.clone()))
+            .map(|alert| alert.state)
+            .unwrap_or(AlertState::Inactive)
+    }
+
+    /// Evaluates the rules against the snapshot of the next interval, giving the state changes
+    pub fn evaluate(&mut self, snapshot: &Snapshot<F>) -> Vec<AlertTransition<F>> {
+        let mut transitions = Vec::new();
+        let mut seen = HashMap::new();
+        for (idx, rule) in self.rules.iter().enumerate() {
+            for (series, value) in rule.values(snapshot) {
+                let key = (idx, series.clone());
+                seen.insert(key.clone(), value);
+                if !rule.comparison.matches(value, rule.threshold) {
+                    continue;
+                }
+                let alert = self.active.entry(key).or_insert(SeriesAlert {
+                    state: AlertState::Inactive,
+                    intervals: 0,
+                });
+                alert.intervals = alert.intervals.saturating_add(1);
+                let state = if alert.intervals >= rule.for_intervals {
+                    AlertState::Firing
+                } else {
+                    AlertState::Pending
+                };
+                if state != alert.state {
+                    transitions.push(AlertTransition {
+                        rule: rule.name.clone(),
+                        series: series.clone(),
+                        from: alert.state,
+                        to: state,
+                        value: Some(value),
+                    });
+                    alert.state = state;
+                }
+            }
+        }
+
+        let rules = &self.rules;
+        self.active.retain(|(idx, series), alert| {
+            let value = seen.get(&(*idx, series.clone())).copied();
+            let rule = &rules[*idx];
+            if value.map(|value| rule.comparison.matches(value, rule.threshold)).unwrap_or(false) {
+                return true;
+            }
+            transitions.push(AlertTransition {
+                rule: rule.name.clone(),
+                series: series.clone(),
+                from: alert.state,
+                to: AlertState::Inactive,
+                value,
+            });
+            false
+        });
+        transitions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::SnapshotView;
+    use crate::metric::{Metric, MetricValue};
+    use crate::testing::name;
+
+    #[test]
+    fn alert_transitions() {
+        let (web, db) = (name("latency;host=web"), name("latency;host=db"));
+        let snapshot = |web_max: Option<f64>, db_max: f64| {
+            let mut metrics = HashMap::new();
+            if let Some(max) = web_max {
+                metrics.insert(web.clone(), Metric::new(MetricValue::Timer(vec![1f64, max]), None, 1f32));
+            }
+            metrics.insert(db.clone(), Metric::new(MetricValue::Timer(vec![db_max]), None, 1f32));
+            SnapshotView::from(metrics)
+        };
+
+        let rule = AlertRule::new("slow", Aggregate::Max, Comparison::Greater, 100f64)
+            .metric("lat*")
+            .tag("host!=db".parse().unwrap())
+            .for_intervals(2);
+        let mut evaluator = AlertEvaluator::new(vec![rule]);
+        let states = |transitions: Vec<AlertTransition<f64>>| transitions.into_iter().map(|t| (t.from, t.to, t.value)).collect::<Vec<_>>();
+
+        assert!(evaluator.evaluate(&snapshot(Some(50f64), 500f64)).is_empty());
+        assert_eq!(
+            states(evaluator.evaluate(&snapshot(Some(150f64), 500f64))),
+            vec![(AlertState::Inactive, AlertState::Pending, Some(150f64))]
+        );
+        assert_eq!(
+            states(evaluator.evaluate(&snapshot(Some(200f64), 500f64))),
+            vec![(AlertState::Pending, AlertState::Firing, Some(200f64))]
+        );
+        assert!(evaluator.evaluate(&snapshot(Some(200f64), 500f64)).is_empty());
+        assert_eq!(evaluator.state(0, &web), AlertState::Firing);
+        assert_eq!(evaluator.state(0, &db), AlertState::Inactive);
+
+        // the series has disappeared
+        assert_eq!(
+            states(evaluator.evaluate(&snapshot(None, 500f64))),
+            vec![(AlertState::Firing, AlertState::Inactive, None)]
+        );
+        evaluator.evaluate(&snapshot(Some(150f64), 500f64));
+        assert_eq!(
+            states(evaluator.evaluate(&snapshot(Some(50f64), 500f64))),
+            vec![(AlertState::Pending, AlertState::Inactive, Some(50f64))]
+        );
+
+        assert!(Comparison::LessOrEqual.matches(1f64, 1f64));
+        assert!(!Comparison::NotEqual.matches(f64::NAN, 1f64));
+    }
+}
@@ -0,0 +1,191 @@
+use std::fmt::Debug;
+
+use num_traits::{AsPrimitive, Float};
+use serde::{Deserialize, Serialize};
+
+use crate::metric::{FromF64, Metric, MetricError, MetricValue};
+
+/// The maximum number of buckets a generated layout may have, protecting from
+/// factors like 1.0000001 given in config by mistake
+pub const MAX_BUCKETS: usize = 1024;
+
+/// Checks the bucket lower bounds are finite and strictly increasing
+pub fn validate_bounds(bounds: &[f64]) -> Result<(), MetricError> {
+    if bounds.is_empty() {
+        return Err(MetricError::Buckets("no buckets"));
+    }
+    if bounds.len() > MAX_BUCKETS {
+        return Err(MetricError::Buckets("too many buckets"));
+    }
+    if !bounds.iter().all(|b| b.is_finite()) {
+        return Err(MetricError::Buckets("bounds must be finite"));
+    }
+    if !bounds.windows(2).all(|w| w[0] < w[1]) {
+        return Err(MetricError::Buckets("bounds must be strictly increasing"));
+    }
+    Ok(())
+}
+
+/// Generates bounds growing by `factor` from `min` while below `max`, the last bound is `max` itself,
+/// i.e. `min = 1, max = 10, factor = 2` gives 1, 2, 4, 8, 10
+pub fn exponential_bounds(min: f64, max: f64, factor: f64) -> Result<Vec<f64>, MetricError> {
+    if !(min > 0f64 && min.is_finite()) {
+        return Err(MetricError::Buckets("exponential buckets must start above zero"));
+    }
+    if !(max > min && max.is_finite()) {
+        return Err(MetricError::Buckets("max must be greater than min"));
+    }
+    if !(factor > 1f64 && factor.is_finite()) {
+        return Err(MetricError::Buckets("factor must be greater than 1"));
+    }
+
+    let mut bounds = Vec::new();
+    let mut idx = 0;
+    loop {
+        // powers instead of repeated multiplication to avoid accumulating error
+        let bound = min * factor.powi(idx);
+        if bound >= max {
+            break;
+        }
+        if bounds.len() >= MAX_BUCKETS {
+            return Err(MetricError::Buckets("too many buckets"));
+        }
+        bounds.push(bound);
+        idx += 1;
+    }
+    bounds.push(max);
+    validate_bounds(&bounds)?;
+    Ok(bounds)
+}
+
+/// Generates bounds splitting each power of 10 between `min` and `max` into `steps` equal buckets,
+/// giving the same relative precision as exponential buckets with round bound values,
+/// i.e. `min = 1, max = 100, steps = 2` gives 1, 5.5, 10, 55, 100
+pub fn log_linear_bounds(min: f64, max: f64, steps: usize) -> Result<Vec<f64>, MetricError> {
+    if !(min > 0f64 && min.is_finite()) {
+        return Err(MetricError::Buckets("log-linear buckets must start above zero"));
+    }
+    if !(max > min && max.is_finite()) {
+        return Err(MetricError::Buckets("max must be greater than min"));
+    }
+    if steps == 0 {
+        return Err(MetricError::Buckets("at least one step per decade is required"));
+    }
+    if steps > MAX_BUCKETS {
+        return Err(MetricError::Buckets("too many buckets"));
+    }
+
+    let mut bounds = vec![min];
+    let mut decade = 10f64.powi(min.log10().floor() as i32);
+    while bounds.len() <= MAX_BUCKETS {
+        let step = decade * 9f64 / steps as f64;
+        for idx in 0..steps {
+            let bound = decade + step * idx as f64;
+            if bound >= max {
+                bounds.push(max);
+                validate_bounds(&bounds)?;
+                return Ok(bounds);
+            }
+            if bound > min {
+                if bounds.len() >= MAX_BUCKETS {
+                    return Err(MetricError::Buckets("too many buckets"));
+                }
+                bounds.push(bound);
+            }
+        }
+        decade *= 10f64;
+    }
+    Err(MetricError::Buckets("too many buckets"))
+}
+
+/// A histogram bucket layout, suitable for config files:
+///
+/// ```toml
+/// buckets = { type = "exponential", min = 1.0, max = 1000.0, factor = 2.0 }
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", tag = "type", deny_unknown_fields)]
+pub enum BucketLayout {
+    /// lower bounds are listed explicitly
+    Explicit { bounds: Vec<f64> },
+    /// see `exponential_bounds`
+    Exponential { min: f64, max: f64, factor: f64 },
+    /// see `log_linear_bounds`
+    LogLinear { min: f64, max: f64, steps: usize },
+}
+
+impl BucketLayout {
+    /// Generates and validates the lower bounds of buckets
+    pub fn bounds(&self) -> Result<Vec<f64>, MetricError> {
+        match self {
+            BucketLayout::Explicit { bounds } => {
+                validate_bounds(bounds)?;
+                Ok(bounds.clone())
+            }
+            BucketLayout::Exponential { min, max, factor } => exponential_bounds(*min, *max, *factor),
+            BucketLayout::LogLinear { min, max, steps } => log_linear_bounds(*min, *max, *steps),
+        }
+    }
+}
+
+/// Makes a histogram with a single value counted in the bucket with the specified lower bounds.
+/// Values below the first bound are counted in the left bucket. The result accumulates
+/// with histograms made with the same bounds.
+pub fn histogram_metric<F>(bounds: &[f64], value: F, timestamp: Option<u64>) -> Result<Metric<F>, MetricError>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    validate_bounds(bounds)?;
+    let mut buckets = bounds.iter().map(|bound| (F::from_f64(*bound), 0u64)).collect::<Vec<_>>();
+    let mut left = 0;
+    match buckets.iter().rposition(|(bound, _)| value >= *bound) {
+        Some(idx) => buckets[idx].1 = 1,
+        None => left = 1,
+    }
+    Ok(Metric::new(MetricValue::CustomHistogram(left, buckets), timestamp, 1f32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_bounds() {
+        assert_eq!(exponential_bounds(1f64, 10f64, 2f64).unwrap(), vec![1f64, 2f64, 4f64, 8f64, 10f64]);
+        assert_eq!(exponential_bounds(1f64, 8f64, 2f64).unwrap(), vec![1f64, 2f64, 4f64, 8f64]);
+        assert!(exponential_bounds(0f64, 10f64, 2f64).is_err());
+        assert!(exponential_bounds(1f64, 10f64, 1f64).is_err());
+        assert!(exponential_bounds(1f64, 1e300, 1.0001).is_err());
+
+        assert_eq!(log_linear_bounds(1f64, 100f64, 2).unwrap(), vec![1f64, 5.5, 10f64, 55f64, 100f64]);
+        assert_eq!(log_linear_bounds(2f64, 30f64, 3).unwrap(), vec![2f64, 4f64, 7f64, 10f64, 30f64]);
+        assert!(log_linear_bounds(1f64, 100f64, 0).is_err());
+        assert!(log_linear_bounds(1f64, 10f64, 1_000_000_000_000).is_err());
+        assert!(log_linear_bounds(1f64, 10f64, MAX_BUCKETS + 1).is_err());
+        assert!(log_linear_bounds(1f64, 1e3, MAX_BUCKETS / 2).is_err());
+        assert_eq!(log_linear_bounds(1f64, 10f64, MAX_BUCKETS - 1).unwrap().len(), MAX_BUCKETS);
+
+        assert!(validate_bounds(&[1f64, 1f64]).is_err());
+        assert!(validate_bounds(&[1f64, f64::INFINITY]).is_err());
+        assert!(validate_bounds(&[]).is_err());
+    }
+
+    #[test]
+    fn bucket_layout_histograms() {
+        let layout = BucketLayout::Exponential {
+            min: 1f64,
+            max: 10f64,
+            factor: 2f64,
+        };
+        let bounds = layout.bounds().unwrap();
+        assert!(BucketLayout::Explicit { bounds: vec![2f64, 1f64] }.bounds().is_err());
+
+        let mut metric = histogram_metric(&bounds, 5f64, None).unwrap();
+        metric.accumulate(histogram_metric(&bounds, 0.5f64, None).unwrap()).unwrap();
+        metric.accumulate(histogram_metric(&bounds, 100f64, None).unwrap()).unwrap();
+        assert_eq!(
+            metric.histogram_buckets(),
+            Some((1, &[(1f64, 0), (2f64, 0), (4f64, 1), (8f64, 0), (10f64, 1)][..]))
+        );
+    }
+}
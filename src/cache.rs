@@ -0,0 +1,931 @@
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use num_traits::{AsPrimitive, Float};
+
+use crate::clock::{FinishedInterval, IntervalClock, WallClock};
+use crate::metric::{accumulate_all, FromF64, Metric, MetricError, MetricValue, TimestampPrecision};
+use crate::name::{MetricName, MetricNameRef, NameKey, PrefixSeparator};
+use crate::protocol::{decode_snapshot_parallel, DecodeOptions};
+use crate::query::SnapshotQuery;
+
+/// A metric cache shared between threads. Metrics are split into a number of maps by name
+/// hash, each behind its own lock, so threads accumulating different metrics rarely wait for each other.
+#[derive(Debug)]
+pub struct ShardedCache<F>
+where
+    F: Copy + PartialEq + Debug,
+{
+    shards: Vec<Mutex<HashMap<MetricName, Metric<F>>>>,
+}
+
+impl<F> ShardedCache<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    /// Creates a cache with the specified number of shards, at least one shard is always created
+    pub fn new(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1)).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+
+    /// All shards of the cache, metric belongs to the shard at `MetricName::shard_index`
+    pub fn shards(&self) -> &[Mutex<HashMap<MetricName, Metric<F>>>] {
+        &self.shards
+    }
+
+    // shard maps stay consistent even if some thread panics holding the lock, so poisoning is ignored
+    fn lock(&self, idx: usize) -> MutexGuard<'_, HashMap<MetricName, Metric<F>>> {
+        self.shards[idx].lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Accumulates a metric into the cache, inserting it if there was no such metric
+    pub fn accumulate(&self, name: MetricName, metric: Metric<F>) -> Result<(), MetricError> {
+        let mut shard = self.lock(name.shard_index(self.shards.len()));
+        match shard.entry(name) {
+            Entry::Occupied(mut entry) => entry.get_mut().accumulate(metric),
+            Entry::Vacant(entry) => {
+                entry.insert(metric);
+                Ok(())
+            }
+        }
+    }
+
+    /// Accumulates a batch of metrics locking each shard only once.
+    /// See `accumulate_all` for details on errors
+    pub fn accumulate_all<I>(&self, incoming: I) -> Vec<(MetricName, MetricError)>
+    where
+        I: IntoIterator<Item = (MetricName, Metric<F>)>,
+    {
+        let mut batches = self.shards.iter().map(|_| Vec::new()).collect::<Vec<_>>();
+        for (name, metric) in incoming {
+            batches[name.shard_index(self.shards.len())].push((name, metric));
+        }
+
+        let mut errors = Vec::new();
+        for (idx, batch) in batches.into_iter().enumerate() {
+            if !batch.is_empty() {
+                errors.extend(accumulate_all(&mut self.lock(idx), batch));
+            }
+        }
+        errors
+    }
+
+    /// Decodes a snapshot message into the cache, see `decode_snapshot_parallel`
+    pub fn decode_snapshot(&self, data: &[u8], options: &DecodeOptions, threads: usize) -> Result<Vec<(MetricName, MetricError)>, MetricError>
+    where
+        F: Send,
+    {
+        decode_snapshot_parallel(data, options, threads, &self.shards)
+    }
+
+    /// Takes all metrics out of the cache, leaving it empty, one map per shard is returned.
+    /// Shards are taken one by one, so metrics accumulated concurrently may get into
+    /// either the returned maps or the cache
+    pub fn rotate(&self) -> Vec<HashMap<MetricName, Metric<F>>> {
+        (0..self.shards.len()).map(|idx| std::mem::take(&mut *self.lock(idx))).collect()
+    }
+
+    /// Same as `rotate`, but gives shareable views of the shards
+    pub fn rotate_views(&self) -> Vec<SnapshotView<F>> {
+        self.rotate().into_iter().map(SnapshotView::from).collect()
+    }
+
+    /// Merges the metrics from the view back to the cache, see `SnapshotView::merge_back`
+    pub fn merge_back(&self, view: SnapshotView<F>) -> Vec<(MetricName, MetricError)> {
+        let mut batches = self.shards.iter().map(|_| HashMap::new()).collect::<Vec<_>>();
+        for (name, metric) in view.into_map() {
+            batches[name.shard_index(self.shards.len())].insert(name, metric);
+        }
+
+        let mut errors = Vec::new();
+        for (idx, batch) in batches.into_iter().enumerate() {
+            if !batch.is_empty() {
+                errors.extend(SnapshotView::from(batch).merge_back(&mut self.lock(idx)));
+            }
+        }
+        errors
+    }
+
+    /// Total number of metrics in all shards
+    pub fn len(&self) -> usize {
+        (0..self.shards.len()).map(|idx| self.lock(idx).len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Calls the closure for every metric in the cache. Shards are locked one by one while
+    /// their metrics are iterated
+    pub fn for_each<C>(&self, mut f: C)
+    where
+        C: FnMut(&MetricName, &Metric<F>),
+    {
+        for idx in 0..self.shards.len() {
+            self.lock(idx).iter().map(|(name, metric)| f(name, metric)).last();
+        }
+    }
+}
+
+/// A metric cache under a single lock, metrics are accumulated into the live map, which is swapped
+/// with an empty one on rotation, so ingesting is only blocked for the time of the swap.
+/// Use `ShardedCache` when a lot of threads ingest metrics at once
+#[derive(Debug)]
+pub struct MetricCache<F>
+where
+    F: Copy + PartialEq + Debug,
+{
+    live: Mutex<HashMap<MetricName, Metric<F>>>,
+    series: Mutex<SeriesBook<F>>,
+}
+
+/// The sample emitted for a series removed by `MetricCache::sweep`. Samples are only made for
+/// gauges and counters, other types have no meaningful final value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FinalSample {
+    #[default]
+    None,
+    /// zero of the same type, so the backend sees the series dropping instead of a gap
+    Zero,
+    /// the last value seen, mostly useful for gauges
+    Last,
+}
+
+/// Last update of a series, counted in rotations of the cache
+#[derive(Debug, Clone)]
+struct SeriesState<F>
+where
+    F: Copy + PartialEq + Debug,
+{
+    interval: u64,
+    last: Option<MetricValue<F>>,
+}
+
+#[derive(Debug)]
+struct SeriesBook<F>
+where
+    F: Copy + PartialEq + Debug,
+{
+    interval: u64,
+    seen: HashMap<MetricName, SeriesState<F>>,
+}
+
+/// A snapshot of metrics taken from `MetricCache`
+pub type Snapshot<F> = SnapshotView<F>;
+
+impl<F> MetricCache<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    pub fn new() -> Self {
+        Self {
+            live: Mutex::new(HashMap::new()),
+            series: Mutex::new(SeriesBook {
+                interval: 0,
+                seen: HashMap::new(),
+            }),
+        }
+    }
+
+    fn series(&self) -> MutexGuard<'_, SeriesBook<F>> {
+        self.series.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn live(&self) -> MutexGuard<'_, HashMap<MetricName, Metric<F>>> {
+        self.live.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Accumulates a metric into the cache, inserting it if there was no such metric
+    pub fn ingest(&self, name: MetricName, metric: Metric<F>) -> Result<(), MetricError> {
+        match self.live().entry(name) {
+            Entry::Occupied(mut entry) => entry.get_mut().accumulate(metric),
+            Entry::Vacant(entry) => {
+                entry.insert(metric);
+                Ok(())
+            }
+        }
+    }
+
+    /// Same as `ingest`, but the owned name is only made if the metric is new to the cache
+    pub fn ingest_ref(&self, name: MetricNameRef<'_>, metric: Metric<F>) -> Result<(), MetricError> {
+        let mut live = self.live();
+        match live.get_mut(&name as &dyn NameKey) {
+            Some(existing) => existing.accumulate(metric),
+            None => {
+                live.insert(name.to_owned_name(), metric);
+                Ok(())
+            }
+        }
+    }
+
+    /// Accumulates a batch of metrics under a single lock, see `accumulate_all` for details on errors
+    pub fn ingest_all<I>(&self, incoming: I) -> Vec<(MetricName, MetricError)>
+    where
+        I: IntoIterator<Item = (MetricName, Metric<F>)>,
+    {
+        accumulate_all(&mut self.live(), incoming)
+    }
+
+    /// Takes all metrics out as a snapshot. The new live map is preallocated for the same
+    /// number of metrics, since the set of metrics rarely changes much between intervals
+    pub fn rotate(&self) -> Snapshot<F> {
+        let snapshot = {
+            let mut live = self.live();
+            let capacity = live.len();
+            SnapshotView::from(std::mem::replace(&mut *live, HashMap::with_capacity(capacity)))
+        };
+
+        // series bookkeeping is done once per interval, not on every ingest
+        let mut series = self.series();
+        series.interval += 1;
+        let interval = series.interval;
+        for (name, metric) in snapshot.iter() {
+            let last = match metric.value() {
+                // tombstones have expired the gauge already
+                MetricValue::Gauge(_) if metric.is_gauge_delta() || metric.is_gauge_unset() => None,
+                value @ MetricValue::Gauge(_) | value @ MetricValue::Counter(_) => Some(value.clone()),
+                _ => None,
+            };
+            match series.seen.get_mut(name) {
+                Some(state) => {
+                    state.interval = interval;
+                    state.last = last;
+                }
+                None => {
+                    series.seen.insert(name.clone(), SeriesState { interval, last });
+                }
+            }
+        }
+        snapshot
+    }
+
+    /// The number of the last rotation, the first rotation is 1
+    pub fn interval(&self) -> u64 {
+        self.series().interval
+    }
+
+    /// The rotation number when the metric was last seen in a snapshot
+    pub fn last_seen(&self, name: &MetricName) -> Option<u64> {
+        self.series().seen.get(name).map(|state| state.interval)
+    }
+
+    /// Forgets the series not updated for at least `idle_intervals` rotations, so the bookkeeping
+    /// does not grow forever with ephemeral names, like the ones including pod names.
+    /// Returns the names of removed series along with the final samples requested.
+    /// Series having new values in the live cache are not considered idle.
+    pub fn sweep(&self, idle_intervals: u64, sample: FinalSample) -> Vec<(MetricName, Option<Metric<F>>)> {
+        let idle_intervals = idle_intervals.max(1);
+        let live = self.live();
+        let mut series = self.series();
+        let interval = series.interval;
+        let mut removed = Vec::new();
+        series.seen.retain(|name, state| {
+            if interval - state.interval < idle_intervals || live.contains_key(name) {
+                return true;
+            }
+            let metric = match (sample, state.last.take()) {
+                (FinalSample::None, _) | (_, None) => None,
+                (FinalSample::Zero, Some(MetricValue::Counter(_))) => Some(Metric::new(MetricValue::Counter(F::zero()), None, 1f32)),
+                (FinalSample::Zero, Some(_)) => Some(Metric::new(MetricValue::Gauge(F::zero()), None, 1f32)),
+                (FinalSample::Last, Some(value)) => Some(Metric::new(value, None, 1f32)),
+            };
+            removed.push((name.clone(), metric));
+            false
+        });
+        removed
+    }
+
+    /// The number of series tracked by `sweep`
+    pub fn series_len(&self) -> usize {
+        self.series().seen.len()
+    }
+
+    /// Merges an unsent snapshot back, see `SnapshotView::merge_back`
+    pub fn merge(&self, snapshot: Snapshot<F>) -> Vec<(MetricName, MetricError)> {
+        // the snapshot map is prepared before taking the lock, it may need cloning
+        let snapshot = SnapshotView::from(snapshot.into_map());
+        snapshot.merge_back(&mut self.live())
+    }
+
+    pub fn len(&self) -> usize {
+        self.live().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live().is_empty()
+    }
+}
+
+impl<F> Default for MetricCache<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A cache aggregating one stream of metrics into several resolutions at once, i.e. 1s, 10s and 60s.
+/// Resolutions are specified as multiples of the base interval, which is the period `rotate` is called with.
+///
+/// Metrics are only accumulated into the base resolution on ingest, coarser resolutions get whole
+/// base snapshots merged into them on rotation, so the cost of ingest does not depend on the number of resolutions
+/// and the names are shared between resolutions.
+#[derive(Debug)]
+pub struct MultiResolutionCache<F>
+where
+    F: Copy + PartialEq + Debug,
+{
+    live: Mutex<HashMap<MetricName, Metric<F>>>,
+    resolutions: Mutex<Resolutions<F>>,
+}
+
+#[derive(Debug)]
+struct Resolutions<F>
+where
+    F: Copy + PartialEq + Debug,
+{
+    rotations: u64,
+    coarse: Vec<(u32, HashMap<MetricName, Metric<F>>)>,
+    base: bool,
+}
+
+/// Snapshots of the resolutions finished by `MultiResolutionCache::rotate`
+#[derive(Debug)]
+pub struct ResolutionSnapshots<F>
+where
+    F: Copy + PartialEq + Debug,
+{
+    /// multiple of the base interval along with the snapshot of it, finest resolutions first
+    pub snapshots: Vec<(u32, Snapshot<F>)>,
+    /// metrics failed to be merged into coarser resolutions, i.e. because of type changing between intervals
+    pub errors: Vec<(MetricName, MetricError)>,
+}
+
+impl<F> MultiResolutionCache<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    /// Zero multiples are ignored, as well as duplicates
+    pub fn new(multiples: &[u32]) -> Self {
+        let mut multiples = multiples.iter().copied().filter(|m| *m > 0).collect::<Vec<_>>();
+        multiples.sort_unstable();
+        multiples.dedup();
+        let base = multiples.first() == Some(&1);
+        let coarse = multiples.into_iter().filter(|m| *m > 1).map(|m| (m, HashMap::new())).collect();
+        Self {
+            live: Mutex::new(HashMap::new()),
+            resolutions: Mutex::new(Resolutions { rotations: 0, coarse, base }),
+        }
+    }
+
+    fn live(&self) -> MutexGuard<'_, HashMap<MetricName, Metric<F>>> {
+        self.live.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The resolutions in multiples of base interval
+    pub fn resolutions(&self) -> Vec<u32> {
+        let resolutions = self.resolutions.lock().unwrap_or_else(PoisonError::into_inner);
+        let base = if resolutions.base { Some(1) } else { None };
+        base.into_iter().chain(resolutions.coarse.iter().map(|(m, _)| *m)).collect()
+    }
+
+    /// Accumulates a metric into the base resolution
+    pub fn ingest(&self, name: MetricName, metric: Metric<F>) -> Result<(), MetricError> {
+        match self.live().entry(name) {
+            Entry::Occupied(mut entry) => entry.get_mut().accumulate(metric),
+            Entry::Vacant(entry) => {
+                entry.insert(metric);
+                Ok(())
+            }
+        }
+    }
+
+    /// Accumulates a batch of metrics into the base resolution under a single lock
+    pub fn ingest_all<I>(&self, incoming: I) -> Vec<(MetricName, MetricError)>
+    where
+        I: IntoIterator<Item = (MetricName, Metric<F>)>,
+    {
+        accumulate_all(&mut self.live(), incoming)
+    }
+
+    /// Finishes the base interval, must be called once per base interval. Returns snapshots of all
+    /// resolutions ending at this rotation: the base one every time, the coarser ones once per their multiple
+    pub fn rotate(&self) -> ResolutionSnapshots<F> {
+        let base = {
+            let mut live = self.live();
+            let capacity = live.len();
+            SnapshotView::from(std::mem::replace(&mut *live, HashMap::with_capacity(capacity)))
+        };
+
+        let mut resolutions = self.resolutions.lock().unwrap_or_else(PoisonError::into_inner);
+        resolutions.rotations += 1;
+        let rotations = resolutions.rotations;
+        let mut result = ResolutionSnapshots {
+            snapshots: Vec::new(),
+            errors: Vec::new(),
+        };
+
+        for (multiple, metrics) in resolutions.coarse.iter_mut() {
+            for (name, metric) in base.iter() {
+                match metrics.get_mut(name) {
+                    Some(existing) => {
+                        if let Err(e) = existing.accumulate_ref(metric) {
+                            result.errors.push((name.clone(), e));
+                        }
+                    }
+                    None => {
+                        metrics.insert(name.clone(), metric.clone());
+                    }
+                }
+            }
+            if rotations.is_multiple_of(u64::from(*multiple)) {
+                let capacity = metrics.len();
+                let finished = std::mem::replace(metrics, HashMap::with_capacity(capacity));
+                result.snapshots.push((*multiple, SnapshotView::from(finished)));
+            }
+        }
+
+        if resolutions.base {
+            result.snapshots.insert(0, (1, base));
+        }
+        result
+    }
+}
+
+/// A cache for metrics carrying their own timestamps. Metrics are accumulated into buckets of
+/// the aligned intervals their timestamps belong to, so late metrics are accounted to the
+/// past intervals instead of the current one. A bucket is kept open for the lateness window
+/// after the interval end, metrics coming later than that are rejected.
+#[derive(Debug)]
+pub struct BucketedCache<F, C = WallClock>
+where
+    F: Copy + PartialEq + Debug,
+    C: IntervalClock,
+{
+    clock: C,
+    interval: u64,
+    lateness: u64,
+    buckets: Mutex<BTreeMap<u64, HashMap<MetricName, Metric<F>>>>,
+}
+
+fn duration_nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+impl<F, C> BucketedCache<F, C>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+    C: IntervalClock,
+{
+    /// Intervals are aligned to the multiples of `interval` since epoch, zero interval is
+    /// treated as the smallest possible one
+    pub fn new(interval: Duration, lateness: Duration, clock: C) -> Self {
+        Self {
+            clock,
+            interval: duration_nanos(interval).max(1),
+            lateness: duration_nanos(lateness),
+            buckets: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn buckets(&self) -> MutexGuard<'_, BTreeMap<u64, HashMap<MetricName, Metric<F>>>> {
+        self.buckets.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn bucket_start(&self, ts: u64) -> u64 {
+        ts - ts % self.interval
+    }
+
+    /// Accumulates a metric into the bucket of its timestamp. Metrics without timestamps and the
+    /// ones from the future are accounted to the current interval.
+    pub fn ingest(&self, name: MetricName, metric: Metric<F>) -> Result<(), MetricError> {
+        let now = duration_nanos(self.clock.now());
+        let ts = metric.timestamp_as(TimestampPrecision::Nanos).unwrap_or(now).min(now);
+        let start = self.bucket_start(ts);
+        if start.saturating_add(self.interval).saturating_add(self.lateness) <= now {
+            return Err(MetricError::TooLate);
+        }
+
+        match self.buckets().entry(start).or_default().entry(name) {
+            Entry::Occupied(mut entry) => entry.get_mut().accumulate(metric),
+            Entry::Vacant(entry) => {
+                entry.insert(metric);
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(&self, start: u64, metrics: HashMap<MetricName, Metric<F>>) -> (FinishedInterval, Snapshot<F>) {
+        let interval = FinishedInterval {
+            start: Duration::from_nanos(start),
+            end: Duration::from_nanos(start.saturating_add(self.interval)),
+        };
+        (interval, SnapshotView::from(metrics))
+    }
+
+    /// Takes out the buckets which can not get any more metrics, oldest first
+    pub fn rotate(&self) -> Vec<(FinishedInterval, Snapshot<F>)> {
+        let now = duration_nanos(self.clock.now());
+        let mut buckets = self.buckets();
+        // buckets ending later than this are still open
+        let open = now.saturating_sub(self.lateness);
+        let open = buckets
+            .keys()
+            .position(|start| start.saturating_add(self.interval) > open)
+            .unwrap_or(buckets.len());
+        let closed: Vec<u64> = buckets.keys().take(open).copied().collect();
+        closed
+            .into_iter()
+            .filter_map(|start| buckets.remove(&start).map(|metrics| (start, metrics)))
+            .map(|(start, metrics)| self.finish(start, metrics))
+            .collect()
+    }
+
+    /// Takes out all buckets regardless of the lateness window, i.e. on shutdown
+    pub fn rotate_all(&self) -> Vec<(FinishedInterval, Snapshot<F>)> {
+        std::mem::take(&mut *self.buckets())
+            .into_iter()
+            .map(|(start, metrics)| self.finish(start, metrics))
+            .collect()
+    }
+
+    /// The number of open buckets
+    pub fn buckets_len(&self) -> usize {
+        self.buckets().len()
+    }
+}
+
+/// A frozen map of metrics, that can be cheaply cloned and read from many threads at once,
+/// i.e. when sending it to peers and flushing to backends, while new metrics are accumulated
+/// into another map
+#[derive(Debug, Clone)]
+pub struct SnapshotView<F>
+where
+    F: Copy + PartialEq + Debug,
+{
+    metrics: Arc<HashMap<MetricName, Metric<F>>>,
+}
+
+impl<F> SnapshotView<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    /// Freezes the current cache contents, leaving an empty map in place of it
+    pub fn take(cache: &mut HashMap<MetricName, Metric<F>>) -> Self {
+        Self::from(std::mem::take(cache))
+    }
+
+    pub fn get(&self, name: &MetricName) -> Option<&Metric<F>> {
+        self.metrics.get(name)
+    }
+
+    pub fn iter(&self) -> std::collections::hash_map::Iter<'_, MetricName, Metric<F>> {
+        self.metrics.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.metrics.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.metrics.is_empty()
+    }
+
+    /// Gives a copy of the snapshot with the namespace prefix added to all the names, i.e. to separate
+    /// tenants. See `MetricName::with_prefix_separated` for the rules of joining
+    pub fn with_prefix(&self, ns: &[u8], separator: PrefixSeparator) -> Self {
+        let metrics = self
+            .metrics
+            .iter()
+            .map(|(name, metric)| (name.with_prefix_separated(ns, separator), metric.clone()))
+            .collect::<HashMap<_, _>>();
+        Self::from(metrics)
+    }
+
+    /// Gives the map back, it is only cloned if there are other views of it
+    pub fn into_map(self) -> HashMap<MetricName, Metric<F>> {
+        Arc::try_unwrap(self.metrics).unwrap_or_else(|metrics| (*metrics).clone())
+    }
+
+    /// Merges the metrics back to the live cache, i.e. when the snapshot could not be sent.
+    /// Metrics from the view are considered older than the ones in the cache, so newer values
+    /// are accumulated on top of them: this keeps the latest gauge values for example.
+    /// Metrics failed to merge are returned along with the error, leaving the cached ones untouched
+    pub fn merge_back(self, cache: &mut HashMap<MetricName, Metric<F>>) -> Vec<(MetricName, MetricError)> {
+        let mut errors = Vec::new();
+        for (name, mut metric) in self.into_map() {
+            match cache.entry(name) {
+                Entry::Occupied(mut entry) => match metric.accumulate_ref(entry.get()) {
+                    Ok(()) => *entry.get_mut() = metric,
+                    Err(e) => errors.push((entry.key().clone(), e)),
+                },
+                Entry::Vacant(entry) => {
+                    entry.insert(metric);
+                }
+            }
+        }
+        errors
+    }
+}
+
+impl<F> SnapshotView<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64> + AsPrimitive<usize>,
+{
+    /// Starts a query over the snapshot, see `SnapshotQuery`
+    pub fn query(&self) -> SnapshotQuery<'_, F> {
+        SnapshotQuery::new(self)
+    }
+}
+
+impl<F> From<HashMap<MetricName, Metric<F>>> for SnapshotView<F>
+where
+    F: Copy + PartialEq + Debug,
+{
+    fn from(metrics: HashMap<MetricName, Metric<F>>) -> Self {
+        Self { metrics: Arc::new(metrics) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregate::{aggregates, Aggregate};
+    use crate::csv::{CsvEncoder, CsvOptions};
+    use crate::jsonlines::{JsonFields, JsonLinesEncoder};
+    use crate::metric::MetricValue;
+    use crate::name::TagFormat;
+    use crate::prometheus::PrometheusEncoder;
+    use crate::testing::name;
+    use bytes::BytesMut;
+
+    #[test]
+    fn sharded_cache_accumulate_rotate() {
+        let cache = ShardedCache::<f64>::new(4);
+        let names = (0..10).map(|idx| name(&format!("some.counter.{}", idx))).collect::<Vec<_>>();
+
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for name in &names {
+                        cache.accumulate(name.clone(), Metric::new(MetricValue::Counter(1f64), None, 1f32)).unwrap();
+                    }
+                    let batch = names.iter().map(|name| (name.clone(), Metric::new(MetricValue::Counter(1f64), None, 1f32)));
+                    assert!(cache.accumulate_all(batch).is_empty());
+                });
+            }
+        });
+
+        assert_eq!(cache.len(), 10);
+        let mut count = 0;
+        cache.for_each(|_, metric| {
+            assert_eq!(metric.value(), &MetricValue::Counter(8f64));
+            count += 1;
+        });
+        assert_eq!(count, 10);
+
+        assert!(cache.accumulate(names[0].clone(), Metric::new(MetricValue::Gauge(1f64), None, 1f32)).is_err());
+
+        let rotated = cache.rotate();
+        assert_eq!(rotated.len(), 4);
+        assert_eq!(rotated.iter().map(|shard| shard.len()).sum::<usize>(), 10);
+        for (idx, shard) in rotated.iter().enumerate() {
+            assert!(shard.keys().all(|name| name.shard_index(4) == idx));
+        }
+        assert_eq!(names[0].shard_index(0), 0);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn snapshot_view_merge_back() {
+        let (counter, gauge, old, bad) = (name("counter"), name("gauge"), name("old"), name("bad"));
+
+        let mut cache = HashMap::new();
+        cache.insert(counter.clone(), Metric::<f64>::new(MetricValue::Counter(1f64), None, 1f32));
+        cache.insert(gauge.clone(), Metric::new(MetricValue::Gauge(1f64), None, 1f32));
+        cache.insert(old.clone(), Metric::new(MetricValue::Gauge(1f64), None, 1f32));
+        cache.insert(bad.clone(), Metric::new(MetricValue::Gauge(1f64), None, 1f32));
+
+        let view = SnapshotView::take(&mut cache);
+        assert!(cache.is_empty());
+        let reader = view.clone();
+        let handle = std::thread::spawn(move || reader.iter().count());
+        assert_eq!(handle.join().unwrap(), 4);
+        assert_eq!(view.get(&counter).unwrap().value(), &MetricValue::Counter(1f64));
+
+        let prefixed = view.with_prefix(b"tenant1", PrefixSeparator::Dot);
+        assert_eq!(prefixed.len(), 4);
+        assert_eq!(prefixed.get(&counter.with_prefix(b"tenant1")).unwrap().value(), &MetricValue::Counter(1f64));
+        assert!(prefixed.get(&counter).is_none());
+
+        cache.insert(counter.clone(), Metric::new(MetricValue::Counter(2f64), None, 1f32));
+        cache.insert(gauge.clone(), Metric::new(MetricValue::Gauge(2f64), None, 1f32));
+        cache.insert(bad.clone(), Metric::new(MetricValue::Counter(2f64), None, 1f32));
+
+        let errors = view.merge_back(&mut cache);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, bad);
+        assert_eq!(cache[&counter].value(), &MetricValue::Counter(3f64));
+        assert_eq!(cache[&gauge].value(), &MetricValue::Gauge(2f64));
+        assert_eq!(cache[&old].value(), &MetricValue::Gauge(1f64));
+        assert_eq!(cache[&bad].value(), &MetricValue::Counter(2f64));
+
+        let sharded = ShardedCache::<f64>::new(3);
+        assert!(sharded.accumulate_all(cache).is_empty());
+        let views = sharded.rotate_views();
+        assert!(sharded.is_empty());
+        sharded.accumulate(gauge.clone(), Metric::new(MetricValue::Gauge(5f64), None, 1f32)).unwrap();
+        for view in views {
+            assert!(sharded.merge_back(view).is_empty());
+        }
+        assert_eq!(sharded.len(), 4);
+        sharded.for_each(|name, metric| {
+            if name == &gauge {
+                assert_eq!(metric.value(), &MetricValue::Gauge(5f64));
+            }
+        });
+    }
+
+    #[test]
+    fn expired_gauge_export() {
+        let (expired, gauge) = (name("expired;host=a"), name("gauge;host=a"));
+
+        let cache = MetricCache::<f64>::new();
+        cache.ingest(expired.clone(), Metric::new(MetricValue::Gauge(5f64), None, 1f32)).unwrap();
+        cache.ingest(expired.clone(), Metric::gauge_unset(None)).unwrap();
+        cache.ingest(gauge.clone(), Metric::new(MetricValue::Gauge(1f64), None, 1f32)).unwrap();
+        let snapshot = cache.rotate();
+        assert!(snapshot.get(&expired).unwrap().is_gauge_unset());
+
+        let mut buf = BytesMut::new();
+        PrometheusEncoder::default().encode(&mut buf, snapshot.iter());
+        assert_eq!(&buf[..], &b"# TYPE gauge gauge\ngauge{host=\"a\"} 1\n"[..]);
+
+        buf.clear();
+        CsvEncoder::new(CsvOptions::default()).encode_all(&mut buf, snapshot.iter().filter(|(name, _)| *name == &expired));
+        assert_eq!(&buf[..], &b"name,tags,type,value,count,ts\n"[..]);
+
+        buf.clear();
+        let json = JsonLinesEncoder::new(JsonFields::default());
+        let all = [Aggregate::Value, Aggregate::UpdateCount, Aggregate::Rate(Some(10f64))];
+        let mut metric = snapshot.get(&expired).unwrap().clone();
+        for (aggregate, value) in aggregates(&mut metric, &all) {
+            json.encode(&mut buf, &expired, Some(&aggregate), value, None);
+        }
+        assert!(buf.is_empty());
+        let query = snapshot.query().aggregates(&all);
+        assert!(query.iter().filter(|item| item.name == &expired).all(|item| item.aggregates.is_empty()));
+    }
+
+    #[test]
+    fn metric_cache_rotation() {
+        let mut intermediate = vec![0u8; 128];
+        let (counter, gauge) = (name("counter"), name("gauge"));
+
+        let cache = MetricCache::<f64>::new();
+        cache.ingest(counter.clone(), Metric::new(MetricValue::Counter(1f64), None, 1f32)).unwrap();
+        let errors = cache.ingest_all(vec![
+            (counter.clone(), Metric::new(MetricValue::Counter(1f64), None, 1f32)),
+            (gauge.clone(), Metric::new(MetricValue::Gauge(1f64), None, 1f32)),
+            (gauge.clone(), Metric::new(MetricValue::Counter(1f64), None, 1f32)),
+        ]);
+        assert_eq!(errors.len(), 1);
+
+        let snapshot = cache.rotate();
+        assert!(cache.is_empty());
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot.get(&counter).unwrap().value(), &MetricValue::Counter(2f64));
+
+        cache.ingest(counter.clone(), Metric::new(MetricValue::Counter(5f64), None, 1f32)).unwrap();
+        let reader = snapshot.clone();
+        assert!(cache.merge(snapshot).is_empty());
+        assert_eq!(reader.len(), 2);
+        assert_eq!(cache.len(), 2);
+
+        let snapshot = cache.rotate();
+        assert_eq!(snapshot.get(&counter).unwrap().value(), &MetricValue::Counter(7f64));
+        assert_eq!(snapshot.get(&gauge).unwrap().value(), &MetricValue::Gauge(1f64));
+
+        let mut arena = b"counter".to_vec();
+        let name = MetricNameRef::new(&mut arena[..], TagFormat::Graphite, &mut intermediate).unwrap();
+        cache.ingest_ref(name, Metric::new(MetricValue::Counter(1f64), None, 1f32)).unwrap();
+        cache.ingest_ref(name, Metric::new(MetricValue::Counter(2f64), None, 1f32)).unwrap();
+        assert_eq!(cache.rotate().get(&counter).unwrap().value(), &MetricValue::Counter(3f64));
+    }
+
+    #[test]
+    fn bucketed_cache_backfill() {
+        use crate::clock::ManualClock;
+
+        let counter = name("counter");
+        let clock = ManualClock::new(Duration::from_secs(125));
+        let cache = BucketedCache::<f64, _>::new(Duration::from_secs(10), Duration::from_secs(30), clock.clone());
+        let at = |ts: Option<u64>| Metric::new(MetricValue::Counter(1f64), ts, 1f32);
+
+        cache.ingest(counter.clone(), at(None)).unwrap();
+        cache.ingest(counter.clone(), at(Some(121))).unwrap();
+        cache.ingest(counter.clone(), at(Some(100))).unwrap();
+        let mut millis = at(Some(100_500));
+        millis.set_timestamp_precision(TimestampPrecision::Millis);
+        cache.ingest(counter.clone(), millis).unwrap();
+        // the future is now
+        cache.ingest(counter.clone(), at(Some(1000))).unwrap();
+        assert!(matches!(cache.ingest(counter.clone(), at(Some(80))), Err(MetricError::TooLate)));
+        assert_eq!(cache.buckets_len(), 2);
+
+        // the 100-110 bucket is still open for late metrics
+        assert!(cache.rotate().is_empty());
+        clock.set(Duration::from_secs(140));
+        let rotated = cache.rotate();
+        assert_eq!(rotated.len(), 1);
+        assert_eq!(rotated[0].0.start, Duration::from_secs(100));
+        assert_eq!(rotated[0].0.end, Duration::from_secs(110));
+        assert_eq!(rotated[0].1.get(&counter).unwrap().value(), &MetricValue::Counter(2f64));
+
+        let rotated = cache.rotate_all();
+        assert_eq!(rotated.len(), 1);
+        assert_eq!(rotated[0].0.start, Duration::from_secs(120));
+        assert_eq!(rotated[0].1.get(&counter).unwrap().value(), &MetricValue::Counter(3f64));
+        assert_eq!(cache.buckets_len(), 0);
+    }
+
+    #[test]
+    fn multi_resolution_cache() {
+        let counter = name("counter");
+        let cache = MultiResolutionCache::<f64>::new(&[6, 1, 0, 2, 2]);
+        assert_eq!(cache.resolutions(), vec![1, 2, 6]);
+
+        let mut finished = Vec::new();
+        for i in 0..6 {
+            cache.ingest(counter.clone(), Metric::new(MetricValue::Counter(i as f64), None, 1f32)).unwrap();
+            let rotated = cache.rotate();
+            assert!(rotated.errors.is_empty());
+            for (multiple, snapshot) in rotated.snapshots {
+                finished.push((multiple, snapshot.get(&counter).unwrap().value().clone()));
+            }
+        }
+        let expected = vec![
+            (1, 0f64),
+            (1, 1f64),
+            (2, 1f64),
+            (1, 2f64),
+            (1, 3f64),
+            (2, 5f64),
+            (1, 4f64),
+            (1, 5f64),
+            (2, 9f64),
+            (6, 15f64),
+        ];
+        let expected = expected.into_iter().map(|(m, v)| (m, MetricValue::Counter(v))).collect::<Vec<_>>();
+        assert_eq!(finished, expected);
+
+        cache.ingest(counter.clone(), Metric::new(MetricValue::Gauge(1f64), None, 1f32)).unwrap();
+        cache.rotate();
+        cache.ingest(counter.clone(), Metric::new(MetricValue::Counter(1f64), None, 1f32)).unwrap();
+        let rotated = cache.rotate();
+        assert_eq!(rotated.errors.len(), 2);
+        assert_eq!(rotated.snapshots.len(), 2);
+    }
+
+    #[test]
+    fn metric_cache_sweep() {
+        let (counter, gauge, timer) = (name("counter"), name("gauge"), name("timer"));
+
+        let cache = MetricCache::<f64>::new();
+        cache.ingest(counter.clone(), Metric::new(MetricValue::Counter(1f64), None, 1f32)).unwrap();
+        cache.ingest(gauge.clone(), Metric::new(MetricValue::Gauge(3f64), None, 1f32)).unwrap();
+        cache.ingest(timer.clone(), Metric::new(MetricValue::Timer(vec![1f64]), None, 1f32)).unwrap();
+        cache.rotate();
+        assert_eq!(cache.series_len(), 3);
+
+        cache.ingest(gauge.clone(), Metric::new(MetricValue::Gauge(4f64), None, 1f32)).unwrap();
+        cache.rotate();
+        assert_eq!(cache.interval(), 2);
+        assert_eq!(cache.last_seen(&counter), Some(1));
+        assert_eq!(cache.last_seen(&gauge), Some(2));
+        assert!(cache.sweep(2, FinalSample::Zero).is_empty());
+
+        // the counter is idle, but has a new value pending
+        cache.ingest(counter.clone(), Metric::new(MetricValue::Counter(1f64), None, 1f32)).unwrap();
+        cache.rotate();
+        cache.rotate();
+        assert_eq!(cache.sweep(3, FinalSample::Last), vec![(timer.clone(), None)]);
+
+        cache.rotate();
+        let mut removed = cache.sweep(2, FinalSample::Last);
+        removed.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+        assert_eq!(removed.len(), 2);
+        assert_eq!(removed[0].0, counter);
+        assert_eq!(removed[0].1.as_ref().unwrap().value(), &MetricValue::Counter(1f64));
+        assert_eq!(removed[1].0, gauge);
+        assert_eq!(removed[1].1.as_ref().unwrap().value(), &MetricValue::Gauge(4f64));
+        assert_eq!(cache.series_len(), 0);
+    }
+}
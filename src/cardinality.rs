@@ -0,0 +1,327 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use bytes::BytesMut;
+use num_traits::{AsPrimitive, Float};
+
+use crate::metric::{FromF64, Metric, MetricValue};
+use crate::name::{escape_tag_value, MetricName};
+
+// distinct values by tag key, values are kept as hashes to save memory
+type TagValues = HashMap<Vec<u8>, HashSet<u64>>;
+
+/// Default limit of names tracked by `CardinalityCollector` in an interval
+pub const DEFAULT_MAX_NAMES: usize = 10_000;
+
+/// Default limit of distinct values tracked for a tag of a name in an interval
+pub const DEFAULT_MAX_VALUES: usize = 10_000;
+
+/// Number of distinct values of a tag seen with a metric name in an interval
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagCardinality {
+    /// name without tags
+    pub name: Vec<u8>,
+    pub key: Vec<u8>,
+    pub values: usize,
+}
+
+#[derive(Debug, Default)]
+struct Tracked {
+    names: HashMap<Vec<u8>, TagValues>,
+    dropped_names: usize,
+}
+
+/// Counts distinct tag values per metric name and tag key, so the names and tags producing
+/// too many series can be found before they reach the storage. Can be fed from many threads at once.
+///
+/// Memory is bounded by the limits of names and of values per tag: names seen after the limit
+/// is reached are only counted as dropped, values over the limit are not counted, so the tags
+/// having the limit of values have at least that many.
+#[derive(Debug)]
+pub struct CardinalityCollector {
+    tracked: Mutex<Tracked>,
+    max_names: usize,
+    max_values: usize,
+}
+
+impl Default for CardinalityCollector {
+    fn default() -> Self {
+        Self {
+            tracked: Mutex::new(Tracked::default()),
+            max_names: DEFAULT_MAX_NAMES,
+            max_values: DEFAULT_MAX_VALUES,
+        }
+    }
+}
+
+impl CardinalityCollector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the limit of names tracked in an interval, `DEFAULT_MAX_NAMES` by default
+    pub fn with_max_names(mut self, max_names: usize) -> Self {
+        self.max_names = max_names;
+        self
+    }
+
+    /// Sets the limit of distinct values tracked for a tag of a name, `DEFAULT_MAX_VALUES` by default
+    pub fn with_max_values(mut self, max_values: usize) -> Self {
+        self.max_values = max_values;
+        self
+    }
+
+    fn tracked(&self) -> MutexGuard<'_, Tracked> {
+        self.tracked.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn observe_locked(&self, tracked: &mut Tracked, name: &MetricName) {
+        if name.tags_len() == 0 {
+            return;
+        }
+        let base = name.name_without_tags();
+        let full = tracked.names.len() >= self.max_names;
+        let keys = match tracked.names.get_mut(base) {
+            Some(keys) => keys,
+            None if full => {
+                tracked.dropped_names += 1;
+                return;
+            }
+            None => tracked.names.entry(base.to_vec()).or_default(),
+        };
+        for (key, value) in name.tags() {
+            let mut hasher = DefaultHasher::new();
+            value.hash(&mut hasher);
+            let hash = hasher.finish();
+            match keys.get_mut(key) {
+                Some(values) if values.len() < self.max_values => {
+                    values.insert(hash);
+                }
+                Some(_) => (),
+                None if self.max_values > 0 => {
+                    keys.insert(key.to_vec(), std::iter::once(hash).collect());
+                }
+                None => (),
+            }
+        }
+    }
+
+    /// Counts tag values of the name, untagged names are ignored
+    pub fn observe(&self, name: &MetricName) {
+        self.observe_locked(&mut self.tracked(), name)
+    }
+
+    /// Same as `observe`, but takes the lock once for all the names
+    pub fn observe_all<'a, I>(&self, names: I)
+    where
+        I: IntoIterator<Item = &'a MetricName>,
+    {
+        let mut locked = self.tracked();
+        for name in names {
+            self.observe_locked(&mut locked, name)
+        }
+    }
+
+    /// Takes the statistics for the interval, resetting the collector
+    pub fn take(&self) -> CardinalityReport {
+        let tracked = std::mem::take(&mut *self.tracked());
+        let mut entries = tracked
+            .names
+            .into_iter()
+            .flat_map(|(name, keys)| {
+                keys.into_iter().map(move |(key, values)| TagCardinality {
+                    name: name.clone(),
+                    key,
+                    values: values.len(),
+                })
+            })
+            .collect::<Vec<_>>();
+        entries.sort_unstable_by(|a, b| b.values.cmp(&a.values).then_with(|| a.name.cmp(&b.name)).then_with(|| a.key.cmp(&b.key)));
+        CardinalityReport {
+            entries,
+            dropped_names: tracked.dropped_names,
+        }
+    }
+}
+
+/// Tag cardinalities of an interval, ordered from the largest
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CardinalityReport {
+    pub entries: Vec<TagCardinality>,
+    /// the number of observations of names over the limit, which are not in the entries
+    pub dropped_names: usize,
+}
+
+impl CardinalityReport {
+    /// The `n` largest cardinalities
+    pub fn top(&self, n: usize) -> &[TagCardinality] {
+        &self.entries[..n.min(self.entries.len())]
+    }
+
+    /// Makes gauges named `<prefix>;key=<tag key>;metric=<name>` for the cardinalities of at least `min_values`,
+    /// the prefix is a dotted untagged name like in `SelfMetrics`. Tag keys and names are escaped with `escape_tag_value`
+    pub fn metrics<F>(&self, prefix: &[u8], min_values: usize, timestamp: Option<u64>) -> Vec<(MetricName, Metric<F>)>
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<f64>,
+    {
+        self.entries
+            .iter()
+            .take_while(|entry| entry.values >= min_values)
+            .map(|entry| {
+                let key = escape_tag_value(&entry.key);
+                let metric = escape_tag_value(&entry.name);
+                let mut buf = BytesMut::with_capacity(prefix.len() + key.len() + metric.len() + 13);
+                // the tags are sorted already, key < metric
+                buf.extend_from_slice(prefix);
+                buf.extend_from_slice(b";key=");
+                buf.extend_from_slice(&key);
+                buf.extend_from_slice(b";metric=");
+                buf.extend_from_slice(&metric);
+                let name = MetricName::from_sorted_parts(buf.freeze(), Some(prefix.len()));
+                (name, Metric::new(MetricValue::Gauge(F::from_f64(entry.values as f64)), timestamp, 1f32))
+            })
+            .collect()
+    }
+}
+
+/// Load produced by metrics with the same name prefix
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrefixUsage {
+    pub prefix: Vec<u8>,
+    pub series: usize,
+    pub updates: f64,
+}
+
+/// The heaviest name prefixes of a snapshot
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PrefixReport {
+    pub by_series: Vec<PrefixUsage>,
+    pub by_updates: Vec<PrefixUsage>,
+}
+
+/// Finds the `top` prefixes of `depth` name segments having the most series and the most updates,
+/// i.e. with depth of 2 both `team.app.requests` and `team.app.errors` count for `team.app`.
+/// Names having less segments than `depth` are counted as whole. Can be used with a snapshot as
+/// `prefix_usage(snapshot.iter(), 2, 10)`.
+pub fn prefix_usage<'a, F, I>(metrics: I, depth: usize, top: usize) -> PrefixReport
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64> + 'a,
+    I: IntoIterator<Item = (&'a MetricName, &'a Metric<F>)>,
+{
+    let mut prefixes: HashMap<&'a [u8], (usize, f64)> = HashMap::new();
+    for (name, metric) in metrics {
+        let len = name.segments().take(depth).map(|segment| segment.len() + 1).sum::<usize>().saturating_sub(1);
+        let usage = prefixes.entry(&name.name_without_tags()[..len]).or_default();
+        usage.0 += 1;
+        usage.1 += metric.updates().as_();
+    }
+
+    let mut usages = prefixes
+        .into_iter()
+        .map(|(prefix, (series, updates))| PrefixUsage {
+            prefix: prefix.to_vec(),
+            series,
+            updates,
+        })
+        .collect::<Vec<_>>();
+    usages.sort_unstable_by(|a, b| b.series.cmp(&a.series).then_with(|| a.prefix.cmp(&b.prefix)));
+    let by_series = usages.iter().take(top).cloned().collect();
+    usages.sort_unstable_by(|a, b| {
+        b.updates
+            .partial_cmp(&a.updates)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| a.prefix.cmp(&b.prefix))
+    });
+    usages.truncate(top);
+    PrefixReport { by_series, by_updates: usages }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::name;
+
+    #[test]
+    fn tag_cardinality() {
+        let names = [
+            name("requests;user=1;env=prod"),
+            name("requests;user=2;env=prod"),
+            name("requests;user=3;env=dev"),
+            name("requests;user=1;env=prod"),
+            name("errors;code=500"),
+            name("untagged"),
+        ];
+
+        let collector = CardinalityCollector::new();
+        collector.observe(&names[0]);
+        collector.observe_all(names[1..].iter());
+        let report = collector.take();
+        assert_eq!(collector.take(), CardinalityReport::default());
+
+        let entry = |name: &str, key: &str, values| TagCardinality {
+            name: name.as_bytes().to_vec(),
+            key: key.as_bytes().to_vec(),
+            values,
+        };
+        assert_eq!(
+            report.entries,
+            vec![entry("requests", "user", 3), entry("requests", "env", 2), entry("errors", "code", 1)]
+        );
+        assert_eq!(report.top(1), &[entry("requests", "user", 3)]);
+        assert_eq!(report.top(10).len(), 3);
+
+        let metrics = report.metrics::<f64>(b"cardinality", 2, Some(10));
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(&metrics[0].0.name[..], b"cardinality;key=user;metric=requests");
+        assert_eq!(metrics[0].0.tag_value(b"metric"), Some(&b"requests"[..]));
+        assert_eq!(metrics[1].1.value(), &MetricValue::Gauge(2f64));
+
+        // names and keys are escaped to make valid tag values
+        let escaped = CardinalityReport {
+            entries: vec![entry("a~b%c", "user id", 1)],
+            dropped_names: 0,
+        };
+        let metrics = escaped.metrics::<f64>(b"cardinality", 1, None);
+        assert_eq!(&metrics[0].0.name[..], b"cardinality;key=user%20id;metric=a%7Eb%25c");
+        assert_eq!(metrics[0].0.tag_value_unescaped(b"metric").as_deref(), Some(&b"a~b%c"[..]));
+
+        let collector = CardinalityCollector::new().with_max_names(1).with_max_values(2);
+        collector.observe_all(names.iter());
+        let report = collector.take();
+        assert_eq!(report.entries, vec![entry("requests", "env", 2), entry("requests", "user", 2)]);
+        assert_eq!(report.dropped_names, 1);
+    }
+
+    #[test]
+    fn prefix_usage_report() {
+        let counter = |updates: usize| {
+            let mut metric = Metric::new(MetricValue::Counter(1f64), None, 1f32);
+            for _ in 1..updates {
+                metric.accumulate(Metric::new(MetricValue::Counter(1f64), None, 1f32)).unwrap();
+            }
+            metric
+        };
+        let metrics = [
+            (name("team.app.requests;host=a"), counter(1)),
+            (name("team.app.requests;host=b"), counter(1)),
+            (name("team.app.errors"), counter(1)),
+            (name("team.db.queries"), counter(10)),
+            (name("other"), counter(2)),
+        ];
+
+        let report = prefix_usage(metrics.iter().map(|(name, metric)| (name, metric)), 2, 2);
+        let prefixes = |usages: &[PrefixUsage]| usages.iter().map(|usage| String::from_utf8(usage.prefix.clone()).unwrap()).collect::<Vec<_>>();
+        assert_eq!(prefixes(&report.by_series), vec!["team.app", "other"]);
+        assert_eq!(report.by_series[0].series, 3);
+        assert_eq!(report.by_series[0].updates, 3f64);
+        assert_eq!(prefixes(&report.by_updates), vec!["team.db", "team.app"]);
+        assert_eq!(report.by_updates[0].updates, 10f64);
+
+        let report = prefix_usage(metrics.iter().map(|(name, metric)| (name, metric)), 1, 10);
+        assert_eq!(prefixes(&report.by_series), vec!["team", "other"]);
+        assert_eq!(report.by_series[0].series, 4);
+    }
+}
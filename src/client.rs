@@ -0,0 +1,240 @@
+use std::fmt::Debug;
+use std::io::Write;
+
+use bytes::{Buf, Bytes, BytesMut};
+use num_traits::Float;
+
+use crate::metric::{MetricError, StatsdMetric, StatsdType};
+use crate::name::UnicodePolicy;
+
+// debug formatting gives the shortest representation of a float, keeping f32 values as is
+fn put_value<F: Float + Debug>(buf: &mut Vec<u8>, value: F) {
+    let start = buf.len();
+    // writing to a vector never fails
+    write!(buf, "{:?}", value).unwrap_or_default();
+    if buf[start..].ends_with(b".0") {
+        buf.truncate(buf.len() - 2);
+    }
+}
+
+/// Accumulates metrics into statsd datagram payloads no larger than the specified size, i.e. the MTU
+/// of the network minus headers. Lines are never split between packets. The output is the format
+/// the crate parser reads, so it can be used as a core of statsd client libraries.
+#[derive(Debug, Clone)]
+pub struct PacketBuilder {
+    max_size: usize,
+    current: BytesMut,
+    ready: Vec<Bytes>,
+    line: Vec<u8>,
+    unicode: UnicodePolicy,
+}
+
+impl PacketBuilder {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            current: BytesMut::with_capacity(max_size),
+            ready: Vec::new(),
+            line: Vec::new(),
+            unicode: UnicodePolicy::default(),
+        }
+    }
+
+    /// Apply the policy to names with non-ASCII characters, pushing the metrics with rejected names fails
+    pub fn with_unicode(mut self, policy: UnicodePolicy) -> Self {
+        self.unicode = policy;
+        self
+    }
+
+    /// Adds a line as is, several lines separated by newlines are kept in the same packet
+    pub fn push_line(&mut self, line: &[u8]) -> Result<(), MetricError> {
+        if line.len() > self.max_size {
+            return Err(MetricError::PacketSize(line.len()));
+        }
+        if !self.current.is_empty() && self.current.len() + 1 + line.len() > self.max_size {
+            self.ready.push(self.current.split().freeze());
+        }
+        if !self.current.is_empty() {
+            self.current.extend_from_slice(b"\n");
+        }
+        self.current.extend_from_slice(line);
+        Ok(())
+    }
+
+    /// Adds the metric. The name is given with tags in Graphite format, i.e. `requests;env=prod`.
+    /// Negative gauges are sent as two lines, setting the gauge to zero and subtracting
+    /// the value, because a negative sign means a change in statsd.
+    pub fn push<F: Float + Debug>(&mut self, name: &[u8], metric: &StatsdMetric<F>) -> Result<(), MetricError> {
+        metric.validate()?;
+        let name = self.unicode.apply(name)?;
+        let name = &name[..];
+        let mut line = std::mem::take(&mut self.line);
+        line.clear();
+        let put = |line: &mut Vec<u8>, sign: Option<&[u8]>, value: F| {
+            line.extend_from_slice(name);
+            line.extend_from_slice(b":");
+            if let Some(sign) = sign {
+                line.extend_from_slice(sign);
+            }
+            put_value(line, value);
+            line.extend_from_slice(b"|");
+            match metric.mtype() {
+                StatsdType::Gauge(_) => line.extend_from_slice(b"g"),
+                StatsdType::Counter => line.extend_from_slice(b"c"),
+                StatsdType::Timer => line.extend_from_slice(b"ms"),
+                StatsdType::Set => line.extend_from_slice(b"s"),
+                StatsdType::CustomHistogram(start, end) => {
+                    line.extend_from_slice(b"H");
+                    put_value(line, *start);
+                    line.extend_from_slice(b",");
+                    put_value(line, *end);
+                }
+            }
+            match metric.sampling() {
+                Some(sampling) if sampling < 1f32 => {
+                    // writing to a vector never fails
+                    write!(line, "|@{:?}", sampling).unwrap_or_default();
+                }
+                _ => {}
+            }
+        };
+
+        let value = metric.value();
+        match metric.mtype() {
+            StatsdType::Gauge(Some(sign)) => put(&mut line, Some(if *sign < 0 { b"-" } else { b"+" }), value.abs()),
+            StatsdType::Gauge(None) if value < F::zero() => {
+                put(&mut line, None, F::zero());
+                line.push(b'\n');
+                put(&mut line, Some(b"-"), value.abs());
+            }
+            _ => put(&mut line, None, value),
+        }
+
+        let result = self.push_line(&line);
+        self.line = line;
+        result
+    }
+
+    /// Takes the packets that are full
+    pub fn take_ready(&mut self) -> Vec<Bytes> {
+        std::mem::take(&mut self.ready)
+    }
+
+    /// Takes all the packets including the last incomplete one, leaving the builder empty
+    pub fn finish(&mut self) -> Vec<Bytes> {
+        if !self.current.is_empty() {
+            self.ready.push(self.current.split().freeze());
+        }
+        self.take_ready()
+    }
+}
+
+/// Splits newline-separated output of text encoders, like `WavefrontEncoder` or `JsonLinesEncoder`,
+/// into packets no larger than `max_size`, never breaking a line between packets. Packets share
+/// the memory of the buffer and have no trailing newline.
+pub fn split_packets(buf: Bytes, max_size: usize) -> Packets {
+    Packets { rest: buf, max_size }
+}
+
+/// An iterator over packets made by `split_packets`. A line that cannot fit into a packet is skipped
+/// giving an error, the iteration may be continued after it
+#[derive(Debug, Clone)]
+pub struct Packets {
+    rest: Bytes,
+    max_size: usize,
+}
+
+impl Iterator for Packets {
+    type Item = Result<Bytes, MetricError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let skip = self.rest.iter().take_while(|c| **c == b'\n').count();
+        self.rest.advance(skip);
+        if self.rest.is_empty() {
+            return None;
+        }
+
+        let mut end = self.rest.len();
+        if end > self.max_size {
+            // a newline right after max_size bytes still leaves the line in the packet
+            match self.rest[..=self.max_size].iter().rposition(|c| *c == b'\n') {
+                Some(pos) => end = pos,
+                None => {
+                    let len = self.rest.iter().position(|c| *c == b'\n').unwrap_or(self.rest.len());
+                    self.rest.advance(len);
+                    return Some(Err(MetricError::PacketSize(len)));
+                }
+            }
+        }
+        let mut packet = self.rest.split_to(end);
+        if packet.ends_with(b"\n") {
+            packet.truncate(packet.len() - 1);
+        }
+        Some(Ok(packet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::validate_line;
+
+    #[test]
+    fn statsd_packets() {
+        let mut builder = PacketBuilder::new(41);
+        builder
+            .push(b"requests;env=prod", &StatsdMetric::new(1f64, StatsdType::Counter, Some(0.5)).unwrap())
+            .unwrap();
+        builder.push(b"latency", &StatsdMetric::new(0.1f32, StatsdType::Timer, None).unwrap()).unwrap();
+        assert!(builder.take_ready().is_empty());
+        builder
+            .push(b"temp", &StatsdMetric::new(-5f64, StatsdType::Gauge(None), None).unwrap())
+            .unwrap();
+        builder
+            .push(b"temp", &StatsdMetric::new(2.5f64, StatsdType::Gauge(Some(-1)), Some(1f32)).unwrap())
+            .unwrap();
+        builder
+            .push(b"sizes", &StatsdMetric::new(3f64, StatsdType::CustomHistogram(0f64, 10f64), None).unwrap())
+            .unwrap();
+        let mut packets = builder.take_ready();
+        assert_eq!(
+            packets,
+            vec![
+                Bytes::from("requests;env=prod:1|c|@0.5\nlatency:0.1|ms"),
+                Bytes::from("temp:0|g\ntemp:-5|g\ntemp:-2.5|g")
+            ]
+        );
+
+        let long = vec![b'a'; 42];
+        assert!(matches!(builder.push_line(&long), Err(MetricError::PacketSize(42))));
+        assert!(builder.push(b"bad", &StatsdMetric::new(f64::NAN, StatsdType::Counter, None).unwrap()).is_err());
+
+        let mut unicode = PacketBuilder::new(100).with_unicode(UnicodePolicy::Transliterate);
+        let counter = StatsdMetric::new(1f64, StatsdType::Counter, None).unwrap();
+        unicode.push("température".as_bytes(), &counter).unwrap();
+        assert_eq!(unicode.finish(), vec![Bytes::from("temperature:1|c")]);
+        let mut rejecting = PacketBuilder::new(100).with_unicode(UnicodePolicy::Reject);
+        assert!(matches!(rejecting.push("température".as_bytes(), &counter), Err(MetricError::Name(_))));
+
+        assert_eq!(builder.finish(), vec![Bytes::from("sizes:3|H0,10")]);
+        packets.push(Bytes::from("sizes:3|H0,10"));
+        assert!(builder.finish().is_empty());
+        for line in packets.iter().flat_map(|packet| packet.split(|c| *c == b'\n')) {
+            assert!(validate_line(line, 100).is_ok(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn split_encoded_packets() {
+        let buf = Bytes::from("aaaa\nbbbb\ncccccccccccc\ndd\n\nee\n");
+        let packets = split_packets(buf, 9).collect::<Vec<_>>();
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets[0].as_ref().unwrap(), &Bytes::from("aaaa\nbbbb"));
+        assert!(matches!(packets[1], Err(MetricError::PacketSize(12))));
+        assert_eq!(packets[2].as_ref().unwrap(), &Bytes::from("dd\n\nee"));
+
+        let packets = split_packets(Bytes::from("aaaa\nbbbb\n"), 100).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(packets, vec![Bytes::from("aaaa\nbbbb")]);
+        assert_eq!(split_packets(Bytes::new(), 10).count(), 0);
+    }
+}
@@ -0,0 +1,158 @@
+use std::convert::TryFrom;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use num_traits::{AsPrimitive, Float};
+
+use crate::aggregate::Aggregate;
+use crate::metric::{FromF64, TimestampPrecision};
+
+/// A source of time for aggregation intervals
+pub trait IntervalClock {
+    /// Time passed since unix epoch
+    fn now(&self) -> Duration;
+}
+
+/// The system wall clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WallClock;
+
+impl IntervalClock for WallClock {
+    fn now(&self) -> Duration {
+        // clock set before epoch is not a thing to care about
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+    }
+}
+
+/// A clock moved only explicitly, i.e. for tests. Clones share the same time
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    nanos: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    pub fn new(now: Duration) -> Self {
+        let clock = Self::default();
+        clock.set(now);
+        clock
+    }
+
+    pub fn set(&self, now: Duration) {
+        self.nanos.store(u64::try_from(now.as_nanos()).unwrap_or(u64::MAX), Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.set(self.now() + by);
+    }
+}
+
+impl IntervalClock for ManualClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::SeqCst))
+    }
+}
+
+/// Keeps track of the current aggregation interval, so all consumers
+/// stamp aggregates and count rates the same way
+#[derive(Debug, Clone)]
+pub struct Interval<C: IntervalClock> {
+    clock: C,
+    start: Duration,
+}
+
+impl<C: IntervalClock> Interval<C> {
+    /// Starts the first interval at the current time
+    pub fn new(clock: C) -> Self {
+        let start = clock.now();
+        Self { clock, start }
+    }
+
+    pub fn start(&self) -> Duration {
+        self.start
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.clock.now().saturating_sub(self.start)
+    }
+
+    /// Finishes the current interval and starts the next one right at its end
+    pub fn rotate(&mut self) -> FinishedInterval {
+        let end = self.clock.now().max(self.start);
+        let finished = FinishedInterval { start: self.start, end };
+        self.start = end;
+        finished
+    }
+}
+
+/// Bounds of a finished aggregation interval
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FinishedInterval {
+    pub start: Duration,
+    pub end: Duration,
+}
+
+impl FinishedInterval {
+    pub fn duration(&self) -> Duration {
+        self.end - self.start
+    }
+
+    /// The timestamp for aggregates of the interval, which is the end of it
+    pub fn timestamp(&self, precision: TimestampPrecision) -> u64 {
+        let nanos = u64::try_from(self.end.as_nanos()).unwrap_or(u64::MAX);
+        TimestampPrecision::Nanos.convert(nanos, precision)
+    }
+
+    /// Sets the interval to the rate aggregates parsed from config with no interval set, the rates
+    /// with explicit interval are left as is. Zero length intervals are left unset, because no rate
+    /// can be counted for them
+    pub fn set_rates<F>(&self, aggregates: &mut [Aggregate<F>])
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<usize>,
+    {
+        let secs = self.duration().as_secs_f64();
+        if secs <= 0f64 {
+            return;
+        }
+        for aggregate in aggregates.iter_mut() {
+            if let Aggregate::Rate(ref mut interval @ None) = aggregate {
+                *interval = Some(F::from_f64(secs));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interval_rotation() {
+        let clock = ManualClock::new(Duration::from_secs(100));
+        let mut interval = Interval::new(clock.clone());
+        clock.advance(Duration::from_millis(30_500));
+        assert_eq!(interval.elapsed(), Duration::from_millis(30_500));
+
+        let finished = interval.rotate();
+        assert_eq!(finished.start, Duration::from_secs(100));
+        assert_eq!(finished.duration(), Duration::from_millis(30_500));
+        assert_eq!(finished.timestamp(TimestampPrecision::Seconds), 130);
+        assert_eq!(finished.timestamp(TimestampPrecision::Millis), 130_500);
+        assert_eq!(interval.start(), finished.end);
+
+        let mut aggregates = vec![Aggregate::<f64>::Rate(None), Aggregate::Count, Aggregate::Rate(Some(60f64))];
+        finished.set_rates(&mut aggregates);
+        assert_eq!(aggregates, vec![Aggregate::Rate(Some(30.5)), Aggregate::Count, Aggregate::Rate(Some(60f64))]);
+
+        // clock going backwards must not make negative intervals
+        clock.set(Duration::from_secs(10));
+        let finished = interval.rotate();
+        assert_eq!(finished.duration(), Duration::from_secs(0));
+        let mut aggregates = vec![Aggregate::<f64>::Rate(None)];
+        finished.set_rates(&mut aggregates);
+        assert_eq!(aggregates, vec![Aggregate::Rate(None)]);
+
+        assert!(Interval::new(WallClock).start() > Duration::from_secs(1_500_000_000));
+    }
+}
@@ -0,0 +1,208 @@
+use std::fmt::Debug;
+
+use bytes::BytesMut;
+use num_traits::{AsPrimitive, Float};
+
+use crate::metric::{FromF64, Metric, MetricError, MetricValue, TimestampPrecision};
+use crate::name::MetricName;
+
+const PART_HOST: u16 = 0x0000;
+const PART_TIME: u16 = 0x0001;
+const PART_PLUGIN: u16 = 0x0002;
+const PART_PLUGIN_INSTANCE: u16 = 0x0003;
+const PART_TYPE: u16 = 0x0004;
+const PART_TYPE_INSTANCE: u16 = 0x0005;
+const PART_VALUES: u16 = 0x0006;
+const PART_TIME_HR: u16 = 0x0008;
+const PART_ENCRYPTION: u16 = 0x0210;
+
+const VALUE_COUNTER: u8 = 0;
+const VALUE_GAUGE: u8 = 1;
+const VALUE_DERIVE: u8 = 2;
+const VALUE_ABSOLUTE: u8 = 3;
+
+/// The values identifying the series, parts only change the fields they carry
+#[derive(Debug, Default)]
+struct State<'a> {
+    host: &'a [u8],
+    plugin: &'a [u8],
+    plugin_instance: &'a [u8],
+    type_name: &'a [u8],
+    type_instance: &'a [u8],
+    time: Option<(u64, TimestampPrecision)>,
+}
+
+impl<'a> State<'a> {
+    /// The name like in collectd write_graphite plugin: `host.plugin-instance.type-instance`,
+    /// dots in the host name are escaped with underscores. Data sources are numbered when there are many of them,
+    /// since their names come from types.db which is not available here.
+    fn name(&self, source: Option<usize>) -> MetricName {
+        let mut name =
+            BytesMut::with_capacity(self.host.len() + self.plugin.len() + self.plugin_instance.len() + self.type_name.len() + self.type_instance.len() + 8);
+        name.extend(self.host.iter().map(|c| if *c == b'.' { b'_' } else { *c }));
+        for (part, instance) in [(self.plugin, self.plugin_instance), (self.type_name, self.type_instance)] {
+            name.extend_from_slice(b".");
+            name.extend_from_slice(part);
+            if !instance.is_empty() {
+                name.extend_from_slice(b"-");
+                name.extend_from_slice(instance);
+            }
+        }
+        if let Some(source) = source {
+            name.extend_from_slice(format!(".{}", source).as_bytes());
+        }
+        MetricName::new_untagged(name)
+    }
+}
+
+fn be_u64(data: &[u8]) -> u64 {
+    data.iter().take(8).fold(0u64, |acc, b| (acc << 8) | u64::from(*b))
+}
+
+fn string_part(payload: &[u8]) -> Result<&[u8], MetricError> {
+    match payload.split_last() {
+        Some((0, string)) => Ok(string),
+        _ => Err(MetricError::Collectd("string is not null-terminated")),
+    }
+}
+
+/// Parses a packet of collectd network binary protocol. Signed packets are accepted without checking
+/// the signature, encrypted ones are rejected. Notifications are skipped.
+///
+/// Collectd data sources are mapped to metric types as follows:
+///
+/// * GAUGE becomes a gauge
+/// * COUNTER and DERIVE are cumulative values, they become gauges too, so the rate must be derived
+///   from them downstream; summing them as counters would make no sense
+/// * ABSOLUTE is a number of events since the last read, so it becomes a counter
+///
+/// Metrics are timestamped with the time of collectd, high resolution times are converted to nanoseconds.
+pub fn parse_collectd<F>(mut data: &[u8]) -> Result<Vec<(MetricName, Metric<F>)>, MetricError>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    let mut state = State::default();
+    let mut metrics = Vec::new();
+    while !data.is_empty() {
+        if data.len() < 4 {
+            return Err(MetricError::Collectd("truncated part header"));
+        }
+        let part = u16::from_be_bytes([data[0], data[1]]);
+        let len = usize::from(u16::from_be_bytes([data[2], data[3]]));
+        if len < 4 || len > data.len() {
+            return Err(MetricError::Collectd("bad part length"));
+        }
+        let payload = &data[4..len];
+        data = &data[len..];
+
+        match part {
+            PART_HOST => state.host = string_part(payload)?,
+            PART_PLUGIN => state.plugin = string_part(payload)?,
+            PART_PLUGIN_INSTANCE => state.plugin_instance = string_part(payload)?,
+            PART_TYPE => state.type_name = string_part(payload)?,
+            PART_TYPE_INSTANCE => state.type_instance = string_part(payload)?,
+            PART_TIME if payload.len() == 8 => state.time = Some((be_u64(payload), TimestampPrecision::Seconds)),
+            // the high resolution time is in 2^-30 seconds
+            PART_TIME_HR if payload.len() == 8 => {
+                let time = be_u64(payload);
+                let nanos = (u128::from(time) * 1_000_000_000) >> 30;
+                state.time = Some((nanos as u64, TimestampPrecision::Nanos));
+            }
+            PART_TIME | PART_TIME_HR => return Err(MetricError::Collectd("bad time part")),
+            PART_VALUES => {
+                if payload.len() < 2 {
+                    return Err(MetricError::Collectd("truncated values part"));
+                }
+                let count = usize::from(u16::from_be_bytes([payload[0], payload[1]]));
+                if payload.len() != 2 + count * 9 {
+                    return Err(MetricError::Collectd("bad values part length"));
+                }
+                let (types, values) = payload[2..].split_at(count);
+                for (idx, (kind, value)) in types.iter().zip(values.chunks(8)).enumerate() {
+                    let value = match *kind {
+                        VALUE_GAUGE => {
+                            // gauges are the only values in little endian
+                            let mut bytes = [0u8; 8];
+                            bytes.copy_from_slice(value);
+                            MetricValue::Gauge(F::from_f64(f64::from_le_bytes(bytes)))
+                        }
+                        VALUE_COUNTER => MetricValue::Gauge(F::from_f64(be_u64(value) as f64)),
+                        VALUE_DERIVE => MetricValue::Gauge(F::from_f64(be_u64(value) as i64 as f64)),
+                        VALUE_ABSOLUTE => MetricValue::Counter(F::from_f64(be_u64(value) as f64)),
+                        _ => return Err(MetricError::Collectd("unknown value type")),
+                    };
+                    let mut metric = Metric::new(value, state.time.map(|(ts, _)| ts), 1f32);
+                    if let Some((_, precision)) = state.time {
+                        metric.set_timestamp_precision(precision);
+                    }
+                    let source = if count > 1 { Some(idx) } else { None };
+                    metrics.push((state.name(source), metric));
+                }
+            }
+            PART_ENCRYPTION => return Err(MetricError::Collectd("encrypted packets are not supported")),
+            // intervals, notifications and signatures
+            _ => {}
+        }
+    }
+    Ok(metrics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part(kind: u16, payload: &[u8]) -> Vec<u8> {
+        let mut part = kind.to_be_bytes().to_vec();
+        part.extend_from_slice(&(payload.len() as u16 + 4).to_be_bytes());
+        part.extend_from_slice(payload);
+        part
+    }
+
+    #[test]
+    fn parse_collectd_packet() {
+        let mut packet = Vec::new();
+        packet.extend(part(PART_HOST, b"web.example.com\0"));
+        packet.extend(part(PART_TIME_HR, &(1_600_000_000u64 << 30).to_be_bytes()));
+        packet.extend(part(PART_PLUGIN, b"interface\0"));
+        packet.extend(part(PART_PLUGIN_INSTANCE, b"eth0\0"));
+        packet.extend(part(PART_TYPE, b"if_octets\0"));
+        packet.extend(part(PART_TYPE_INSTANCE, b"\0"));
+        let mut values = vec![0u8, 2, VALUE_DERIVE, VALUE_COUNTER];
+        values.extend_from_slice(&100u64.to_be_bytes());
+        values.extend_from_slice(&200u64.to_be_bytes());
+        packet.extend(part(PART_VALUES, &values));
+
+        packet.extend(part(PART_TIME, &1_600_000_001u64.to_be_bytes()));
+        packet.extend(part(PART_PLUGIN, b"load\0"));
+        packet.extend(part(PART_PLUGIN_INSTANCE, b"\0"));
+        packet.extend(part(PART_TYPE, b"load\0"));
+        packet.extend(part(PART_TYPE_INSTANCE, b"shortterm\0"));
+        let mut values = vec![0u8, 1, VALUE_GAUGE];
+        values.extend_from_slice(&0.5f64.to_le_bytes());
+        packet.extend(part(PART_VALUES, &values));
+        packet.extend(part(0x0101, &[0u8; 8]));
+
+        let metrics = parse_collectd::<f64>(&packet).unwrap();
+        let names = metrics
+            .iter()
+            .map(|(name, _)| String::from_utf8(name.name.to_vec()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "web_example_com.interface-eth0.if_octets.0",
+                "web_example_com.interface-eth0.if_octets.1",
+                "web_example_com.load.load-shortterm"
+            ]
+        );
+        assert_eq!(metrics[0].1.value(), &MetricValue::Gauge(100f64));
+        assert_eq!(metrics[0].1.timestamp_as(TimestampPrecision::Seconds), Some(1_600_000_000));
+        assert_eq!(metrics[0].1.timestamp_precision(), TimestampPrecision::Nanos);
+        assert_eq!(metrics[2].1.value(), &MetricValue::Gauge(0.5f64));
+        assert_eq!(metrics[2].1.timestamp(), Some(1_600_000_001));
+
+        assert!(parse_collectd::<f64>(&packet[..packet.len() - 2]).is_err());
+        assert!(parse_collectd::<f64>(&part(PART_HOST, b"web")).is_err());
+        assert!(parse_collectd::<f64>(&part(PART_ENCRYPTION, b"")).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use arrow_array::builder::{ArrayBuilder, Float64Builder, StringDictionaryBuilder, UInt64Builder};
+use arrow_array::types::{Int32Type, Int8Type};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use num_traits::{AsPrimitive, Float};
+
+use crate::aggregate::Aggregate;
+use crate::metric::{FromF64, MetricError, MetricTypeName};
+use crate::name::{MetricName, UnicodePolicy};
+
+/// The schema of exported batches. Names, tags, types and aggregates repeat a lot,
+/// so they are dictionary encoded. Tags are stored in their canonical sorted form `key=value;key=value`.
+pub fn snapshot_schema() -> SchemaRef {
+    let dictionary = |key: DataType| DataType::Dictionary(Box::new(key), Box::new(DataType::Utf8));
+    Arc::new(Schema::new(vec![
+        Field::new("name", dictionary(DataType::Int32), false),
+        Field::new("tags", dictionary(DataType::Int32), false),
+        Field::new("type", dictionary(DataType::Int8), false),
+        Field::new("aggregate", dictionary(DataType::Int32), true),
+        Field::new("value", DataType::Float64, false),
+        Field::new("ts", DataType::UInt64, true),
+    ]))
+}
+
+/// Collects aggregated values into an Arrow record batch with `snapshot_schema`
+pub struct SnapshotBatchBuilder {
+    name: StringDictionaryBuilder<Int32Type>,
+    tags: StringDictionaryBuilder<Int32Type>,
+    mtype: StringDictionaryBuilder<Int8Type>,
+    aggregate: StringDictionaryBuilder<Int32Type>,
+    value: Float64Builder,
+    timestamp: UInt64Builder,
+    unicode: UnicodePolicy,
+}
+
+impl Debug for SnapshotBatchBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SnapshotBatchBuilder").field("len", &self.len()).finish()
+    }
+}
+
+impl Default for SnapshotBatchBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SnapshotBatchBuilder {
+    pub fn new() -> Self {
+        Self {
+            name: StringDictionaryBuilder::new(),
+            tags: StringDictionaryBuilder::new(),
+            mtype: StringDictionaryBuilder::new(),
+            aggregate: StringDictionaryBuilder::new(),
+            value: Float64Builder::new(),
+            timestamp: UInt64Builder::new(),
+            unicode: UnicodePolicy::default(),
+        }
+    }
+
+    /// Apply the policy to names with non-ASCII characters, the rows for rejected names are skipped
+    pub fn with_unicode(mut self, policy: UnicodePolicy) -> Self {
+        self.unicode = policy;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.value.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds a row, `Aggregate::Value` is stored as null aggregate like no aggregate at all
+    pub fn push<F>(&mut self, name: &MetricName, mtype: MetricTypeName, aggregate: Option<&Aggregate<F>>, value: F, timestamp: Option<u64>)
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<usize> + AsPrimitive<f64>,
+    {
+        let name = match self.unicode.encoded_name(name) {
+            Some(name) => name,
+            None => return,
+        };
+        self.name.append_value(String::from_utf8_lossy(name.name_without_tags()));
+        self.tags
+            .append_value(String::from_utf8_lossy(name.tags_without_name().get(1..).unwrap_or_default()));
+        self.mtype.append_value(mtype.to_string());
+        match aggregate.map(|aggregate| aggregate.to_string()).filter(|aggregate| !aggregate.is_empty()) {
+            Some(aggregate) => self.aggregate.append_value(aggregate),
+            None => self.aggregate.append_null(),
+        }
+        self.value.append_value(value.as_());
+        self.timestamp.append_option(timestamp);
+    }
+
+    /// Gives the batch of all rows pushed so far, the builder is emptied and can be reused
+    pub fn finish(&mut self) -> Result<RecordBatch, MetricError> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.name.finish()),
+            Arc::new(self.tags.finish()),
+            Arc::new(self.mtype.finish()),
+            Arc::new(self.aggregate.finish()),
+            Arc::new(self.value.finish()),
+            Arc::new(self.timestamp.finish()),
+        ];
+        RecordBatch::try_new(snapshot_schema(), columns).map_err(|e| MetricError::Columnar(e.to_string()))
+    }
+}
+
+/// Writes batches with `snapshot_schema` to a Parquet file
+#[cfg(feature = "parquet-export")]
+pub fn write_parquet<W>(writer: W, batches: &[RecordBatch]) -> Result<(), MetricError>
+where
+    W: std::io::Write + Send,
+{
+    let mut writer = parquet::arrow::ArrowWriter::try_new(writer, snapshot_schema(), None).map_err(|e| MetricError::Columnar(e.to_string()))?;
+    for batch in batches {
+        writer.write(batch).map_err(|e| MetricError::Columnar(e.to_string()))?;
+    }
+    writer.close().map(|_| ()).map_err(|e| MetricError::Columnar(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::name;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Float64Type, UInt64Type};
+    use arrow_array::Array;
+
+    #[test]
+    fn snapshot_batch() {
+        let (name, unicode) = (name("requests;path=/a;host=h1"), name("température"));
+        let mut builder = SnapshotBatchBuilder::new();
+        builder.push(&name, MetricTypeName::Timer, Some(&Aggregate::Max), 3f64, Some(10));
+        builder.push(&name, MetricTypeName::Timer, Some(&Aggregate::Min), 1f64, Some(10));
+        builder.push(&name, MetricTypeName::Counter, Some(&Aggregate::Value), 2f64, None);
+        assert_eq!(builder.len(), 3);
+
+        let batch = builder.finish().unwrap();
+        assert!(builder.is_empty());
+        assert_eq!(batch.num_rows(), 3);
+        let names = batch.column(0).as_dictionary::<Int32Type>();
+        assert_eq!(names.values().len(), 1);
+        let tags = batch.column(1).as_dictionary::<Int32Type>();
+        assert_eq!(tags.values().as_string::<i32>().value(0), "host=h1;path=/a");
+        let aggregates = batch.column(3).as_dictionary::<Int32Type>();
+        assert!(aggregates.is_null(2));
+        assert_eq!(batch.column(4).as_primitive::<Float64Type>().values(), &[3f64, 1f64, 2f64]);
+        assert!(batch.column(5).as_primitive::<UInt64Type>().is_null(2));
+
+        let mut rejecting = SnapshotBatchBuilder::new().with_unicode(UnicodePolicy::Reject);
+        rejecting.push(&unicode, MetricTypeName::Gauge, None::<&Aggregate<f64>>, 1f64, None);
+        rejecting.push(&name, MetricTypeName::Gauge, None::<&Aggregate<f64>>, 1f64, None);
+        assert_eq!(rejecting.len(), 1);
+
+        #[cfg(feature = "parquet-export")]
+        {
+            let mut file = Vec::new();
+            write_parquet(&mut file, &[batch]).unwrap();
+            assert_eq!(&file[..4], b"PAR1");
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use std::borrow::Cow;
+use std::fmt::{Debug, Write};
+
+use bytes::BytesMut;
+use num_traits::{AsPrimitive, Float};
+use serde::{Deserialize, Serialize};
+
+use crate::metric::{FromF64, Metric, MetricTypeName, MetricValue};
+use crate::name::{MetricName, UnicodePolicy};
+
+/// A column of exported table
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CsvColumn {
+    /// name without tags
+    Name,
+    /// value of the tag with this key, empty if the metric has no such tag
+    Tag(String),
+    /// all tags as `key=value` pairs separated with semicolons
+    Tags,
+    Type,
+    /// value of gauges and counters, cardinality of sets, number of values in timers and histograms
+    Value,
+    /// number of updates
+    Count,
+    Timestamp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct CsvOptions {
+    /// `,` for CSV, `\t` for TSV
+    pub delimiter: char,
+    pub columns: Vec<CsvColumn>,
+    pub header: bool,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: ',',
+            columns: vec![
+                CsvColumn::Name,
+                CsvColumn::Tags,
+                CsvColumn::Type,
+                CsvColumn::Value,
+                CsvColumn::Count,
+                CsvColumn::Timestamp,
+            ],
+            header: true,
+        }
+    }
+}
+
+/// Writes snapshots as CSV or TSV tables for offline analysis. Fields containing delimiter, quotes
+/// or line breaks are quoted according to RFC 4180, which is understood by spreadsheets, pandas and DuckDB.
+#[derive(Debug, Clone)]
+pub struct CsvEncoder {
+    options: CsvOptions,
+    unicode: UnicodePolicy,
+}
+
+impl CsvEncoder {
+    pub fn new(options: CsvOptions) -> Self {
+        Self {
+            options,
+            unicode: UnicodePolicy::default(),
+        }
+    }
+
+    /// Apply the policy to names with non-ASCII characters, the rows for rejected names are skipped
+    pub fn with_unicode(mut self, policy: UnicodePolicy) -> Self {
+        self.unicode = policy;
+        self
+    }
+
+    fn put_field(&self, buf: &mut BytesMut, field: &[u8]) {
+        let mut delimiter = [0u8; 4];
+        let delimiter = self.options.delimiter.encode_utf8(&mut delimiter).as_bytes();
+        let quoted = field.iter().any(|c| *c == b'"' || *c == b'\n' || *c == b'\r') || field.windows(delimiter.len()).any(|w| w == delimiter);
+        if !quoted {
+            buf.extend_from_slice(field);
+            return;
+        }
+        buf.extend_from_slice(b"\"");
+        for c in field {
+            if *c == b'"' {
+                buf.extend_from_slice(b"\"");
+            }
+            buf.extend_from_slice(&[*c]);
+        }
+        buf.extend_from_slice(b"\"");
+    }
+
+    fn put_row<'a, I>(&self, buf: &mut BytesMut, fields: I)
+    where
+        I: Iterator<Item = &'a [u8]>,
+    {
+        for (idx, field) in fields.enumerate() {
+            if idx > 0 {
+                write!(buf, "{}", self.options.delimiter).unwrap_or_default();
+            }
+            self.put_field(buf, field);
+        }
+        buf.extend_from_slice(b"\n");
+    }
+
+    /// Appends the header row, tag columns are named by their keys
+    pub fn header(&self, buf: &mut BytesMut) {
+        let names = self.options.columns.iter().map(|column| match column {
+            CsvColumn::Name => "name",
+            CsvColumn::Tag(key) => key.as_str(),
+            CsvColumn::Tags => "tags",
+            CsvColumn::Type => "type",
+            CsvColumn::Value => "value",
+            CsvColumn::Count => "count",
+            CsvColumn::Timestamp => "ts",
+        });
+        self.put_row(buf, names.map(str::as_bytes));
+    }
+
+    /// Appends a row for the metric, tombstones of expired gauges have no rows
+    pub fn encode<F>(&self, buf: &mut BytesMut, name: &MetricName, metric: &Metric<F>)
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<f64>,
+    {
+        if metric.is_gauge_unset() {
+            return;
+        }
+        let name = match self.unicode.encoded_name(name) {
+            Some(name) => name,
+            None => return,
+        };
+        let fields = self
+            .options
+            .columns
+            .iter()
+            .map(|column| match column {
+                CsvColumn::Name => name.name_without_tags().to_vec(),
+                CsvColumn::Tag(key) => name.tag_value_unescaped(key.as_bytes()).map(Cow::into_owned).unwrap_or_default(),
+                // tags without the leading semicolon
+                CsvColumn::Tags => name.tags_without_name().get(1..).unwrap_or_default().to_vec(),
+                CsvColumn::Type => MetricTypeName::from_metric(metric).to_string().into_bytes(),
+                CsvColumn::Value => {
+                    let value: f64 = match metric.value() {
+                        MetricValue::Gauge(value) | MetricValue::Counter(value) => value.as_(),
+                        MetricValue::Set(_) | MetricValue::SortedSet(_) => metric.set_len().unwrap_or(0) as f64,
+                        MetricValue::Timer(_) | MetricValue::CompactTimer(_) => metric.timer_len().unwrap_or(0) as f64,
+                        MetricValue::CustomHistogram(left, buckets) => (left + buckets.iter().map(|(_, count)| count).sum::<u64>()) as f64,
+                    };
+                    value.to_string().into_bytes()
+                }
+                CsvColumn::Count => {
+                    let updates: f64 = metric.updates().as_();
+                    updates.to_string().into_bytes()
+                }
+                CsvColumn::Timestamp => metric.timestamp().map(|ts| ts.to_string()).unwrap_or_default().into_bytes(),
+            })
+            .collect::<Vec<_>>();
+        self.put_row(buf, fields.iter().map(Vec::as_slice));
+    }
+
+    /// Appends the whole table, including the header if it is enabled
+    pub fn encode_all<'a, F, I>(&self, buf: &mut BytesMut, metrics: I)
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<f64> + 'a,
+        I: IntoIterator<Item = (&'a MetricName, &'a Metric<F>)>,
+    {
+        if self.options.header {
+            self.header(buf);
+        }
+        for (name, metric) in metrics {
+            self.encode(buf, name, metric);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::name;
+
+    #[test]
+    fn csv_tables() {
+        let metrics = [
+            (name("requests;path=/a,b;host=h1"), Metric::new(MetricValue::Counter(5f64), Some(100), 1f32)),
+            (name("latency"), Metric::new(MetricValue::Timer(vec![1f64, 2f64]), None, 1f32)),
+        ];
+
+        let mut buf = BytesMut::new();
+        CsvEncoder::new(CsvOptions::default()).encode_all(&mut buf, metrics.iter().map(|(n, m)| (n, m)));
+        assert_eq!(
+            String::from_utf8(buf.to_vec()).unwrap(),
+            "name,tags,type,value,count,ts\nrequests,\"host=h1;path=/a,b\",counter,5,1,100\nlatency,,timer,2,1,\n"
+        );
+
+        let mut buf = BytesMut::new();
+        let options = CsvOptions {
+            delimiter: '\t',
+            columns: vec![CsvColumn::Name, CsvColumn::Tag("path".into()), CsvColumn::Value],
+            header: false,
+        };
+        CsvEncoder::new(options).encode_all(&mut buf, metrics.iter().map(|(n, m)| (n, m)));
+        assert_eq!(String::from_utf8(buf.to_vec()).unwrap(), "requests\t/a,b\t5\nlatency\t\t2\n");
+
+        let metric = Metric::new(MetricValue::Gauge(1f64), None, 1f32);
+        let mut buf = BytesMut::new();
+        let options = CsvOptions {
+            columns: vec![CsvColumn::Name, CsvColumn::Value],
+            header: false,
+            ..CsvOptions::default()
+        };
+        let encoder = CsvEncoder::new(options).with_unicode(UnicodePolicy::Transliterate);
+        encoder.encode(&mut buf, &name("température"), &metric);
+        encoder
+            .clone()
+            .with_unicode(UnicodePolicy::Reject)
+            .encode(&mut buf, &name("température"), &metric);
+        assert_eq!(String::from_utf8(buf.to_vec()).unwrap(), "temperature,1\n");
+    }
+}
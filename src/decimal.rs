@@ -0,0 +1,576 @@
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt;
+use std::num::FpCategory;
+use std::ops::{Add, Div, Mul, Neg, Rem, Sub};
+use std::str::FromStr;
+
+use num_traits::{AsPrimitive, Float, Num, NumCast, One, ToPrimitive, Zero};
+
+use crate::metric::FromF64;
+
+const NAN: i64 = i64::MIN;
+const NEG_INF: i64 = i64::MIN + 1;
+const INF: i64 = i64::MAX;
+const MIN_FINITE: i64 = i64::MIN + 2;
+const MAX_FINITE: i64 = i64::MAX - 1;
+
+/// A fixed-point value stored as `i64` scaled by `10^SCALE`, i.e. `Decimal<2>` keeps cents exactly.
+/// Addition, subtraction and multiplication by integers are exact, so counters and sums of
+/// timers accumulate without float rounding errors, which matters for billing metrics.
+///
+/// The type implements `Float`, so it can be used as a metric value everywhere `f32` and `f64` are.
+/// Results of multiplication and division are rounded to the scale, half away from zero.
+/// Values out of range become infinities, NaN and infinities are kept as special values,
+/// functions like `sqrt` or `ln` are calculated through `f64`. The scale can be up to 18.
+///
+/// Note that statsd parsing is bound to lexical's `FromLexical`, which cannot be implemented outside
+/// of lexical, so parsed values are converted from floats with `FromF64` or `FromStr`. The latter is exact.
+#[derive(Clone, Copy, Default)]
+pub struct Decimal<const SCALE: u32>(i64);
+
+/// An error parsing a decimal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseDecimalError;
+
+impl fmt::Display for ParseDecimalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid decimal value")
+    }
+}
+
+impl std::error::Error for ParseDecimalError {}
+
+// division rounding half away from zero
+fn div_round(n: i128, d: i128) -> i128 {
+    let (q, r) = (n / d, n % d);
+    if 2 * r.abs() >= d.abs() {
+        q + if (n < 0) == (d < 0) { 1 } else { -1 }
+    } else {
+        q
+    }
+}
+
+impl<const SCALE: u32> Decimal<SCALE> {
+    /// 10^SCALE, the raw value of 1
+    pub const UNIT: i64 = 10i64.pow(SCALE);
+
+    /// Makes the value from the scaled integer, i.e. `Decimal::<2>::from_raw(150)` is 1.5
+    pub fn from_raw(raw: i64) -> Self {
+        Self::from_wide(raw as i128)
+    }
+
+    /// The scaled integer, special values are kept as `i64::MIN` for NaN, `i64::MIN + 1` and `i64::MAX` for infinities
+    pub fn raw(self) -> i64 {
+        self.0
+    }
+
+    fn from_wide(raw: i128) -> Self {
+        if raw > MAX_FINITE as i128 {
+            Self(INF)
+        } else if raw < MIN_FINITE as i128 {
+            Self(NEG_INF)
+        } else {
+            Self(raw as i64)
+        }
+    }
+
+    fn wide(self) -> i128 {
+        self.0 as i128
+    }
+
+    fn special(self) -> bool {
+        self.0 < MIN_FINITE || self.0 > MAX_FINITE
+    }
+
+    // the operation on finite values, anything else is calculated as f64
+    fn exact<E, G>(self, other: Self, exact: E, float: G) -> Self
+    where
+        E: FnOnce(i128, i128) -> Option<i128>,
+        G: FnOnce(f64, f64) -> f64,
+    {
+        if self.special() || other.special() {
+            return Self::from_f64(float(self.as_f64(), other.as_f64()));
+        }
+        match exact(self.wide(), other.wide()) {
+            Some(raw) => Self::from_wide(raw),
+            None => Self::from_f64(float(self.as_f64(), other.as_f64())),
+        }
+    }
+
+    fn via_f64<G: FnOnce(f64) -> f64>(self, f: G) -> Self {
+        Self::from_f64(f(self.as_f64()))
+    }
+
+    fn as_f64(self) -> f64 {
+        match self.0 {
+            NAN => f64::NAN,
+            NEG_INF => f64::NEG_INFINITY,
+            INF => f64::INFINITY,
+            raw => raw as f64 / Self::UNIT as f64,
+        }
+    }
+
+    fn integer_part(self, round: fn(i128, i128) -> i128) -> Self {
+        if self.special() {
+            return self;
+        }
+        let unit = Self::UNIT as i128;
+        Self::from_wide(round(self.wide(), unit) * unit)
+    }
+}
+
+impl<const SCALE: u32> FromF64 for Decimal<SCALE> {
+    fn from_f64(value: f64) -> Self {
+        if value.is_nan() {
+            return Self(NAN);
+        }
+        let scaled = (value * Self::UNIT as f64).round();
+        // casts saturate, so infinities and large values go out of the finite range
+        Self::from_wide(scaled as i128)
+    }
+}
+
+impl<const SCALE: u32> PartialEq for Decimal<SCALE> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 != NAN && self.0 == other.0
+    }
+}
+
+impl<const SCALE: u32> PartialOrd for Decimal<SCALE> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if self.0 == NAN || other.0 == NAN {
+            None
+        } else {
+            Some(self.0.cmp(&other.0))
+        }
+    }
+}
+
+impl<const SCALE: u32> fmt::Display for Decimal<SCALE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            NAN => return f.write_str("NaN"),
+            NEG_INF => return f.write_str("-inf"),
+            INF => return f.write_str("inf"),
+            _ => {}
+        }
+        let unit = Self::UNIT.unsigned_abs();
+        let abs = self.0.unsigned_abs();
+        if self.0 < 0 {
+            f.write_str("-")?;
+        }
+        write!(f, "{}", abs / unit)?;
+        let fraction = abs % unit;
+        if fraction != 0 {
+            let digits = format!("{:0width$}", fraction, width = SCALE as usize);
+            write!(f, ".{}", digits.trim_end_matches('0'))?;
+        }
+        Ok(())
+    }
+}
+
+impl<const SCALE: u32> fmt::Debug for Decimal<SCALE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl<const SCALE: u32> FromStr for Decimal<SCALE> {
+    type Err = ParseDecimalError;
+
+    /// Plain decimals like `-12.345` are parsed exactly, rounding extra fraction digits,
+    /// other forms like `1e3` or `inf` are parsed as `f64`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let unsigned = s.strip_prefix(|c| c == '-' || c == '+').unwrap_or(s);
+        let (int, fraction) = match unsigned.split_once('.') {
+            Some((int, fraction)) => (int, fraction),
+            None => (unsigned, ""),
+        };
+        let plain = !(int.is_empty() && fraction.is_empty()) && int.bytes().chain(fraction.bytes()).all(|c| c.is_ascii_digit());
+        if !plain {
+            return s.parse::<f64>().map(Self::from_f64).map_err(|_| ParseDecimalError);
+        }
+
+        let digit = |acc: i128, c: u8| acc.saturating_mul(10).saturating_add((c - b'0') as i128);
+        let mut raw = int.bytes().fold(0i128, digit);
+        let scale = SCALE as usize;
+        raw = fraction.bytes().chain(std::iter::repeat(b'0')).take(scale).fold(raw, digit);
+        if fraction.as_bytes().get(scale).map(|c| *c >= b'5').unwrap_or(false) {
+            raw += 1;
+        }
+        Ok(Self::from_wide(if s.starts_with('-') { -raw } else { raw }))
+    }
+}
+
+impl<const SCALE: u32> Neg for Decimal<SCALE> {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        match self.0 {
+            NAN => self,
+            NEG_INF => Self(INF),
+            INF => Self(NEG_INF),
+            raw => Self(-raw),
+        }
+    }
+}
+
+impl<const SCALE: u32> Add for Decimal<SCALE> {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        self.exact(other, |a, b| Some(a + b), |a, b| a + b)
+    }
+}
+
+impl<const SCALE: u32> Sub for Decimal<SCALE> {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        self.exact(other, |a, b| Some(a - b), |a, b| a - b)
+    }
+}
+
+impl<const SCALE: u32> Mul for Decimal<SCALE> {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        self.exact(other, |a, b| Some(div_round(a * b, Self::UNIT as i128)), |a, b| a * b)
+    }
+}
+
+impl<const SCALE: u32> Div for Decimal<SCALE> {
+    type Output = Self;
+
+    fn div(self, other: Self) -> Self {
+        self.exact(
+            other,
+            |a, b| if b == 0 { None } else { Some(div_round(a * Self::UNIT as i128, b)) },
+            |a, b| a / b,
+        )
+    }
+}
+
+impl<const SCALE: u32> Rem for Decimal<SCALE> {
+    type Output = Self;
+
+    fn rem(self, other: Self) -> Self {
+        self.exact(other, |a, b| if b == 0 { None } else { Some(a % b) }, |a, b| a % b)
+    }
+}
+
+impl<const SCALE: u32> Zero for Decimal<SCALE> {
+    fn zero() -> Self {
+        Self(0)
+    }
+
+    fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl<const SCALE: u32> One for Decimal<SCALE> {
+    fn one() -> Self {
+        Self(Self::UNIT)
+    }
+}
+
+impl<const SCALE: u32> Num for Decimal<SCALE> {
+    type FromStrRadixErr = ParseDecimalError;
+
+    fn from_str_radix(s: &str, radix: u32) -> Result<Self, ParseDecimalError> {
+        if radix == 10 {
+            s.parse()
+        } else {
+            Err(ParseDecimalError)
+        }
+    }
+}
+
+impl<const SCALE: u32> ToPrimitive for Decimal<SCALE> {
+    fn to_i64(&self) -> Option<i64> {
+        if self.special() {
+            None
+        } else {
+            Some(self.0 / Self::UNIT)
+        }
+    }
+
+    fn to_u64(&self) -> Option<u64> {
+        self.to_i64().and_then(|value| u64::try_from(value).ok())
+    }
+
+    fn to_f64(&self) -> Option<f64> {
+        Some(self.as_f64())
+    }
+}
+
+impl<const SCALE: u32> NumCast for Decimal<SCALE> {
+    fn from<T: ToPrimitive>(n: T) -> Option<Self> {
+        n.to_f64().map(Self::from_f64)
+    }
+}
+
+impl<const SCALE: u32> AsPrimitive<f64> for Decimal<SCALE> {
+    fn as_(self) -> f64 {
+        self.as_f64()
+    }
+}
+
+impl<const SCALE: u32> AsPrimitive<usize> for Decimal<SCALE> {
+    fn as_(self) -> usize {
+        self.as_f64() as usize
+    }
+}
+
+macro_rules! via_f64 {
+    ($($name:ident),*) => {
+        $(
+            fn $name(self) -> Self {
+                self.via_f64(f64::$name)
+            }
+        )*
+    };
+}
+
+impl<const SCALE: u32> Float for Decimal<SCALE> {
+    fn nan() -> Self {
+        Self(NAN)
+    }
+
+    fn infinity() -> Self {
+        Self(INF)
+    }
+
+    fn neg_infinity() -> Self {
+        Self(NEG_INF)
+    }
+
+    fn neg_zero() -> Self {
+        Self(0)
+    }
+
+    fn min_value() -> Self {
+        Self(MIN_FINITE)
+    }
+
+    fn min_positive_value() -> Self {
+        Self(1)
+    }
+
+    fn epsilon() -> Self {
+        Self(1)
+    }
+
+    fn max_value() -> Self {
+        Self(MAX_FINITE)
+    }
+
+    fn is_nan(self) -> bool {
+        self.0 == NAN
+    }
+
+    fn is_infinite(self) -> bool {
+        self.0 == INF || self.0 == NEG_INF
+    }
+
+    fn is_finite(self) -> bool {
+        !self.special()
+    }
+
+    fn is_normal(self) -> bool {
+        self.classify() == FpCategory::Normal
+    }
+
+    fn classify(self) -> FpCategory {
+        match self.0 {
+            NAN => FpCategory::Nan,
+            NEG_INF | INF => FpCategory::Infinite,
+            0 => FpCategory::Zero,
+            _ => FpCategory::Normal,
+        }
+    }
+
+    fn floor(self) -> Self {
+        self.integer_part(|n, d| n.div_euclid(d))
+    }
+
+    fn ceil(self) -> Self {
+        self.integer_part(|n, d| -(-n).div_euclid(d))
+    }
+
+    fn round(self) -> Self {
+        self.integer_part(div_round)
+    }
+
+    fn trunc(self) -> Self {
+        self.integer_part(|n, d| n / d)
+    }
+
+    fn fract(self) -> Self {
+        self - self.trunc()
+    }
+
+    fn abs(self) -> Self {
+        if self.0 < 0 && self.0 != NAN {
+            -self
+        } else {
+            self
+        }
+    }
+
+    fn signum(self) -> Self {
+        match self.0 {
+            NAN => self,
+            raw if raw < 0 => -Self::one(),
+            _ => Self::one(),
+        }
+    }
+
+    fn is_sign_positive(self) -> bool {
+        self.0 >= 0
+    }
+
+    fn is_sign_negative(self) -> bool {
+        self.0 < 0 && self.0 != NAN
+    }
+
+    fn mul_add(self, a: Self, b: Self) -> Self {
+        self * a + b
+    }
+
+    fn recip(self) -> Self {
+        Self::one() / self
+    }
+
+    fn powi(self, n: i32) -> Self {
+        self.via_f64(|value| value.powi(n))
+    }
+
+    fn powf(self, n: Self) -> Self {
+        self.via_f64(|value| value.powf(n.as_f64()))
+    }
+
+    fn log(self, base: Self) -> Self {
+        self.via_f64(|value| value.log(base.as_f64()))
+    }
+
+    fn max(self, other: Self) -> Self {
+        match self.partial_cmp(&other) {
+            Some(Ordering::Less) => other,
+            None if self.is_nan() => other,
+            _ => self,
+        }
+    }
+
+    fn min(self, other: Self) -> Self {
+        match self.partial_cmp(&other) {
+            Some(Ordering::Greater) => other,
+            None if self.is_nan() => other,
+            _ => self,
+        }
+    }
+
+    fn abs_sub(self, other: Self) -> Self {
+        if self <= other {
+            Self::zero()
+        } else {
+            self - other
+        }
+    }
+
+    fn hypot(self, other: Self) -> Self {
+        self.via_f64(|value| value.hypot(other.as_f64()))
+    }
+
+    fn atan2(self, other: Self) -> Self {
+        self.via_f64(|value| value.atan2(other.as_f64()))
+    }
+
+    fn sin_cos(self) -> (Self, Self) {
+        (self.sin(), self.cos())
+    }
+
+    fn integer_decode(self) -> (u64, i16, i8) {
+        self.as_f64().integer_decode()
+    }
+
+    via_f64!(sqrt, exp, exp2, ln, log2, log10, cbrt, sin, cos, tan, asin, acos, atan, exp_m1, ln_1p, sinh, cosh, tanh, asinh, acosh, atanh);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregate::{aggregates, Aggregate};
+    use crate::metric::{Metric, MetricValue};
+
+    type Cents = Decimal<2>;
+
+    fn d(s: &str) -> Cents {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn decimal_parse_display() {
+        assert_eq!(d("12.34").raw(), 1234);
+        assert_eq!(d("-0.5").raw(), -50);
+        assert_eq!(d("+7").raw(), 700);
+        assert_eq!(d(".25").raw(), 25);
+        assert_eq!(d("1.005").raw(), 101);
+        assert_eq!(d("-1.005").raw(), -101);
+        assert_eq!(d("1e2").raw(), 10000);
+        assert!(d("NaN").is_nan());
+        assert_eq!(d("inf"), Cents::infinity());
+        assert!("1.2.3".parse::<Cents>().is_err());
+        assert!("".parse::<Cents>().is_err());
+
+        assert_eq!(d("12.30").to_string(), "12.3");
+        assert_eq!(d("-0.05").to_string(), "-0.05");
+        assert_eq!(d("100").to_string(), "100");
+        assert_eq!(format!("{:?}", Cents::neg_infinity()), "-inf");
+        assert_eq!(Cents::from_f64(0.1).raw(), 10);
+        assert_eq!(Cents::from_f64(1e30), Cents::infinity());
+    }
+
+    #[test]
+    fn decimal_arithmetic() {
+        assert_eq!(d("0.1") + d("0.2"), d("0.3"));
+        assert_eq!(d("1.5") * d("-1.5"), d("-2.25"));
+        assert_eq!(d("0.05") * d("0.5"), d("0.03"));
+        assert_eq!(d("10") / d("3"), d("3.33"));
+        assert_eq!(d("2") / d("3"), d("0.67"));
+        assert_eq!(d("7.5") % d("2"), d("1.5"));
+        assert_eq!(d("1") / d("0"), Cents::infinity());
+        assert!((d("0") / d("0")).is_nan());
+        assert_eq!(Cents::max_value() + d("1"), Cents::infinity());
+        assert_eq!(Cents::infinity() - d("1"), Cents::infinity());
+        assert!(Cents::nan() != Cents::nan());
+        assert!(d("-1") < d("0.01") && Cents::neg_infinity() < Cents::min_value());
+
+        assert_eq!(d("-1.5").floor(), d("-2"));
+        assert_eq!(d("-1.5").ceil(), d("-1"));
+        assert_eq!(d("-1.5").round(), d("-2"));
+        assert_eq!(d("-1.5").trunc(), d("-1"));
+        assert_eq!(d("-1.25").fract(), d("-0.25"));
+        assert_eq!(d("-1.25").abs(), d("1.25"));
+        assert_eq!(d("2.25").sqrt(), d("1.5"));
+        assert_eq!(Cents::nan().max(d("1")), d("1"));
+    }
+
+    #[test]
+    fn decimal_metrics() {
+        let mut metric = Metric::new(MetricValue::Counter(d("0.1")), None, 1f32);
+        for _ in 0..9 {
+            metric.accumulate(Metric::new(MetricValue::Counter(d("0.1")), None, 1f32)).unwrap();
+        }
+        assert_eq!(metric.value(), &MetricValue::Counter(d("1")));
+
+        let mut timer = Metric::new(MetricValue::Timer(vec![d("0.1"), d("0.2"), d("0.3"), d("19.99")]), None, 1f32);
+        let result = aggregates(&mut timer, &[Aggregate::Sum, Aggregate::Max, Aggregate::Mean, Aggregate::Count]).collect::<Vec<_>>();
+        assert_eq!(
+            result.iter().map(|(_, value)| *value).collect::<Vec<_>>(),
+            vec![d("20.59"), d("19.99"), d("5.15"), d("4")]
+        );
+    }
+}
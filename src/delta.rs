@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+use bytes::Bytes;
+use capnp::message::Builder;
+use num_traits::{AsPrimitive, Float};
+
+use crate::cache::{Snapshot, SnapshotView};
+use crate::metric::{FromF64, Metric, MetricError};
+use crate::name::{find_tag_pos, MetricName, TagFormat};
+use crate::protocol::{fill_snapshot, read_message_from_slice, read_snapshot, DecodeOptions};
+use crate::protocol_v2_capnp::message;
+
+/// A snapshot of the metrics changed since the snapshot of the base generation. The base of 0
+/// means a full snapshot, replacing everything the receiver had
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeltaSnapshot<F>
+where
+    F: Copy + PartialEq + Debug,
+{
+    pub generation: u64,
+    pub base: u64,
+    pub metrics: Vec<(MetricName, Metric<F>)>,
+}
+
+impl<F> DeltaSnapshot<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    pub fn is_full(&self) -> bool {
+        self.base == 0
+    }
+
+    /// Fills the snapshot message, see `fill_snapshot`
+    pub fn fill_capnp(&self, builder: &mut message::Builder, use_dictionary: bool) {
+        fill_snapshot(builder, self.metrics.iter().map(|(name, metric)| (name, metric)), use_dictionary);
+        builder.set_generation(self.generation);
+        builder.set_base_generation(self.base);
+    }
+
+    /// Reads the snapshot message, the messages not made by `fill_capnp` are read as full snapshots
+    pub fn from_capnp(reader: message::Reader) -> Result<Self, MetricError> {
+        let (generation, base) = (reader.get_generation(), reader.get_base_generation());
+        Ok(Self {
+            generation,
+            base,
+            metrics: read_snapshot(reader)?,
+        })
+    }
+}
+
+/// Sender side of delta snapshots. The metrics are compared with the last sent ones using
+/// `Metric::semantically_eq`, so only the changed series are sent, which saves a lot of traffic when
+/// most of the series are idle. Series removed from the state are kept by the receiver until the
+/// next full snapshot, see `reset`.
+///
+/// Only one snapshot can be in flight: the next delta is made against the last committed one.
+#[derive(Debug, Clone)]
+pub struct DeltaSender<F>
+where
+    F: Copy + PartialEq + Debug,
+{
+    sent: HashMap<MetricName, Metric<F>>,
+    generation: u64,
+}
+
+impl<F> Default for DeltaSender<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F> DeltaSender<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    pub fn new() -> Self {
+        Self {
+            sent: HashMap::new(),
+            generation: 0,
+        }
+    }
+
+    /// The generation of the last committed snapshot
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Makes a snapshot of the metrics changed since the last committed one. The first snapshot
+    /// and the one after `reset` are full
+    pub fn delta<'a, I>(&self, metrics: I) -> DeltaSnapshot<F>
+    where
+        I: IntoIterator<Item = (&'a MetricName, &'a Metric<F>)>,
+        F: 'a,
+    {
+        let metrics = metrics
+            .into_iter()
+            .filter(|(name, metric)| self.sent.get(*name).map(|sent| !sent.semantically_eq(metric)).unwrap_or(true))
+            .map(|(name, metric)| (name.clone(), metric.clone()))
+            .collect();
+        DeltaSnapshot {
+            generation: self.generation + 1,
+            base: self.generation,
+            metrics,
+        }
+    }
+
+    /// Remembers the snapshot as sent successfully, must be called for every snapshot delivered
+    pub fn commit(&mut self, snapshot: DeltaSnapshot<F>) {
+        if snapshot.is_full() {
+            self.sent.clear();
+        }
+        self.sent.extend(snapshot.metrics);
+        self.generation = snapshot.generation;
+    }
+
+    /// Forgets what was sent, so the next snapshot is full, i.e. when the receiver restarted
+    /// or rejected a delta with `MetricError::DeltaBase`
+    pub fn reset(&mut self) {
+        self.sent.clear();
+        self.generation = 0;
+    }
+}
+
+/// Receiver side of delta snapshots, keeping the state of the sender
+#[derive(Debug, Clone)]
+pub struct DeltaReceiver<F>
+where
+    F: Copy + PartialEq + Debug,
+{
+    state: HashMap<MetricName, Metric<F>>,
+    generation: u64,
+}
+
+impl<F> Default for DeltaReceiver<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F> DeltaReceiver<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    pub fn new() -> Self {
+        Self {
+            state: HashMap::new(),
+            generation: 0,
+        }
+    }
+
+    /// The generation of the last applied snapshot
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn state(&self) -> &HashMap<MetricName, Metric<F>> {
+        &self.state
+    }
+
+    /// Applies the snapshot, the changed metrics replace the ones in the state. A delta based on
+    /// a generation other than the last applied one gives `MetricError::DeltaBase` and changes nothing,
+    /// the sender should be asked for a full snapshot then
+    pub fn apply(&mut self, snapshot: DeltaSnapshot<F>) -> Result<(), MetricError> {
+        if snapshot.is_full() {
+            self.state.clear();
+        } else if snapshot.base != self.generation {
+            return Err(MetricError::DeltaBase(snapshot.base, self.generation));
+        }
+        self.state.extend(snapshot.metrics);
+        self.generation = snapshot.generation;
+        Ok(())
+    }
+}
+
+/// The difference between two consecutive snapshots of the same cache, i.e. for replicating
+/// the cache to standby aggregators. Unlike `DeltaSnapshot`, removed metrics are included, so applying
+/// the patch to the old snapshot gives exactly the new one, and metrics are compared exactly, including
+/// update counters
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotPatch<F>
+where
+    F: Copy + PartialEq + Debug,
+{
+    /// new metrics and the changed ones
+    pub changed: Vec<(MetricName, Metric<F>)>,
+    pub removed: Vec<MetricName>,
+}
+
+impl<F> SnapshotPatch<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    pub fn diff(old: &Snapshot<F>, new: &Snapshot<F>) -> Self {
+        let changed = new
+            .iter()
+            .filter(|(name, metric)| old.get(name) != Some(metric))
+            .map(|(name, metric)| (name.clone(), metric.clone()))
+            .collect();
+        let removed = old.iter().filter(|(name, _)| new.get(name).is_none()).map(|(name, _)| name.clone()).collect();
+        Self { changed, removed }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.removed.is_empty()
+    }
+
+    /// Applies the patch to the snapshot it was made against
+    pub fn apply(self, snapshot: Snapshot<F>) -> Snapshot<F> {
+        let mut metrics = snapshot.into_map();
+        for name in &self.removed {
+            metrics.remove(name);
+        }
+        metrics.extend(self.changed);
+        SnapshotView::from(metrics)
+    }
+
+    /// Fills the snapshot message with the changed metrics, see `fill_snapshot`, and the names of removed ones
+    pub fn fill_capnp(&self, builder: &mut message::Builder, use_dictionary: bool) {
+        fill_snapshot(builder, self.changed.iter().map(|(name, metric)| (name, metric)), use_dictionary);
+        let mut removed = builder.reborrow().init_removed(self.removed.len() as u32);
+        for (idx, name) in self.removed.iter().enumerate() {
+            removed.set(idx as u32, &String::from_utf8_lossy(&name.name));
+        }
+    }
+
+    pub fn from_capnp(reader: message::Reader) -> Result<Self, MetricError> {
+        let removed = if reader.has_removed() {
+            reader
+                .get_removed()
+                .map_err(MetricError::Capnp)?
+                .iter()
+                .map(|name| {
+                    // the names were sorted by sender
+                    let name = Bytes::copy_from_slice(name.map_err(MetricError::Capnp)?.as_bytes());
+                    let tag_pos = find_tag_pos(&name, TagFormat::Graphite);
+                    Ok(MetricName::from_raw_parts(name, tag_pos))
+                })
+                .collect::<Result<Vec<_>, MetricError>>()?
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            changed: read_snapshot(reader)?,
+            removed,
+        })
+    }
+
+    /// Serializes the patch into a single message
+    pub fn encode(&self, use_dictionary: bool) -> Result<Vec<u8>, MetricError> {
+        let mut builder = Builder::new_default();
+        self.fill_capnp(&mut builder.init_root::<message::Builder>(), use_dictionary);
+        let mut buf = Vec::new();
+        capnp::serialize::write_message(&mut buf, &builder).map_err(MetricError::Capnp)?;
+        Ok(buf)
+    }
+
+    pub fn decode(mut data: &[u8], options: &DecodeOptions) -> Result<Self, MetricError> {
+        let message = read_message_from_slice(&mut data, options)?;
+        let reader = message.get_root::<message::Reader>().map_err(MetricError::Capnp)?;
+        Self::from_capnp(reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metric::MetricValue;
+    use crate::testing::name;
+
+    #[test]
+    fn delta_snapshots() {
+        let gauge = |value| Metric::<f64>::new(MetricValue::Gauge(value), Some(10), 1f32);
+        let mut state = HashMap::new();
+        state.insert(name("idle"), gauge(1f64));
+        state.insert(name("busy"), gauge(1f64));
+
+        let mut sender = DeltaSender::new();
+        let mut receiver = DeltaReceiver::new();
+        let snapshot = sender.delta(state.iter());
+        assert!(snapshot.is_full());
+        assert_eq!(snapshot.metrics.len(), 2);
+        receiver.apply(snapshot.clone()).unwrap();
+        sender.commit(snapshot);
+
+        state.insert(name("busy"), gauge(2f64));
+        let snapshot = sender.delta(state.iter());
+        assert_eq!((snapshot.base, snapshot.generation), (1, 2));
+        assert_eq!(snapshot.metrics, vec![(name("busy"), gauge(2f64))]);
+        receiver.apply(snapshot.clone()).unwrap();
+        // the same delta cannot be applied twice
+        assert!(matches!(receiver.apply(snapshot.clone()), Err(MetricError::DeltaBase(1, 2))));
+        sender.commit(snapshot);
+        assert_eq!(receiver.state(), &state);
+        assert!(sender.delta(state.iter()).metrics.is_empty());
+
+        // the receiver restarted
+        let mut receiver = DeltaReceiver::new();
+        state.insert(name("busy"), gauge(3f64));
+        assert!(receiver.apply(sender.delta(state.iter())).is_err());
+        sender.reset();
+        let snapshot = sender.delta(state.iter());
+        assert!(snapshot.is_full());
+        receiver.apply(snapshot).unwrap();
+        assert_eq!(receiver.state(), &state);
+    }
+
+    fn patch_snapshots() -> (Snapshot<f64>, Snapshot<f64>) {
+        let gauge = |value| Metric::<f64>::new(MetricValue::Gauge(value), None, 1f32);
+        let old = vec![
+            (name("same;a=b"), gauge(1f64)),
+            (name("changed"), gauge(1f64)),
+            (name("removed;x=y"), gauge(1f64)),
+        ];
+        let new = vec![(name("same;a=b"), gauge(1f64)), (name("changed"), gauge(2f64)), (name("added"), gauge(1f64))];
+        (
+            Snapshot::from(old.into_iter().collect::<HashMap<_, _>>()),
+            Snapshot::from(new.into_iter().collect::<HashMap<_, _>>()),
+        )
+    }
+
+    #[test]
+    fn snapshot_patch() {
+        let (old, new) = patch_snapshots();
+        let mut patch = SnapshotPatch::diff(&old, &new);
+        patch.changed.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+        let names = patch.changed.iter().map(|(name, _)| &name.name[..]).collect::<Vec<_>>();
+        assert_eq!(names, vec![&b"added"[..], &b"changed"[..]]);
+        assert_eq!(patch.removed.len(), 1);
+        assert_eq!(&patch.removed[0].name[..], b"removed;x=y");
+
+        assert_eq!(patch.apply(old).into_map(), new.clone().into_map());
+        assert!(SnapshotPatch::diff(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn snapshot_patch_encoding() {
+        let (old, new) = patch_snapshots();
+        let patch = SnapshotPatch::diff(&old, &new);
+        for use_dictionary in [false, true] {
+            let encoded = patch.encode(use_dictionary).unwrap();
+            let decoded = SnapshotPatch::decode(&encoded, &DecodeOptions::default()).unwrap();
+            assert_eq!(decoded, patch);
+            assert_eq!(decoded.removed[0].tag_value(b"x"), Some(&b"y"[..]));
+        }
+    }
+}
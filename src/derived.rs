@@ -0,0 +1,371 @@
+use std::convert::TryFrom;
+use std::fmt::Debug;
+
+use num_traits::{AsPrimitive, Float};
+use serde::{Deserialize, Serialize};
+
+use crate::aggregate::{aggregates, Aggregate};
+use crate::cache::Snapshot;
+use crate::metric::{FromF64, Metric, MetricError, MetricValue};
+use crate::name::{parse_name, MetricName};
+
+/// What to do when an operand of the expression has no value, i.e. the metric was not updated
+/// during the interval or the aggregate is not applicable to its type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MissingOperand {
+    /// the derived metric is not produced
+    #[default]
+    Skip,
+    /// the operand is considered zero, i.e. for error counters not sent when there were no errors
+    Zero,
+}
+
+/// A metric computed from aggregates of other metrics at flush time
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct DerivedMetricOptions {
+    /// the name of the new metric, may include tags
+    pub name: String,
+
+    /// an expression like `errors.count / requests.count * 100`, see `Expression`
+    pub expression: String,
+
+    #[serde(default)]
+    pub missing: MissingOperand,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token<'a> {
+    Number(f64),
+    Operand(&'a str),
+    Op(Op),
+    Open,
+    Close,
+}
+
+// operands are everything up to whitespace, operator or parenthesis, dashes are allowed inside
+// operands, since they are common in names and needed for `percentile-99`
+fn tokenize(expression: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = expression.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let token = match c {
+            '+' => Token::Op(Op::Add),
+            '-' => Token::Op(Op::Sub),
+            '*' => Token::Op(Op::Mul),
+            '/' => Token::Op(Op::Div),
+            '(' => Token::Open,
+            ')' => Token::Close,
+            _ => {
+                let len = rest.find(|c: char| c.is_whitespace() || "+*/()".contains(c)).unwrap_or(rest.len());
+                let (word, tail) = rest.split_at(len);
+                rest = tail.trim_start();
+                match word.parse::<f64>() {
+                    Ok(number) => tokens.push(Token::Number(number)),
+                    Err(_) => tokens.push(Token::Operand(word)),
+                }
+                continue;
+            }
+        };
+        tokens.push(token);
+        rest = rest[1..].trim_start();
+    }
+    tokens
+}
+
+#[derive(Debug, Clone)]
+enum Node<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize>,
+{
+    Number(F),
+    Operand(MetricName, Aggregate<F>),
+    Neg(Box<Node<F>>),
+    Binary(Op, Box<Node<F>>, Box<Node<F>>),
+}
+
+struct ExpressionParser<'a, 'e> {
+    expression: &'e str,
+    tokens: &'a [Token<'e>],
+    pos: usize,
+}
+
+impl<'a, 'e> ExpressionParser<'a, 'e> {
+    fn error(&self, message: &str) -> MetricError {
+        MetricError::Expression(format!("{} in '{}'", message, self.expression))
+    }
+
+    fn next_op(&mut self, ops: &[Op]) -> Option<Op> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) if ops.contains(op) => {
+                self.pos += 1;
+                Some(*op)
+            }
+            _ => None,
+        }
+    }
+
+    fn sum<F>(&mut self) -> Result<Node<F>, MetricError>
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<usize>,
+    {
+        let mut node = self.product()?;
+        while let Some(op) = self.next_op(&[Op::Add, Op::Sub]) {
+            node = Node::Binary(op, Box::new(node), Box::new(self.product()?));
+        }
+        Ok(node)
+    }
+
+    fn product<F>(&mut self) -> Result<Node<F>, MetricError>
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<usize>,
+    {
+        let mut node = self.unary()?;
+        while let Some(op) = self.next_op(&[Op::Mul, Op::Div]) {
+            node = Node::Binary(op, Box::new(node), Box::new(self.unary()?));
+        }
+        Ok(node)
+    }
+
+    fn unary<F>(&mut self) -> Result<Node<F>, MetricError>
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<usize>,
+    {
+        if self.next_op(&[Op::Sub]).is_some() {
+            return Ok(Node::Neg(Box::new(self.unary()?)));
+        }
+        let token = self.tokens.get(self.pos).ok_or_else(|| self.error("unexpected end"))?;
+        self.pos += 1;
+        match token {
+            Token::Number(number) => Ok(Node::Number(F::from_f64(*number))),
+            Token::Operand(operand) => self.operand(operand),
+            Token::Open => {
+                let node = self.sum()?;
+                match self.tokens.get(self.pos) {
+                    Some(Token::Close) => {
+                        self.pos += 1;
+                        Ok(node)
+                    }
+                    _ => Err(self.error("unclosed parenthesis")),
+                }
+            }
+            Token::Op(_) | Token::Close => Err(self.error("operand expected")),
+        }
+    }
+
+    fn operand<F>(&self, operand: &str) -> Result<Node<F>, MetricError>
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<usize>,
+    {
+        let (name, aggregate) = operand.rsplit_once('.').ok_or_else(|| self.error(&format!("no aggregate in '{}'", operand)))?;
+        let aggregate = Aggregate::try_from(aggregate).map_err(|e| self.error(&format!("{} '{}'", e, aggregate)))?;
+        let name = parse_name(name).ok_or_else(|| self.error(&format!("bad name '{}'", name)))?;
+        Ok(Node::Operand(name, aggregate))
+    }
+}
+
+/// An arithmetic expression over aggregates of metrics. Operands are written as the metric name
+/// followed by the aggregate name after the last dot, like `requests;dc=ams.count` or `latency.percentile-99`,
+/// with `+`, `-`, `*`, `/`, parentheses and numbers between them. The `count` of a counter is its value,
+/// so `errors.count / requests.count * 100` works for counters as well as for timers.
+/// Since dashes are allowed in names, the binary minus must be separated by whitespace
+#[derive(Debug, Clone)]
+pub struct Expression<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize>,
+{
+    root: Node<F>,
+}
+
+impl<F> Expression<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64> + AsPrimitive<usize>,
+{
+    pub fn parse(expression: &str) -> Result<Self, MetricError> {
+        let tokens = tokenize(expression);
+        let mut parser = ExpressionParser {
+            expression,
+            tokens: &tokens,
+            pos: 0,
+        };
+        let root = parser.sum()?;
+        if parser.pos < tokens.len() {
+            return Err(parser.error("unexpected trailing input"));
+        }
+        Ok(Self { root })
+    }
+
+    /// The operands the expression refers to
+    pub fn operands(&self) -> Vec<(&MetricName, &Aggregate<F>)> {
+        let mut operands = Vec::new();
+        let mut stack = vec![&self.root];
+        while let Some(node) = stack.pop() {
+            match node {
+                Node::Number(_) => {}
+                Node::Operand(name, aggregate) => operands.push((name, aggregate)),
+                Node::Neg(node) => stack.push(node),
+                Node::Binary(_, left, right) => {
+                    stack.push(right);
+                    stack.push(left);
+                }
+            }
+        }
+        operands
+    }
+
+    /// Evaluates the expression getting the values of operands from `lookup`. Gives None if
+    /// an operand is missing and `missing` is `Skip`, on division by zero and on non-finite results
+    pub fn eval<L>(&self, missing: MissingOperand, mut lookup: L) -> Option<F>
+    where
+        L: FnMut(&MetricName, &Aggregate<F>) -> Option<F>,
+    {
+        let value = Self::eval_node(&self.root, missing, &mut lookup)?;
+        if value.is_finite() {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    fn eval_node<L>(node: &Node<F>, missing: MissingOperand, lookup: &mut L) -> Option<F>
+    where
+        L: FnMut(&MetricName, &Aggregate<F>) -> Option<F>,
+    {
+        match node {
+            Node::Number(number) => Some(*number),
+            Node::Operand(name, aggregate) => match (lookup(name, aggregate), missing) {
+                (Some(value), _) => Some(value),
+                (None, MissingOperand::Zero) => Some(F::zero()),
+                (None, MissingOperand::Skip) => None,
+            },
+            Node::Neg(node) => Self::eval_node(node, missing, lookup).map(|value| -value),
+            Node::Binary(op, left, right) => {
+                let left = Self::eval_node(left, missing, lookup)?;
+                let right = Self::eval_node(right, missing, lookup)?;
+                match op {
+                    Op::Add => Some(left + right),
+                    Op::Sub => Some(left - right),
+                    Op::Mul => Some(left * right),
+                    Op::Div if right == F::zero() => None,
+                    Op::Div => Some(left / right),
+                }
+            }
+        }
+    }
+}
+
+/// A metric computed by the expression at flush time
+#[derive(Debug, Clone)]
+pub struct DerivedMetric<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize>,
+{
+    name: MetricName,
+    expression: Expression<F>,
+    missing: MissingOperand,
+}
+
+impl<F> DerivedMetric<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64> + AsPrimitive<usize>,
+{
+    pub fn new(options: &DerivedMetricOptions) -> Result<Self, MetricError> {
+        let name = parse_name(&options.name).ok_or_else(|| MetricError::Expression(format!("bad derived metric name '{}'", options.name)))?;
+        Ok(Self {
+            name,
+            expression: Expression::parse(&options.expression)?,
+            missing: options.missing,
+        })
+    }
+
+    pub fn name(&self) -> &MetricName {
+        &self.name
+    }
+
+    pub fn expression(&self) -> &Expression<F> {
+        &self.expression
+    }
+
+    /// Computes the value using the aggregates of the metrics in snapshot, giving it as a gauge
+    pub fn eval_snapshot(&self, snapshot: &Snapshot<F>) -> Option<(MetricName, Metric<F>)> {
+        let value = self.expression.eval(self.missing, |name, aggregate| {
+            let mut metric = snapshot.get(name)?.clone();
+            let aggregate = match (metric.value(), aggregate) {
+                (MetricValue::Counter(_), Aggregate::Count) => [Aggregate::Value],
+                _ => [*aggregate],
+            };
+            // the iterator borrowing the locals must be dropped before returning
+            let value = aggregates(&mut metric, &aggregate).next().map(|(_, value)| value);
+            value
+        })?;
+        Some((self.name.clone(), Metric::new(MetricValue::Gauge(value), None, 1f32)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::SnapshotView;
+    use crate::testing::name;
+    use std::collections::HashMap;
+
+    fn options(expression: &str, missing: MissingOperand) -> DerivedMetricOptions {
+        DerivedMetricOptions {
+            name: "error-rate;svc=api".into(),
+            expression: expression.into(),
+            missing,
+        }
+    }
+
+    #[test]
+    fn derived_metrics() {
+        let mut metrics = HashMap::new();
+        metrics.insert(name("requests;svc=api"), Metric::<f64>::new(MetricValue::Counter(200f64), None, 1f32));
+        metrics.insert(name("errors"), Metric::new(MetricValue::Counter(5f64), None, 1f32));
+        metrics.insert(name("latency-ms"), Metric::new(MetricValue::Timer(vec![3f64, 1f64, 2f64]), None, 1f32));
+        let snapshot = SnapshotView::from(metrics);
+
+        let derived = DerivedMetric::new(&options("errors.value / requests;svc=api.value * 100", MissingOperand::Skip)).unwrap();
+        let (derived_name, metric) = derived.eval_snapshot(&snapshot).unwrap();
+        assert_eq!(&derived_name.name[..], b"error-rate;svc=api");
+        assert_eq!(metric.value(), &MetricValue::Gauge(2.5f64));
+        assert_eq!(derived.expression().operands().len(), 2);
+
+        // the count of counters is their value
+        let mut counters = HashMap::new();
+        counters.insert(name("errors"), Metric::<f64>::new(MetricValue::Counter(5f64), None, 1f32));
+        counters.insert(name("requests"), Metric::new(MetricValue::Counter(200f64), None, 1f32));
+        let derived = DerivedMetric::new(&options("errors.count / requests.count * 100", MissingOperand::Skip)).unwrap();
+        assert_eq!(
+            derived.eval_snapshot(&SnapshotView::from(counters)).unwrap().1.value(),
+            &MetricValue::Gauge(2.5f64)
+        );
+
+        let derived = DerivedMetric::new(&options("-(latency-ms.max - latency-ms.min) / 2 + 1", MissingOperand::Skip)).unwrap();
+        assert_eq!(derived.eval_snapshot(&snapshot).unwrap().1.value(), &MetricValue::Gauge(0f64));
+
+        // missing operands and division by zero
+        let derived = DerivedMetric::new(&options("timeouts.value / requests;svc=api.value", MissingOperand::Skip)).unwrap();
+        assert!(derived.eval_snapshot(&snapshot).is_none());
+        let derived = DerivedMetric::new(&options("timeouts.value / requests;svc=api.value", MissingOperand::Zero)).unwrap();
+        assert_eq!(derived.eval_snapshot(&snapshot).unwrap().1.value(), &MetricValue::Gauge(0f64));
+        let derived = DerivedMetric::new(&options("requests;svc=api.value / timeouts.value", MissingOperand::Zero)).unwrap();
+        assert!(derived.eval_snapshot(&snapshot).is_none());
+        // the aggregate is not applicable to counters
+        let derived = DerivedMetric::new(&options("errors.median", MissingOperand::Skip)).unwrap();
+        assert!(derived.eval_snapshot(&snapshot).is_none());
+
+        for bad in &["", "errors", "errors.nosuch", "(errors.value", "errors.value 2", "errors.value * / 2", ".value"] {
+            assert!(Expression::<f64>::parse(bad).is_err(), "{} parsed", bad);
+        }
+    }
+}
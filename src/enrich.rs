@@ -0,0 +1,455 @@
+use std::collections::BTreeMap;
+
+use bytes::{BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
+
+use crate::metric::MetricError;
+use crate::name::{escape_tag_value, MetricName};
+
+// Graphite tag keys cannot be empty or contain `;`, `!`, `^` and `=`, `~` and whitespace are not allowed for
+// consistency with values
+fn valid_tag_key(key: &[u8]) -> bool {
+    !key.is_empty() && !key.iter().any(|c| b";!^=~".contains(c) || c.is_ascii_whitespace())
+}
+
+// `%` is allowed in values, so escaped values are valid
+fn valid_tag_value(value: &[u8]) -> bool {
+    !value.is_empty() && !value.iter().any(|c| *c == b';' || *c == b'~' || c.is_ascii_whitespace())
+}
+
+/// A tag with the value taken from the metric name, i.e. `service` from the first name segment
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SegmentTag {
+    pub key: String,
+    /// zero-based index of dot-separated segment of the name without tags
+    pub segment: usize,
+}
+
+/// Tags to add to metric names
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct EnrichOptions {
+    /// static tags like hostname or datacenter
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+
+    /// tags computed from the name
+    #[serde(default)]
+    pub segment_tags: Vec<SegmentTag>,
+}
+
+/// Adds tags to metric names. Tags already present in a name are never replaced.
+///
+/// Static tags are kept as a precomputed sorted suffix, so untagged names only get it appended,
+/// other names have their tags merged and sorted.
+#[derive(Debug, Clone)]
+pub struct Enricher {
+    static_keys: Vec<Vec<u8>>,
+    // ;key=value parts sorted by tag
+    suffix: Vec<u8>,
+    segment_tags: Vec<(Vec<u8>, usize)>,
+}
+
+impl Enricher {
+    /// Tag keys and values must be valid Graphite tags, values with the characters not allowed
+    /// can be escaped with `escape_tag_value` beforehand
+    pub fn new(options: EnrichOptions) -> Result<Self, MetricError> {
+        for (key, value) in &options.tags {
+            if !valid_tag_key(key.as_bytes()) {
+                return Err(MetricError::Tag(format!("bad key '{}'", key)));
+            }
+            if !valid_tag_value(value.as_bytes()) {
+                return Err(MetricError::Tag(format!("bad value '{}' of '{}'", value, key)));
+            }
+        }
+        if let Some(tag) = options.segment_tags.iter().find(|tag| !valid_tag_key(tag.key.as_bytes())) {
+            return Err(MetricError::Tag(format!("bad key '{}'", tag.key)));
+        }
+
+        let mut parts = options.tags.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<_>>();
+        parts.sort_unstable();
+        let mut suffix = Vec::new();
+        for part in parts {
+            suffix.push(b';');
+            suffix.extend_from_slice(part.as_bytes());
+        }
+        Ok(Self {
+            static_keys: options.tags.into_keys().map(String::into_bytes).collect(),
+            suffix,
+            segment_tags: options.segment_tags.into_iter().map(|tag| (tag.key.into_bytes(), tag.segment)).collect(),
+        })
+    }
+
+    /// Gives the name with tags added. The new name is built in `buf`, `intermediate` is
+    /// used for sorting tags and grows if required, so both should be reused between calls
+    pub fn enrich(&self, name: &MetricName, buf: &mut BytesMut, intermediate: &mut Vec<u8>) -> MetricName {
+        if self.static_keys.is_empty() && self.segment_tags.is_empty() {
+            return name.clone();
+        }
+
+        let base = name.name_without_tags();
+        let start = buf.len();
+        buf.reserve(name.name_with_tags().len() + self.suffix.len());
+        buf.extend_from_slice(name.name_with_tags());
+
+        // the most common case, when no tags need sorting
+        if name.tags_len() == 0 && self.segment_tags.is_empty() {
+            buf.extend_from_slice(&self.suffix);
+            return MetricName::from_raw_parts(buf.split_off(start).freeze(), Some(base.len()));
+        }
+
+        if name.tags_len() == 0 {
+            buf.extend_from_slice(b";");
+        }
+        let tags_start = buf.len();
+        if self.static_keys.iter().all(|key| name.tag_value(key).is_none()) {
+            // the suffix already has a leading semicolon
+            buf.extend_from_slice(&self.suffix);
+        } else {
+            for part in self.suffix.split(|c| *c == b';').filter(|part| !part.is_empty()) {
+                let key = part.split(|c| *c == b'=').next().unwrap_or(part);
+                if name.tag_value(key).is_none() {
+                    buf.extend_from_slice(b";");
+                    buf.extend_from_slice(part);
+                }
+            }
+        }
+
+        for (key, segment) in &self.segment_tags {
+            if name.tag_value(key).is_some() || self.static_keys.contains(key) {
+                continue;
+            }
+            if let Some(value) = base.split(|c| *c == b'.').nth(*segment) {
+                buf.extend_from_slice(b";");
+                buf.extend_from_slice(key);
+                buf.extend_from_slice(b"=");
+                buf.extend_from_slice(value);
+            }
+        }
+
+        if buf.len() == tags_start && name.tags_len() == 0 {
+            // nothing was added to an untagged name
+            buf.truncate(start);
+            return name.clone();
+        }
+
+        MetricName::from_unsorted_tags(buf.split_off(start), Some(base.len()), intermediate)
+    }
+}
+
+/// A small set of tags known at startup, like host and datacenter, spliced into names right when
+/// they are encoded. Unlike `Enricher`, no names are made: the sorted tags are encoded once, then
+/// merged with the tags of each name on the fly, so the only buffer written is the one passed by
+/// the caller, i.e. the scratch buffer of an encoder. Tags already present in a name are never replaced.
+#[derive(Debug, Clone)]
+pub struct StaticTags {
+    // key=value parts sorted like in names, with key lengths
+    parts: Vec<(Vec<u8>, usize)>,
+    // ;key=value parts joined
+    suffix: Vec<u8>,
+}
+
+impl StaticTags {
+    /// Keys and values must be valid Graphite tags, like in `Enricher::new`, this is only checked in
+    /// debug builds since the tags are known at compile time. The first of the tags with the same key is used
+    pub fn new(tags: &[(&'static str, &'static str)]) -> Self {
+        let mut parts: Vec<(Vec<u8>, usize)> = Vec::with_capacity(tags.len());
+        for (key, value) in tags {
+            debug_assert!(
+                valid_tag_key(key.as_bytes()) && valid_tag_value(value.as_bytes()),
+                "bad static tag {}={}",
+                key,
+                value
+            );
+            if parts.iter().all(|(part, len)| &part[..*len] != key.as_bytes()) {
+                parts.push((format!("{}={}", key, value).into_bytes(), key.len()));
+            }
+        }
+        parts.sort_unstable();
+        let mut suffix = Vec::new();
+        for (part, _) in &parts {
+            suffix.push(b';');
+            suffix.extend_from_slice(part);
+        }
+        Self { parts, suffix }
+    }
+
+    /// Puts the name with the tags added to the buffer, keeping the tags sorted
+    pub fn put(&self, name: &MetricName, buf: &mut BytesMut) {
+        buf.reserve(name.name.len() + self.suffix.len());
+        if self.parts.is_empty() {
+            buf.put_slice(name.name_with_tags());
+            return;
+        }
+
+        buf.put_slice(name.name_without_tags());
+        if name.tags().next().is_none() {
+            buf.put_slice(&self.suffix);
+            return;
+        }
+
+        let mut parts = self
+            .parts
+            .iter()
+            .filter(|(part, len)| name.tag_value(&part[..*len]).is_none())
+            .map(|(part, _)| &part[..])
+            .peekable();
+        for tag in name.tags_without_name().split(|c| *c == b';').filter(|tag| !tag.is_empty()) {
+            while let Some(part) = parts.next_if(|part| *part < tag) {
+                buf.put_u8(b';');
+                buf.put_slice(part);
+            }
+            buf.put_u8(b';');
+            buf.put_slice(tag);
+        }
+        for part in parts {
+            buf.put_u8(b';');
+            buf.put_slice(part);
+        }
+    }
+}
+
+/// What to do with an attribute in `AttributeMapping`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AttributeRule {
+    /// use the attribute value as a tag with the specified key
+    Rename(String),
+    Drop,
+}
+
+/// Mapping of key-value attributes coming from other metric systems, i.e. OTel resource
+/// attributes like `service.name` and `host.name`, into tags
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct AttributeMappingOptions {
+    /// rules by attribute key
+    #[serde(default)]
+    pub rules: BTreeMap<String, AttributeRule>,
+
+    /// drop attributes having no rule instead of keeping them under sanitized keys
+    #[serde(default)]
+    pub drop_unmapped: bool,
+
+    /// percent-encode the characters not allowed in tag values instead of replacing them, so the
+    /// original values can be restored with `MetricName::tag_value_unescaped`
+    #[serde(default)]
+    pub escape_values: bool,
+}
+
+/// Converts attributes to Graphite-compatible tags. Keys of attributes without rules only have the
+/// characters other than alphanumerics, `_` and `-` replaced with `_`, so `service.name` becomes `service_name`.
+/// Characters not allowed in tag values are replaced the same way, unless escaping of values is enabled.
+/// Attributes with empty values are dropped.
+#[derive(Debug, Clone)]
+pub struct AttributeMapping {
+    options: AttributeMappingOptions,
+}
+
+impl AttributeMapping {
+    pub fn new(options: AttributeMappingOptions) -> Self {
+        Self { options }
+    }
+
+    /// The tag key for the attribute, None if the attribute is dropped
+    pub fn tag_key(&self, key: &str) -> Option<String> {
+        match self.options.rules.get(key) {
+            Some(AttributeRule::Rename(renamed)) => Some(renamed.clone()),
+            Some(AttributeRule::Drop) => None,
+            None if self.options.drop_unmapped => None,
+            None => Some(
+                key.chars()
+                    .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Gives tags for the attributes, the later attribute wins if several are mapped to the same key
+    pub fn tags<'a, I>(&self, attributes: I) -> BTreeMap<String, String>
+    where
+        I: IntoIterator<Item = (&'a str, &'a str)>,
+    {
+        attributes
+            .into_iter()
+            .filter(|(_, value)| !value.is_empty())
+            .filter_map(|(key, value)| {
+                let value = if self.options.escape_values {
+                    String::from_utf8_lossy(&escape_tag_value(value.as_bytes())).into_owned()
+                } else {
+                    value
+                        .chars()
+                        .map(|c| if c == ';' || c == '=' || c == '~' || c.is_whitespace() { '_' } else { c })
+                        .collect()
+                };
+                self.tag_key(key).map(|key| (key, value))
+            })
+            .collect()
+    }
+
+    /// Makes an enricher adding tags for the attributes. Attributes of a resource are usually
+    /// shared by many metrics, so the enricher should be made once per resource. Fails if an
+    /// attribute is renamed to a bad tag key
+    pub fn enricher<'a, I>(&self, attributes: I) -> Result<Enricher, MetricError>
+    where
+        I: IntoIterator<Item = (&'a str, &'a str)>,
+    {
+        Enricher::new(EnrichOptions {
+            tags: self.tags(attributes),
+            segment_tags: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::name;
+
+    fn enricher() -> Enricher {
+        Enricher::new(EnrichOptions {
+            tags: vec![("host".to_string(), "h1".to_string()), ("dc".to_string(), "eu".to_string())]
+                .into_iter()
+                .collect(),
+            segment_tags: vec![SegmentTag {
+                key: "service".into(),
+                segment: 0,
+            }],
+        })
+        .unwrap()
+    }
+
+    fn enrich(enricher: &Enricher, n: &str) -> String {
+        let name = name(n);
+        let mut buf = BytesMut::new();
+        let mut intermediate = Vec::new();
+        let enriched = enricher.enrich(&name, &mut buf, &mut intermediate);
+        assert_eq!(enriched.name_without_tags(), name.name_without_tags());
+        String::from_utf8(enriched.name.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn enrich_names() {
+        let enricher = enricher();
+        assert_eq!(enrich(&enricher, "web.requests"), "web.requests;dc=eu;host=h1;service=web");
+        assert_eq!(
+            enrich(&enricher, "web.requests;zone=a;env=prod"),
+            "web.requests;dc=eu;env=prod;host=h1;service=web;zone=a"
+        );
+        // existing tags are never replaced
+        assert_eq!(enrich(&enricher, "web.requests;host=h2;service=api"), "web.requests;dc=eu;host=h2;service=api");
+
+        let static_only = Enricher::new(EnrichOptions {
+            tags: vec![("host".to_string(), "h1".to_string())].into_iter().collect(),
+            segment_tags: Vec::new(),
+        })
+        .unwrap();
+        assert_eq!(enrich(&static_only, "requests"), "requests;host=h1");
+        assert_eq!(enrich(&static_only, "requests;host=h2"), "requests;host=h2");
+
+        let segment_only = Enricher::new(EnrichOptions {
+            tags: BTreeMap::new(),
+            segment_tags: vec![SegmentTag {
+                key: "kind".into(),
+                segment: 2,
+            }],
+        })
+        .unwrap();
+        assert_eq!(enrich(&segment_only, "a.b"), "a.b");
+        assert_eq!(enrich(&segment_only, "a.b.c"), "a.b.c;kind=c");
+        assert_eq!(enrich(&Enricher::new(EnrichOptions::default()).unwrap(), "a;b=c"), "a;b=c");
+
+        // buffers are reused between calls, with some data before the name
+        let mut buf = BytesMut::from(&b"prefix"[..]);
+        let mut intermediate = Vec::new();
+        for (n, expected) in [("a.b", "a.b"), ("a.b.c", "a.b.c;kind=c"), ("x.y.z;a=b", "x.y.z;a=b;kind=z"), ("a.b", "a.b")] {
+            let enriched = segment_only.enrich(&name(n), &mut buf, &mut intermediate);
+            assert_eq!(enriched, name(expected));
+            assert_eq!(&buf[..], b"prefix");
+        }
+
+        for (key, value) in [("", "a"), ("a=b", "c"), ("a b", "c"), ("a", ""), ("a", "b;c=d"), ("a", "b c"), ("a", "b~c")] {
+            let options = EnrichOptions {
+                tags: vec![(key.to_string(), value.to_string())].into_iter().collect(),
+                segment_tags: Vec::new(),
+            };
+            assert!(Enricher::new(options).is_err(), "{}={}", key, value);
+        }
+        let options = EnrichOptions {
+            tags: vec![("path".to_string(), String::from_utf8(escape_tag_value(b"a b;c").into_owned()).unwrap())]
+                .into_iter()
+                .collect(),
+            segment_tags: vec![SegmentTag {
+                key: "kind;x".into(),
+                segment: 0,
+            }],
+        };
+        assert!(Enricher::new(options.clone()).is_err());
+        let enricher = Enricher::new(EnrichOptions {
+            segment_tags: Vec::new(),
+            ..options
+        })
+        .unwrap();
+        assert_eq!(enrich(&enricher, "a;path=x"), "a;path=x");
+        assert_eq!(enrich(&enricher, "a;b=c"), "a;b=c;path=a%20b%3Bc");
+    }
+
+    #[test]
+    fn static_tags() {
+        let tags = StaticTags::new(&[("host", "h1"), ("dc", "eu"), ("host", "h2")]);
+        let enricher = Enricher::new(EnrichOptions {
+            tags: vec![("host".to_string(), "h1".to_string()), ("dc".to_string(), "eu".to_string())]
+                .into_iter()
+                .collect(),
+            segment_tags: Vec::new(),
+        })
+        .unwrap();
+        let mut buf = BytesMut::new();
+        for n in &[
+            "requests",
+            "requests;",
+            "requests;zone=a;env=prod",
+            "requests;host=h3;a=b",
+            "requests;dc=us;host=h3",
+        ] {
+            let name = name(n);
+            tags.put(&name, &mut buf);
+            assert_eq!(String::from_utf8_lossy(&buf.split()), enrich(&enricher, &String::from_utf8_lossy(&name.name)));
+        }
+
+        let name = MetricName::new_untagged(BytesMut::from("requests"));
+        StaticTags::new(&[]).put(&name, &mut buf);
+        assert_eq!(&buf.split()[..], b"requests");
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "bad static tag")]
+    fn static_tags_bad_value() {
+        StaticTags::new(&[("host", "h1;dc=eu")]);
+    }
+
+    #[test]
+    fn attribute_mapping() {
+        let mut options = AttributeMappingOptions::default();
+        options.rules.insert("service.name".into(), AttributeRule::Rename("service".into()));
+        options.rules.insert("process.pid".into(), AttributeRule::Drop);
+        let mapping = AttributeMapping::new(options.clone());
+
+        let attributes = vec![("service.name", "api"), ("host.name", "h1;x=y"), ("process.pid", "1234"), ("empty", "")];
+        let enricher = mapping.enricher(attributes.iter().copied()).unwrap();
+        assert_eq!(enrich(&enricher, "requests;env=prod"), "requests;env=prod;host_name=h1_x_y;service=api");
+
+        options.escape_values = true;
+        let mapping = AttributeMapping::new(options.clone());
+        let enricher = mapping.enricher(attributes.iter().copied()).unwrap();
+        assert_eq!(enrich(&enricher, "requests"), "requests;host_name=h1%3Bx=y;service=api");
+
+        options.drop_unmapped = true;
+        let mapping = AttributeMapping::new(options);
+        let tags = mapping.tags(attributes);
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags["service"], "api");
+    }
+}
@@ -0,0 +1,157 @@
+//! An optional envelope for serialized snapshots, allowing peers to authenticate and,
+//! optionally, encrypt the data using a pre-shared key.
+//!
+//! Envelope layout (all numbers are single bytes):
+//!
+//! * signed: `version`, `mode = 0`, payload, 32 bytes of HMAC-SHA256 over all previous bytes
+//! * encrypted: `version`, `mode = 1`, 12 bytes of nonce, ChaCha20-Poly1305 encrypted payload
+//! with 16 bytes of authentication tag, the first two bytes are authenticated as associated data
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::metric::MetricError;
+
+const ENVELOPE_VERSION: u8 = 1;
+const HEADER_LEN: usize = 2;
+const SIGNATURE_LEN: usize = 32;
+
+/// The way the payload is protected by envelope
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvelopeMode {
+    /// payload is sent as is, but with a signature
+    Sign = 0,
+    /// payload is encrypted and authenticated
+    Encrypt = 1,
+}
+
+/// Seals and opens envelopes using the pre-shared key
+pub struct Envelope {
+    sign_key: hmac::Key,
+    encrypt_key: LessSafeKey,
+    rng: SystemRandom,
+}
+
+impl Envelope {
+    /// Creates envelope from a pre-shared key. Separate keys for signing and encryption
+    /// are derived from it, so the same key is never used for both purposes
+    pub fn new(psk: &[u8; 32]) -> Self {
+        let sign_key = hmac::Key::new(hmac::HMAC_SHA256, &psk[..]);
+        let derived = hmac::sign(&sign_key, b"bioyino-envelope-encryption");
+        // CHACHA20_POLY1305 key length is exactly 32 bytes, same as HMAC-SHA256 output
+        let encrypt_key = LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, derived.as_ref()).unwrap());
+        Self {
+            sign_key,
+            encrypt_key,
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// Wraps the payload into the envelope
+    pub fn seal(&self, mode: EnvelopeMode, payload: &[u8]) -> Result<Vec<u8>, MetricError> {
+        let header = [ENVELOPE_VERSION, mode as u8];
+        match mode {
+            EnvelopeMode::Sign => {
+                let mut sealed = Vec::with_capacity(HEADER_LEN + payload.len() + SIGNATURE_LEN);
+                sealed.extend_from_slice(&header);
+                sealed.extend_from_slice(payload);
+                let signature = hmac::sign(&self.sign_key, &sealed);
+                sealed.extend_from_slice(signature.as_ref());
+                Ok(sealed)
+            }
+            EnvelopeMode::Encrypt => {
+                let mut nonce = [0u8; NONCE_LEN];
+                self.rng.fill(&mut nonce).map_err(|_| MetricError::Envelope("generating nonce failed"))?;
+
+                let mut in_out = payload.to_vec();
+                self.encrypt_key
+                    .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(header), &mut in_out)
+                    .map_err(|_| MetricError::Envelope("encryption failed"))?;
+
+                let mut sealed = Vec::with_capacity(HEADER_LEN + NONCE_LEN + in_out.len());
+                sealed.extend_from_slice(&header);
+                sealed.extend_from_slice(&nonce);
+                sealed.extend_from_slice(&in_out);
+                Ok(sealed)
+            }
+        }
+    }
+
+    /// Checks the envelope and returns the payload from it
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, MetricError> {
+        if sealed.len() < HEADER_LEN {
+            return Err(MetricError::Envelope("envelope is too short"));
+        }
+        if sealed[0] != ENVELOPE_VERSION {
+            return Err(MetricError::Envelope("unknown envelope version"));
+        }
+
+        match sealed[1] {
+            m if m == EnvelopeMode::Sign as u8 => {
+                if sealed.len() < HEADER_LEN + SIGNATURE_LEN {
+                    return Err(MetricError::Envelope("envelope is too short"));
+                }
+                let (signed, signature) = sealed.split_at(sealed.len() - SIGNATURE_LEN);
+                hmac::verify(&self.sign_key, signed, signature).map_err(|_| MetricError::Envelope("bad signature"))?;
+                Ok(signed[HEADER_LEN..].to_vec())
+            }
+            m if m == EnvelopeMode::Encrypt as u8 => {
+                if sealed.len() < HEADER_LEN + NONCE_LEN {
+                    return Err(MetricError::Envelope("envelope is too short"));
+                }
+                let header = [sealed[0], sealed[1]];
+                let mut nonce = [0u8; NONCE_LEN];
+                nonce.copy_from_slice(&sealed[HEADER_LEN..HEADER_LEN + NONCE_LEN]);
+
+                let mut in_out = sealed[HEADER_LEN + NONCE_LEN..].to_vec();
+                let len = self
+                    .encrypt_key
+                    .open_in_place(Nonce::assume_unique_for_key(nonce), Aad::from(header), &mut in_out)
+                    .map_err(|_| MetricError::Envelope("decryption failed"))?
+                    .len();
+                in_out.truncate(len);
+                Ok(in_out)
+            }
+            _ => Err(MetricError::Envelope("unknown envelope mode")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn envelope_roundtrip() {
+        let envelope = Envelope::new(&[42u8; 32]);
+        let payload = b"some serialized snapshot".to_vec();
+
+        for mode in [EnvelopeMode::Sign, EnvelopeMode::Encrypt] {
+            let sealed = envelope.seal(mode, &payload).unwrap();
+            assert_eq!(envelope.open(&sealed).unwrap(), payload);
+
+            // any modification must be detected
+            for idx in 0..sealed.len() {
+                let mut broken = sealed.clone();
+                broken[idx] ^= 1;
+                assert!(
+                    envelope.open(&broken).is_err(),
+                    "modification of byte {} was not detected in {:?} mode",
+                    idx,
+                    mode
+                );
+            }
+
+            // other key must not open the envelope
+            let other = Envelope::new(&[43u8; 32]);
+            assert!(other.open(&sealed).is_err());
+        }
+
+        // payload must not be seen in encrypted envelope
+        let sealed = envelope.seal(EnvelopeMode::Encrypt, &payload).unwrap();
+        assert!(!sealed.windows(payload.len()).any(|w| w == &payload[..]));
+
+        assert!(envelope.open(&[]).is_err());
+        assert!(envelope.open(&[ENVELOPE_VERSION, EnvelopeMode::Sign as u8]).is_err());
+    }
+}
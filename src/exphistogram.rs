@@ -0,0 +1,528 @@
+use std::convert::TryFrom;
+use std::iter::repeat_n;
+
+use serde::{Deserialize, Serialize};
+
+use crate::metric::MetricError;
+
+/// The finest scale allowed by OpenTelemetry
+pub const MAX_SCALE: i32 = 20;
+/// The coarsest scale allowed by OpenTelemetry
+pub const MIN_SCALE: i32 = -10;
+
+// index of the bucket `(base^index, base^(index + 1)]` the positive value falls into, where base is 2^(2^-scale)
+fn map_to_index(value: f64, scale: i32) -> i32 {
+    let bits = value.to_bits();
+    let raw_exp = ((bits >> 52) & 0x7ff) as i32;
+    let mantissa = bits & ((1 << 52) - 1);
+    if raw_exp != 0 && mantissa == 0 {
+        // exact powers of two are the upper bounds of buckets
+        let exp = raw_exp - 1023;
+        return if scale <= 0 { (exp - 1) >> -scale } else { (exp << scale) - 1 };
+    }
+    if scale <= 0 && raw_exp != 0 {
+        return (raw_exp - 1023) >> -scale;
+    }
+    // subnormals and positive scales, the logarithm may be off near the bucket bounds,
+    // which is allowed by the specification
+    (value.log2() * 2f64.powi(scale)).ceil() as i32 - 1
+}
+
+// the number of scale steps needed to fit the index range into max_size buckets, but not more than
+// `max_by`, so the scale doesn't go below MIN_SCALE
+fn downscale_needed(low: i32, high: i32, max_size: usize, max_by: u32) -> u32 {
+    let mut by = 0;
+    while by < max_by && ((i64::from(high) >> by) - (i64::from(low) >> by)) as usize >= max_size {
+        by += 1;
+    }
+    by
+}
+
+/// Consecutive buckets of one sign of `ExpHistogram`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExpBuckets {
+    offset: i32,
+    counts: Vec<u64>,
+}
+
+impl ExpBuckets {
+    /// Index of the first bucket
+    pub fn offset(&self) -> i32 {
+        self.offset
+    }
+
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    // None on overflow, which is only possible for the buckets coming from outside
+    fn total(&self) -> Option<u64> {
+        self.counts.iter().try_fold(0u64, |total, count| total.checked_add(*count))
+    }
+
+    // the index next to the last bucket, None if it doesn't fit into i32
+    fn end(&self) -> Option<i32> {
+        i32::try_from(self.counts.len()).ok().and_then(|len| self.offset.checked_add(len))
+    }
+
+    // the range of indexes after the index would be added
+    fn range_with(&self, index: i32) -> (i32, i32) {
+        if self.counts.is_empty() {
+            (index, index)
+        } else {
+            (self.offset.min(index), (self.offset + self.counts.len() as i32 - 1).max(index))
+        }
+    }
+
+    fn increment(&mut self, index: i32, count: u64) {
+        if self.counts.is_empty() {
+            self.offset = index;
+            self.counts.push(count);
+            return;
+        }
+        if index < self.offset {
+            let grow = (self.offset - index) as usize;
+            self.counts.splice(0..0, repeat_n(0, grow));
+            self.offset = index;
+        }
+        let pos = (index - self.offset) as usize;
+        if pos >= self.counts.len() {
+            self.counts.resize(pos + 1, 0);
+        }
+        self.counts[pos] += count;
+    }
+
+    fn downscale(&mut self, by: u32) {
+        if by == 0 || self.counts.is_empty() {
+            return;
+        }
+        let offset = self.offset >> by;
+        let mut counts = Vec::with_capacity((self.counts.len() >> by) + 1);
+        for (pos, count) in self.counts.iter().enumerate() {
+            let new_pos = (((self.offset + pos as i32) >> by) - offset) as usize;
+            if new_pos >= counts.len() {
+                counts.push(0);
+            }
+            counts[new_pos] += count;
+        }
+        self.offset = offset;
+        self.counts = counts;
+    }
+
+    fn merge(&mut self, other: &ExpBuckets) {
+        for (pos, count) in other.counts.iter().enumerate() {
+            self.increment(other.offset + pos as i32, *count);
+        }
+    }
+}
+
+/// A data point of OpenTelemetry exponential histogram with the fields named after the OTLP
+/// `ExponentialHistogramDataPoint` message, except for timestamps and attributes which are
+/// handled the same way as for other metrics
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ExpHistogramPoint {
+    pub scale: i32,
+    pub zero_count: u64,
+    pub zero_threshold: f64,
+    pub positive_offset: i32,
+    pub positive_bucket_counts: Vec<u64>,
+    pub negative_offset: i32,
+    pub negative_bucket_counts: Vec<u64>,
+    pub count: u64,
+    pub sum: Option<f64>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+/// A histogram with exponentially growing buckets, which are defined by a single scale parameter
+/// instead of the list of bounds, as in OpenTelemetry. Bucket `i` counts values in `(base^i, base^(i+1)]`,
+/// where `base = 2^(2^-scale)`. When values don't fit into the maximal number of buckets, the scale
+/// is decreased, merging each pair of neighbouring buckets, so the relative error stays bounded
+/// for any range of values, though at `MIN_SCALE` the number of buckets is not limited anymore.
+/// Histograms of different scales are merged at the coarser one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "ExpHistogramState")]
+pub struct ExpHistogram {
+    scale: i32,
+    max_size: usize,
+    zero_count: u64,
+    positive: ExpBuckets,
+    negative: ExpBuckets,
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+// the fields of deserialized histograms, which are checked the same way as in `ExpHistogram::new`
+#[derive(Deserialize)]
+struct ExpHistogramState {
+    scale: i32,
+    max_size: usize,
+    zero_count: u64,
+    #[serde(default)]
+    positive: ExpBuckets,
+    #[serde(default)]
+    negative: ExpBuckets,
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl TryFrom<ExpHistogramState> for ExpHistogram {
+    type Error = MetricError;
+
+    fn try_from(state: ExpHistogramState) -> Result<Self, Self::Error> {
+        let histogram = Self::new(state.scale, state.max_size)?;
+        if state.positive.end().is_none() || state.negative.end().is_none() {
+            return Err(MetricError::Buckets("bucket index overflow"));
+        }
+        Ok(Self {
+            zero_count: state.zero_count,
+            positive: state.positive,
+            negative: state.negative,
+            count: state.count,
+            sum: state.sum,
+            min: state.min,
+            max: state.max,
+            ..histogram
+        })
+    }
+}
+
+impl ExpHistogram {
+    /// Creates an empty histogram starting at the scale, with at most `max_size` buckets for each sign
+    pub fn new(scale: i32, max_size: usize) -> Result<Self, MetricError> {
+        if !(MIN_SCALE..=MAX_SCALE).contains(&scale) {
+            return Err(MetricError::Buckets("scale is out of range"));
+        }
+        if max_size < 4 {
+            return Err(MetricError::Buckets("at least 4 buckets are required"));
+        }
+        Ok(Self {
+            scale,
+            max_size,
+            zero_count: 0,
+            positive: ExpBuckets::default(),
+            negative: ExpBuckets::default(),
+            count: 0,
+            sum: 0f64,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        })
+    }
+
+    pub fn scale(&self) -> i32 {
+        self.scale
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    pub fn zero_count(&self) -> u64 {
+        self.zero_count
+    }
+
+    pub fn positive(&self) -> &ExpBuckets {
+        &self.positive
+    }
+
+    pub fn negative(&self) -> &ExpBuckets {
+        &self.negative
+    }
+
+    /// The minimal value recorded, None for empty histogram
+    pub fn min(&self) -> Option<f64> {
+        if self.count > 0 {
+            Some(self.min)
+        } else {
+            None
+        }
+    }
+
+    /// The maximal value recorded, None for empty histogram
+    pub fn max(&self) -> Option<f64> {
+        if self.count > 0 {
+            Some(self.max)
+        } else {
+            None
+        }
+    }
+
+    fn downscale(&mut self, by: u32) {
+        self.scale -= by as i32;
+        self.positive.downscale(by);
+        self.negative.downscale(by);
+    }
+
+    pub fn record(&mut self, value: f64) -> Result<(), MetricError> {
+        if !value.is_finite() {
+            return Err(MetricError::Buckets("value must be finite"));
+        }
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        if value == 0f64 {
+            self.zero_count += 1;
+            return Ok(());
+        }
+
+        let mut index = map_to_index(value.abs(), self.scale);
+        let buckets = if value > 0f64 { &self.positive } else { &self.negative };
+        let (low, high) = buckets.range_with(index);
+        let by = downscale_needed(low, high, self.max_size, (self.scale - MIN_SCALE) as u32);
+        if by > 0 {
+            self.downscale(by);
+            index >>= by;
+        }
+        let buckets = if value > 0f64 { &mut self.positive } else { &mut self.negative };
+        buckets.increment(index, 1);
+        Ok(())
+    }
+
+    /// Adds the buckets of other histogram, both are brought to the finest scale both
+    /// of them can be represented at
+    pub fn merge(&mut self, other: &ExpHistogram) {
+        let mut other = other.clone();
+        let scale = self.scale.min(other.scale);
+        self.downscale((self.scale - scale) as u32);
+        other.downscale((other.scale - scale) as u32);
+
+        let mut by = 0;
+        for (buckets, other) in [(&self.positive, &other.positive), (&self.negative, &other.negative)] {
+            if other.is_empty() {
+                continue;
+            }
+            let (low, _) = buckets.range_with(other.offset);
+            let (_, high) = buckets.range_with(other.offset + other.counts.len() as i32 - 1);
+            by = by.max(downscale_needed(low, high, self.max_size, (scale - MIN_SCALE) as u32));
+        }
+        self.downscale(by);
+        other.downscale(by);
+
+        self.positive.merge(&other.positive);
+        self.negative.merge(&other.negative);
+        self.zero_count += other.zero_count;
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    /// Converts to the OTLP data point
+    pub fn to_point(&self) -> ExpHistogramPoint {
+        ExpHistogramPoint {
+            scale: self.scale,
+            zero_count: self.zero_count,
+            zero_threshold: 0f64,
+            positive_offset: self.positive.offset,
+            positive_bucket_counts: self.positive.counts.clone(),
+            negative_offset: self.negative.offset,
+            negative_bucket_counts: self.negative.counts.clone(),
+            count: self.count,
+            sum: Some(self.sum),
+            min: self.min(),
+            max: self.max(),
+        }
+    }
+
+    /// Makes a histogram from the OTLP data point, downscaling it if it has more than `max_size` buckets.
+    /// Missing sum is taken as zero, missing min and max are taken from the bucket bounds.
+    pub fn from_point(point: &ExpHistogramPoint, max_size: usize) -> Result<Self, MetricError> {
+        let mut histogram = Self::new(point.scale, max_size)?;
+        let positive = ExpBuckets {
+            offset: point.positive_offset,
+            counts: point.positive_bucket_counts.clone(),
+        };
+        let negative = ExpBuckets {
+            offset: point.negative_offset,
+            counts: point.negative_bucket_counts.clone(),
+        };
+        let total = positive
+            .total()
+            .zip(negative.total())
+            .and_then(|(positive, negative)| positive.checked_add(negative))
+            .and_then(|total| total.checked_add(point.zero_count))
+            .ok_or(MetricError::Buckets("bucket counts overflow"))?;
+        if total != point.count {
+            return Err(MetricError::Buckets("count does not match the buckets"));
+        }
+
+        let mut by = 0;
+        for buckets in [&positive, &negative] {
+            let end = buckets.end().ok_or(MetricError::Buckets("bucket index overflow"))?;
+            if !buckets.is_empty() {
+                by = by.max(downscale_needed(buckets.offset, end - 1, max_size, (histogram.scale - MIN_SCALE) as u32));
+            }
+        }
+        histogram.positive = positive;
+        histogram.negative = negative;
+        histogram.downscale(by);
+
+        histogram.zero_count = point.zero_count;
+        histogram.count = point.count;
+        histogram.sum = point.sum.unwrap_or(0f64);
+        if point.count > 0 {
+            // bounds of the outermost buckets are the closest estimate without the values
+            let scale = histogram.scale;
+            let bound = |index: i32| 2f64.powf(f64::from(index) * 2f64.powi(-scale));
+            let (positive, negative) = (&histogram.positive, &histogram.negative);
+            let low = if !negative.is_empty() {
+                -bound(negative.offset + negative.counts.len() as i32)
+            } else if point.zero_count > 0 {
+                0f64
+            } else {
+                bound(positive.offset)
+            };
+            let high = if !positive.is_empty() {
+                bound(positive.offset + positive.counts.len() as i32)
+            } else if point.zero_count > 0 {
+                0f64
+            } else {
+                -bound(negative.offset)
+            };
+            histogram.min = point.min.unwrap_or(low);
+            histogram.max = point.max.unwrap_or(high);
+        }
+        Ok(histogram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_indexes() {
+        assert_eq!(map_to_index(1f64, 0), -1);
+        assert_eq!(map_to_index(2f64, 0), 0);
+        assert_eq!(map_to_index(3f64, 0), 1);
+        assert_eq!(map_to_index(4f64, 0), 1);
+        assert_eq!(map_to_index(4f64, -1), 0);
+        assert_eq!(map_to_index(5f64, -1), 1);
+        assert_eq!(map_to_index(2f64, 1), 1);
+        assert_eq!(map_to_index(1.5, 1), 1);
+        assert_eq!(map_to_index(1.3, 1), 0);
+        assert_eq!(map_to_index(f64::MIN_POSITIVE / 2f64, 0), -1024);
+    }
+
+    #[test]
+    fn exponential_histogram() {
+        assert!(ExpHistogram::new(21, 160).is_err());
+
+        let mut histogram = ExpHistogram::new(3, 4).unwrap();
+        for value in &[1.5, 3f64, 0f64, -2f64] {
+            histogram.record(*value).unwrap();
+        }
+        assert!(histogram.record(f64::NAN).is_err());
+        // 1.5 and 3 are 8 buckets apart at scale 3, so scale is decreased to fit into 4
+        assert_eq!(histogram.scale(), 1);
+        assert_eq!(histogram.positive().offset(), 1);
+        assert_eq!(histogram.positive().counts(), &[1, 0, 1]);
+        assert_eq!(histogram.negative().offset(), 1);
+        assert_eq!(histogram.negative().counts(), &[1]);
+        assert_eq!(histogram.zero_count(), 1);
+        assert_eq!(
+            (histogram.count(), histogram.sum(), histogram.min(), histogram.max()),
+            (4, 2.5, Some(-2f64), Some(3f64))
+        );
+
+        let mut other = ExpHistogram::new(0, 4).unwrap();
+        other.record(16f64).unwrap();
+        other.record(32f64).unwrap();
+        histogram.merge(&other);
+        // buckets 0..4 at scale 0 don't fit, so they become 0..2 at scale -1
+        assert_eq!(histogram.scale(), -1);
+        assert_eq!(histogram.positive().offset(), 0);
+        assert_eq!(histogram.positive().counts(), &[2, 1, 1]);
+        assert_eq!(histogram.negative().counts(), &[1]);
+        assert_eq!(histogram.count(), 6);
+        assert_eq!(histogram.max(), Some(32f64));
+
+        let point = histogram.to_point();
+        assert_eq!(ExpHistogram::from_point(&point, 4).unwrap(), histogram);
+        let mut bad = point;
+        bad.count += 1;
+        assert!(ExpHistogram::from_point(&bad, 4).is_err());
+
+        let point = ExpHistogramPoint {
+            positive_bucket_counts: vec![1, 1, 1, 1, 1],
+            count: 5,
+            ..Default::default()
+        };
+        let imported = ExpHistogram::from_point(&point, 4).unwrap();
+        assert_eq!(imported.scale(), -1);
+        assert_eq!(imported.positive().counts(), &[2, 2, 1]);
+        assert_eq!((imported.min(), imported.max()), (Some(1f64), Some(64f64)));
+
+        let overflowing = ExpHistogramPoint {
+            positive_bucket_counts: vec![u64::MAX, 1],
+            count: 0,
+            ..Default::default()
+        };
+        assert!(ExpHistogram::from_point(&overflowing, 4).is_err());
+        let overflowing = ExpHistogramPoint {
+            positive_offset: i32::MAX,
+            positive_bucket_counts: vec![1, 1],
+            count: 2,
+            ..Default::default()
+        };
+        assert!(ExpHistogram::from_point(&overflowing, 4).is_err());
+    }
+
+    #[test]
+    fn exponential_histogram_limits() {
+        use serde::de::value::{Error, MapDeserializer};
+
+        let deserialize = |scale: i64, max_size: i64| {
+            let fields = vec![
+                ("scale", scale),
+                ("max_size", max_size),
+                ("zero_count", 0),
+                ("count", 0),
+                ("sum", 0),
+                ("min", 0),
+                ("max", 0),
+            ];
+            ExpHistogram::deserialize(MapDeserializer::<_, Error>::new(fields.into_iter()))
+        };
+        assert_eq!(
+            deserialize(2, 4).unwrap(),
+            ExpHistogram {
+                min: 0f64,
+                max: 0f64,
+                ..ExpHistogram::new(2, 4).unwrap()
+            }
+        );
+        assert!(deserialize(2, 0).is_err());
+        assert!(deserialize(i64::from(MAX_SCALE) + 1, 4).is_err());
+        assert!(deserialize(i64::from(MIN_SCALE) - 1, 4).is_err());
+
+        // buckets from outside may not fit at the coarsest scale
+        let point = ExpHistogramPoint {
+            scale: MIN_SCALE,
+            positive_offset: -1000,
+            positive_bucket_counts: vec![1; 2000],
+            count: 2000,
+            ..Default::default()
+        };
+        let mut histogram = ExpHistogram::from_point(&point, 4).unwrap();
+        assert_eq!(histogram.scale(), MIN_SCALE);
+        assert_eq!(histogram.positive().counts().len(), 2000);
+        histogram.record(f64::MAX).unwrap();
+        histogram.merge(&ExpHistogram::from_point(&point, 4).unwrap());
+        assert_eq!(histogram.scale(), MIN_SCALE);
+        assert_eq!(histogram.count(), 4001);
+    }
+}
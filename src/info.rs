@@ -0,0 +1,164 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+
+use bytes::BytesMut;
+use num_traits::{AsPrimitive, Float};
+use serde::{Deserialize, Serialize};
+
+use crate::enrich::{EnrichOptions, Enricher};
+use crate::metric::{FromF64, Metric, MetricError, MetricValue};
+use crate::name::MetricName;
+use crate::protocol_v2_capnp::metric::metric_meta;
+
+/// A metric carrying string labels instead of a value, like version and commit of a build or
+/// a deploy marker. Following the Prometheus `_info` convention, it is a gauge always equal to 1,
+/// with the labels becoming tags, so it can be joined with other metrics in queries.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Info {
+    labels: BTreeMap<String, String>,
+}
+
+impl Info {
+    /// Labels must be valid tags: keys of alphanumerics, `_` and `-`, non-empty values without
+    /// `;`, `=`, `~` and whitespace
+    pub fn new<I, K, V>(labels: I) -> Result<Self, MetricError>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let labels = labels.into_iter().map(|(key, value)| (key.into(), value.into())).collect::<BTreeMap<_, _>>();
+        if labels.is_empty() {
+            return Err(MetricError::Info("no labels"));
+        }
+        for (key, value) in &labels {
+            if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                return Err(MetricError::Info("bad label key"));
+            }
+            if value.is_empty() || value.chars().any(|c| c == ';' || c == '=' || c == '~' || c.is_whitespace()) {
+                return Err(MetricError::Info("bad label value"));
+            }
+        }
+        Ok(Self { labels })
+    }
+
+    pub fn labels(&self) -> &BTreeMap<String, String> {
+        &self.labels
+    }
+
+    pub fn label(&self, key: &str) -> Option<&str> {
+        self.labels.get(key).map(String::as_str)
+    }
+
+    /// The newer info replaces the labels completely, since they describe the current state of the source
+    pub fn accumulate(&mut self, new: Info) {
+        self.labels = new.labels;
+    }
+
+    /// The gauge of 1 for receivers not supporting info metrics, the labels are kept in
+    /// `Metric::info` while no regular gauge updates come
+    pub fn to_metric<F>(&self, timestamp: Option<u64>) -> Metric<F>
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<f64>,
+    {
+        Metric::new(MetricValue::Gauge(F::one()), timestamp, 1f32).with_info(self.clone())
+    }
+
+    /// Gives the gauge of 1 with labels added to the name as tags for the backends not supporting
+    /// info metrics, the tags already in the name are kept. Fails if the labels are not valid tags,
+    /// which is only possible for deserialized info
+    pub fn to_tagged<F>(&self, name: &MetricName, timestamp: Option<u64>) -> Result<(MetricName, Metric<F>), MetricError>
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<f64>,
+    {
+        let enricher = Enricher::new(EnrichOptions {
+            tags: self.labels.clone(),
+            segment_tags: Vec::new(),
+        })?;
+        let name = enricher.enrich(name, &mut BytesMut::new(), &mut Vec::new());
+        Ok((name, Metric::new(MetricValue::Gauge(F::one()), timestamp, 1f32)))
+    }
+
+    /// Approximate number of bytes occupied by the labels, including heap buffers
+    pub fn approx_mem_size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self
+                .labels
+                .iter()
+                .map(|(key, value)| std::mem::size_of::<(String, String)>() + key.capacity() + value.capacity())
+                .sum::<usize>()
+    }
+
+    pub fn fill_capnp(&self, builder: metric_meta::Builder) {
+        let mut l_builder = builder.init_info(self.labels.len() as u32);
+        for (idx, (key, value)) in self.labels.iter().enumerate() {
+            let mut label = l_builder.reborrow().get(idx as u32);
+            label.set_key(key);
+            label.set_value(value);
+        }
+    }
+
+    /// Reads the labels from metric meta, None if it is not an info metric
+    pub fn from_capnp(reader: metric_meta::Reader) -> Result<Option<Self>, MetricError> {
+        if !reader.has_info() {
+            return Ok(None);
+        }
+        let labels = reader
+            .get_info()
+            .map_err(MetricError::Capnp)?
+            .iter()
+            .map(|label| {
+                let key = label.get_key().map_err(MetricError::Capnp)?;
+                let value = label.get_value().map_err(MetricError::Capnp)?;
+                Ok((key.to_string(), value.to_string()))
+            })
+            .collect::<Result<Vec<_>, MetricError>>()?;
+        Self::new(labels).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{fill_snapshot, read_snapshot};
+    use crate::protocol_v2_capnp::message;
+    use crate::testing::name;
+
+    #[test]
+    fn info_metric() {
+        assert!(Info::new(Vec::<(String, String)>::new()).is_err());
+        assert!(Info::new(vec![("version", "1.0 beta")]).is_err());
+        assert!(Info::new(vec![("build.version", "1.0")]).is_err());
+
+        let mut info = Info::new(vec![("version", "1.0"), ("commit", "abc123")]).unwrap();
+        assert_eq!(info.label("commit"), Some("abc123"));
+        info.accumulate(Info::new(vec![("version", "1.1")]).unwrap());
+        assert_eq!(info.label("commit"), None);
+        assert_eq!(info.label("version"), Some("1.1"));
+
+        let info = Info::new(vec![("version", "1.0"), ("commit", "abc123"), ("host", "b")]).unwrap();
+        let name = name("app.build_info;host=a");
+        let (name, metric) = info.to_tagged::<f64>(&name, Some(10)).unwrap();
+        assert_eq!(&name.name[..], b"app.build_info;commit=abc123;host=a;version=1.0");
+        assert_eq!(metric.value(), &MetricValue::Gauge(1f64));
+    }
+
+    #[test]
+    fn info_metric_snapshot() {
+        let mut metric = Info::new(vec![("version", "1.0")]).unwrap().to_metric::<f64>(Some(10));
+        let info = Info::new(vec![("version", "1.1"), ("commit", "abc123")]).unwrap();
+        metric.accumulate(info.to_metric(Some(20))).unwrap();
+        assert_eq!(metric.info(), Some(&info));
+
+        let name = MetricName::new_untagged(BytesMut::from("app.build_info"));
+        let mut builder = capnp::message::Builder::new_default();
+        fill_snapshot(&mut builder.init_root::<message::Builder>(), std::iter::once((&name, &metric)), false);
+        let reader = builder.get_root_as_reader::<message::Reader>().unwrap();
+        let read = read_snapshot::<f64>(reader).unwrap();
+        assert_eq!(read, vec![(name, metric.clone())]);
+        assert_eq!(read[0].1.info(), Some(&info));
+
+        metric.accumulate(Metric::gauge_unset(None)).unwrap();
+        assert_eq!(metric.info(), None);
+    }
+}
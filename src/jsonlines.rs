@@ -0,0 +1,192 @@
+use std::fmt::{Debug, Write};
+
+use bytes::BytesMut;
+use num_traits::{AsPrimitive, Float};
+use serde::{Deserialize, Serialize};
+
+use crate::aggregate::Aggregate;
+use crate::metric::FromF64;
+use crate::name::{unescape_tag_value, MetricName, UnicodePolicy};
+
+/// Field names of JSON objects, so they can match the schema of the receiving side
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct JsonFields {
+    pub name: String,
+    pub tags: String,
+    pub aggregate: String,
+    pub value: String,
+    pub timestamp: String,
+}
+
+impl Default for JsonFields {
+    fn default() -> Self {
+        Self {
+            name: "name".into(),
+            tags: "tags".into(),
+            aggregate: "aggregate".into(),
+            value: "value".into(),
+            timestamp: "ts".into(),
+        }
+    }
+}
+
+fn put_string(buf: &mut BytesMut, value: &[u8]) {
+    buf.reserve(value.len() + 2);
+    buf.extend_from_slice(b"\"");
+    for c in String::from_utf8_lossy(value).chars() {
+        match c {
+            '"' => buf.extend_from_slice(b"\\\""),
+            '\\' => buf.extend_from_slice(b"\\\\"),
+            '\n' => buf.extend_from_slice(b"\\n"),
+            '\r' => buf.extend_from_slice(b"\\r"),
+            '\t' => buf.extend_from_slice(b"\\t"),
+            // writing to BytesMut never fails
+            c if c.is_control() => write!(buf, "\\u{:04x}", c as u32).unwrap_or_default(),
+            c => {
+                let mut bytes = [0u8; 4];
+                buf.extend_from_slice(c.encode_utf8(&mut bytes).as_bytes());
+            }
+        }
+    }
+    buf.extend_from_slice(b"\"");
+}
+
+/// Encodes metrics as JSON lines, one object per aggregate value, i.e.
+///
+/// `{"name":"requests","tags":{"host":"h1"},"aggregate":"max","value":1.5,"ts":1600000000}`
+///
+/// Tags are always present as an object, the aggregate and timestamp fields are only written when known.
+/// Tags are nested, so their keys never collide with the fixed fields, while a tag repeated in the name
+/// is only written with its first value, because JSON objects cannot have duplicate keys.
+/// Non-finite values are written as `null`, since JSON has no way to represent them.
+#[derive(Debug, Clone, Default)]
+pub struct JsonLinesEncoder {
+    fields: JsonFields,
+    unicode: UnicodePolicy,
+}
+
+impl JsonLinesEncoder {
+    pub fn new(fields: JsonFields) -> Self {
+        Self {
+            fields,
+            unicode: UnicodePolicy::default(),
+        }
+    }
+
+    /// Apply the policy to names with non-ASCII characters, the lines for rejected names are skipped
+    pub fn with_unicode(mut self, policy: UnicodePolicy) -> Self {
+        self.unicode = policy;
+        self
+    }
+
+    /// Appends a line for the value, `Aggregate::Value` is written the same way as no aggregate
+    pub fn encode<F>(&self, buf: &mut BytesMut, name: &MetricName, aggregate: Option<&Aggregate<F>>, value: F, timestamp: Option<u64>)
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<usize> + AsPrimitive<f64>,
+    {
+        let name = match self.unicode.encoded_name(name) {
+            Some(name) => name,
+            None => return,
+        };
+        buf.extend_from_slice(b"{");
+        put_string(buf, self.fields.name.as_bytes());
+        buf.extend_from_slice(b":");
+        put_string(buf, name.name_without_tags());
+
+        buf.extend_from_slice(b",");
+        put_string(buf, self.fields.tags.as_bytes());
+        buf.extend_from_slice(b":{");
+        let mut last_key: Option<&[u8]> = None;
+        for (key, value) in name.tags() {
+            // tags are sorted, so the repeated ones go one after another
+            if let Some(last) = last_key {
+                if last == key {
+                    continue;
+                }
+                buf.extend_from_slice(b",");
+            }
+            last_key = Some(key);
+            put_string(buf, key);
+            buf.extend_from_slice(b":");
+            put_string(buf, &unescape_tag_value(value));
+        }
+        buf.extend_from_slice(b"}");
+
+        if let Some(aggregate) = aggregate.map(|aggregate| aggregate.to_string()).filter(|aggregate| !aggregate.is_empty()) {
+            buf.extend_from_slice(b",");
+            put_string(buf, self.fields.aggregate.as_bytes());
+            buf.extend_from_slice(b":");
+            put_string(buf, aggregate.as_bytes());
+        }
+
+        buf.extend_from_slice(b",");
+        put_string(buf, self.fields.value.as_bytes());
+        let value: f64 = value.as_();
+        if value.is_finite() {
+            write!(buf, ":{}", value).unwrap_or_default();
+        } else {
+            buf.extend_from_slice(b":null");
+        }
+
+        if let Some(timestamp) = timestamp {
+            buf.extend_from_slice(b",");
+            put_string(buf, self.fields.timestamp.as_bytes());
+            write!(buf, ":{}", timestamp).unwrap_or_default();
+        }
+        buf.extend_from_slice(b"}\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::name;
+
+    #[test]
+    fn json_lines() {
+        let tagged = name("requests;path=/\"a\";host=h1");
+        let plain = name("cpu\tload");
+
+        let mut buf = BytesMut::new();
+        let encoder = JsonLinesEncoder::default();
+        encoder.encode(&mut buf, &tagged, Some(&Aggregate::Percentile(0.99f64, 99)), 1.5f64, Some(1_600_000_000));
+        encoder.encode(&mut buf, &plain, Some(&Aggregate::Value), f64::NAN, None);
+        assert_eq!(
+            String::from_utf8(buf.to_vec()).unwrap(),
+            concat!(
+                r#"{"name":"requests","tags":{"host":"h1","path":"/\"a\""},"aggregate":"percentile.99","value":1.5,"ts":1600000000}"#,
+                "\n",
+                r#"{"name":"cpu\tload","tags":{},"value":null}"#,
+                "\n"
+            )
+        );
+
+        let fields = JsonFields {
+            name: "metric".into(),
+            timestamp: "time".into(),
+            ..JsonFields::default()
+        };
+        let mut buf = BytesMut::new();
+        JsonLinesEncoder::new(fields).encode::<f32>(&mut buf, &plain, None, 2f32, Some(1));
+        assert_eq!(&buf[..], &b"{\"metric\":\"cpu\\tload\",\"tags\":{},\"value\":2,\"time\":1}\n"[..]);
+
+        let unicode = name("température;host=h1");
+        let mut buf = BytesMut::new();
+        JsonLinesEncoder::default()
+            .with_unicode(UnicodePolicy::Transliterate)
+            .encode::<f64>(&mut buf, &unicode, None, 1f64, None);
+        JsonLinesEncoder::default()
+            .with_unicode(UnicodePolicy::Reject)
+            .encode::<f64>(&mut buf, &unicode, None, 1f64, None);
+        assert_eq!(&buf[..], &b"{\"name\":\"temperature\",\"tags\":{\"host\":\"h1\"},\"value\":1}\n"[..]);
+
+        let repeated = name("requests;name=a;host=h2;host=h1");
+        let mut buf = BytesMut::new();
+        JsonLinesEncoder::default().encode::<f64>(&mut buf, &repeated, None, 1f64, None);
+        assert_eq!(
+            &buf[..],
+            &b"{\"name\":\"requests\",\"tags\":{\"host\":\"h1\",\"name\":\"a\"},\"value\":1}\n"[..]
+        );
+    }
+}
@@ -9,14 +9,105 @@
 
 /// Aggregation routines
 pub mod aggregate;
+/// Alert rules evaluated over aggregates of successive intervals
+pub mod alert;
+/// Histogram bucket layouts
+pub mod buckets;
+/// Concurrent metric cache
+pub mod cache;
+/// Tag cardinality and name prefix usage statistics
+pub mod cardinality;
+/// Statsd client packet building and splitting of encoded output into packets
+pub mod client;
+/// Aggregation interval clock
+pub mod clock;
+/// Collectd binary protocol parsing
+pub mod collectd;
+/// Arrow and Parquet export of aggregated snapshots
+#[cfg(feature = "arrow")]
+pub mod columnar;
+/// CSV and TSV snapshot export
+pub mod csv;
+/// Fixed-point decimal metric values
+pub mod decimal;
+/// Delta snapshots carrying only the changed metrics
+pub mod delta;
+/// Metrics computed from aggregates of other metrics
+pub mod derived;
+/// Metric name enrichment with tags
+pub mod enrich;
+/// Snapshot authentication and encryption
+#[cfg(feature = "envelope")]
+pub mod envelope;
+/// OpenTelemetry style exponential histograms
+pub mod exphistogram;
+/// Info metrics carrying build and version labels
+pub mod info;
+/// JSON lines encoder
+pub mod jsonlines;
+/// Mapping of legacy metric names into tagged ones
+pub mod mapping;
+/// Generic merging of metrics and snapshots
+pub mod merge;
+/// Registry of declared metric types, units and descriptions
+pub mod metadata;
 /// Metric values routines
 pub mod metric;
+/// Exact monotonic u64 counters
+pub mod monotonic;
 /// Metric name routines
 pub mod name;
 /// Metric parsing routines
 pub mod parser;
+/// Carbon pickle protocol parsing
+pub mod pickle;
+/// WASM hooks for custom filtering and aggregation
+#[cfg(feature = "wasm-plugin")]
+pub mod plugin;
 /// Convenience types
 pub mod prelude;
+/// Prometheus text format encoder
+pub mod prometheus;
+/// Peer protocol routines
+pub mod protocol;
+/// Filtering of aggregated snapshots for inspection
+pub mod query;
+/// Per tag value update quotas
+pub mod quota;
+/// Recording rules producing series aggregated over groups of metrics
+pub mod recording;
+/// Redis RESP encoder
+pub mod resp;
+/// Rollup of aggregated series into coarser intervals
+pub mod rollup;
+/// Rule-based metric routing
+pub mod router;
+/// Deterministic sampling of series
+pub mod sampler;
+/// Redaction of sensitive tag values
+pub mod scrub;
+/// Self-monitoring metrics
+pub mod selfstats;
+/// Compact set storage
+pub mod set;
+/// Validation of metric tags against tagging standards
+pub mod tagschema;
+/// Helpers for comparing metrics in tests
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+/// Compact timer storage
+pub mod timer;
+/// Strongly typed metric wrappers
+pub mod typed;
+/// Gauges of several named components
+pub mod vector;
+/// Wavefront line format encoder
+pub mod wavefront;
+/// Counter accumulation beyond f64 precision
+pub mod wide;
+/// Async snapshot writer waiting for the socket to accept data
+#[cfg(feature = "tokio")]
+pub mod writer;
 
 pub use crate::metric::*;
 pub use crate::name::MetricName;
@@ -0,0 +1,250 @@
+use std::collections::BTreeMap;
+
+use bytes::BytesMut;
+use serde::{Deserialize, Serialize};
+
+use crate::metric::MetricError;
+use crate::name::MetricName;
+
+/// What to do with the metrics matching a rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MappingAction {
+    #[default]
+    Map,
+    Drop,
+}
+
+/// A mapping rule like the ones of prometheus statsd_exporter, i.e. `servers.*.cpu.*` mapped
+/// to `cpu_usage` with `host: $1` and `mode: $2` tags
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct MappingRule {
+    /// dot-separated pattern for the name without tags, a segment of `*` captures exactly one
+    /// segment of the name, other segments must match exactly
+    #[serde(rename = "match")]
+    pub pattern: String,
+
+    /// the new name, `$1`, `$2` and so on are replaced with captured segments
+    #[serde(default)]
+    pub name: String,
+
+    /// tags to add, values are templates like the name
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+
+    #[serde(default)]
+    pub action: MappingAction,
+}
+
+/// The outcome of mapping a name
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mapped {
+    /// no rule matched, the name should be used as is
+    Unmatched,
+    Drop,
+    Name(MetricName),
+}
+
+#[derive(Debug, Clone)]
+enum TemplatePart {
+    Literal(Vec<u8>),
+    Capture(usize),
+}
+
+#[derive(Debug, Clone)]
+struct Template(Vec<TemplatePart>);
+
+impl Template {
+    fn parse(template: &str, captures: usize) -> Result<Self, MetricError> {
+        let mut parts = Vec::new();
+        let mut literal = Vec::new();
+        let mut rest = template.as_bytes();
+        while let Some(pos) = rest.iter().position(|c| *c == b'$') {
+            literal.extend_from_slice(&rest[..pos]);
+            rest = &rest[pos + 1..];
+            let digits = rest.iter().take_while(|c| c.is_ascii_digit()).count();
+            // digits are ascii, so the conversion cannot fail
+            let capture = std::str::from_utf8(&rest[..digits]).ok().and_then(|n| n.parse::<usize>().ok());
+            match capture {
+                Some(capture) if capture >= 1 && capture <= captures => {
+                    if !literal.is_empty() {
+                        parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(TemplatePart::Capture(capture - 1));
+                    rest = &rest[digits..];
+                }
+                _ => return Err(MetricError::Mapping(format!("bad capture reference in '{}'", template))),
+            }
+        }
+        literal.extend_from_slice(rest);
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+        Ok(Self(parts))
+    }
+
+    fn expand(&self, captures: &[&[u8]], buf: &mut BytesMut) {
+        for part in &self.0 {
+            match part {
+                TemplatePart::Literal(literal) => buf.extend_from_slice(literal),
+                TemplatePart::Capture(idx) => buf.extend_from_slice(captures[*idx]),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CompiledMapping {
+    // None stands for a capturing segment
+    segments: Vec<Option<Vec<u8>>>,
+    name: Template,
+    tags: Vec<(Vec<u8>, Template)>,
+    action: MappingAction,
+}
+
+/// Rewrites untagged names of legacy clients into tagged ones. Rules are tried in order, the first matching one is applied.
+///
+/// Tags of the original name are kept unless the rule sets tags with the same keys.
+#[derive(Debug, Clone)]
+pub struct Mapper {
+    rules: Vec<CompiledMapping>,
+}
+
+impl Mapper {
+    pub fn new(rules: Vec<MappingRule>) -> Result<Self, MetricError> {
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                let segments = rule
+                    .pattern
+                    .split('.')
+                    .map(|segment| match segment {
+                        "" => Err(MetricError::Mapping(format!("empty segment in pattern '{}'", rule.pattern))),
+                        "*" => Ok(None),
+                        segment => Ok(Some(segment.as_bytes().to_vec())),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                let captures = segments.iter().filter(|segment| segment.is_none()).count();
+                if rule.action == MappingAction::Map && rule.name.is_empty() {
+                    return Err(MetricError::Mapping(format!("no name for pattern '{}'", rule.pattern)));
+                }
+                let name = Template::parse(&rule.name, captures)?;
+                let tags = rule
+                    .tags
+                    .iter()
+                    .map(|(key, value)| Ok((key.as_bytes().to_vec(), Template::parse(value, captures)?)))
+                    .collect::<Result<Vec<_>, MetricError>>()?;
+                Ok(CompiledMapping {
+                    segments,
+                    name,
+                    tags,
+                    action: rule.action,
+                })
+            })
+            .collect::<Result<Vec<_>, MetricError>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Maps the name. The new name is built in `buf`, `intermediate` is used for sorting tags
+    /// and grows if required, so both should be reused between calls
+    pub fn map(&self, name: &MetricName, buf: &mut BytesMut, intermediate: &mut Vec<u8>) -> Mapped {
+        let segments = name.name_without_tags().split(|c| *c == b'.').collect::<Vec<_>>();
+        let mut captures = Vec::new();
+        let rule = self.rules.iter().find(|rule| {
+            if rule.segments.len() != segments.len() {
+                return false;
+            }
+            captures.clear();
+            rule.segments.iter().zip(segments.iter()).all(|(pattern, segment)| match pattern {
+                Some(literal) => &literal[..] == *segment,
+                None if segment.is_empty() => false,
+                None => {
+                    captures.push(*segment);
+                    true
+                }
+            })
+        });
+
+        let rule = match rule {
+            None => return Mapped::Unmatched,
+            Some(rule) if rule.action == MappingAction::Drop => return Mapped::Drop,
+            Some(rule) => rule,
+        };
+
+        let start = buf.len();
+        rule.name.expand(&captures, buf);
+        let tag_pos = buf.len() - start;
+        for (key, value) in &rule.tags {
+            buf.extend_from_slice(b";");
+            buf.extend_from_slice(key);
+            buf.extend_from_slice(b"=");
+            value.expand(&captures, buf);
+        }
+        for (key, value) in name.tags() {
+            if rule.tags.iter().any(|(rule_key, _)| &rule_key[..] == key) {
+                continue;
+            }
+            buf.extend_from_slice(b";");
+            buf.extend_from_slice(key);
+            buf.extend_from_slice(b"=");
+            buf.extend_from_slice(value);
+        }
+
+        let tag_pos = if buf.len() - start == tag_pos { None } else { Some(tag_pos) };
+        Mapped::Name(MetricName::from_unsorted_tags(buf.split_off(start), tag_pos, intermediate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::name;
+
+    fn rule(pattern: &str, name: &str, tags: &[(&str, &str)]) -> MappingRule {
+        MappingRule {
+            pattern: pattern.into(),
+            name: name.into(),
+            tags: tags.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            action: MappingAction::Map,
+        }
+    }
+
+    fn map(mapper: &Mapper, n: &str) -> Option<String> {
+        let name = name(n);
+        // some data is left before the name in the reused buffer
+        let mut buf = BytesMut::from(&b"prefix"[..]);
+        let mapped = match mapper.map(&name, &mut buf, &mut Vec::new()) {
+            Mapped::Name(name) => Some(String::from_utf8(name.name.to_vec()).unwrap()),
+            Mapped::Drop => Some("dropped".into()),
+            Mapped::Unmatched => None,
+        };
+        assert_eq!(&buf[..], b"prefix");
+        mapped
+    }
+
+    #[test]
+    fn map_names() {
+        let mut drop = rule("debug.*", "", &[]);
+        drop.action = MappingAction::Drop;
+        let mapper = Mapper::new(vec![
+            rule("servers.*.cpu.*", "cpu_usage", &[("host", "$1"), ("mode", "$2")]),
+            rule("servers.*.*", "server_$2", &[("host", "$1")]),
+            drop,
+            rule("plain.*", "plain.$1", &[]),
+        ])
+        .unwrap();
+
+        assert_eq!(map(&mapper, "servers.h1.cpu.idle").unwrap(), "cpu_usage;host=h1;mode=idle");
+        assert_eq!(map(&mapper, "servers.h1.mem").unwrap(), "server_mem;host=h1");
+        assert_eq!(map(&mapper, "servers.h1.mem;env=prod;host=h0").unwrap(), "server_mem;env=prod;host=h1");
+        assert_eq!(map(&mapper, "debug.anything").unwrap(), "dropped");
+        assert_eq!(map(&mapper, "plain.x").unwrap(), "plain.x");
+        assert_eq!(map(&mapper, "servers.h1"), None);
+        assert_eq!(map(&mapper, "other"), None);
+
+        assert!(Mapper::new(vec![rule("a.*", "b_$2", &[])]).is_err());
+        assert!(Mapper::new(vec![rule("a..b", "b", &[])]).is_err());
+        assert!(Mapper::new(vec![rule("a.*", "", &[])]).is_err());
+    }
+}
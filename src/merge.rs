@@ -0,0 +1,256 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt::Debug;
+
+use num_traits::{AsPrimitive, Float};
+
+use crate::cache::{Snapshot, SnapshotView};
+use crate::exphistogram::ExpHistogram;
+use crate::metric::{accumulate_all, FromF64, Metric, MetricError, MetricValue};
+use crate::name::MetricName;
+use crate::set::SortedSet;
+use crate::timer::CompactTimer;
+
+/// Anything that can be merged with another value of the same type, like metrics from different nodes
+/// of a cluster. Allows writing merging code once for all such types.
+pub trait Mergeable {
+    type Error;
+
+    /// Merges other into self. Depending on the type, self may be partially merged on errors
+    fn merge(&mut self, other: Self) -> Result<(), Self::Error>;
+}
+
+impl<F> Mergeable for MetricValue<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    type Error = MetricError;
+
+    fn merge(&mut self, other: Self) -> Result<(), MetricError> {
+        self.accumulate(other)
+    }
+}
+
+impl<F> Mergeable for Metric<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    type Error = MetricError;
+
+    fn merge(&mut self, other: Self) -> Result<(), MetricError> {
+        self.accumulate(other)
+    }
+}
+
+/// Snapshots are merged metric by metric, all metrics that could be merged are merged
+/// and the failed ones are returned in error
+impl<F> Mergeable for HashMap<MetricName, Metric<F>>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    type Error = Vec<(MetricName, MetricError)>;
+
+    fn merge(&mut self, other: Self) -> Result<(), Self::Error> {
+        let errors = accumulate_all(self, other);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl Mergeable for SortedSet {
+    type Error = Infallible;
+
+    fn merge(&mut self, other: Self) -> Result<(), Infallible> {
+        SortedSet::merge(self, &other);
+        Ok(())
+    }
+}
+
+impl Mergeable for CompactTimer {
+    type Error = Infallible;
+
+    fn merge(&mut self, other: Self) -> Result<(), Infallible> {
+        CompactTimer::merge(self, &other);
+        Ok(())
+    }
+}
+
+/// Histograms of different scales are merged at the coarser one
+impl Mergeable for ExpHistogram {
+    type Error = Infallible;
+
+    fn merge(&mut self, other: Self) -> Result<(), Infallible> {
+        ExpHistogram::merge(self, &other);
+        Ok(())
+    }
+}
+
+/// Statistics of merging snapshots from several peers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MergeStats {
+    pub snapshots: usize,
+    /// metrics in all the snapshots merged
+    pub metrics: usize,
+    /// metrics found in more than one snapshot, so they were accumulated
+    pub conflicts: usize,
+    /// metrics failed to be accumulated, i.e. because of different types on different peers
+    pub dropped: usize,
+}
+
+/// The result of `merge_snapshots`
+#[derive(Debug)]
+pub struct MergedSnapshot<F>
+where
+    F: Copy + PartialEq + Debug,
+{
+    pub snapshot: Snapshot<F>,
+    pub stats: MergeStats,
+    /// errors of the dropped metrics
+    pub errors: Vec<(MetricName, MetricError)>,
+}
+
+/// Accumulates same named metrics from snapshots of several peers, i.e. on the leader of a cluster.
+/// Snapshots are merged in order they are given, so for gauges the value from the last one wins.
+/// A metric failed to be accumulated is dropped, keeping the one merged before it
+pub fn merge_snapshots<F>(snapshots: Vec<Snapshot<F>>) -> MergedSnapshot<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    let mut stats = MergeStats {
+        snapshots: snapshots.len(),
+        ..Default::default()
+    };
+    let mut errors = Vec::new();
+    let mut snapshots = snapshots.into_iter();
+    let mut merged = snapshots.next().map(SnapshotView::into_map).unwrap_or_default();
+    stats.metrics = merged.len();
+    for snapshot in snapshots {
+        stats.metrics += snapshot.len();
+        for (name, metric) in snapshot.into_map() {
+            match merged.entry(name) {
+                Entry::Occupied(mut entry) => {
+                    stats.conflicts += 1;
+                    if let Err(e) = entry.get_mut().accumulate(metric) {
+                        stats.dropped += 1;
+                        errors.push((entry.key().clone(), e));
+                    }
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(metric);
+                }
+            }
+        }
+    }
+    MergedSnapshot {
+        snapshot: SnapshotView::from(merged),
+        stats,
+        errors,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::name;
+
+    // merges all values in a generic way, like cluster merging code would do
+    fn merge_all<M: Mergeable>(values: Vec<M>) -> Result<Option<M>, M::Error> {
+        let mut values = values.into_iter();
+        let mut acc = match values.next() {
+            Some(first) => first,
+            None => return Ok(None),
+        };
+        for value in values {
+            acc.merge(value)?;
+        }
+        Ok(Some(acc))
+    }
+
+    #[test]
+    fn merge_generic() {
+        let metrics = (1..4).map(|v| Metric::<f64>::new(MetricValue::Counter(v as f64), None, 1f32)).collect();
+        assert_eq!(merge_all(metrics).unwrap().unwrap().value(), &MetricValue::Counter(6f64));
+
+        let sets = vec![vec![1, 3].into_iter().collect::<SortedSet>(), vec![2, 3].into_iter().collect()];
+        assert_eq!(merge_all(sets).unwrap().unwrap().as_slice(), &[1, 2, 3]);
+
+        let histograms = [1f64, 10f64, 100f64]
+            .iter()
+            .map(|value| {
+                let mut histogram = ExpHistogram::new(8, 16).unwrap();
+                histogram.record(*value).unwrap();
+                histogram
+            })
+            .collect();
+        let histogram = merge_all(histograms).unwrap().unwrap();
+        assert_eq!((histogram.count(), histogram.sum()), (3, 111f64));
+        assert_eq!((histogram.min(), histogram.max()), (Some(1f64), Some(100f64)));
+
+        let (first, second) = (name("first"), name("second"));
+        let snapshots = vec![
+            vec![(first.clone(), Metric::<f64>::new(MetricValue::Counter(1f64), None, 1f32))]
+                .into_iter()
+                .collect::<HashMap<_, _>>(),
+            vec![
+                (first.clone(), Metric::new(MetricValue::Counter(1f64), None, 1f32)),
+                (second.clone(), Metric::new(MetricValue::Gauge(1f64), None, 1f32)),
+            ]
+            .into_iter()
+            .collect(),
+        ];
+        let merged = merge_all(snapshots).unwrap().unwrap();
+        assert_eq!(merged[&first].value(), &MetricValue::Counter(2f64));
+        assert_eq!(merged[&second].value(), &MetricValue::Gauge(1f64));
+
+        let mut snapshot = merged;
+        let other = vec![(second.clone(), Metric::new(MetricValue::Counter(1f64), None, 1f32))]
+            .into_iter()
+            .collect();
+        let errors = snapshot.merge(other).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, second);
+    }
+
+    #[test]
+    fn merge_peer_snapshots() {
+        let (counter, gauge, only) = (name("counter"), name("gauge"), name("only"));
+        let metric = |value| Metric::<f64>::new(value, None, 1f32);
+        let peer = |metrics: Vec<(MetricName, Metric<f64>)>| Snapshot::from(metrics.into_iter().collect::<HashMap<_, _>>());
+        let snapshots = vec![
+            peer(vec![
+                (counter.clone(), metric(MetricValue::Counter(1f64))),
+                (gauge.clone(), metric(MetricValue::Gauge(1f64))),
+            ]),
+            peer(vec![
+                (counter.clone(), metric(MetricValue::Counter(2f64))),
+                (gauge.clone(), Metric::new(MetricValue::Counter(1f64), Some(100), 1f32)),
+            ]),
+            peer(vec![(only.clone(), metric(MetricValue::Gauge(3f64)))]),
+        ];
+
+        let merged = merge_snapshots(snapshots);
+        assert_eq!(
+            merged.stats,
+            MergeStats {
+                snapshots: 3,
+                metrics: 5,
+                conflicts: 2,
+                dropped: 1,
+            }
+        );
+        assert_eq!(merged.errors.len(), 1);
+        assert_eq!(merged.errors[0].0, gauge);
+        let snapshot = merged.snapshot;
+        assert_eq!(snapshot.len(), 3);
+        assert_eq!(snapshot.get(&counter).unwrap().value(), &MetricValue::Counter(3f64));
+        // the failed metric has not changed the merged one at all
+        assert_eq!(snapshot.get(&gauge).unwrap(), &metric(MetricValue::Gauge(1f64)));
+        assert_eq!(snapshot.get(&only).unwrap().value(), &MetricValue::Gauge(3f64));
+
+        assert!(merge_snapshots::<f64>(Vec::new()).snapshot.is_empty());
+    }
+}
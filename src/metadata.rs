@@ -0,0 +1,172 @@
+use std::fmt::Debug;
+
+use num_traits::{AsPrimitive, Float};
+use serde::{Deserialize, Serialize};
+
+use crate::metric::{FromF64, Metric, MetricError, MetricTypeName, MetricUnit, StatsdMetric, StatsdType};
+use crate::name::MetricName;
+use crate::router::glob_match;
+
+/// What to do with a statsd metric of a type other than the declared one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TypeMismatch {
+    #[default]
+    Reject,
+    /// the value is taken as a value of the declared type, i.e. `1|c` sent by mistake to
+    /// a gauge sets the gauge to 1
+    Coerce,
+}
+
+/// Declared properties of the metrics with names matching the pattern
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct MetricMetadata {
+    /// glob for the name without tags, globs are the same as in routing rules
+    #[serde(rename = "match")]
+    pub pattern: String,
+
+    /// the type is not enforced for `default`
+    #[serde(rename = "type")]
+    pub mtype: MetricTypeName,
+
+    #[serde(default)]
+    pub unit: Option<MetricUnit>,
+
+    #[serde(default)]
+    pub description: Option<String>,
+
+    #[serde(default)]
+    pub mismatch: TypeMismatch,
+}
+
+/// Metric metadata consulted at ingestion, so metrics of wrong types are rejected or coerced
+/// instead of failing later with `MetricError::Aggregating` in the middle of the interval.
+/// The first declaration matching the name is used, names matching none are not checked.
+#[derive(Debug, Clone, Default)]
+pub struct MetadataRegistry {
+    declarations: Vec<MetricMetadata>,
+}
+
+impl MetadataRegistry {
+    pub fn new(declarations: Vec<MetricMetadata>) -> Self {
+        Self { declarations }
+    }
+
+    pub fn lookup(&self, name: &MetricName) -> Option<&MetricMetadata> {
+        let name = name.name_without_tags();
+        self.declarations.iter().find(|meta| glob_match(meta.pattern.as_bytes(), name))
+    }
+
+    /// Checks the type of a statsd metric, giving the metric with the declared type if
+    /// coercion is allowed. Custom histograms have bounds that cannot be guessed, so metrics
+    /// of other types are never coerced into them.
+    pub fn check_statsd<F>(&self, name: &MetricName, metric: StatsdMetric<F>) -> Result<StatsdMetric<F>, MetricError>
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<f64>,
+    {
+        let meta = match self.lookup(name) {
+            Some(meta) if meta.mtype != MetricTypeName::Default => meta,
+            _ => return Ok(metric),
+        };
+        let found = MetricTypeName::from_statsd_metric(&metric);
+        if found == meta.mtype {
+            return Ok(metric);
+        }
+        let mtype = match (meta.mismatch, meta.mtype) {
+            (TypeMismatch::Reject, _) | (_, MetricTypeName::CustomHistogram) | (_, MetricTypeName::Default) => {
+                return Err(MetricError::TypeMismatch(meta.mtype, found))
+            }
+            (TypeMismatch::Coerce, MetricTypeName::Counter) => StatsdType::Counter,
+            (TypeMismatch::Coerce, MetricTypeName::Timer) => StatsdType::Timer,
+            (TypeMismatch::Coerce, MetricTypeName::Gauge) => StatsdType::Gauge(None),
+            (TypeMismatch::Coerce, MetricTypeName::Set) => StatsdType::Set,
+        };
+        // relative gauge changes keep their sign
+        let value = match metric.mtype() {
+            StatsdType::Gauge(Some(sign)) if *sign < 0 => -metric.value(),
+            _ => metric.value(),
+        };
+        StatsdMetric::new(value, mtype, metric.sampling())
+    }
+
+    /// Checks the type of an already aggregated metric, i.e. one received from a peer.
+    /// Such metrics are never coerced.
+    pub fn check_metric<F>(&self, name: &MetricName, metric: &Metric<F>) -> Result<(), MetricError>
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<f64>,
+    {
+        match self.lookup(name) {
+            Some(meta) if meta.mtype != MetricTypeName::Default => {
+                let found = MetricTypeName::from_metric(metric);
+                if found == meta.mtype {
+                    Ok(())
+                } else {
+                    Err(MetricError::TypeMismatch(meta.mtype, found))
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Sets the declared unit for metrics not carrying one
+    pub fn annotate<F>(&self, name: &MetricName, metric: &mut Metric<F>)
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<f64>,
+    {
+        if metric.unit().is_some() {
+            return;
+        }
+        if let Some(unit) = self.lookup(name).and_then(|meta| meta.unit.clone()) {
+            metric.set_unit(Some(unit));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metric::MetricValue;
+    use crate::testing::name;
+
+    fn declare(pattern: &str, mtype: MetricTypeName, mismatch: TypeMismatch) -> MetricMetadata {
+        MetricMetadata {
+            pattern: pattern.into(),
+            mtype,
+            unit: Some(MetricUnit::Millis),
+            description: Some("test metric".into()),
+            mismatch,
+        }
+    }
+
+    #[test]
+    fn metadata_type_enforcement() {
+        let registry = MetadataRegistry::new(vec![
+            declare("requests.*", MetricTypeName::Counter, TypeMismatch::Reject),
+            declare("queue.*", MetricTypeName::Gauge, TypeMismatch::Coerce),
+            declare("latency", MetricTypeName::CustomHistogram, TypeMismatch::Coerce),
+            declare("*", MetricTypeName::Default, TypeMismatch::Reject),
+        ]);
+        let counter = || StatsdMetric::new(2f64, StatsdType::Counter, Some(0.5)).unwrap();
+        let decrement = || StatsdMetric::new(2f64, StatsdType::Gauge(Some(-1)), None).unwrap();
+
+        assert_eq!(registry.check_statsd(&name("requests.total;dc=ams"), counter()).unwrap(), counter());
+        assert!(matches!(
+            registry.check_statsd(&name("requests.total"), decrement()),
+            Err(MetricError::TypeMismatch(MetricTypeName::Counter, MetricTypeName::Gauge))
+        ));
+        assert_eq!(
+            registry.check_statsd(&name("queue.size"), counter()).unwrap(),
+            StatsdMetric::new(2f64, StatsdType::Gauge(None), Some(0.5)).unwrap()
+        );
+        assert!(registry.check_statsd(&name("latency"), counter()).is_err());
+        assert_eq!(registry.check_statsd(&name("other"), decrement()).unwrap(), decrement());
+
+        let mut metric = Metric::<f64>::new(MetricValue::Gauge(1f64), None, 1f32);
+        assert!(registry.check_metric(&name("requests.total"), &metric).is_err());
+        assert!(registry.check_metric(&name("queue.size"), &metric).is_ok());
+        registry.annotate(&name("queue.size"), &mut metric);
+        assert_eq!(metric.unit(), Some(&MetricUnit::Millis));
+        assert_eq!(registry.lookup(&name("unknown")).map(|meta| meta.mtype), Some(MetricTypeName::Default));
+    }
+}
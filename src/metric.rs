@@ -1,17 +1,25 @@
 use std::borrow::Cow;
-use std::collections::HashSet;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
+use std::time::Duration;
 
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use capnp::message::{Allocator, Builder, HeapAllocator};
+use capnp::text_list;
 use num_traits::{AsPrimitive, Float};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::name::{find_tag_pos, MetricName, TagFormat};
+use crate::info::Info;
+use crate::name::MetricName;
+use crate::protocol::SchemaViolation;
 use crate::protocol_capnp::{gauge as gauge_v1, metric as cmetric_v1, metric_type};
-use crate::protocol_v2_capnp::{metric as cmetric, metric::metric_meta::tags, metric::metric_value, ID as V2ID};
+use crate::protocol_v2_capnp::{metric as cmetric, metric::metric_meta::tags, metric::metric_value, metric::timestamp::Precision as CPrecision, ID as V2ID};
+use crate::set::{SetStorage, SortedSet};
+use crate::timer::{CompactTimer, TimerSampling, TimerStorage, TimerUnit};
+use crate::vector::VectorGauge;
 
 #[derive(Error, Debug)]
 pub enum MetricError {
@@ -44,6 +52,148 @@ pub enum MetricError {
 
     #[error("unknown protocol version '{}'", _0)]
     BadProtoVersion(String),
+
+    #[error("name reference {} is out of dictionary bounds", _0)]
+    DictionaryRef(u32),
+
+    #[error("envelope error: {}", _0)]
+    Envelope(&'static str),
+
+    #[error("message does not conform to schema: {:?}", _0)]
+    Schema(Vec<SchemaViolation>),
+
+    #[error("metric invariants violated: {:?}", _0)]
+    Invariant(Vec<InvariantViolation>),
+
+    #[error("negative counter value")]
+    NegativeCounter,
+
+    #[error("metric timestamp is too late for any open interval")]
+    TooLate,
+
+    #[error("bad mapping rule: {}", _0)]
+    Mapping(String),
+
+    #[error("pickle error: {}", _0)]
+    Pickle(&'static str),
+
+    #[error("collectd protocol error: {}", _0)]
+    Collectd(&'static str),
+
+    #[error("columnar export error: {}", _0)]
+    Columnar(String),
+
+    #[error("bad scrub rule: {}", _0)]
+    Scrub(String),
+
+    #[error("line of {} bytes does not fit into a packet", _0)]
+    PacketSize(usize),
+
+    #[error("metric of {} bytes does not fit into the message size budget", _0)]
+    BatchSize(usize),
+
+    #[error("bad histogram buckets: {}", _0)]
+    Buckets(&'static str),
+
+    #[error("bad vector gauge: {}", _0)]
+    VectorGauge(&'static str),
+
+    #[error("bad info metric: {}", _0)]
+    Info(&'static str),
+
+    #[error("delta snapshot is based on generation {}, but {} was applied last", _0, _1)]
+    DeltaBase(u64, u64),
+
+    #[error("chunked snapshot error: {}", _0)]
+    Chunk(&'static str),
+
+    #[error("peer authentication failed: {}", _0)]
+    Auth(&'static str),
+
+    #[error("snapshot integrity check failed: {}", _0)]
+    Integrity(&'static str),
+
+    #[error("bad query: {}", _0)]
+    Query(&'static str),
+
+    #[error("bad expression: {}", _0)]
+    Expression(String),
+
+    #[error("bad recording rule: {}", _0)]
+    Recording(String),
+
+    #[error("metric is declared as {:?} but has type {:?}", _0, _1)]
+    TypeMismatch(MetricTypeName, MetricTypeName),
+
+    #[error("plugin error: {}", _0)]
+    Plugin(String),
+
+    #[error("bad metric name: {}", _0)]
+    Name(&'static str),
+
+    #[error("io error: {}", _0)]
+    Io(std::io::Error),
+
+    #[error("compact timer quantum {} is not a positive finite number", _0)]
+    TimerQuantum(f64),
+
+    #[error("cannot write wavefront line: {}", _0)]
+    Wavefront(&'static str),
+
+    #[error("bad tag: {}", _0)]
+    Tag(String),
+
+    #[error("monotonic counter reading dropped from {} to {}, which is neither a wrap nor a reset", _0, _1)]
+    CounterDrop(u64, u64),
+}
+
+/// A broken metric invariant found by `validate`
+#[derive(Debug, Clone, PartialEq)]
+pub enum InvariantViolation {
+    /// sampling is not in (0, 1] range
+    Sampling(f32),
+    /// gauge sign is not -1 or 1
+    GaugeSign(i8),
+    /// gauge or counter value is NaN or infinite
+    NonFiniteValue,
+    /// a timer has no values
+    EmptyTimer,
+    /// a set has no values
+    EmptySet,
+    /// histogram bucket starts are not increasing
+    HistogramBuckets,
+    /// the metric has never been updated
+    ZeroUpdates,
+    /// a set does not contain the bits of the value it was updated with
+    SetValueMissing,
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvariantViolation::Sampling(s) => write!(f, "sampling {} is out of (0, 1] range", s),
+            InvariantViolation::GaugeSign(s) => write!(f, "gauge sign {} is not -1 or 1", s),
+            InvariantViolation::NonFiniteValue => write!(f, "value is not finite"),
+            InvariantViolation::EmptyTimer => write!(f, "timer is empty"),
+            InvariantViolation::EmptySet => write!(f, "set is empty"),
+            InvariantViolation::HistogramBuckets => write!(f, "histogram buckets are not sorted"),
+            InvariantViolation::ZeroUpdates => write!(f, "update counter is zero"),
+            InvariantViolation::SetValueMissing => write!(f, "set does not contain the updated value"),
+        }
+    }
+}
+
+fn violations_result(violations: Vec<InvariantViolation>) -> Result<(), MetricError> {
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(MetricError::Invariant(violations))
+    }
+}
+
+#[inline]
+pub(crate) fn sampling_valid(sampling: f32) -> bool {
+    sampling > 0f32 && sampling <= 1f32
 }
 
 #[derive(Debug, PartialEq)]
@@ -106,6 +256,45 @@ where
 
         Ok(Self { value, mtype, sampling })
     }
+
+    pub fn value(&self) -> F {
+        self.value
+    }
+
+    pub fn mtype(&self) -> &StatsdType<F> {
+        &self.mtype
+    }
+
+    pub fn sampling(&self) -> Option<f32> {
+        self.sampling
+    }
+
+    /// Sign of a gauge increment or decrement, None for other types and gauges set to the value
+    pub fn gauge_sign(&self) -> Option<i8> {
+        if let StatsdType::Gauge(sign) = self.mtype {
+            sign
+        } else {
+            None
+        }
+    }
+
+    /// Checks the metric is sane, reporting all problems found. Note that the parser never
+    /// produces metrics failing the check, so it is mostly useful for metrics made by other means
+    pub fn validate(&self) -> Result<(), MetricError> {
+        let mut violations = Vec::new();
+        match self.sampling {
+            Some(s) if !sampling_valid(s) => violations.push(InvariantViolation::Sampling(s)),
+            _ => (),
+        }
+        match self.mtype {
+            StatsdType::Gauge(Some(sign)) if sign != 1 && sign != -1 => violations.push(InvariantViolation::GaugeSign(sign)),
+            _ => (),
+        }
+        if !self.value.is_finite() {
+            violations.push(InvariantViolation::NonFiniteValue);
+        }
+        violations_result(violations)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -116,7 +305,11 @@ where
     Gauge(F),
     Counter(F),
     Timer(Vec<F>),
+    /// A timer with quantized and delta-encoded samples, see `TimerStorage`
+    CompactTimer(CompactTimer),
     Set(HashSet<u64>),
+    /// A set stored in a compact sorted vector, see `SetStorage`
+    SortedSet(SortedSet),
     /// Histograms store a counter for the very left bucket, and a list of buckets with their start
     /// values
     CustomHistogram(u64, Vec<(F, u64)>),
@@ -129,19 +322,49 @@ where
     /// accumulates a previously created metric data into self
     pub fn accumulate(&mut self, new: MetricValue<F>) -> Result<(), MetricError> {
         match (self, new) {
-            (&mut MetricValue::Counter(ref mut value), MetricValue::Counter(new)) => {
+            // owned timer values are moved instead of copying
+            (&mut MetricValue::Timer(ref mut agg), MetricValue::Timer(mut agg2)) => {
+                agg.append(&mut agg2);
+                Ok(())
+            }
+            (value, new) => value.accumulate_ref(&new),
+        }
+    }
+
+    /// accumulates a metric data into self without taking ownership, copying only the values required
+    pub fn accumulate_ref(&mut self, new: &MetricValue<F>) -> Result<(), MetricError> {
+        match (self, new) {
+            (&mut MetricValue::Counter(ref mut value), &MetricValue::Counter(new)) => {
                 *value = *value + new;
             }
-            (&mut MetricValue::Gauge(ref mut value), MetricValue::Gauge(new)) => {
+            (&mut MetricValue::Gauge(ref mut value), &MetricValue::Gauge(new)) => {
                 *value = new;
             }
-            (&mut MetricValue::Timer(ref mut agg), MetricValue::Timer(ref mut agg2)) => {
-                agg.append(agg2);
+            (&mut MetricValue::Timer(ref mut agg), MetricValue::Timer(agg2)) => {
+                agg.extend_from_slice(agg2);
+            }
+            (&mut MetricValue::CompactTimer(ref mut agg), MetricValue::CompactTimer(agg2)) => {
+                agg.merge(agg2);
+            }
+            (&mut MetricValue::Timer(ref mut agg), MetricValue::CompactTimer(agg2)) => {
+                agg.extend(agg2.iter().map(F::from_f64));
             }
-            (&mut MetricValue::Set(ref mut hs), MetricValue::Set(ref mut hs2)) => {
+            (&mut MetricValue::CompactTimer(ref mut agg), MetricValue::Timer(agg2)) => {
+                agg2.iter().map(|v| agg.push(v.as_())).last();
+            }
+            (&mut MetricValue::Set(ref mut hs), MetricValue::Set(hs2)) => {
                 hs.extend(hs2.iter());
             }
-            (&mut MetricValue::CustomHistogram(ref mut left_c1, ref mut buckets1), MetricValue::CustomHistogram(left_c2, ref buckets2)) => {
+            (&mut MetricValue::SortedSet(ref mut ss), MetricValue::SortedSet(ss2)) => {
+                ss.merge(ss2);
+            }
+            (&mut MetricValue::Set(ref mut hs), MetricValue::SortedSet(ss2)) => {
+                hs.extend(ss2.iter());
+            }
+            (&mut MetricValue::SortedSet(ref mut ss), MetricValue::Set(hs2)) => {
+                ss.extend(hs2.iter().copied());
+            }
+            (&mut MetricValue::CustomHistogram(ref mut left_c1, ref mut buckets1), &MetricValue::CustomHistogram(left_c2, ref buckets2)) => {
                 if buckets1.len() != buckets2.len() {
                     return Err(MetricError::CustomHistrogramRange);
                 }
@@ -208,10 +431,18 @@ where
                 acc.push(statsd.value);
                 Ok(())
             }
+            (MetricValue::CompactTimer(ref mut acc), StatsdType::Timer) => {
+                acc.push(statsd.value.as_());
+                Ok(())
+            }
             (MetricValue::Set(ref mut acc), StatsdType::Set) => {
                 acc.insert(statsd.value.as_().to_bits());
                 Ok(())
             }
+            (MetricValue::SortedSet(ref mut acc), StatsdType::Set) => {
+                acc.insert(statsd.value.as_().to_bits());
+                Ok(())
+            }
             (MetricValue::CustomHistogram(ref mut left, ref mut buckets), StatsdType::CustomHistogram(start, end)) => {
                 // check if histogram limits are valid
                 if *start != buckets[0].0 || *end != buckets[buckets.len() - 1].0 {
@@ -257,7 +488,28 @@ where
                     .last();
                 0f64
             }
+            MetricValue::CompactTimer(ref v) => {
+                let mut timer_builder = builder.reborrow().init_timer(v.len() as u32);
+                v.iter()
+                    .enumerate()
+                    .map(|(idx, value)| {
+                        timer_builder.set(idx as u32, value);
+                    })
+                    .last();
+                0f64
+            }
             MetricValue::Set(ref v) => {
+                let mut sebuilder = builder.reborrow().init_set(v.len() as u32);
+                sorted_set(v)
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, value)| {
+                        sebuilder.set(idx as u32, *value);
+                    })
+                    .last();
+                0f64
+            }
+            MetricValue::SortedSet(ref v) => {
                 let mut sebuilder = builder.reborrow().init_set(v.len() as u32);
                 v.iter()
                     .enumerate()
@@ -299,7 +551,26 @@ where
                     })
                     .last();
             }
+            MetricValue::CompactTimer(ref v) => {
+                let mut timer_values = builder.reborrow().init_timer(v.len() as u32);
+                v.iter()
+                    .enumerate()
+                    .map(|(idx, value)| {
+                        timer_values.set(idx as u32, value);
+                    })
+                    .last();
+            }
             MetricValue::Set(ref v) => {
+                let mut set_builder = builder.reborrow().init_set(v.len() as u32);
+                sorted_set(v)
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, value)| {
+                        set_builder.set(idx as u32, *value);
+                    })
+                    .last();
+            }
+            MetricValue::SortedSet(ref v) => {
                 let mut set_builder = builder.reborrow().init_set(v.len() as u32);
                 v.iter()
                     .enumerate()
@@ -325,6 +596,61 @@ where
         };
     }
 
+    /// Compares values regardless of the storage used and the order of timer samples.
+    /// Compact timers are compared by their dequantized samples
+    pub fn semantically_eq(&self, other: &MetricValue<F>) -> bool {
+        match (self, other) {
+            (MetricValue::Set(s1), MetricValue::Set(s2)) => s1 == s2,
+            (MetricValue::Set(hs), MetricValue::SortedSet(ss)) | (MetricValue::SortedSet(ss), MetricValue::Set(hs)) => {
+                hs.len() == ss.len() && ss.iter().all(|v| hs.contains(v))
+            }
+            (MetricValue::SortedSet(s1), MetricValue::SortedSet(s2)) => s1 == s2,
+            (MetricValue::Timer(_), _) | (MetricValue::CompactTimer(_), _) => match (self.sorted_timer_samples(), other.sorted_timer_samples()) {
+                (Some(t1), Some(t2)) => t1 == t2,
+                _ => false,
+            },
+            (v1, v2) => v1 == v2,
+        }
+    }
+
+    fn sorted_timer_samples(&self) -> Option<Vec<f64>> {
+        let mut samples: Vec<f64> = match self {
+            MetricValue::Timer(agg) => agg.iter().map(|v| v.as_()).collect(),
+            MetricValue::CompactTimer(agg) => agg.iter().collect(),
+            _ => return None,
+        };
+        samples.sort_unstable_by(|v1, v2| v1.partial_cmp(v2).unwrap_or(std::cmp::Ordering::Equal));
+        Some(samples)
+    }
+
+    /// Converts set values to the specified storage, other values are left as is
+    pub fn into_set_storage(self, storage: SetStorage) -> Self {
+        match (self, storage) {
+            (MetricValue::Set(hs), SetStorage::Sorted) => MetricValue::SortedSet(hs.into_iter().collect()),
+            (MetricValue::SortedSet(ss), SetStorage::Hash) => MetricValue::Set(ss.into_vec().into_iter().collect()),
+            (value, _) => value,
+        }
+    }
+
+    /// Converts timer values to the specified storage, other values are left as is.
+    /// Conversion to compact timer is lossy, see `CompactTimer`
+    pub fn into_timer_storage(self, storage: TimerStorage) -> Result<Self, MetricError> {
+        Ok(match (self, storage) {
+            (MetricValue::Timer(v), TimerStorage::Compact(quantum)) => {
+                let mut timer = CompactTimer::new(quantum)?;
+                v.iter().map(|value| timer.push(value.as_())).last();
+                MetricValue::CompactTimer(timer)
+            }
+            (MetricValue::CompactTimer(v), TimerStorage::Compact(quantum)) if (v.quantum() - quantum).abs() > f64::EPSILON => {
+                let mut timer = CompactTimer::new(quantum)?;
+                timer.merge(&v);
+                MetricValue::CompactTimer(timer)
+            }
+            (MetricValue::CompactTimer(v), TimerStorage::Vec) => MetricValue::Timer(v.iter().map(F::from_f64).collect()),
+            (value, _) => value,
+        })
+    }
+
     pub fn from_capnp_v1(reader: metric_type::Reader, value: F) -> Result<Self, MetricError> {
         match reader.which().map_err(MetricError::CapnpSchema)? {
             metric_type::Which::Counter(()) => Ok(MetricValue::Counter(value)),
@@ -396,6 +722,153 @@ where
     }
 }
 
+/// What to do when metrics with different sampling rates are accumulated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SamplingPolicy {
+    /// Keep the sampling of the metric accumulated into
+    #[default]
+    Ignore,
+    /// Fail with `MetricError::Sampling`
+    Error,
+    /// Keep the highest sampling rate of both, i.e. the least sampled one, converting the values
+    /// of the other metric to it, see `Metric::resample`
+    KeepMostPrecise,
+    /// Convert both metrics to effective values with sampling of 1 before accumulating,
+    /// see `Metric::normalize_sampling`
+    Normalize,
+}
+
+/// What to do with negative counter values, i.e. `-1|c` in statsd, which is ambiguous in the spec
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NegativeCounterPolicy {
+    /// Negative values decrement the counter
+    #[default]
+    Decrement,
+    /// Negative values are errors
+    Reject,
+    /// Negative values are replaced with zero
+    Clamp,
+}
+
+impl NegativeCounterPolicy {
+    /// Gives the counter value to use according to policy
+    pub fn apply<F: Float>(self, value: F) -> Result<F, MetricError> {
+        if value >= F::zero() {
+            return Ok(value);
+        }
+        match self {
+            NegativeCounterPolicy::Decrement => Ok(value),
+            NegativeCounterPolicy::Reject => Err(MetricError::NegativeCounter),
+            NegativeCounterPolicy::Clamp => Ok(F::zero()),
+        }
+    }
+}
+
+/// Unit of metric values, carried along with the metric, so encoders can expose it
+/// instead of relying on name suffix conventions
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MetricUnit {
+    #[serde(rename = "ms")]
+    Millis,
+    #[serde(rename = "s")]
+    Seconds,
+    Bytes,
+    Percent,
+    /// a number of items or events, i.e. dimensionless
+    Count,
+    Custom(String),
+}
+
+impl MetricUnit {
+    /// Parses the short name given by `as_str`, unknown names become custom units
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "ms" => MetricUnit::Millis,
+            "s" => MetricUnit::Seconds,
+            "bytes" => MetricUnit::Bytes,
+            "percent" => MetricUnit::Percent,
+            "count" => MetricUnit::Count,
+            custom => MetricUnit::Custom(custom.to_string()),
+        }
+    }
+
+    /// The short name, used in snapshots and configs
+    pub fn as_str(&self) -> &str {
+        match self {
+            MetricUnit::Millis => "ms",
+            MetricUnit::Seconds => "s",
+            MetricUnit::Bytes => "bytes",
+            MetricUnit::Percent => "percent",
+            MetricUnit::Count => "count",
+            MetricUnit::Custom(custom) => custom,
+        }
+    }
+
+    /// The unit name as OpenMetrics and OTLP spell it, None for dimensionless values
+    pub fn long_name(&self) -> Option<&str> {
+        match self {
+            MetricUnit::Millis => Some("milliseconds"),
+            MetricUnit::Seconds => Some("seconds"),
+            MetricUnit::Bytes => Some("bytes"),
+            MetricUnit::Percent => Some("percent"),
+            MetricUnit::Count => None,
+            MetricUnit::Custom(custom) => Some(custom),
+        }
+    }
+}
+
+/// Units of metric timestamp, since different sources use different ones,
+/// i.e. graphite sends seconds while OTLP uses nanoseconds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TimestampPrecision {
+    #[default]
+    Seconds,
+    Millis,
+    Nanos,
+}
+
+impl TimestampPrecision {
+    /// Number of timestamp units in one second
+    pub fn units_per_second(self) -> u64 {
+        match self {
+            TimestampPrecision::Seconds => 1,
+            TimestampPrecision::Millis => 1_000,
+            TimestampPrecision::Nanos => 1_000_000_000,
+        }
+    }
+
+    /// Converts the timestamp from these units to other ones. Precision is lost when converting
+    /// to coarser units, conversion to finer ones saturates on overflow
+    pub fn convert(self, timestamp: u64, to: TimestampPrecision) -> u64 {
+        let (from, to) = (self.units_per_second(), to.units_per_second());
+        if from > to {
+            timestamp / (from / to)
+        } else {
+            timestamp.saturating_mul(to / from)
+        }
+    }
+
+    fn from_capnp(precision: CPrecision) -> Self {
+        match precision {
+            CPrecision::Seconds => TimestampPrecision::Seconds,
+            CPrecision::Millis => TimestampPrecision::Millis,
+            CPrecision::Nanos => TimestampPrecision::Nanos,
+        }
+    }
+
+    fn to_capnp(self) -> CPrecision {
+        match self {
+            TimestampPrecision::Seconds => CPrecision::Seconds,
+            TimestampPrecision::Millis => CPrecision::Millis,
+            TimestampPrecision::Nanos => CPrecision::Nanos,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 /// A typed, optionally timestamped metric value (i.e. without name)
 pub struct Metric<F>
@@ -404,8 +877,29 @@ where
 {
     value: MetricValue<F>,
     timestamp: Option<u64>,
-    update_counter: u32,
+    #[serde(default)]
+    timestamp_precision: TimestampPrecision,
+    update_counter: u64,
     sampling: f32,
+    // the gauge value only consists of statsd +value or -value changes, so it must be added
+    // to the gauge it is accumulated into
+    #[serde(default)]
+    gauge_delta: bool,
+    // the gauge source has disappeared, the gauge must be expired downstream instead of
+    // keeping the last value
+    #[serde(default)]
+    gauge_unset: bool,
+    #[serde(default)]
+    unit: Option<MetricUnit>,
+    // the exact total of a monotonic counter, kept while only monotonic counters are accumulated
+    #[serde(default)]
+    counter_total: Option<u64>,
+    // components of a vector gauge, replaced by every gauge update
+    #[serde(default)]
+    vector: Option<Box<VectorGauge<F>>>,
+    // labels of an info gauge, replaced by every gauge update
+    #[serde(default)]
+    info: Option<Box<Info>>,
 }
 
 impl<F> Metric<F>
@@ -416,16 +910,64 @@ where
     /// Only metric type is required because it may already contain the value or many accumulated
     /// inside. If value is provided it will be accumulated according to the type used
     ///
-    /// Timestamp wil be saved for later use
+    /// Timestamp wil be saved for later use, it is considered to be in seconds,
+    /// use `set_timestamp_precision` for other units
     pub fn new(value: MetricValue<F>, timestamp: Option<u64>, sampling: f32) -> Self {
         Self {
             value,
             timestamp,
+            timestamp_precision: TimestampPrecision::Seconds,
             update_counter: 1,
             sampling,
+            gauge_delta: false,
+            gauge_unset: false,
+            unit: None,
+            counter_total: None,
+            vector: None,
+            info: None,
         }
     }
 
+    /// Creates a tombstone of a gauge whose source has disappeared. It replaces the gauge it is
+    /// accumulated into, so receivers can expire the gauge instead of repeating its last value.
+    /// The value of the tombstone is zero for receivers not knowing about it, while aggregates
+    /// and encoders of the crate give no values for it
+    pub fn gauge_unset(timestamp: Option<u64>) -> Self {
+        let mut metric = Self::new(MetricValue::Gauge(F::zero()), timestamp, 1f32);
+        metric.gauge_unset = true;
+        metric
+    }
+
+    /// Same as `new`, but applies the policy to counter values
+    pub fn new_checked(value: MetricValue<F>, timestamp: Option<u64>, sampling: f32, policy: NegativeCounterPolicy) -> Result<Self, MetricError> {
+        let value = match value {
+            MetricValue::Counter(v) => MetricValue::Counter(policy.apply(v)?),
+            value => value,
+        };
+        Ok(Self::new(value, timestamp, sampling))
+    }
+
+    /// Creates an unsampled timer with a single sample in milliseconds, so Rust applications
+    /// can measure with `Instant::elapsed` without converting units manually
+    pub fn timer_from_duration(duration: Duration) -> Self {
+        Self::new(MetricValue::Timer(vec![F::from_f64(TimerUnit::Millis.convert_duration(duration))]), None, 1f32)
+    }
+
+    /// Creates an unsampled metric of the specified type, optionally holding a single value.
+    /// Without value timers and sets are empty, counters and gauges are zero.
+    /// Histograms cannot be created without a range and fail with an error as well as the default type.
+    pub fn default_for(name: MetricTypeName, value: Option<F>) -> Result<Self, MetricError> {
+        let value = match name {
+            MetricTypeName::Counter => MetricValue::Counter(value.unwrap_or_else(F::zero)),
+            MetricTypeName::Gauge => MetricValue::Gauge(value.unwrap_or_else(F::zero)),
+            MetricTypeName::Timer => MetricValue::Timer(value.into_iter().collect()),
+            MetricTypeName::Set => MetricValue::Set(value.into_iter().map(|v| v.as_().to_bits()).collect()),
+            MetricTypeName::CustomHistogram => return Err(MetricError::CustomHistrogramRange),
+            MetricTypeName::Default => return Err(MetricError::BadTypeName(name.to_string())),
+        };
+        Ok(Self::new(value, None, 1f32))
+    }
+
     pub fn from_statsd(m: &StatsdMetric<F>, buckets: usize, timestamp: Option<u64>) -> Result<Self, MetricError> {
         let value = match m.mtype {
             StatsdType::Gauge(sign) => {
@@ -485,12 +1027,14 @@ where
             }
         };
 
-        Ok(Self::new(value?, timestamp, convert_sampling(&m.sampling)))
+        let mut metric = Self::new(value?, timestamp, convert_sampling(&m.sampling));
+        metric.gauge_delta = matches!(m.mtype, StatsdType::Gauge(Some(_)));
+        Ok(metric)
     }
 
     #[inline]
     pub fn updates(&self) -> F {
-        F::from_f64(f64::from(self.update_counter))
+        F::from_f64(self.update_counter as f64)
     }
 
     #[inline]
@@ -506,23 +1050,435 @@ where
         self.timestamp
     }
 
+    pub fn timestamp_precision(&self) -> TimestampPrecision {
+        self.timestamp_precision
+    }
+
+    /// Sets the units the timestamp is in, the timestamp value itself is not changed
+    pub fn set_timestamp_precision(&mut self, precision: TimestampPrecision) {
+        self.timestamp_precision = precision;
+    }
+
+    /// Sets the sampling rate the metric was collected with, the value is not changed
+    pub fn set_sampling(&mut self, sampling: f32) -> Result<(), MetricError> {
+        if !sampling_valid(sampling) {
+            return Err(MetricError::Sampling);
+        }
+        self.sampling = sampling;
+        Ok(())
+    }
+
+    /// Checks internal invariants, reporting all violations found. Useful as a debug check of
+    /// metrics decoded from untrusted peers. Note that empty timers and sets, like the ones made
+    /// by `default_for` without value, are reported too, because they don't come from real updates
+    pub fn validate(&self) -> Result<(), MetricError> {
+        violations_result(self.violations())
+    }
+
+    /// Checks the invariants like `validate` along with the ones holding after the statsd update
+    /// was accumulated into the metric: a set must contain the bits of the update value
+    pub fn validate_update(&self, update: &StatsdMetric<F>) -> Result<(), MetricError> {
+        let mut violations = self.violations();
+        let bits = update.value.as_().to_bits();
+        match self.value {
+            MetricValue::Set(ref hs) if !hs.contains(&bits) => violations.push(InvariantViolation::SetValueMissing),
+            MetricValue::SortedSet(ref ss) if !ss.contains(&bits) => violations.push(InvariantViolation::SetValueMissing),
+            _ => (),
+        }
+        violations_result(violations)
+    }
+
+    fn violations(&self) -> Vec<InvariantViolation> {
+        let mut violations = Vec::new();
+        if !sampling_valid(self.sampling) {
+            violations.push(InvariantViolation::Sampling(self.sampling));
+        }
+        if self.update_counter == 0 {
+            violations.push(InvariantViolation::ZeroUpdates);
+        }
+        match self.value {
+            MetricValue::Gauge(v) | MetricValue::Counter(v) if !v.is_finite() => violations.push(InvariantViolation::NonFiniteValue),
+            MetricValue::Gauge(_) | MetricValue::Counter(_) => (),
+            MetricValue::Timer(ref agg) if agg.is_empty() => violations.push(InvariantViolation::EmptyTimer),
+            MetricValue::CompactTimer(ref agg) if agg.is_empty() => violations.push(InvariantViolation::EmptyTimer),
+            MetricValue::Timer(_) | MetricValue::CompactTimer(_) => (),
+            MetricValue::Set(ref hs) if hs.is_empty() => violations.push(InvariantViolation::EmptySet),
+            MetricValue::SortedSet(ref ss) if ss.is_empty() => violations.push(InvariantViolation::EmptySet),
+            MetricValue::Set(_) | MetricValue::SortedSet(_) => (),
+            MetricValue::CustomHistogram(_, ref buckets) => {
+                if buckets
+                    .windows(2)
+                    .any(|pair| pair[0].0.partial_cmp(&pair[1].0) != Some(std::cmp::Ordering::Less))
+                {
+                    violations.push(InvariantViolation::HistogramBuckets);
+                }
+            }
+        }
+        violations
+    }
+
+    /// Compares metrics by values, ignoring the bookkeeping fields: update counter, sampling and
+    /// timestamp. Values are compared with `MetricValue::semantically_eq`
+    pub fn semantically_eq(&self, other: &Metric<F>) -> bool {
+        self.gauge_delta == other.gauge_delta
+            && self.gauge_unset == other.gauge_unset
+            && self.counter_total == other.counter_total
+            && self.vector == other.vector
+            && self.info == other.info
+            && self.value.semantically_eq(&other.value)
+    }
+
+    /// Converts the timestamp to the specified units
+    pub fn normalize_timestamp(&mut self, precision: TimestampPrecision) {
+        self.timestamp = self.timestamp.map(|ts| self.timestamp_precision.convert(ts, precision));
+        self.timestamp_precision = precision;
+    }
+
+    /// The timestamp converted to the specified units
+    pub fn timestamp_as(&self, precision: TimestampPrecision) -> Option<u64> {
+        self.timestamp.map(|ts| self.timestamp_precision.convert(ts, precision))
+    }
+
+    /// Counter value, None for other types
+    pub fn as_counter(&self) -> Option<F> {
+        if let MetricValue::Counter(value) = self.value {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    /// Gauge value, None for other types
+    pub fn as_gauge(&self) -> Option<F> {
+        if let MetricValue::Gauge(value) = self.value {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    /// Timer samples, None for other types and compact timers which have no slice to return,
+    /// `timer_len` works for all timers
+    pub fn timer_samples(&self) -> Option<&[F]> {
+        if let MetricValue::Timer(ref agg) = self.value {
+            Some(agg)
+        } else {
+            None
+        }
+    }
+
+    /// Number of timer samples regardless of timer storage, None for other types
+    pub fn timer_len(&self) -> Option<usize> {
+        match self.value {
+            MetricValue::Timer(ref agg) => Some(agg.len()),
+            MetricValue::CompactTimer(ref agg) => Some(agg.len()),
+            _ => None,
+        }
+    }
+
+    /// Number of unique values in set regardless of set storage, None for other types
+    pub fn set_len(&self) -> Option<usize> {
+        match self.value {
+            MetricValue::Set(ref set) => Some(set.len()),
+            MetricValue::SortedSet(ref set) => Some(set.len()),
+            _ => None,
+        }
+    }
+
+    /// Counter of the left bucket and the list of other buckets with their start values,
+    /// None for other types
+    pub fn histogram_buckets(&self) -> Option<(u64, &[(F, u64)])> {
+        if let MetricValue::CustomHistogram(left, ref buckets) = self.value {
+            Some((left, buckets))
+        } else {
+            None
+        }
+    }
+
+    /// Approximate number of bytes occupied by the metric, including heap buffers
+    pub fn approx_mem_size(&self) -> usize {
+        let heap = match self.value {
+            MetricValue::Gauge(_) | MetricValue::Counter(_) => 0,
+            MetricValue::Timer(ref v) => v.capacity() * std::mem::size_of::<F>(),
+            MetricValue::CompactTimer(ref v) => v.capacity(),
+            // hashbrown stores one control byte per bucket in addition to the value
+            MetricValue::Set(ref v) => v.capacity() * (std::mem::size_of::<u64>() + 1),
+            MetricValue::SortedSet(ref v) => v.capacity() * std::mem::size_of::<u64>(),
+            MetricValue::CustomHistogram(_, ref v) => v.capacity() * std::mem::size_of::<(F, u64)>(),
+        };
+        let unit = match self.unit {
+            Some(MetricUnit::Custom(ref custom)) => custom.capacity(),
+            _ => 0,
+        };
+        let vector = self.vector.as_ref().map(|vector| vector.approx_mem_size()).unwrap_or(0);
+        let info = self.info.as_ref().map(|info| info.approx_mem_size()).unwrap_or(0);
+        std::mem::size_of::<Self>() + heap + unit + vector + info
+    }
+
+    /// Unit of the values, if known
+    pub fn unit(&self) -> Option<&MetricUnit> {
+        self.unit.as_ref()
+    }
+
+    pub fn set_unit(&mut self, unit: Option<MetricUnit>) {
+        self.unit = unit;
+    }
+
+    /// Changes the storage used for set values
+    pub fn set_storage(&mut self, storage: SetStorage) {
+        let value = std::mem::replace(&mut self.value, MetricValue::Counter(F::zero()));
+        self.value = value.into_set_storage(storage);
+    }
+
+    /// Changes the storage used for timer values, the metric is left as is if the storage is invalid
+    pub fn timer_storage(&mut self, storage: TimerStorage) -> Result<(), MetricError> {
+        storage.validate()?;
+        let value = std::mem::replace(&mut self.value, MetricValue::Counter(F::zero()));
+        self.value = value.into_timer_storage(storage)?;
+        Ok(())
+    }
+
+    /// Converts compact timer to the vector one, does nothing for other types
+    pub(crate) fn expand_timer(&mut self) {
+        if let MetricValue::CompactTimer(ref agg) = self.value {
+            let expanded = agg.iter().map(F::from_f64).collect();
+            self.value = MetricValue::Timer(expanded);
+        }
+    }
+
+    /// Applies the sampling mode to the timer values, does nothing for other metric types
+    pub fn timer_sampling(&mut self, mode: TimerSampling) {
+        if mode == TimerSampling::Ignore || self.sampling >= 1f32 || self.sampling <= 0f32 {
+            return;
+        }
+
+        let copies = ((1f32 / self.sampling).round() as usize).max(1);
+        if self.replicate_timer(copies) {
+            self.sampling = 1f32;
+        }
+    }
+
+    // repeats every timer value, false if the metric is not a timer
+    fn replicate_timer(&mut self, copies: usize) -> bool {
+        match self.value {
+            MetricValue::Timer(ref mut agg) => {
+                let mut expanded = Vec::with_capacity(agg.len() * copies);
+                agg.iter().map(|value| expanded.extend(std::iter::repeat_n(*value, copies))).last();
+                *agg = expanded;
+            }
+            MetricValue::CompactTimer(ref mut agg) => {
+                let samples = agg.clone();
+                agg.clear();
+                samples
+                    .iter()
+                    .flat_map(|value| std::iter::repeat_n(value, copies))
+                    .map(|value| agg.push(value))
+                    .last();
+            }
+            _ => return false,
+        }
+        true
+    }
+
     pub fn sort_timer(&mut self) {
         if let MetricValue::Timer(ref mut agg) = self.value {
             agg.sort_unstable_by(|ref v1, ref v2| v1.partial_cmp(v2).unwrap());
         }
     }
 
+    /// Accumulates other metric into self, keeping the sampling of self,
+    /// use `accumulate_with` to handle different sampling rates. Self is left as is on errors
     pub fn accumulate(&mut self, other: Metric<F>) -> Result<(), MetricError> {
         let Metric {
             value,
             timestamp,
+            timestamp_precision,
             update_counter,
-            sampling,
+            gauge_delta,
+            gauge_unset,
+            unit,
+            counter_total,
+            vector,
+            info,
+            ..
         } = other;
-        self.update_counter += update_counter;
-        if (sampling - other.sampling).abs() > f32::EPSILON {
+        // values fail to be accumulated before changing anything, so they go first
+        if let (MetricValue::Gauge(_), MetricValue::Gauge(new)) = (&self.value, &value) {
+            self.accumulate_gauge(*new, gauge_delta, gauge_unset);
+            self.vector = vector;
+            self.info = info;
+        } else {
+            self.value.accumulate(value)?;
+            self.accumulate_counter_total(counter_total);
+        }
+        self.update_counter = self.update_counter.saturating_add(update_counter);
+        self.accumulate_timestamp(timestamp, timestamp_precision);
+        if self.unit.is_none() {
+            self.unit = unit;
+        }
+        Ok(())
+    }
+
+    /// Accumulates other metric handling the different sampling rates according to policy.
+    /// Metrics are left as is if they cannot be converted to the common sampling
+    pub fn accumulate_with(&mut self, mut other: Metric<F>, policy: SamplingPolicy) -> Result<(), MetricError> {
+        if (self.sampling - other.sampling).abs() > f32::EPSILON {
+            let sampling = match policy {
+                SamplingPolicy::Ignore => return self.accumulate(other),
+                SamplingPolicy::Error => return Err(MetricError::Sampling),
+                SamplingPolicy::KeepMostPrecise => self.sampling.max(other.sampling),
+                SamplingPolicy::Normalize => 1f32,
+            };
+            self.check_resample(sampling)?;
+            other.check_resample(sampling)?;
+            self.resample(sampling)?;
+            other.resample(sampling)?;
+        }
+        self.accumulate(other)
+    }
+
+    /// Converts the metric to effective values, as if it was not sampled: counters, histogram
+    /// buckets and the update counter are divided by sampling, timer values are replicated
+    /// like in `TimerSampling::Replicate`. Sampling is set to 1 then.
+    /// Sets cannot be converted and fail with `MetricError::Sampling`, leaving the metric as is
+    pub fn normalize_sampling(&mut self) -> Result<(), MetricError> {
+        self.resample(1f32)
+    }
+
+    /// Converts the metric values to a sampling rate not lower than the current one the same way
+    /// as `normalize_sampling` does, i.e. a counter of 1 sampled at 0.1 becomes 2 sampled at 0.2
+    pub fn resample(&mut self, sampling: f32) -> Result<(), MetricError> {
+        self.check_resample(sampling)?;
+        let factor = f64::from(sampling) / f64::from(self.sampling);
+        match self.value {
+            MetricValue::Gauge(_) | MetricValue::Set(_) | MetricValue::SortedSet(_) => (),
+            MetricValue::Counter(ref mut value) => *value = *value * F::from_f64(factor),
+            MetricValue::Timer(_) | MetricValue::CompactTimer(_) => {
+                self.replicate_timer(factor.round().max(1f64) as usize);
+            }
+            MetricValue::CustomHistogram(ref mut left, ref mut buckets) => {
+                *left = (*left as f64 * factor).round() as u64;
+                buckets
+                    .iter_mut()
+                    .map(|(_, counter)| *counter = (*counter as f64 * factor).round() as u64)
+                    .last();
+            }
+        }
+        if factor != 1f64 {
+            self.counter_total = None;
+        }
+        self.update_counter = (self.update_counter as f64 * factor).round() as u64;
+        self.sampling = sampling;
+        Ok(())
+    }
+
+    // fails if the metric cannot be resampled, so the both accumulated metrics could be checked
+    // before changing any
+    fn check_resample(&self, sampling: f32) -> Result<(), MetricError> {
+        if !sampling_valid(self.sampling) || !sampling_valid(sampling) || sampling < self.sampling {
             return Err(MetricError::Sampling);
         }
+        match self.value {
+            MetricValue::Set(_) | MetricValue::SortedSet(_) if sampling > self.sampling => Err(MetricError::Sampling),
+            _ => Ok(()),
+        }
+    }
+
+    /// Accumulates other metric without taking ownership of it, so the same metric can be
+    /// accumulated into many others without cloning it as a whole. Self is left as is on errors
+    pub fn accumulate_ref(&mut self, other: &Metric<F>) -> Result<(), MetricError> {
+        if let (MetricValue::Gauge(_), MetricValue::Gauge(new)) = (&self.value, &other.value) {
+            self.accumulate_gauge(*new, other.gauge_delta, other.gauge_unset);
+            self.vector = other.vector.clone();
+            self.info = other.info.clone();
+        } else {
+            self.value.accumulate_ref(&other.value)?;
+            self.accumulate_counter_total(other.counter_total);
+        }
+        self.update_counter = self.update_counter.saturating_add(other.update_counter);
+        self.accumulate_timestamp(other.timestamp, other.timestamp_precision);
+        if self.unit.is_none() {
+            self.unit = other.unit.clone();
+        }
+        Ok(())
+    }
+
+    // the exact total is lost once a counter without it is accumulated
+    fn accumulate_counter_total(&mut self, total: Option<u64>) {
+        self.counter_total = match (self.counter_total, total) {
+            (Some(total), Some(new)) => Some(total.wrapping_add(new)),
+            _ => None,
+        };
+    }
+
+    // a delta is added to any gauge keeping it's state, while an absolute value
+    // replaces the gauge making it absolute too
+    // a tombstone replaces any gauge, the deltas after it are counted from zero, since the
+    // source has appeared again
+    fn accumulate_gauge(&mut self, new: F, delta: bool, unset: bool) {
+        if let MetricValue::Gauge(ref mut value) = self.value {
+            if unset {
+                *value = F::zero();
+                self.gauge_delta = false;
+                self.gauge_unset = true;
+            } else if delta && !self.gauge_unset {
+                *value = *value + new;
+            } else {
+                *value = new;
+                self.gauge_delta = false;
+                self.gauge_unset = false;
+            }
+        }
+    }
+
+    /// True if the gauge consists only of relative changes, i.e. statsd `+value` or `-value`,
+    /// not applied to any absolute value yet. Such gauges are added to the gauges they are
+    /// accumulated into instead of replacing them. Note that v1 protocol does not keep this state
+    pub fn is_gauge_delta(&self) -> bool {
+        self.gauge_delta
+    }
+
+    pub(crate) fn with_counter_total(mut self, total: u64) -> Self {
+        self.counter_total = Some(total);
+        self
+    }
+
+    /// The exact total of a counter made by `MonotonicCounter::to_metric`, None if any counter
+    /// without it was accumulated, since the float value is not exact then
+    pub fn counter_total(&self) -> Option<u64> {
+        self.counter_total
+    }
+
+    pub(crate) fn with_vector(mut self, vector: VectorGauge<F>) -> Self {
+        self.vector = Some(Box::new(vector));
+        self
+    }
+
+    /// The components of a gauge made by `VectorGauge::to_metric`, None if a regular gauge
+    /// update came after it
+    pub fn vector(&self) -> Option<&VectorGauge<F>> {
+        self.vector.as_deref()
+    }
+
+    pub(crate) fn with_info(mut self, info: Info) -> Self {
+        self.info = Some(Box::new(info));
+        self
+    }
+
+    /// The labels of a gauge made by `Info::to_metric`, None if a regular gauge update came after it
+    pub fn info(&self) -> Option<&Info> {
+        self.info.as_deref()
+    }
+
+    /// True if the gauge is a tombstone made by `gauge_unset` and no values came after it.
+    /// Note that v1 protocol does not keep this state
+    pub fn is_gauge_unset(&self) -> bool {
+        self.gauge_unset
+    }
+
+    // the newest timestamp is kept in units of self
+    fn accumulate_timestamp(&mut self, timestamp: Option<u64>, precision: TimestampPrecision) {
+        let timestamp = timestamp.map(|ts| precision.convert(ts, self.timestamp_precision));
         self.timestamp = match (self.timestamp, timestamp) {
             (_, None) => self.timestamp,
             (None, Some(value)) => Some(value),
@@ -534,25 +1490,49 @@ where
                 }
             }
         };
+    }
 
-        self.value.accumulate(value)
+    /// Accumulates a batch of metrics at once, reserving timer storage for all incoming values
+    /// in advance. Accumulation stops at the first error, leaving the metrics before it accumulated.
+    pub fn accumulate_many<I>(&mut self, other: I) -> Result<(), MetricError>
+    where
+        I: IntoIterator<Item = Metric<F>>,
+    {
+        let other: Vec<Metric<F>> = other.into_iter().collect();
+        if let MetricValue::Timer(ref mut agg) = self.value {
+            let additional = other.iter().map(|m| if let MetricValue::Timer(ref v) = m.value { v.len() } else { 0 }).sum();
+            agg.reserve(additional);
+        }
+
+        for metric in other {
+            self.accumulate(metric)?;
+        }
+        Ok(())
     }
 
     pub fn accumulate_statsd(&mut self, statsd: StatsdMetric<F>) -> Result<(), MetricError> {
-        self.update_counter += 1;
+        self.update_counter = self.update_counter.saturating_add(1);
 
         if (self.sampling - convert_sampling(&statsd.sampling)).abs() > f32::EPSILON {
             return Err(MetricError::Sampling);
         }
 
+        if let StatsdType::Gauge(None) = statsd.mtype {
+            self.gauge_delta = false;
+        }
+        // the value of a tombstone is zero, so deltas are counted from it
+        if let StatsdType::Gauge(_) = statsd.mtype {
+            self.gauge_unset = false;
+            self.vector = None;
+            self.info = None;
+        }
+        self.counter_total = None;
         self.value.accumulate_statsd(statsd)
     }
 
     pub fn from_capnp_v1(reader: cmetric_v1::Reader) -> Result<(MetricName, Metric<F>), MetricError> {
         let name: &[u8] = reader.get_name().map_err(MetricError::Capnp)?.as_bytes();
-        let name = Bytes::copy_from_slice(name);
-        let tag_pos = find_tag_pos(&name[..], TagFormat::Graphite);
-        let name = MetricName::from_raw_parts(name, tag_pos);
+        let name = MetricName::new_lazy(Bytes::copy_from_slice(name));
         let value: F = F::from_f64(reader.get_value());
         let (sampling, up_counter) = match reader.get_meta() {
             Ok(reader) => (
@@ -561,7 +1541,7 @@ where
                 } else {
                     None
                 },
-                Some(reader.get_update_counter()),
+                Some(decode_update_counter(reader.get_update_counter(), reader.get_update_counter64())),
             ),
             Err(_) => (None, None),
         };
@@ -585,8 +1565,24 @@ where
     }
 
     pub fn from_capnp(reader: cmetric::Reader) -> Result<(MetricName, Metric<F>), MetricError> {
-        let name: &[u8] = reader.get_name().map_err(MetricError::Capnp)?.as_bytes();
-        let name = Bytes::copy_from_slice(name);
+        Self::from_capnp_with_dictionary(reader, None)
+    }
+
+    /// Same as `from_capnp`, but also decodes names encoded as references to the message
+    /// dictionary, if one is provided
+    pub fn from_capnp_with_dictionary(reader: cmetric::Reader, dictionary: Option<text_list::Reader>) -> Result<(MetricName, Metric<F>), MetricError> {
+        let name = match dictionary {
+            Some(dictionary) if !reader.has_name() && reader.has_name_refs() => {
+                let refs = reader.get_name_refs().map_err(MetricError::Capnp)?;
+                let mut name = BytesMut::new();
+                for idx in refs.iter() {
+                    let part = dictionary.try_get(idx).ok_or(MetricError::DictionaryRef(idx))?;
+                    name.extend_from_slice(part.map_err(MetricError::Capnp)?.as_bytes());
+                }
+                name.freeze()
+            }
+            _ => Bytes::copy_from_slice(reader.get_name().map_err(MetricError::Capnp)?.as_bytes()),
+        };
 
         let m_reader = reader.get_meta().map_err(MetricError::Capnp)?;
         let tag_pos = match m_reader.get_tags().which().map_err(MetricError::CapnpSchema)? {
@@ -596,21 +1592,38 @@ where
 
         let name = MetricName::from_raw_parts(name, tag_pos);
 
-        let update_counter = m_reader.get_update_counter();
+        let update_counter = decode_update_counter(m_reader.get_update_counter(), m_reader.get_update_counter64());
+        let gauge_delta = m_reader.get_gauge_delta();
+        let gauge_unset = m_reader.get_gauge_unset();
+        let counter_total = if m_reader.get_monotonic() { Some(m_reader.get_counter_u64()) } else { None };
+        let unit = if m_reader.has_unit() {
+            Some(MetricUnit::from_name(m_reader.get_unit().map_err(MetricError::Capnp)?))
+        } else {
+            None
+        };
 
         let mv_reader = reader.get_value().map_err(MetricError::Capnp)?;
         let mvalue = MetricValue::from_capnp(mv_reader)?;
 
-        let timestamp = if reader.has_timestamp() {
-            Some(reader.get_timestamp().map_err(MetricError::Capnp)?.get_ts())
+        let (timestamp, timestamp_precision) = if reader.has_timestamp() {
+            let ts_reader = reader.get_timestamp().map_err(MetricError::Capnp)?;
+            let precision = ts_reader.get_precision().map_err(MetricError::CapnpSchema)?;
+            (Some(ts_reader.get_ts()), TimestampPrecision::from_capnp(precision))
         } else {
-            None
+            (None, TimestampPrecision::Seconds)
         };
 
         let sampling = reader.get_sampling();
 
         let mut metric: Metric<F> = Metric::new(mvalue, timestamp, sampling);
+        metric.timestamp_precision = timestamp_precision;
         metric.update_counter = update_counter;
+        metric.gauge_delta = gauge_delta;
+        metric.gauge_unset = gauge_unset;
+        metric.unit = unit;
+        metric.counter_total = counter_total;
+        metric.vector = VectorGauge::from_capnp(m_reader)?.map(Box::new);
+        metric.info = Info::from_capnp(m_reader)?.map(Box::new);
 
         Ok((name, metric))
     }
@@ -623,15 +1636,16 @@ where
         let value: f64 = self.value.fill_capnp_v1(&mut t_builder);
         builder.set_value(value);
 
-        // timestamp
-        if let Some(timestamp) = self.timestamp {
+        // timestamp, v1 protocol only knows seconds
+        if let Some(timestamp) = self.timestamp_as(TimestampPrecision::Seconds) {
             builder.reborrow().init_timestamp().set_ts(timestamp);
         }
 
         // meta
         let mut m_builder = builder.reborrow().init_meta();
 
-        m_builder.set_update_counter(self.update_counter);
+        m_builder.set_update_counter(saturate_update_counter(self.update_counter));
+        m_builder.set_update_counter64(self.update_counter);
         if (self.sampling - 1f32).abs() > f32::EPSILON {
             m_builder.init_sampling().set_sampling(self.sampling);
         }
@@ -645,7 +1659,9 @@ where
         self.value.fill_capnp(&mut v_builder);
         // timestamp
         if let Some(timestamp) = self.timestamp {
-            builder.reborrow().init_timestamp().set_ts(timestamp);
+            let mut ts_builder = builder.reborrow().init_timestamp();
+            ts_builder.set_ts(timestamp);
+            ts_builder.set_precision(self.timestamp_precision.to_capnp());
         }
 
         builder.set_sampling(self.sampling);
@@ -657,12 +1673,40 @@ where
             builder.reborrow().init_meta()
         };
 
-        m_builder.set_update_counter(self.update_counter);
+        m_builder.set_update_counter(saturate_update_counter(self.update_counter));
+        m_builder.set_update_counter64(self.update_counter);
+        m_builder.set_gauge_delta(self.gauge_delta);
+        m_builder.set_gauge_unset(self.gauge_unset);
+        if let Some(ref unit) = self.unit {
+            m_builder.set_unit(unit.as_str());
+        }
+        if let Some(total) = self.counter_total {
+            m_builder.set_counter_u64(total);
+            m_builder.set_monotonic(true);
+        }
+        if let Some(ref vector) = self.vector {
+            vector.fill_capnp(m_builder.reborrow());
+        }
+        if let Some(ref info) = self.info {
+            info.fill_capnp(m_builder.reborrow());
+        }
     }
 
     /// fills the name related parts. `unicode_checked` flag must signal that name part was
     /// already checked to be valid unicode
     pub fn fill_capnp_name<'a>(&self, builder: &mut cmetric::Builder<'a>, name: &MetricName, unicode_checked: bool) {
+        self.fill_capnp_tags(builder, name);
+
+        let name = if unicode_checked {
+            Cow::Borrowed(unsafe { std::str::from_utf8_unchecked(&name.name) })
+        } else {
+            String::from_utf8_lossy(&name.name)
+        };
+        builder.set_name(&name);
+    }
+
+    /// fills the tag related parts of the name, leaving the name field itself untouched
+    pub fn fill_capnp_tags<'a>(&self, builder: &mut cmetric::Builder<'a>, name: &MetricName) {
         // meta (may be initialized if fill_capnp was called before)
         let m_builder = if builder.has_meta() {
             builder.reborrow().get_meta().unwrap()
@@ -672,18 +1716,11 @@ where
 
         let mut t_builder = m_builder.init_tags();
 
-        if let Some(pos) = name.tag_pos {
+        if let Some(pos) = name.tag_pos() {
             t_builder.set_graphite(pos as u64);
         } else {
             t_builder.set_no_tags(());
         }
-
-        let name = if unicode_checked {
-            Cow::Borrowed(unsafe { std::str::from_utf8_unchecked(&name.name) })
-        } else {
-            String::from_utf8_lossy(&name.name)
-        };
-        builder.set_name(&name);
     }
 
     // may be useful in future somehow
@@ -724,6 +1761,62 @@ where
         let allocator = HeapAllocator::new();
         self.as_capnp(allocator, name)
     }
+
+    /// Serializes metric (and optionally a name) into the canonical capnp form.
+    /// Serializing the same metric always gives the same bytes, so the result
+    /// can be used for hashing, signing or deduplicating metrics by content
+    pub fn canonical_bytes(&self, name: Option<&MetricName>) -> Result<Vec<u8>, MetricError> {
+        let builder = self.as_capnp_heap(name.map(|name| (name, false)));
+        let words = builder.into_reader().canonicalize().map_err(MetricError::Capnp)?;
+        Ok(capnp::Word::words_to_bytes(&words).to_vec())
+    }
+}
+
+/// Accumulates a batch of named metrics into the map, inserting the new ones.
+///
+/// Consecutive metrics with the same name are accumulated together before touching the map,
+/// so the hash lookup is done once for each such series.
+/// Errors do not stop the accumulation and are returned along with the names of failed metrics.
+pub fn accumulate_all<F, I>(cache: &mut HashMap<MetricName, Metric<F>>, incoming: I) -> Vec<(MetricName, MetricError)>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+    I: IntoIterator<Item = (MetricName, Metric<F>)>,
+{
+    let mut errors = Vec::new();
+    let mut pending: Option<(MetricName, Metric<F>)> = None;
+
+    for (name, metric) in incoming {
+        if let Some((ref pname, ref mut pmetric)) = pending {
+            if pname == &name {
+                if let Err(e) = pmetric.accumulate(metric) {
+                    errors.push((name, e));
+                }
+                continue;
+            }
+        }
+
+        if let Some((pname, pmetric)) = pending.replace((name, metric)) {
+            errors.extend(accumulate_entry(cache, pname, pmetric));
+        }
+    }
+
+    if let Some((pname, pmetric)) = pending {
+        errors.extend(accumulate_entry(cache, pname, pmetric));
+    }
+    errors
+}
+
+fn accumulate_entry<F>(cache: &mut HashMap<MetricName, Metric<F>>, name: MetricName, metric: Metric<F>) -> Option<(MetricName, MetricError)>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    match cache.entry(name) {
+        Entry::Occupied(mut entry) => entry.get_mut().accumulate(metric).err().map(|e| (entry.key().clone(), e)),
+        Entry::Vacant(entry) => {
+            entry.insert(metric);
+            None
+        }
+    }
 }
 
 /// Metric type specification simplified to use for naming in configs etc
@@ -746,9 +1839,9 @@ impl MetricTypeName {
     {
         match m.value() {
             MetricValue::Counter(_) => MetricTypeName::Counter,
-            MetricValue::Timer(_) => MetricTypeName::Timer,
+            MetricValue::Timer(_) | MetricValue::CompactTimer(_) => MetricTypeName::Timer,
             MetricValue::Gauge(_) => MetricTypeName::Gauge,
-            MetricValue::Set(_) => MetricTypeName::Set,
+            MetricValue::Set(_) | MetricValue::SortedSet(_) => MetricTypeName::Set,
             MetricValue::CustomHistogram(_, _) => MetricTypeName::CustomHistogram,
         }
     }
@@ -828,6 +1921,28 @@ impl ProtocolVersion {
     }
 }
 
+// sets are serialized in sorted order to keep serialization deterministic
+fn sorted_set(set: &HashSet<u64>) -> Vec<u64> {
+    let mut sorted: Vec<u64> = set.iter().copied().collect();
+    sorted.sort_unstable();
+    sorted
+}
+
+// older peers only send the 32-bit counter, leaving the 64-bit one zero
+#[inline]
+fn decode_update_counter(counter32: u32, counter64: u64) -> u64 {
+    if counter64 == 0 {
+        u64::from(counter32)
+    } else {
+        counter64
+    }
+}
+
+#[inline]
+fn saturate_update_counter(counter: u64) -> u32 {
+    u32::try_from(counter).unwrap_or(u32::MAX)
+}
+
 #[inline]
 fn convert_sampling(sampling: &Option<f32>) -> f32 {
     if let Some(s) = sampling {
@@ -845,6 +1960,8 @@ fn convert_sampling(sampling: &Option<f32>) -> f32 {
 mod tests {
 
     use super::*;
+    use crate::name::TagFormat;
+    use crate::testing::name;
     use capnp::serialize::{read_message, write_message};
     type Float = f64;
 
@@ -1068,6 +2185,225 @@ mod tests {
         capnp_test(metric1);
     }
 
+    #[test]
+    fn test_metric_sorted_set() {
+        let smetric = StatsdMetric::new(2f64, StatsdType::Set, None).unwrap();
+        let mut metric = Metric::<Float>::from_statsd(&smetric, 1, None).unwrap();
+        metric.set_storage(SetStorage::Sorted);
+
+        let smetric = StatsdMetric::new(1f64, StatsdType::Set, None).unwrap();
+        metric.accumulate_statsd(smetric).unwrap();
+
+        let mut hs = HashSet::new();
+        hs.insert(3f64.to_bits());
+        hs.insert(2f64.to_bits());
+        metric.accumulate(Metric::new(MetricValue::Set(hs), None, 1f32)).unwrap();
+
+        let expected: SortedSet = vec![1f64.to_bits(), 2f64.to_bits(), 3f64.to_bits()].into_iter().collect();
+        assert_eq!(metric.value, MetricValue::SortedSet(expected.clone()));
+        assert_eq!(MetricTypeName::from_metric(&metric), MetricTypeName::Set);
+
+        metric.set_storage(SetStorage::Hash);
+        assert_eq!(metric.value, MetricValue::Set(expected.iter().copied().collect()));
+    }
+
+    #[test]
+    fn test_metric_approx_mem_size() {
+        let base = std::mem::size_of::<Metric<Float>>();
+        let metric = Metric::<Float>::new(MetricValue::Counter(1f64), None, 1f32);
+        assert_eq!(metric.approx_mem_size(), base);
+
+        let metric = Metric::<Float>::new(MetricValue::Timer(Vec::with_capacity(100)), None, 1f32);
+        assert_eq!(metric.approx_mem_size(), base + 800);
+
+        let metric = Metric::<Float>::new(MetricValue::SortedSet(SortedSet::with_capacity(10)), None, 1f32);
+        assert_eq!(metric.approx_mem_size(), base + 80);
+
+        let metric = Metric::<Float>::new(MetricValue::Set(HashSet::with_capacity(10)), None, 1f32);
+        assert!(metric.approx_mem_size() >= base + 90);
+    }
+
+    #[test]
+    fn test_metric_compact_timer() {
+        let smetric = StatsdMetric::new(2f64, StatsdType::Timer, None).unwrap();
+        let mut metric = Metric::<Float>::from_statsd(&smetric, 1, None).unwrap();
+        metric.timer_storage(TimerStorage::Compact(0.5)).unwrap();
+
+        let smetric = StatsdMetric::new(1f64, StatsdType::Timer, None).unwrap();
+        metric.accumulate_statsd(smetric).unwrap();
+        metric.accumulate(Metric::new(MetricValue::Timer(vec![3f64, 1.5]), None, 1f32)).unwrap();
+
+        let mut other = Metric::new(MetricValue::Timer(vec![10f64]), None, 1f32);
+        other.timer_storage(TimerStorage::Compact(0.5)).unwrap();
+        metric.accumulate(other).unwrap();
+        assert_eq!(MetricTypeName::from_metric(&metric), MetricTypeName::Timer);
+
+        for quantum in [0f64, -1f64, f64::NAN, f64::INFINITY] {
+            assert!(matches!(
+                metric.timer_storage(TimerStorage::Compact(quantum)),
+                Err(MetricError::TimerQuantum(_))
+            ));
+            assert_eq!(metric.timer_len(), Some(5));
+        }
+
+        metric.timer_storage(TimerStorage::Vec).unwrap();
+        assert_eq!(metric.value, MetricValue::Timer(vec![2f64, 1f64, 3f64, 1.5f64, 10f64]));
+    }
+
+    #[test]
+    fn test_metric_default_for() {
+        let metric = Metric::<Float>::default_for(MetricTypeName::Timer, None).unwrap();
+        assert_eq!(metric, Metric::new(MetricValue::Timer(Vec::new()), None, 1f32));
+        let metric = Metric::<Float>::default_for(MetricTypeName::Timer, Some(2f64)).unwrap();
+        assert_eq!(metric.value, MetricValue::Timer(vec![2f64]));
+
+        let metric = Metric::<Float>::default_for(MetricTypeName::Set, None).unwrap();
+        assert_eq!(metric.value, MetricValue::Set(HashSet::new()));
+        let metric = Metric::<Float>::default_for(MetricTypeName::Set, Some(2f64)).unwrap();
+        let smetric = StatsdMetric::new(2f64, StatsdType::Set, None).unwrap();
+        assert_eq!(metric, Metric::from_statsd(&smetric, 1, None).unwrap());
+
+        let metric = Metric::<Float>::default_for(MetricTypeName::Gauge, None).unwrap();
+        assert_eq!(metric.value, MetricValue::Gauge(0f64));
+        let metric = Metric::<Float>::default_for(MetricTypeName::Counter, Some(3f64)).unwrap();
+        assert_eq!(metric.value, MetricValue::Counter(3f64));
+
+        assert!(Metric::<Float>::default_for(MetricTypeName::CustomHistogram, None).is_err());
+        assert!(Metric::<Float>::default_for(MetricTypeName::Default, Some(1f64)).is_err());
+    }
+
+    #[test]
+    fn test_metric_accessors() {
+        let counter = Metric::<Float>::new(MetricValue::Counter(1f64), None, 1f32);
+        assert_eq!(counter.as_counter(), Some(1f64));
+        assert_eq!(counter.as_gauge(), None);
+        assert_eq!(counter.timer_len(), None);
+
+        let gauge = Metric::<Float>::new(MetricValue::Gauge(2f64), None, 1f32);
+        assert_eq!(gauge.as_gauge(), Some(2f64));
+        assert_eq!(gauge.as_counter(), None);
+
+        let mut timer = Metric::<Float>::new(MetricValue::Timer(vec![1f64, 2f64]), None, 1f32);
+        assert_eq!(timer.timer_samples(), Some(&[1f64, 2f64][..]));
+        assert_eq!(timer.timer_len(), Some(2));
+        assert_eq!(timer.set_len(), None);
+        timer.timer_storage(TimerStorage::Compact(1f64)).unwrap();
+        assert_eq!(timer.timer_samples(), None);
+        assert_eq!(timer.timer_len(), Some(2));
+
+        let mut set = Metric::<Float>::default_for(MetricTypeName::Set, Some(1f64)).unwrap();
+        assert_eq!(set.set_len(), Some(1));
+        set.set_storage(SetStorage::Sorted);
+        assert_eq!(set.set_len(), Some(1));
+        assert_eq!(set.histogram_buckets(), None);
+
+        let histogram = Metric::<Float>::new(MetricValue::CustomHistogram(1, vec![(0f64, 2)]), None, 1f32);
+        assert_eq!(histogram.histogram_buckets(), Some((1, &[(0f64, 2)][..])));
+
+        let decrement = StatsdMetric::<Float>::new(1f64, StatsdType::Gauge(Some(-1)), None).unwrap();
+        assert_eq!(decrement.gauge_sign(), Some(-1));
+        let gauge = StatsdMetric::<Float>::new(1f64, StatsdType::Gauge(None), None).unwrap();
+        assert_eq!(gauge.gauge_sign(), None);
+        let counter = StatsdMetric::<Float>::new(1f64, StatsdType::Counter, None).unwrap();
+        assert_eq!(counter.gauge_sign(), None);
+    }
+
+    #[test]
+    fn test_metric_accumulate_ref() {
+        let incoming = [
+            Metric::<Float>::new(MetricValue::Timer(vec![2f64, 3f64]), Some(10), 1f32),
+            Metric::new(MetricValue::Counter(2f64), None, 1f32),
+            Metric::new(MetricValue::Set(vec![1u64, 2u64].into_iter().collect()), None, 1f32),
+            Metric::new(MetricValue::CustomHistogram(1, vec![(0f64, 1), (1f64, 2)]), None, 1f32),
+        ];
+        let initial = vec![
+            Metric::<Float>::new(MetricValue::Timer(vec![1f64]), Some(5), 1f32),
+            Metric::new(MetricValue::Counter(1f64), None, 1f32),
+            Metric::new(MetricValue::Set(vec![3u64].into_iter().collect()), None, 1f32),
+            Metric::new(MetricValue::CustomHistogram(0, vec![(0f64, 1), (1f64, 1)]), None, 1f32),
+        ];
+
+        for (init, other) in initial.into_iter().zip(incoming.iter()) {
+            let mut by_ref = init.clone();
+            by_ref.accumulate_ref(other).unwrap();
+            by_ref.accumulate_ref(other).unwrap();
+
+            let mut by_value = init;
+            by_value.accumulate(other.clone()).unwrap();
+            by_value.accumulate(other.clone()).unwrap();
+            assert_eq!(by_ref, by_value);
+        }
+
+        let mut counter = Metric::<Float>::new(MetricValue::Counter(1f64), None, 1f32);
+        assert!(counter.accumulate_ref(&incoming[0]).is_err());
+    }
+
+    #[test]
+    fn test_metric_accumulate_many() {
+        let mut metric = Metric::<Float>::new(MetricValue::Timer(vec![1f64]), None, 1f32);
+        let others = (2..5).map(|v| Metric::new(MetricValue::Timer(vec![v as f64, v as f64]), Some(v), 1f32));
+        metric.accumulate_many(others).unwrap();
+        assert_eq!(metric.value, MetricValue::Timer(vec![1f64, 2f64, 2f64, 3f64, 3f64, 4f64, 4f64]));
+        assert_eq!(metric.update_counter, 4);
+        assert_eq!(metric.timestamp, Some(4));
+
+        let mut metric = Metric::<Float>::new(MetricValue::Counter(1f64), None, 1f32);
+        let others = vec![
+            Metric::new(MetricValue::Counter(1f64), None, 1f32),
+            Metric::new(MetricValue::Gauge(1f64), None, 1f32),
+            Metric::new(MetricValue::Counter(1f64), None, 1f32),
+        ];
+        assert!(metric.accumulate_many(others).is_err());
+        assert_eq!(metric.value, MetricValue::Counter(2f64));
+    }
+
+    #[test]
+    fn test_accumulate_all() {
+        let (first, second, third) = (name("first"), name("second"), name("third"));
+
+        let mut cache = HashMap::new();
+        cache.insert(first.clone(), Metric::<Float>::new(MetricValue::Counter(1f64), None, 1f32));
+
+        let incoming = vec![
+            (first.clone(), Metric::new(MetricValue::Counter(1f64), None, 1f32)),
+            (first.clone(), Metric::new(MetricValue::Counter(1f64), None, 1f32)),
+            (second.clone(), Metric::new(MetricValue::Counter(10f64), None, 1f32)),
+            (second.clone(), Metric::new(MetricValue::Gauge(1f64), None, 1f32)),
+            (first.clone(), Metric::new(MetricValue::Counter(1f64), None, 1f32)),
+            (third.clone(), Metric::new(MetricValue::Gauge(5f64), None, 1f32)),
+            (third.clone(), Metric::new(MetricValue::Gauge(6f64), None, 1f32)),
+        ];
+
+        let errors = accumulate_all(&mut cache, incoming);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, second);
+
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache[&first].value, MetricValue::Counter(4f64));
+        assert_eq!(cache[&first].update_counter, 4);
+        assert_eq!(cache[&second].value, MetricValue::Counter(10f64));
+        assert_eq!(cache[&third].value, MetricValue::Gauge(6f64));
+    }
+
+    #[test]
+    fn test_metric_canonical_bytes() {
+        // sets with the same values, but different insertion order
+        let mut set1 = HashSet::new();
+        set1.extend(0u64..100);
+        let mut set2 = HashSet::new();
+        set2.extend((0u64..100).rev());
+
+        let metric1 = Metric::<Float>::new(MetricValue::Set(set1), Some(10), 1f32);
+        let metric2 = Metric::<Float>::new(MetricValue::Set(set2), Some(10), 1f32);
+
+        let name = name("some.set;b=c;a=b");
+        assert_eq!(metric1.canonical_bytes(Some(&name)).unwrap(), metric2.canonical_bytes(Some(&name)).unwrap());
+        assert_eq!(metric1.canonical_bytes(None).unwrap(), metric1.canonical_bytes(None).unwrap());
+
+        let metric3 = Metric::<Float>::new(MetricValue::Counter(1f64), Some(10), 1f32);
+        assert_ne!(metric1.canonical_bytes(None).unwrap(), metric3.canonical_bytes(None).unwrap());
+    }
+
     #[test]
     fn test_metric_capnp_custom_histogram() {
         let mvalue = MetricValue::CustomHistogram(1, vec![(3f64, 0), (5f64, 0), (7f64, 0)]);
@@ -1079,4 +2415,285 @@ mod tests {
         capnp_test_v1(metric1.clone());
         capnp_test(metric1);
     }
+
+    #[test]
+    fn timer_sampling_replicate() {
+        let mut timer = Metric::<f64>::new(MetricValue::Timer(vec![1f64, 100f64]), None, 0.3);
+        timer.timer_sampling(TimerSampling::Ignore);
+        assert_eq!(timer.timer_len(), Some(2));
+
+        timer.timer_sampling(TimerSampling::Replicate);
+        assert_eq!(timer.sampling(), 1f64);
+        assert_eq!(timer.timer_samples().unwrap(), &[1f64, 1f64, 1f64, 100f64, 100f64, 100f64][..]);
+
+        // sampled values must outweigh the unsampled ones when mixed
+        let mut sampled = Metric::<f64>::new(MetricValue::Timer(vec![1f64]), None, 0.25);
+        sampled.timer_sampling(TimerSampling::Replicate);
+        sampled.accumulate(Metric::new(MetricValue::Timer(vec![100f64, 100f64]), None, 1f32)).unwrap();
+        let mut median = crate::aggregate::aggregates(&mut sampled, &[crate::aggregate::Aggregate::Median]).map(|(_, v)| v);
+        assert_eq!(median.next(), Some(1f64));
+
+        let mut compact = Metric::<f64>::new(MetricValue::Timer(vec![2f64]), None, 0.5);
+        compact.timer_storage(TimerStorage::Compact(1f64)).unwrap();
+        compact.timer_sampling(TimerSampling::Replicate);
+        assert_eq!(compact.timer_len(), Some(2));
+        assert_eq!(compact.sampling(), 1f64);
+
+        let mut counter = Metric::<f64>::new(MetricValue::Counter(1f64), None, 0.5);
+        counter.timer_sampling(TimerSampling::Replicate);
+        assert_eq!(counter.sampling(), 0.5);
+    }
+
+    #[test]
+    fn update_counter_saturates() {
+        let mut metric = Metric::<f64>::new(MetricValue::Counter(1f64), None, 1f32);
+        metric.update_counter = u64::from(u32::MAX);
+        metric.accumulate(Metric::new(MetricValue::Counter(1f64), None, 1f32)).unwrap();
+        assert_eq!(metric.update_counter, u64::from(u32::MAX) + 1);
+        assert_eq!(saturate_update_counter(metric.update_counter), u32::MAX);
+
+        metric.update_counter = u64::MAX - 1;
+        metric.accumulate_ref(&Metric::new(MetricValue::Counter(1f64), None, 1f32)).unwrap();
+        metric.accumulate(Metric::new(MetricValue::Counter(1f64), None, 1f32)).unwrap();
+        assert_eq!(metric.update_counter, u64::MAX);
+
+        assert_eq!(decode_update_counter(5, 0), 5);
+        assert_eq!(decode_update_counter(u32::MAX, 1 << 40), 1 << 40);
+    }
+
+    #[test]
+    fn timestamp_precision() {
+        assert_eq!(TimestampPrecision::Seconds.convert(2, TimestampPrecision::Nanos), 2_000_000_000);
+        assert_eq!(TimestampPrecision::Nanos.convert(2_999_999_999, TimestampPrecision::Millis), 2_999);
+        assert_eq!(TimestampPrecision::Millis.convert(u64::MAX / 10, TimestampPrecision::Nanos), u64::MAX);
+        assert_eq!(TimestampPrecision::Millis.convert(5, TimestampPrecision::Millis), 5);
+
+        let mut metric = Metric::<f64>::new(MetricValue::Counter(1f64), Some(1_600_000_000), 1f32);
+        let mut nanos = Metric::new(MetricValue::Counter(1f64), Some(1_600_000_001_500_000_000), 1f32);
+        nanos.set_timestamp_precision(TimestampPrecision::Nanos);
+        assert_eq!(nanos.timestamp_as(TimestampPrecision::Seconds), Some(1_600_000_001));
+
+        // newer timestamp in other units must win and be converted
+        metric.accumulate_ref(&nanos).unwrap();
+        assert_eq!(metric.timestamp(), Some(1_600_000_001));
+        assert_eq!(metric.timestamp_precision(), TimestampPrecision::Seconds);
+
+        metric.normalize_timestamp(TimestampPrecision::Millis);
+        assert_eq!(metric.timestamp(), Some(1_600_000_001_000));
+        nanos.accumulate(metric).unwrap();
+        assert_eq!(nanos.timestamp(), Some(1_600_000_001_500_000_000));
+    }
+
+    #[test]
+    fn metric_semantically_eq() {
+        let mut m1 = Metric::<f64>::new(MetricValue::Timer(vec![1f64, 2f64, 3f64]), Some(10), 1f32);
+        let mut m2 = Metric::new(MetricValue::Timer(vec![3f64]), Some(10_000), 0.5);
+        m2.set_timestamp_precision(TimestampPrecision::Millis);
+        m2.accumulate(Metric::new(MetricValue::Timer(vec![1f64, 2f64]), None, 0.5)).unwrap();
+        assert_ne!(m1, m2);
+        assert!(m1.semantically_eq(&m2));
+
+        m2.timer_storage(TimerStorage::Compact(1f64)).unwrap();
+        assert!(m1.semantically_eq(&m2));
+        m1.accumulate_ref(&Metric::new(MetricValue::Timer(vec![1f64]), None, 1f32)).unwrap();
+        assert!(!m1.semantically_eq(&m2));
+
+        let mut s1 = Metric::<f64>::new(MetricValue::Set(vec![1, 2].into_iter().collect()), None, 1f32);
+        let s2 = Metric::new(MetricValue::SortedSet(vec![2, 1].into_iter().collect()), None, 1f32);
+        assert!(s1.semantically_eq(&s2));
+        s1.set_storage(SetStorage::Sorted);
+        assert!(s2.semantically_eq(&s1));
+
+        let c1 = Metric::<f64>::new(MetricValue::Counter(1f64), Some(1), 1f32);
+        assert!(!c1.semantically_eq(&Metric::new(MetricValue::Gauge(1f64), Some(1), 1f32)));
+        assert!(c1.semantically_eq(&Metric::new(MetricValue::Counter(1f64), Some(2), 1f32)));
+        assert!(!c1.semantically_eq(&m1));
+    }
+
+    #[test]
+    fn metric_validate() {
+        assert!(Metric::<f64>::new(MetricValue::Timer(vec![1f64]), None, 0.5).validate().is_ok());
+        assert!(Metric::<f64>::new(MetricValue::CustomHistogram(0, vec![(0f64, 1), (1f64, 0)]), None, 1f32)
+            .validate()
+            .is_ok());
+
+        let mut metric = Metric::<f64>::default_for(MetricTypeName::Timer, None).unwrap();
+        metric.sampling = 0f32;
+        metric.update_counter = 0;
+        match metric.validate() {
+            Err(MetricError::Invariant(violations)) => assert_eq!(
+                violations,
+                vec![
+                    InvariantViolation::Sampling(0f32),
+                    InvariantViolation::ZeroUpdates,
+                    InvariantViolation::EmptyTimer
+                ]
+            ),
+            other => panic!("bad validation result: {:?}", other),
+        }
+
+        let set = Metric::<f64>::new(MetricValue::SortedSet(SortedSet::new()), None, 1f32);
+        assert!(matches!(set.validate(), Err(MetricError::Invariant(v)) if v == vec![InvariantViolation::EmptySet]));
+        let gauge = Metric::<f64>::new(MetricValue::Gauge(f64::NAN), None, 1f32);
+        assert!(matches!(gauge.validate(), Err(MetricError::Invariant(v)) if v == vec![InvariantViolation::NonFiniteValue]));
+        let histogram = Metric::<f64>::new(MetricValue::CustomHistogram(0, vec![(1f64, 1), (1f64, 0)]), None, 1f32);
+        assert!(matches!(histogram.validate(), Err(MetricError::Invariant(v)) if v == vec![InvariantViolation::HistogramBuckets]));
+
+        assert!(StatsdMetric::new(1f64, StatsdType::Gauge(Some(-1)), Some(0.1)).unwrap().validate().is_ok());
+        let statsd = StatsdMetric::new(f64::INFINITY, StatsdType::Gauge(Some(2)), Some(1.5)).unwrap();
+        match statsd.validate() {
+            Err(MetricError::Invariant(violations)) => assert_eq!(
+                violations,
+                vec![
+                    InvariantViolation::Sampling(1.5),
+                    InvariantViolation::GaugeSign(2),
+                    InvariantViolation::NonFiniteValue
+                ]
+            ),
+            other => panic!("bad validation result: {:?}", other),
+        }
+
+        let update = StatsdMetric::new(2f64, StatsdType::Set, None).unwrap();
+        let mut set = Metric::<f64>::from_statsd(&update, 1, None).unwrap();
+        assert!(set.validate_update(&update).is_ok());
+        set.set_storage(SetStorage::Sorted);
+        assert!(set.validate_update(&update).is_ok());
+        let other = StatsdMetric::new(3f64, StatsdType::Set, None).unwrap();
+        assert!(matches!(set.validate_update(&other), Err(MetricError::Invariant(v)) if v == vec![InvariantViolation::SetValueMissing]));
+        assert!(Metric::<f64>::from_statsd(&other, 1, None).unwrap().validate_update(&update).is_err());
+    }
+
+    #[test]
+    fn gauge_delta_relay() {
+        let delta = |sign, value| Metric::<f64>::from_statsd(&StatsdMetric::new(value, StatsdType::Gauge(Some(sign)), None).unwrap(), 1, None).unwrap();
+
+        // relay accumulates deltas without knowing the absolute value
+        let mut relayed = delta(1, 5f64);
+        assert!(relayed.is_gauge_delta());
+        relayed.accumulate_ref(&delta(-1, 2f64)).unwrap();
+        assert_eq!(relayed.value, MetricValue::Gauge(3f64));
+        assert!(relayed.is_gauge_delta());
+
+        // the receiver applies them to its absolute value
+        let mut gauge = Metric::<f64>::new(MetricValue::Gauge(100f64), None, 1f32);
+        gauge.accumulate(relayed.clone()).unwrap();
+        assert_eq!(gauge.value, MetricValue::Gauge(103f64));
+        assert!(!gauge.is_gauge_delta());
+
+        // an absolute value overrides deltas
+        relayed.accumulate(Metric::new(MetricValue::Gauge(10f64), None, 1f32)).unwrap();
+        assert_eq!(relayed.value, MetricValue::Gauge(10f64));
+        assert!(!relayed.is_gauge_delta());
+        relayed.accumulate(delta(1, 1f64)).unwrap();
+        assert_eq!(relayed.value, MetricValue::Gauge(11f64));
+        assert!(!relayed.is_gauge_delta());
+
+        let mut statsd = delta(1, 1f64);
+        statsd
+            .accumulate_statsd(StatsdMetric::new(7f64, StatsdType::Gauge(None), None).unwrap())
+            .unwrap();
+        assert!(!statsd.is_gauge_delta());
+        assert!(!delta(1, 1f64).semantically_eq(&Metric::new(MetricValue::Gauge(1f64), None, 1f32)));
+    }
+
+    #[test]
+    fn metric_units() {
+        assert_eq!(MetricUnit::from_name("ms"), MetricUnit::Millis);
+        assert_eq!(MetricUnit::from_name("requests"), MetricUnit::Custom("requests".into()));
+        assert_eq!(MetricUnit::Custom("requests".into()).as_str(), "requests");
+        assert_eq!(MetricUnit::Count.long_name(), None);
+
+        let mut metric = Metric::<f64>::new(MetricValue::Counter(1f64), None, 1f32);
+        let mut with_unit = metric.clone();
+        with_unit.set_unit(Some(MetricUnit::Bytes));
+        metric.accumulate(with_unit).unwrap();
+        assert_eq!(metric.unit(), Some(&MetricUnit::Bytes));
+        // the unit known first is kept
+        let mut other = Metric::new(MetricValue::Counter(1f64), None, 1f32);
+        other.set_unit(Some(MetricUnit::Count));
+        metric.accumulate_ref(&other).unwrap();
+        assert_eq!(metric.unit(), Some(&MetricUnit::Bytes));
+    }
+
+    #[test]
+    fn gauge_unset_tombstone() {
+        let mut gauge = Metric::<f64>::new(MetricValue::Gauge(100f64), Some(1), 1f32);
+        gauge.accumulate(Metric::gauge_unset(Some(2))).unwrap();
+        assert!(gauge.is_gauge_unset());
+        assert_eq!(gauge.value, MetricValue::Gauge(0f64));
+        assert_eq!(gauge.timestamp(), Some(2));
+        assert!(!gauge.semantically_eq(&Metric::new(MetricValue::Gauge(0f64), Some(2), 1f32)));
+
+        // deltas after the tombstone are counted from zero as an absolute value
+        let mut relayed = gauge.clone();
+        let delta = |value| Metric::from_statsd(&StatsdMetric::new(value, StatsdType::Gauge(Some(1)), None).unwrap(), 1, None).unwrap();
+        relayed.accumulate_ref(&delta(5f64)).unwrap();
+        assert_eq!(relayed.value, MetricValue::Gauge(5f64));
+        assert!(!relayed.is_gauge_unset());
+        assert!(!relayed.is_gauge_delta());
+
+        gauge
+            .accumulate_statsd(StatsdMetric::new(3f64, StatsdType::Gauge(Some(-1)), None).unwrap())
+            .unwrap();
+        assert_eq!(gauge.value, MetricValue::Gauge(-3f64));
+        assert!(!gauge.is_gauge_unset());
+
+        // a tombstone overrides pending deltas too
+        let mut pending = delta(1f64);
+        pending.accumulate(Metric::gauge_unset(None)).unwrap();
+        assert!(pending.is_gauge_unset());
+        assert!(!pending.is_gauge_delta());
+    }
+
+    #[test]
+    fn sampling_policy() {
+        let sampled = || Metric::new(MetricValue::Counter(1f64), None, 0.1);
+        let unsampled = || Metric::new(MetricValue::Counter(2f64), None, 1f32);
+
+        let mut metric = sampled();
+        assert!(metric.accumulate_with(unsampled(), SamplingPolicy::Error).is_err());
+        assert_eq!(metric, sampled());
+        metric.accumulate_with(sampled(), SamplingPolicy::Error).unwrap();
+        assert_eq!(metric.value, MetricValue::Counter(2f64));
+
+        let mut metric = sampled();
+        metric.accumulate_with(unsampled(), SamplingPolicy::Ignore).unwrap();
+        assert_eq!(metric.sampling, 0.1);
+
+        let mut metric = sampled();
+        metric.accumulate_with(unsampled(), SamplingPolicy::KeepMostPrecise).unwrap();
+        assert_eq!(metric.sampling, 1f32);
+        assert!((metric.as_counter().unwrap() - 12f64).abs() < 1e-6);
+
+        let mut metric = Metric::new(MetricValue::Counter(3f64), None, 0.5);
+        metric.accumulate_with(sampled(), SamplingPolicy::KeepMostPrecise).unwrap();
+        assert_eq!(metric.sampling, 0.5);
+        assert!((metric.as_counter().unwrap() - 8f64).abs() < 1e-6);
+
+        let mut metric = sampled();
+        metric.accumulate_with(unsampled(), SamplingPolicy::Normalize).unwrap();
+        assert_eq!(metric.sampling, 1f32);
+        assert!((metric.as_counter().unwrap() - 12f64).abs() < 1e-6);
+        assert_eq!(metric.update_counter, 11);
+
+        let mut histogram = Metric::<f64>::new(MetricValue::CustomHistogram(1, vec![(0f64, 2)]), None, 0.5);
+        histogram.normalize_sampling().unwrap();
+        assert_eq!(histogram.value, MetricValue::CustomHistogram(2, vec![(0f64, 4)]));
+
+        let mut set = Metric::<f64>::default_for(MetricTypeName::Set, Some(1f64)).unwrap();
+        assert!(set
+            .accumulate_with(Metric::new(MetricValue::Set(HashSet::new()), None, 0.5), SamplingPolicy::Normalize)
+            .is_err());
+
+        // nothing is changed if any of the metrics cannot be converted
+        let mut metric = sampled();
+        let sampled_set = Metric::<f64>::new(MetricValue::Set(HashSet::new()), None, 0.5);
+        assert!(metric.accumulate_with(sampled_set, SamplingPolicy::Normalize).is_err());
+        assert_eq!(metric, sampled());
+
+        let mut timer = Metric::<f64>::new(MetricValue::Timer(vec![1f64]), None, 0.25);
+        timer.resample(0.5).unwrap();
+        assert_eq!(timer.value, MetricValue::Timer(vec![1f64, 1f64]));
+        assert!(timer.resample(0.25).is_err());
+    }
 }
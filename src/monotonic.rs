@@ -0,0 +1,278 @@
+use std::fmt::Debug;
+
+use num_traits::{AsPrimitive, Float};
+use serde::{Deserialize, Serialize};
+
+use crate::metric::{FromF64, Metric, MetricError, MetricValue};
+use crate::parser::validate_line;
+
+/// How to treat a reading of a cumulative counter lower than the previous one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CounterWrap {
+    /// the source has restarted counting from zero
+    #[default]
+    Reset,
+    /// the source counter is 32-bit and has wrapped
+    Wrap32,
+    /// the source counter is 64-bit and has wrapped
+    Wrap64,
+}
+
+/// An update of monotonic counter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MonotonicUpdate {
+    /// an increment, statsd `c` type
+    Delta(u64),
+    /// a cumulative reading of the source counter, statsd `g` type, like interface byte counters
+    Reading(u64),
+}
+
+/// A counter keeping the exact `u64` total, unlike `MetricValue::Counter`, which loses precision
+/// over 2^53 when passed through `f64`, i.e. byte counters of fast interfaces.
+/// The total wraps around on `u64` overflow.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MonotonicCounter {
+    total: u64,
+    last: Option<u64>,
+    wrap: CounterWrap,
+    wrap_threshold: Option<u64>,
+    reset_threshold: Option<u64>,
+    zero_on_reset: bool,
+    resets: u64,
+}
+
+impl MonotonicCounter {
+    pub fn new(wrap: CounterWrap) -> Self {
+        Self { wrap, ..Default::default() }
+    }
+
+    /// A lower reading is only taken for a wrap if the increment it gives is not greater than
+    /// the threshold, otherwise it is checked for a reset. By default any lower reading wraps.
+    pub fn with_wrap_threshold(mut self, threshold: Option<u64>) -> Self {
+        self.wrap_threshold = threshold;
+        self
+    }
+
+    /// A lower reading which is not a wrap is only taken for a reset if it is not greater than
+    /// the threshold, because restarted sources count from zero. Greater readings are errors.
+    /// By default any such reading is a reset.
+    pub fn with_reset_threshold(mut self, threshold: Option<u64>) -> Self {
+        self.reset_threshold = threshold;
+        self
+    }
+
+    /// Adds nothing on reset instead of the new reading, so restarts do not make spikes
+    pub fn with_zero_on_reset(mut self, zero: bool) -> Self {
+        self.zero_on_reset = zero;
+        self
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// The last reading seen
+    pub fn last(&self) -> Option<u64> {
+        self.last
+    }
+
+    /// The number of wraps or resets detected
+    pub fn resets(&self) -> u64 {
+        self.resets
+    }
+
+    /// Adds the update, giving the increment applied. The first reading only sets the baseline.
+    /// A lower reading which is neither a wrap nor a reset is an error, it still becomes the new
+    /// baseline, but nothing is added.
+    pub fn update(&mut self, update: MonotonicUpdate) -> Result<u64, MetricError> {
+        let delta = match update {
+            MonotonicUpdate::Delta(delta) => delta,
+            MonotonicUpdate::Reading(reading) => match self.last.replace(reading) {
+                None => 0,
+                Some(last) if reading >= last => reading - last,
+                Some(last) => {
+                    let wrapped = match self.wrap {
+                        CounterWrap::Reset => None,
+                        // readings over 32 bits cannot come from a 32-bit counter, so this is a reset
+                        CounterWrap::Wrap32 if last > u64::from(u32::MAX) => None,
+                        CounterWrap::Wrap32 => Some(u64::from(u32::MAX) - last + reading + 1),
+                        CounterWrap::Wrap64 => Some((u64::MAX - last).wrapping_add(reading).wrapping_add(1)),
+                    };
+                    let delta = match wrapped {
+                        Some(delta) if self.wrap_threshold.is_none_or(|threshold| delta <= threshold) => delta,
+                        _ if self.reset_threshold.is_some_and(|threshold| reading > threshold) => {
+                            return Err(MetricError::CounterDrop(last, reading));
+                        }
+                        _ if self.zero_on_reset => 0,
+                        _ => reading,
+                    };
+                    self.resets += 1;
+                    delta
+                }
+            },
+        };
+        self.total = self.total.wrapping_add(delta);
+        Ok(delta)
+    }
+
+    /// Adds the total of other counter, i.e. the one received from a peer. The baseline reading is kept.
+    pub fn accumulate(&mut self, other: &MonotonicCounter) {
+        self.total = self.total.wrapping_add(other.total);
+        self.resets += other.resets;
+    }
+
+    /// Takes the total of the interval, keeping the baseline reading for the next one
+    pub fn take(&mut self) -> u64 {
+        self.resets = 0;
+        std::mem::take(&mut self.total)
+    }
+
+    /// The total as a counter metric. The float value loses precision for large totals,
+    /// the exact one is kept in `Metric::counter_total` while it is accumulated with other
+    /// monotonic counters and sent to peers
+    pub fn to_metric<F>(&self, timestamp: Option<u64>) -> Metric<F>
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<f64>,
+    {
+        Metric::new(MetricValue::Counter(F::from_f64(self.total as f64)), timestamp, 1f32).with_counter_total(self.total)
+    }
+}
+
+/// Parses a statsd line with an exact integer value, giving the name with tags and the update.
+/// Counters give deltas, gauges give readings. Sampling is not allowed, because sampled values
+/// cannot be exact.
+pub fn parse_monotonic(line: &[u8]) -> Result<(&[u8], MonotonicUpdate), &'static str> {
+    let valid = validate_line(line, usize::MAX)?;
+    let mut parts = line[valid.name_len + 1..].split(|c| *c == b'|');
+    let value = parts.next().unwrap_or_default();
+    let mtype = parts.next().unwrap_or_default();
+    if parts.next().is_some() {
+        return Err("monotonic counters cannot be sampled");
+    }
+
+    let signed = value.starts_with(b"+");
+    let digits = if signed { &value[1..] } else { value };
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return Err("value is not an unsigned integer");
+    }
+    let value = digits
+        .iter()
+        .try_fold(0u64, |acc, c| acc.checked_mul(10)?.checked_add(u64::from(c - b'0')))
+        .ok_or("value does not fit into 64 bits")?;
+
+    let update = match mtype {
+        b"c" => MonotonicUpdate::Delta(value),
+        b"g" if signed => return Err("gauge changes cannot be monotonic readings"),
+        b"g" => MonotonicUpdate::Reading(value),
+        _ => return Err("monotonic counter must be a counter or a gauge"),
+    };
+    Ok((&line[..valid.name_len], update))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::name::MetricName;
+    use crate::protocol::{fill_snapshot, read_snapshot};
+    use crate::protocol_v2_capnp::message;
+    use bytes::BytesMut;
+
+    #[test]
+    fn monotonic_readings() {
+        let mut counter = MonotonicCounter::new(CounterWrap::Wrap32);
+        assert_eq!(counter.update(MonotonicUpdate::Reading(u64::from(u32::MAX) - 10)).unwrap(), 0);
+        assert_eq!(counter.update(MonotonicUpdate::Reading(u64::from(u32::MAX) - 5)).unwrap(), 5);
+        assert_eq!(counter.update(MonotonicUpdate::Reading(4)).unwrap(), 10);
+        assert_eq!(counter.update(MonotonicUpdate::Delta(1)).unwrap(), 1);
+        assert_eq!(counter.total(), 16);
+        assert_eq!(counter.resets(), 1);
+        assert_eq!(counter.take(), 16);
+        assert_eq!(counter.total(), 0);
+        assert_eq!(counter.last(), Some(4));
+
+        let mut reset = MonotonicCounter::new(CounterWrap::Reset);
+        reset.update(MonotonicUpdate::Reading(1000)).unwrap();
+        assert_eq!(reset.update(MonotonicUpdate::Reading(7)).unwrap(), 7);
+
+        let mut wide = MonotonicCounter::new(CounterWrap::Wrap64);
+        wide.update(MonotonicUpdate::Reading(u64::MAX - 1)).unwrap();
+        assert_eq!(wide.update(MonotonicUpdate::Reading(1)).unwrap(), 3);
+
+        // precision is kept over 2^53
+        let mut big = MonotonicCounter::default();
+        big.update(MonotonicUpdate::Delta((1 << 60) + 1)).unwrap();
+        big.accumulate(&MonotonicCounter {
+            total: 1,
+            ..Default::default()
+        });
+        assert_eq!(big.total(), (1 << 60) + 2);
+    }
+
+    #[test]
+    fn monotonic_reset_thresholds() {
+        // a drop giving a wrap increment over the threshold is a reset
+        let mut counter = MonotonicCounter::new(CounterWrap::Wrap32).with_wrap_threshold(Some(100));
+        counter.update(MonotonicUpdate::Reading(u64::from(u32::MAX) - 10)).unwrap();
+        assert_eq!(counter.update(MonotonicUpdate::Reading(20)).unwrap(), 31);
+        assert_eq!(counter.update(MonotonicUpdate::Reading(10)).unwrap(), 10);
+        assert_eq!(counter.resets(), 2);
+
+        let mut counter = MonotonicCounter::new(CounterWrap::Reset).with_zero_on_reset(true);
+        counter.update(MonotonicUpdate::Reading(1000)).unwrap();
+        assert_eq!(counter.update(MonotonicUpdate::Reading(7)).unwrap(), 0);
+        assert_eq!(counter.update(MonotonicUpdate::Reading(9)).unwrap(), 2);
+        assert_eq!(counter.total(), 2);
+        assert_eq!(counter.resets(), 1);
+
+        // a drop to a reading over the threshold is neither a wrap nor a reset
+        let mut counter = MonotonicCounter::new(CounterWrap::Wrap64)
+            .with_wrap_threshold(Some(100))
+            .with_reset_threshold(Some(10));
+        counter.update(MonotonicUpdate::Reading(1000)).unwrap();
+        assert!(matches!(
+            counter.update(MonotonicUpdate::Reading(500)),
+            Err(MetricError::CounterDrop(1000, 500))
+        ));
+        assert_eq!(counter.last(), Some(500));
+        assert_eq!(counter.update(MonotonicUpdate::Reading(510)).unwrap(), 10);
+        assert_eq!(counter.update(MonotonicUpdate::Reading(5)).unwrap(), 5);
+        assert_eq!(counter.total(), 15);
+        assert_eq!(counter.resets(), 1);
+    }
+
+    #[test]
+    fn parse_monotonic_lines() {
+        assert_eq!(
+            parse_monotonic(b"if.bytes;host=a:18446744073709551615|g"),
+            Ok((&b"if.bytes;host=a"[..], MonotonicUpdate::Reading(u64::MAX)))
+        );
+        assert_eq!(parse_monotonic(b"requests:+5|c"), Ok((&b"requests"[..], MonotonicUpdate::Delta(5))));
+        assert!(parse_monotonic(b"if.bytes:18446744073709551616|g").is_err());
+        assert!(parse_monotonic(b"requests:5|c|@0.5").is_err());
+        assert!(parse_monotonic(b"requests:1.5|c").is_err());
+        assert!(parse_monotonic(b"requests:-1|c").is_err());
+        assert!(parse_monotonic(b"temp:+1|g").is_err());
+        assert!(parse_monotonic(b"latency:1|ms").is_err());
+    }
+
+    #[test]
+    fn monotonic_metric() {
+        let mut counter = MonotonicCounter::default();
+        counter.update(MonotonicUpdate::Delta((1 << 60) + 1)).unwrap();
+        let mut metric = counter.to_metric::<f64>(Some(10));
+        metric.accumulate(counter.to_metric(None)).unwrap();
+        assert_eq!(metric.counter_total(), Some((1 << 61) + 2));
+
+        let name = MetricName::new_untagged(BytesMut::from("if.bytes"));
+        let mut builder = capnp::message::Builder::new_default();
+        fill_snapshot(&mut builder.init_root::<message::Builder>(), std::iter::once((&name, &metric)), false);
+        let reader = builder.get_root_as_reader::<message::Reader>().unwrap();
+        let read = read_snapshot::<f64>(reader).unwrap();
+        assert_eq!(read, vec![(name, metric.clone())]);
+        assert_eq!(read[0].1.counter_total(), Some((1 << 61) + 2));
+
+        metric.accumulate(Metric::new(MetricValue::Counter(1f64), None, 1f32)).unwrap();
+        assert_eq!(metric.counter_total(), None);
+    }
+}
@@ -1,19 +1,22 @@
+use std::borrow::{Borrow, Cow};
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
 
 use bytes::{BufMut, Bytes, BytesMut};
 use num_traits::{AsPrimitive, Float};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::aggregate::Aggregate;
-use crate::metric::{FromF64, MetricTypeName};
+use crate::metric::{FromF64, MetricError, MetricTypeName};
 
 // TODO: Think error type. There is single possible error atm, so sort_tags returns () instead
 // TODO: Think if we need sorted tags in btreemap instead of string (at the moment of writing this we don't, because of allocation)
 // TODO: Handle repeating same tags i.e. gorets;a=b;e=b;a=b:...
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub enum TagFormat {
+    #[default]
     Graphite,
 }
 
@@ -36,6 +39,29 @@ pub fn find_tag_pos(name: &[u8], mode: TagFormat) -> Option<usize> {
     }
 }
 
+/// Splits the tag part of a name in Graphite format into tags like `key=value`
+pub(crate) fn tag_parts(tags: &[u8]) -> impl Iterator<Item = &[u8]> {
+    tags.split(|c| *c == b';').filter(|tag| !tag.is_empty())
+}
+
+/// Splits the tag into key and value, tags without value have it empty
+pub(crate) fn split_tag(tag: &[u8]) -> (&[u8], &[u8]) {
+    match tag.iter().position(|c| *c == b'=') {
+        Some(pos) => (&tag[..pos], &tag[pos + 1..]),
+        None => (tag, &tag[tag.len()..]),
+    }
+}
+
+/// Makes a name in Graphite format from a string, as names are written in rules and expressions,
+/// None for empty or bad names
+pub(crate) fn parse_name(name: &str) -> Option<MetricName> {
+    if name.is_empty() {
+        return None;
+    }
+    let mut intermediate = vec![0u8; name.len()];
+    MetricName::new(BytesMut::from(name), TagFormat::Graphite, &mut intermediate).ok()
+}
+
 /// Sorts tags inside name using intermediate buffer
 pub(crate) fn sort_tags(name: &mut [u8], mode: TagFormat, intermediate: &mut [u8], tag_pos: usize) -> Result<usize, ()> {
     use lazysort::Sorted;
@@ -85,16 +111,447 @@ pub(crate) fn sort_tags(name: &mut [u8], mode: TagFormat, intermediate: &mut [u8
     }
 }
 
+/// How a namespace prefix is joined with the metric name
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PrefixSeparator {
+    /// `ns.name`, like Graphite namespaces
+    #[default]
+    Dot,
+    /// `ns_name`, like Prometheus namespaces
+    Underscore,
+    /// the prefix is put right before the name as is
+    None,
+}
+
+impl PrefixSeparator {
+    fn as_bytes(self) -> &'static [u8] {
+        match self {
+            PrefixSeparator::Dot => b".",
+            PrefixSeparator::Underscore => b"_",
+            PrefixSeparator::None => b"",
+        }
+    }
+}
+
+/// What to do with names containing non-ASCII characters. Many backends, Graphite storage in
+/// particular, cannot work with such names, while some clients send UTF-8 ones
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnicodePolicy {
+    /// pass valid UTF-8 as is
+    #[default]
+    Allow,
+    /// consider names with non-ASCII characters bad
+    Reject,
+    /// replace Latin letters with diacritics and Cyrillic letters with ASCII ones, i.e. `é` with `e`
+    /// and `ж` with `zh`, other non-ASCII characters are replaced with `_`
+    Transliterate,
+    /// replace every non-ASCII byte with `%XX`, note that `%` itself is not encoded
+    PercentEncode,
+}
+
+fn transliterate_lowercase(c: char) -> Option<&'static str> {
+    Some(match c {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => "a",
+        'æ' => "ae",
+        'ç' | 'ć' | 'ĉ' | 'ċ' | 'č' => "c",
+        'ď' | 'đ' | 'ð' => "d",
+        'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => "e",
+        'ĝ' | 'ğ' | 'ġ' | 'ģ' => "g",
+        'ĥ' | 'ħ' => "h",
+        'ì' | 'í' | 'î' | 'ï' | 'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' => "i",
+        'ĵ' => "j",
+        'ķ' => "k",
+        'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => "l",
+        'ñ' | 'ń' | 'ņ' | 'ň' => "n",
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ŏ' | 'ő' => "o",
+        'œ' => "oe",
+        'ŕ' | 'ŗ' | 'ř' => "r",
+        'ś' | 'ŝ' | 'ş' | 'š' => "s",
+        'ß' => "ss",
+        'ţ' | 'ť' | 'ŧ' => "t",
+        'þ' => "th",
+        'ù' | 'ú' | 'û' | 'ü' | 'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => "u",
+        'ŵ' => "w",
+        'ý' | 'ÿ' | 'ŷ' => "y",
+        'ź' | 'ż' | 'ž' => "z",
+        'а' => "a",
+        'б' => "b",
+        'в' => "v",
+        'г' => "g",
+        'д' => "d",
+        'е' | 'ё' | 'э' => "e",
+        'ж' => "zh",
+        'з' => "z",
+        'и' => "i",
+        'й' | 'ы' => "y",
+        'к' => "k",
+        'л' => "l",
+        'м' => "m",
+        'н' => "n",
+        'о' => "o",
+        'п' => "p",
+        'р' => "r",
+        'с' => "s",
+        'т' => "t",
+        'у' => "u",
+        'ф' => "f",
+        'х' => "kh",
+        'ц' => "ts",
+        'ч' => "ch",
+        'ш' => "sh",
+        'щ' => "shch",
+        'ъ' | 'ь' => "",
+        'ю' => "yu",
+        'я' => "ya",
+        _ => return None,
+    })
+}
+
+impl UnicodePolicy {
+    /// Applies the policy to the name, only allocating if there is something to change.
+    /// Gives an error for non-ASCII names with `Reject` and for invalid UTF-8 with `Allow`
+    pub fn apply<'a>(&self, name: &'a [u8]) -> Result<Cow<'a, [u8]>, MetricError> {
+        if name.is_ascii() {
+            return Ok(Cow::Borrowed(name));
+        }
+        match self {
+            UnicodePolicy::Allow => std::str::from_utf8(name)
+                .map(|_| Cow::Borrowed(name))
+                .map_err(|_| MetricError::Name("name is not valid UTF-8")),
+            UnicodePolicy::Reject => Err(MetricError::Name("name is not ASCII")),
+            UnicodePolicy::Transliterate => {
+                let mut out = Vec::with_capacity(name.len());
+                for c in String::from_utf8_lossy(name).chars() {
+                    if c.is_ascii() {
+                        out.push(c as u8);
+                        continue;
+                    }
+                    let lower = c.to_lowercase().next().unwrap_or(c);
+                    match transliterate_lowercase(lower) {
+                        Some(ascii) if lower != c && !ascii.is_empty() => {
+                            out.push(ascii.as_bytes()[0].to_ascii_uppercase());
+                            out.extend_from_slice(&ascii.as_bytes()[1..]);
+                        }
+                        Some(ascii) => out.extend_from_slice(ascii.as_bytes()),
+                        None => out.push(b'_'),
+                    }
+                }
+                Ok(Cow::Owned(out))
+            }
+            UnicodePolicy::PercentEncode => {
+                let mut out = Vec::with_capacity(name.len() * 3);
+                for c in name {
+                    if c.is_ascii() {
+                        out.push(*c);
+                    } else {
+                        out.push(b'%');
+                        out.push(b"0123456789ABCDEF"[(c >> 4) as usize]);
+                        out.push(b"0123456789ABCDEF"[(c & 0xf) as usize]);
+                    }
+                }
+                Ok(Cow::Owned(out))
+            }
+        }
+    }
+}
+
+impl UnicodePolicy {
+    // the name to be written by encoders, None if it is rejected, `Allow` passes any name as is,
+    // since encoders deal with invalid UTF-8 themselves
+    pub(crate) fn encoded_name(self, name: &MetricName) -> Option<MetricName> {
+        match self {
+            UnicodePolicy::Allow => Some(name.clone()),
+            policy => name.with_unicode_policy(policy, &mut Vec::new()).ok(),
+        }
+    }
+}
+
+/// Lowercasing of names, so series differing only by case, like `Requests` and `requests`, become one.
+/// Only ASCII letters are changed, tag values are never touched
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CaseFolding {
+    /// lowercase the name part
+    #[serde(default)]
+    pub names: bool,
+
+    /// lowercase the keys of tags
+    #[serde(default)]
+    pub tag_keys: bool,
+}
+
+// bytes not allowed in Graphite tag values, and the escape character itself
+fn needs_escaping(c: u8) -> bool {
+    c == b';' || c == b'~' || c == b'%' || c.is_ascii_whitespace()
+}
+
+/// Percent-encodes the characters Graphite does not allow in tag values: `;`, `~` and whitespace,
+/// along with `%` itself, so any value survives the round trip with `unescape_tag_value`
+pub fn escape_tag_value(value: &[u8]) -> Cow<'_, [u8]> {
+    if !value.iter().any(|c| needs_escaping(*c)) {
+        return Cow::Borrowed(value);
+    }
+    let mut out = Vec::with_capacity(value.len() + 8);
+    for c in value {
+        if needs_escaping(*c) {
+            out.push(b'%');
+            out.push(b"0123456789ABCDEF"[(c >> 4) as usize]);
+            out.push(b"0123456789ABCDEF"[(c & 0xf) as usize]);
+        } else {
+            out.push(*c);
+        }
+    }
+    Cow::Owned(out)
+}
+
+// escapes the value like `escape_tag_value`, but keeps `%XX` sequences, uppercasing their hex digits
+fn canonical_tag_value(value: &[u8]) -> Cow<'_, [u8]> {
+    let mut out = Vec::with_capacity(value.len() + 8);
+    let mut idx = 0;
+    while idx < value.len() {
+        if let Some([b'%', hi, lo]) = value.get(idx..idx + 3) {
+            if hi.is_ascii_hexdigit() && lo.is_ascii_hexdigit() {
+                out.extend_from_slice(&[b'%', hi.to_ascii_uppercase(), lo.to_ascii_uppercase()]);
+                idx += 3;
+                continue;
+            }
+        }
+        out.extend_from_slice(&escape_tag_value(&value[idx..=idx]));
+        idx += 1;
+    }
+    if out == value {
+        Cow::Borrowed(value)
+    } else {
+        Cow::Owned(out)
+    }
+}
+
+/// Decodes `%XX` sequences made by `escape_tag_value`, the `%` not followed by two hex digits is kept as is
+pub fn unescape_tag_value(value: &[u8]) -> Cow<'_, [u8]> {
+    if !value.contains(&b'%') {
+        return Cow::Borrowed(value);
+    }
+    let hex = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+    let mut out = Vec::with_capacity(value.len());
+    let mut idx = 0;
+    while idx < value.len() {
+        let decoded = match value.get(idx..idx + 3) {
+            Some([b'%', hi, lo]) => hex(*hi).and_then(|hi| hex(*lo).map(|lo| (hi << 4) | lo)),
+            _ => None,
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                idx += 3;
+            }
+            None => {
+                out.push(value[idx]);
+                idx += 1;
+            }
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// What to do when both names have a tag with the same key and different values
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    /// keep the value of the name being merged into
+    #[default]
+    KeepOurs,
+    /// take the value of the other name
+    TakeTheirs,
+    /// fail the merge
+    Fail,
+}
+
+/// The keys of tags differing between two names, see `MetricName::diff_tags`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TagDiff<'a> {
+    /// keys only the other name has
+    pub added: Vec<&'a [u8]>,
+    /// keys only this name has
+    pub removed: Vec<&'a [u8]>,
+    /// keys having different values
+    pub changed: Vec<&'a [u8]>,
+}
+
+impl<'a> TagDiff<'a> {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
 /// Represents a metric name as a buffer containing the full metric name including tags.
 /// Also provides methods to work with tags.
-#[derive(Debug, Eq, Clone, PartialEq, Hash)]
+///
+/// The buffer is shared between clones, so cloning is cheap. Names are never changed in place:
+/// all the mutations are copy-on-write, leaving other clones untouched.
+///
+/// Tag position may be found lazily, when tags are accessed, see `new_lazy`.
+///
+/// Names also remember if their tags are known to be sorted, so `canonicalize` costs nothing
+/// for names that were already sorted or checked.
+#[derive(Debug, Clone)]
 pub struct MetricName {
     pub name: Bytes,
-    pub(crate) tag_pos: Option<usize>,
+    tag_pos: Option<usize>,
+    tags_found: bool,
+    canonical: bool,
     //pub(crate) tag_format: TagFormat,  // TODO we may need this in future
     //pub tags: BTreeMap<BytesMut, BytesMut>, // we may need btreemap to have tags sorted
 }
 
+/// A name that maps keyed by `MetricName` can be searched with, through `&dyn NameKey`,
+/// without making an owned name
+pub trait NameKey {
+    /// the full name with tags
+    fn key(&self) -> &[u8];
+}
+
+impl NameKey for MetricName {
+    fn key(&self) -> &[u8] {
+        &self.name
+    }
+}
+
+impl<'a> Borrow<dyn NameKey + 'a> for MetricName {
+    fn borrow(&self) -> &(dyn NameKey + 'a) {
+        self
+    }
+}
+
+// must be the same as for MetricName
+impl Hash for dyn NameKey + '_ {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
+
+impl PartialEq for dyn NameKey + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for dyn NameKey + '_ {}
+
+/// A name compared and hashed by the name part only, ignoring tags, for the bookkeeping done per name
+/// rather than per series, like quotas or type registries. Maps keyed by it can be searched with
+/// the name without tags as `&[u8]`
+#[derive(Debug, Clone)]
+pub struct NameOnly(pub MetricName);
+
+impl PartialEq for NameOnly {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.name_without_tags() == other.0.name_without_tags()
+    }
+}
+
+impl Eq for NameOnly {}
+
+impl Hash for NameOnly {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.name_without_tags().hash(state);
+    }
+}
+
+impl Borrow<[u8]> for NameOnly {
+    fn borrow(&self) -> &[u8] {
+        self.0.name_without_tags()
+    }
+}
+
+/// A metric name borrowing the bytes from a buffer provided by the caller, i.e. an arena where
+/// names of the whole packet are kept. Most of the updates are for the series already existing
+/// in a cache, so the owned `MetricName` only has to be made for the new ones, see `NameKey`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetricNameRef<'a> {
+    name: &'a [u8],
+    tag_pos: Option<usize>,
+}
+
+impl<'a> MetricNameRef<'a> {
+    /// Sorts tags in place, the requirements for `intermediate` are the same as for `MetricName::new`
+    pub fn new<B: AsMut<[u8]>>(name: &'a mut [u8], mode: TagFormat, intermediate: &mut B) -> Result<Self, MetricError> {
+        let tag_pos = find_tag_pos(name, mode);
+        let len = match tag_pos {
+            Some(pos) => sort_tags(name, mode, intermediate.as_mut(), pos).map_err(|()| MetricError::Name("intermediate buffer is too small for tags"))?,
+            None => name.len(),
+        };
+        let name: &'a [u8] = name;
+        Ok(Self::from_raw_parts(&name[..len], tag_pos))
+    }
+
+    /// Assemble name from internal parts *without checks*, see `MetricName::from_raw_parts`
+    pub fn from_raw_parts(name: &'a [u8], tag_pos: Option<usize>) -> Self {
+        Self { name, tag_pos }
+    }
+
+    /// returns slice with full name, including tags
+    pub fn name_with_tags(&self) -> &'a [u8] {
+        self.name
+    }
+
+    /// returns only name, without tags
+    pub fn name_without_tags(&self) -> &'a [u8] {
+        &self.name[..self.tag_pos.unwrap_or(self.name.len())]
+    }
+
+    /// Copies the bytes to make an owned name
+    pub fn to_owned_name(&self) -> MetricName {
+        MetricName::from_raw_parts(Bytes::copy_from_slice(self.name), self.tag_pos)
+    }
+}
+
+impl<'a> NameKey for MetricNameRef<'a> {
+    fn key(&self) -> &[u8] {
+        self.name
+    }
+}
+
+// the tag position is defined by the name bytes, so only them are compared and hashed,
+// which also saves lazy names from searching for tags on every lookup
+impl PartialEq for MetricName {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for MetricName {}
+
+impl Hash for MetricName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+    }
+}
+
+/// Names are serialized as strings including tags
+impl Serialize for MetricName {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&String::from_utf8_lossy(&self.name))
+    }
+}
+
+/// Names are deserialized from strings in the default tag format, see `deserialize_with_format`
+/// for other formats
+impl<'de> Deserialize<'de> for MetricName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_with_format(TagFormat::default(), deserializer)
+    }
+}
+
+/// Deserializes name from string, sorting tags in the specified format.
+/// May be used in `#[serde(deserialize_with)]` wrappers
+pub fn deserialize_with_format<'de, D: Deserializer<'de>>(mode: TagFormat, deserializer: D) -> Result<MetricName, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    let mut intermediate = vec![0u8; name.len()];
+    MetricName::new(BytesMut::from(&name[..]), mode, &mut intermediate).map_err(|()| serde::de::Error::custom("bad metric name"))
+}
+
 impl MetricName {
     pub fn new<B: AsMut<[u8]>>(mut name: BytesMut, mode: TagFormat, intermediate: &mut B) -> Result<Self, ()> {
         let tag_pos = find_tag_pos(&name[..], mode);
@@ -104,7 +561,7 @@ impl MetricName {
         match tag_pos {
             // tag position was not found, so no tags
             // but it is ok since we have nothing to sort
-            None => return Ok(Self { name: name.freeze(), tag_pos }),
+            None => return Ok(Self::from_sorted_parts(name.freeze(), tag_pos)),
             Some(pos) => {
                 let intermediate: &mut [u8] = intermediate.as_mut();
                 let newlen = sort_tags(&mut name[..], mode, intermediate, pos)?;
@@ -112,24 +569,109 @@ impl MetricName {
             }
         };
 
-        Ok(Self {
-            name: name.freeze(),
-            tag_pos, /*tag_format*/
-        })
+        Ok(Self::from_sorted_parts(name.freeze(), tag_pos /*tag_format*/))
     }
 
     /// Convenience method to create metric that is for sure has no tags in any format
     pub fn new_untagged(name: BytesMut) -> Self {
+        Self::from_sorted_parts(name.freeze(), None)
+    }
+
+    /// Assemble name from internal parts *without checks*. Tags should be sorted, tag position must
+    /// be found according to required format. Names from other sources, like peers, may have tags
+    /// in any order, so the order is checked when the canonical form is needed, see `canonicalize`
+    pub fn from_raw_parts(name: Bytes, tag_pos: Option<usize>) -> Self {
+        Self {
+            name,
+            tag_pos,
+            tags_found: true,
+            canonical: false,
+        }
+    }
+
+    /// Same as `from_raw_parts`, for the names with tags sorted by the caller
+    pub(crate) fn from_sorted_parts(name: Bytes, tag_pos: Option<usize>) -> Self {
+        Self {
+            name,
+            tag_pos,
+            tags_found: true,
+            canonical: true,
+        }
+    }
+
+    /// Makes the name from the buffer with tags in any order starting at `tag_pos`, sorting them.
+    /// `intermediate` grows if required, so it should be reused between calls
+    pub(crate) fn from_unsorted_tags(mut name: BytesMut, tag_pos: Option<usize>, intermediate: &mut Vec<u8>) -> Self {
+        if let Some(pos) = tag_pos {
+            if intermediate.len() < name.len() - pos {
+                intermediate.resize(name.len() - pos, 0);
+            }
+            match sort_tags(&mut name[..], TagFormat::Graphite, intermediate, pos) {
+                Ok(len) => name.truncate(len),
+                // not possible with the buffer resized, but the name must not be marked sorted anyway
+                Err(()) => return Self::from_raw_parts(name.freeze(), tag_pos),
+            }
+        }
+        Self::from_sorted_parts(name.freeze(), tag_pos)
+    }
+
+    /// Creates a name without searching for tags. Tag position is only found when tags are
+    /// accessed, so the names that are never inspected, like the relayed ones, don't pay for it.
+    /// Tags must be in graphite format, use `canonicalize` if they may be not sorted
+    pub fn new_lazy(name: Bytes) -> Self {
         Self {
-            name: name.freeze(),
+            name,
             tag_pos: None,
+            tags_found: false,
+            canonical: false,
         }
     }
 
-    /// Assemble name from internal parts *without checks*. Tags must be sorted, tag position must
-    /// be found according to required format
-    pub fn from_raw_parts(name: Bytes, tag_pos: Option<usize>) -> Self {
-        Self { name, tag_pos }
+    /// Finds the tag position if it was not found yet and caches it
+    pub fn find_tags(&mut self) -> Option<usize> {
+        if !self.tags_found {
+            self.tag_pos = find_tag_pos(&self.name, TagFormat::Graphite);
+            self.tags_found = true;
+        }
+        self.tag_pos
+    }
+
+    /// Position of the semicolon starting the tags. For lazy names it is searched on each call
+    /// until `find_tags` caches it
+    pub(crate) fn tag_pos(&self) -> Option<usize> {
+        if self.tags_found {
+            self.tag_pos
+        } else {
+            find_tag_pos(&self.name, TagFormat::Graphite)
+        }
+    }
+
+    /// Changes the name using the provided closure. The buffer is changed in place when no other
+    /// clones of the name share it, otherwise it is copied before the change, so the clones keep
+    /// pointing to the old one. Tags are sorted and tag position is searched again after the change.
+    /// If intermediate buffer is too small for sorting, the changed name is kept with tags unsorted
+    pub fn make_mut<B, M, R>(&mut self, mode: TagFormat, intermediate: &mut B, f: M) -> Result<R, MetricError>
+    where
+        B: AsMut<[u8]>,
+        M: FnOnce(&mut BytesMut) -> R,
+    {
+        let mut name = match std::mem::take(&mut self.name).try_into_mut() {
+            Ok(name) => name,
+            Err(shared) => BytesMut::from(&shared[..]),
+        };
+        let result = f(&mut name);
+        let tag_pos = find_tag_pos(&name[..], mode);
+        if let Some(pos) = tag_pos {
+            match sort_tags(&mut name[..], mode, intermediate.as_mut(), pos) {
+                Ok(len) => name.truncate(len),
+                Err(()) => {
+                    *self = Self::from_raw_parts(name.freeze(), tag_pos);
+                    return Err(MetricError::Name("intermediate buffer is too small for tags"));
+                }
+            }
+        }
+        *self = Self::from_sorted_parts(name.freeze(), tag_pos);
+        Ok(result)
     }
 
     // TODO example
@@ -142,20 +684,117 @@ impl MetricName {
     //self.tag_pos.is_some()
     // }
 
+    /// Gives the name with the namespace prefix joined by dot, see `with_prefix_separated`
+    pub fn with_prefix(&self, ns: &[u8]) -> Self {
+        self.with_prefix_separated(ns, PrefixSeparator::Dot)
+    }
+
+    /// Gives the name with the namespace prefix put before it, the tags are kept as is.
+    /// Trailing separators of the prefix are not repeated, so both `ns` and `ns.` give `ns.name`,
+    /// an empty prefix leaves the name unchanged
+    pub fn with_prefix_separated(&self, ns: &[u8], separator: PrefixSeparator) -> Self {
+        let sep = separator.as_bytes();
+        let mut ns = ns;
+        while !sep.is_empty() && ns.ends_with(sep) {
+            ns = &ns[..ns.len() - sep.len()];
+        }
+        if ns.is_empty() {
+            return self.clone();
+        }
+
+        let mut buf = BytesMut::with_capacity(ns.len() + sep.len() + self.name.len());
+        buf.put_slice(ns);
+        buf.put_slice(sep);
+        buf.put_slice(&self.name);
+        let tag_pos = self.tag_pos().map(|pos| pos + ns.len() + sep.len());
+        // the tags are the same, so they are sorted if they were
+        let mut name = Self::from_raw_parts(buf.freeze(), tag_pos);
+        name.canonical = self.canonical;
+        name
+    }
+
+    /// Applies the Unicode policy to the name, tags are sorted again if they were changed
+    pub fn with_unicode_policy(&self, policy: UnicodePolicy, intermediate: &mut Vec<u8>) -> Result<Self, MetricError> {
+        match policy.apply(&self.name)? {
+            Cow::Borrowed(_) => Ok(self.clone()),
+            Cow::Owned(name) => {
+                let tag_pos = find_tag_pos(&name, TagFormat::Graphite);
+                Ok(Self::from_unsorted_tags(BytesMut::from(&name[..]), tag_pos, intermediate))
+            }
+        }
+    }
+
+    /// Lowercases the name and/or tag keys, tags are sorted again if keys were changed. The name is
+    /// only copied if there is something to change
+    pub fn fold_case(&self, folding: CaseFolding, intermediate: &mut Vec<u8>) -> Self {
+        let fold_name = folding.names && self.name_without_tags().iter().any(u8::is_ascii_uppercase);
+        let fold_keys = folding.tag_keys && self.tags().any(|(key, _)| key.iter().any(u8::is_ascii_uppercase));
+        if !fold_name && !fold_keys {
+            return self.clone();
+        }
+
+        let mut name = BytesMut::from(&self.name[..]);
+        let tag_pos = self.tag_pos();
+        let name_len = tag_pos.unwrap_or(name.len());
+        if fold_name {
+            name[..name_len].make_ascii_lowercase();
+        }
+        if fold_keys {
+            let mut in_key = false;
+            for c in name[name_len..].iter_mut() {
+                match *c {
+                    b';' => in_key = true,
+                    b'=' => in_key = false,
+                    _ if in_key => c.make_ascii_lowercase(),
+                    _ => {}
+                }
+            }
+        }
+        Self::from_unsorted_tags(name, tag_pos, intermediate)
+    }
+
+    /// Index of the shard this name belongs to, when names are split into `shards` parts by hash.
+    /// The hash is not randomized, so the index is the same between runs of the same build.
+    /// Zero shards are taken as one
+    pub fn shard_index(&self, shards: usize) -> usize {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        self.hash(&mut hasher);
+        (hasher.finish() % shards.max(1) as u64) as usize
+    }
+
     /// true if metric is tagged
     pub fn has_tags(&mut self) -> bool {
-        self.tag_pos.is_some()
+        self.find_tags().is_some()
     }
 
     /// returns only name, without tags, considers tag position was already found before
     pub fn name_without_tags(&self) -> &[u8] {
-        if let Some(pos) = self.tag_pos {
+        if let Some(pos) = self.tag_pos() {
             &self.name[..pos]
         } else {
             &self.name[..]
         }
     }
 
+    /// The name part as a separate name. The buffer is shared, so this is cheap and the result can
+    /// be kept as a map key
+    pub fn without_tags(&self) -> Self {
+        match self.tag_pos() {
+            Some(pos) => Self::from_sorted_parts(self.name.slice(..pos), None),
+            None => Self::from_sorted_parts(self.name.clone(), None),
+        }
+    }
+
+    /// true if the names are the same ignoring tags
+    pub fn same_name(&self, other: &MetricName) -> bool {
+        self.name_without_tags() == other.name_without_tags()
+    }
+
+    /// iterates over dot-separated segments of the name without tags
+    pub fn segments(&self) -> impl Iterator<Item = &[u8]> {
+        self.name_without_tags().split(|c| *c == b'.')
+    }
+
     /// returns slice with full name, including tags
     pub fn name_with_tags(&self) -> &[u8] {
         &self.name[..]
@@ -164,30 +803,205 @@ impl MetricName {
     /// returns slice with only tags, includes leading semicolon, expects tag position was already
     /// found
     pub fn tags_without_name(&self) -> &[u8] {
-        if let Some(pos) = self.tag_pos {
+        if let Some(pos) = self.tag_pos() {
             &self.name[pos..]
         } else {
             &[]
         }
     }
 
+    /// iterates over tags as key-value pairs, tags without value have it empty
+    pub fn tags(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        self.tag_parts().map(split_tag)
+    }
+
+    /// returns the value of the first tag with the key specified
+    pub fn tag_value(&self, key: &[u8]) -> Option<&[u8]> {
+        self.tags().find(|(k, _)| *k == key).map(|(_, v)| v)
+    }
+
+    /// returns the value of the first tag with the key specified, decoding the escaped characters,
+    /// see `escape_tag_value`
+    pub fn tag_value_unescaped(&self, key: &[u8]) -> Option<Cow<'_, [u8]>> {
+        self.tag_value(key).map(unescape_tag_value)
+    }
+
+    /// Gives the name with the characters `escape_tag_value` escapes escaped in tag values, so values
+    /// written with and without escaping, or with lowercase hex digits, make the same name.
+    /// Existing `%XX` sequences are kept, the `%` not starting one is escaped itself
+    pub fn escape_tag_values(&self, intermediate: &mut Vec<u8>) -> Self {
+        let escaped = |(_, value): (&[u8], &[u8])| !value.iter().any(|c| needs_escaping(*c)) || matches!(canonical_tag_value(value), Cow::Borrowed(_));
+        if self.tags().all(escaped) {
+            return self.clone();
+        }
+
+        let mut name = BytesMut::from(self.name_without_tags());
+        for tag in self.tag_parts() {
+            name.extend_from_slice(b";");
+            match tag.iter().position(|c| *c == b'=') {
+                Some(pos) => {
+                    name.extend_from_slice(&tag[..=pos]);
+                    name.extend_from_slice(&canonical_tag_value(&tag[pos + 1..]));
+                }
+                None => name.extend_from_slice(tag),
+            }
+        }
+        let tag_pos = self.name_without_tags().len();
+        Self::from_unsorted_tags(name, Some(tag_pos), intermediate)
+    }
+
+    // tags as they are in the name, like `key=value`
+    fn tag_parts(&self) -> impl Iterator<Item = &[u8]> {
+        tag_parts(self.tags_without_name())
+    }
+
+    /// Gives the name with the tags of the other name added, the name part is kept. The new name is
+    /// built in `buf`, so it should be reused between calls. Fails only on conflict with `ConflictPolicy::Fail`
+    pub fn merge_tags(&self, other: &MetricName, policy: ConflictPolicy, buf: &mut BytesMut) -> Result<Self, MetricError> {
+        let key = |part: &[u8]| part.split(|c| *c == b'=').next().unwrap_or(part).len();
+        let mut parts = Vec::with_capacity(self.tags().count() + other.tags().count());
+        for part in self.tag_parts() {
+            let (ours, theirs) = (self.tag_value(&part[..key(part)]), other.tag_value(&part[..key(part)]));
+            match theirs {
+                Some(theirs) if Some(theirs) != ours && policy == ConflictPolicy::Fail => return Err(MetricError::Name("conflicting tag values")),
+                Some(_) if policy == ConflictPolicy::TakeTheirs => {}
+                _ => parts.push(part),
+            }
+        }
+        for part in other.tag_parts() {
+            if policy == ConflictPolicy::TakeTheirs || self.tag_value(&part[..key(part)]).is_none() {
+                parts.push(part);
+            }
+        }
+        if parts.is_empty() {
+            return Ok(self.clone());
+        }
+        parts.sort_unstable();
+        parts.dedup();
+
+        let base = self.name_without_tags();
+        buf.reserve(base.len() + parts.iter().map(|part| part.len() + 1).sum::<usize>());
+        buf.put_slice(base);
+        for part in parts {
+            buf.put_u8(b';');
+            buf.put_slice(part);
+        }
+        Ok(Self::from_sorted_parts(buf.split().freeze(), Some(base.len())))
+    }
+
+    /// Compares the tags of the names, `added` are the keys the other name has in addition to this one
+    pub fn diff_tags<'a>(&'a self, other: &'a MetricName) -> TagDiff<'a> {
+        let mut diff = TagDiff::default();
+        for (key, value) in self.tags() {
+            match other.tag_value(key) {
+                None => diff.removed.push(key),
+                Some(other) if other != value => diff.changed.push(key),
+                Some(_) => {}
+            }
+        }
+        diff.added = other.tags().filter(|(key, _)| self.tag_value(key).is_none()).map(|(key, _)| key).collect();
+        diff
+    }
+
+    fn tags_sorted(&self) -> bool {
+        let mut prev: Option<&[u8]> = None;
+        self.canonical || self.tag_parts().all(|tag| prev.replace(tag).map(|prev| prev <= tag).unwrap_or(true))
+    }
+
+    /// true if tags are sorted, the result is cached for the names known to be sorted only,
+    /// see `canonicalize`
+    pub fn is_canonical(&self) -> bool {
+        self.tags_sorted()
+    }
+
+    /// Sorts the tags if they are not sorted yet, copying the buffer. The names made by `new` or
+    /// the parser are known to be sorted, so this is a no-op for them, for others the check is
+    /// only done once. Returns true if the name was changed
+    pub fn canonicalize(&mut self) -> bool {
+        if self.canonical {
+            return false;
+        }
+        let tag_pos = self.find_tags();
+        if self.tags_sorted() {
+            self.canonical = true;
+            return false;
+        }
+        let mut buf = BytesMut::with_capacity(self.name.len());
+        self.canonical_parts(|part| buf.put_slice(part));
+        *self = Self::from_sorted_parts(buf.freeze(), tag_pos);
+        true
+    }
+
+    // feeds the canonical form of the name to the closure: the name, then tags in sorted order,
+    // each with a leading semicolon, like MetricName::new makes them
+    fn canonical_parts<C: FnMut(&[u8])>(&self, mut f: C) {
+        f(self.name_without_tags());
+        let tags = || self.tag_parts();
+        if self.tags_sorted() {
+            tags().for_each(|tag| {
+                f(b";");
+                f(tag)
+            });
+        } else {
+            let mut tags = tags().collect::<Vec<_>>();
+            tags.sort_unstable();
+            tags.into_iter().for_each(|tag| {
+                f(b";");
+                f(tag)
+            });
+        }
+    }
+
+    /// A 64-bit FNV-1a hash of the name with tags sorted. Unlike `Hash`, the value is guaranteed to
+    /// stay the same across crate versions and platforms, so it can be stored, i.e. in deduplication
+    /// tables, or used for routing between nodes running different versions
+    pub fn fingerprint(&self) -> u64 {
+        let mut hash = 0xcbf2_9ce4_8422_2325u64;
+        self.canonical_parts(|part| {
+            for b in part {
+                hash ^= u64::from(*b);
+                hash = hash.wrapping_mul(0x0100_0000_01b3);
+            }
+        });
+        hash
+    }
+
+    /// A 128-bit FNV-1a hash with the same stability guarantees as `fingerprint`, for the cases where
+    /// collisions of 64-bit hash are not acceptable
+    pub fn fingerprint128(&self) -> u128 {
+        let mut hash = 0x6c62_272e_07bb_0142_62b8_2175_6295_c58du128;
+        self.canonical_parts(|part| {
+            for b in part {
+                hash ^= u128::from(*b);
+                hash = hash.wrapping_mul(0x0000_0000_0100_0000_0000_0000_0000_013b);
+            }
+        });
+        hash
+    }
+
     /// returns length of tags field, including leading semicolon
     /// considers tag position was already found before
     pub fn tags_len(&self) -> usize {
-        if let Some(pos) = self.tag_pos {
+        if let Some(pos) = self.tag_pos() {
             self.name.len() - pos
         } else {
             0
         }
     }
 
+    /// Approximate number of bytes occupied by the name. The buffer is counted fully even if it
+    /// is shared with other names
+    pub fn approx_mem_size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.name.len()
+    }
+
     /// put name into buffer with suffix added with dot after name
     fn put_with_suffix(&self, buf: &mut BytesMut, suffix: &[u8], with_tags: bool) {
         let suflen = suffix.len();
         let namelen = self.name.len();
 
         buf.reserve(namelen + suflen + 1);
-        match self.tag_pos {
+        match self.tag_pos() {
             None => {
                 buf.put_slice(&self.name);
                 if suflen > 0 {
@@ -221,7 +1035,7 @@ impl MetricName {
         }
 
         buf.reserve(addlen + 1); // 1 is for `;`
-        match self.tag_pos {
+        match self.tag_pos() {
             None => {
                 // easy case: no tags
                 if !only_tag {
@@ -328,7 +1142,7 @@ impl MetricName {
         // the same applies to tag name and value: we only need them when aggregating to tags
 
         match dest {
-            AggregationDestination::Smart if self.tag_pos.is_none() => {
+            AggregationDestination::Smart if self.tag_pos().is_none() => {
                 // metric is untagged, add aggregate to name
                 self.put_with_suffix(buf, postfix, true)
             }
@@ -365,7 +1179,12 @@ impl MetricName {
     {
         let naming = options.get(&(name, agg)).ok_or(())?;
 
-        self.put_full(buf, naming.destination, &naming.postfix, &naming.prefix, &naming.tag, &naming.tag_value);
+        if naming.escape_tag_values {
+            let escaped = self.escape_tag_values(&mut Vec::new());
+            escaped.put_full(buf, naming.destination, &naming.postfix, &naming.prefix, &naming.tag, &naming.tag_value);
+        } else {
+            self.put_full(buf, naming.destination, &naming.postfix, &naming.prefix, &naming.tag, &naming.tag_value);
+        }
 
         Ok(())
     }
@@ -388,6 +1207,11 @@ pub struct NamingOptions {
 
     /// Where to put aggregate postfix
     pub destination: AggregationDestination,
+
+    /// escape tag values with `MetricName::escape_tag_values`, so values with characters Graphite does
+    /// not allow, i.e. coming from other protocols, can be restored on the receiving side
+    #[serde(default)]
+    pub escape_tag_values: bool,
 }
 
 #[cfg(test)]
@@ -408,6 +1232,7 @@ mod tests {
             tag_value: Bytes::copy_from_slice(s),
             postfix: Bytes::copy_from_slice(s),
             destination: AggregationDestination::Smart,
+            escape_tag_values: false,
         }
     }
 
@@ -423,6 +1248,296 @@ mod tests {
         );
     }
 
+    #[test]
+    fn metric_name_approx_mem_size() {
+        let name = new_name_graphite(b"gorets;a=a");
+        assert_eq!(name.approx_mem_size(), std::mem::size_of::<MetricName>() + 10);
+    }
+
+    #[test]
+    fn metric_name_tags() {
+        let name = new_name_graphite(b"gorets;b=2;a=1;flag");
+        assert_eq!(name.tags().collect::<Vec<_>>(), vec![(&b"a"[..], &b"1"[..]), (b"b", b"2"), (b"flag", b"")]);
+        assert_eq!(name.tag_value(b"b"), Some(&b"2"[..]));
+        assert_eq!(name.tag_value(b"c"), None);
+        assert_eq!(new_name_graphite(b"gorets").tags().count(), 0);
+    }
+
+    #[test]
+    fn metric_name_tag_escaping() {
+        let value = &b"a b;c~d%e"[..];
+        let escaped = escape_tag_value(value);
+        assert_eq!(&escaped[..], b"a%20b%3Bc%7Ed%25e");
+        assert_eq!(&unescape_tag_value(&escaped)[..], value);
+        assert!(matches!(escape_tag_value(b"plain=value"), Cow::Borrowed(_)));
+        assert_eq!(&unescape_tag_value(b"100%%zz%4")[..], b"100%%zz%4");
+
+        let mut name = b"gorets;path=".to_vec();
+        name.extend_from_slice(&escaped);
+        let name = new_name_graphite(&name);
+        assert_eq!(name.tag_value_unescaped(b"path").as_deref(), Some(value));
+        assert_eq!(name.tag_value_unescaped(b"none"), None);
+
+        let mut intermediate = Vec::new();
+        let name = new_name_graphite(b"gorets;path=a b%3b;flag;pct=5%").escape_tag_values(&mut intermediate);
+        assert_eq!(&name.name[..], b"gorets;flag;path=a%20b%3B;pct=5%25");
+        assert_eq!(name.tag_value_unescaped(b"path").as_deref(), Some(&b"a b;"[..]));
+        assert_eq!(name.escape_tag_values(&mut intermediate), name);
+
+        // escaping on encode keeps the sequences already escaped
+        let mut opts = HashMap::new();
+        let mut naming = default_options(b"max");
+        naming.escape_tag_values = true;
+        opts.insert((MetricTypeName::Timer, Aggregate::<f64>::Max), naming);
+        let mut buf = BytesMut::new();
+        let name = new_name_graphite(b"gorets;path=a b~c;pct=5%25");
+        name.put_with_options(&mut buf, MetricTypeName::Timer, Aggregate::Max, &opts).unwrap();
+        assert_eq!(&buf[..], b"gorets;aggregate=max;path=a%20b%7Ec;pct=5%25");
+    }
+
+    #[test]
+    fn metric_name_merge_diff_tags() {
+        let ours = new_name_graphite(b"requests;env=prod;host=a;flag");
+        let theirs = new_name_graphite(b"other;host=b;dc=eu");
+        let mut buf = BytesMut::new();
+
+        let merged = ours.merge_tags(&theirs, ConflictPolicy::KeepOurs, &mut buf).unwrap();
+        assert_eq!(merged, new_name_graphite(b"requests;dc=eu;env=prod;flag;host=a"));
+        let merged = ours.merge_tags(&theirs, ConflictPolicy::TakeTheirs, &mut buf).unwrap();
+        assert_eq!(merged, new_name_graphite(b"requests;dc=eu;env=prod;flag;host=b"));
+        assert!(matches!(ours.merge_tags(&theirs, ConflictPolicy::Fail, &mut buf), Err(MetricError::Name(_))));
+        assert_eq!(ours.merge_tags(&ours, ConflictPolicy::Fail, &mut buf).unwrap(), ours);
+        let untagged = new_name_graphite(b"requests");
+        assert_eq!(untagged.merge_tags(&untagged, ConflictPolicy::Fail, &mut buf).unwrap(), untagged);
+        assert_eq!(
+            untagged.merge_tags(&theirs, ConflictPolicy::Fail, &mut buf).unwrap(),
+            new_name_graphite(b"requests;dc=eu;host=b")
+        );
+
+        let diff = ours.diff_tags(&theirs);
+        assert_eq!(diff.added, vec![&b"dc"[..]]);
+        assert_eq!(diff.removed, vec![&b"env"[..], b"flag"]);
+        assert_eq!(diff.changed, vec![&b"host"[..]]);
+        assert!(ours.diff_tags(&ours).is_empty());
+    }
+
+    #[test]
+    fn metric_name_canonicalize() {
+        let mut name = new_name_graphite(b"gorets;b=b;a=a");
+        assert!(name.is_canonical());
+        assert!(!name.canonicalize());
+
+        let mut lazy = MetricName::new_lazy(Bytes::from_static(b"gorets;b=b;;a=a"));
+        assert!(!lazy.is_canonical());
+        let shared = lazy.clone();
+        assert!(lazy.canonicalize());
+        assert_eq!(lazy, name);
+        assert!(lazy.canonical);
+        assert!(!lazy.canonicalize());
+        // clones are untouched
+        assert_eq!(&shared.name[..], b"gorets;b=b;;a=a");
+
+        let mut lazy = MetricName::new_lazy(Bytes::from_static(b"gorets;a=a"));
+        let ptr = lazy.name.as_ptr();
+        assert!(!lazy.canonicalize());
+        assert!(lazy.canonical);
+        assert_eq!(lazy.name.as_ptr(), ptr);
+    }
+
+    #[test]
+    fn metric_name_ref() {
+        let mut arena = b"gorets;b=b;a=a;bobets".to_vec();
+        let (first, second) = arena.split_at_mut(15);
+        let mut intermediate = vec![0u8; 16];
+        let name = MetricNameRef::new(first, TagFormat::Graphite, &mut intermediate).unwrap();
+        let other = MetricNameRef::new(second, TagFormat::Graphite, &mut intermediate).unwrap();
+        assert_eq!(name.name_with_tags(), b"gorets;a=a;b=b");
+        assert_eq!(name.name_without_tags(), b"gorets");
+        assert_eq!(other.name_with_tags(), b"bobets");
+
+        let owned = new_name_graphite(b"gorets;a=a;b=b");
+        assert_eq!(name.to_owned_name(), owned);
+        let mut map = HashMap::new();
+        map.insert(owned, 1);
+        assert_eq!(map.get(&name as &dyn NameKey), Some(&1));
+        assert_eq!(map.get(&other as &dyn NameKey), None);
+        let lazy = MetricName::new_lazy(Bytes::from_static(b"gorets;a=a;b=b"));
+        assert_eq!(map.get(&lazy as &dyn NameKey), Some(&1));
+    }
+
+    #[test]
+    fn metric_name_fold_case() {
+        let mut intermediate = Vec::new();
+        let name = new_name_graphite(b"Web.Requests;Zone=A;env=Prod");
+        let fold = |names, tag_keys, intermediate: &mut Vec<u8>| name.fold_case(CaseFolding { names, tag_keys }, intermediate);
+
+        assert_eq!(fold(false, false, &mut intermediate), name);
+        assert_eq!(&fold(true, false, &mut intermediate).name[..], b"web.requests;Zone=A;env=Prod");
+        let folded = fold(false, true, &mut intermediate);
+        assert_eq!(&folded.name[..], b"Web.Requests;env=Prod;zone=A");
+        assert_eq!(folded.tag_value(b"zone"), Some(&b"A"[..]));
+        assert_eq!(fold(true, true, &mut intermediate), new_name_graphite(b"web.requests;env=Prod;zone=A"));
+
+        let lower = new_name_graphite(b"requests;zone=A");
+        assert_eq!(
+            lower.fold_case(CaseFolding { names: true, tag_keys: true }, &mut intermediate).name.as_ptr(),
+            lower.name.as_ptr()
+        );
+    }
+
+    #[test]
+    fn metric_name_without_tags() {
+        let name = new_name_graphite(b"requests;env=prod");
+        let base = name.without_tags();
+        assert_eq!(base, new_name_graphite(b"requests"));
+        assert_eq!(base.name.as_ptr(), name.name.as_ptr());
+        assert_eq!(new_name_graphite(b"requests").without_tags(), base);
+        assert!(name.same_name(&new_name_graphite(b"requests;env=dev")));
+        assert!(!name.same_name(&new_name_graphite(b"errors;env=prod")));
+
+        let mut names = HashMap::new();
+        *names.entry(NameOnly(name)).or_insert(0) += 1;
+        *names.entry(NameOnly(new_name_graphite(b"requests;env=dev"))).or_insert(0) += 1;
+        *names.entry(NameOnly(new_name_graphite(b"errors"))).or_insert(0) += 1;
+        assert_eq!(names.len(), 2);
+        assert_eq!(names.get(&b"requests"[..]), Some(&2));
+    }
+
+    #[test]
+    fn metric_name_fingerprint() {
+        // these values must never change
+        assert_eq!(new_name_graphite(b"a").fingerprint(), 0xaf63dc4c8601ec8c);
+        assert_eq!(new_name_graphite(b"a").fingerprint128(), 0xd228cb696f1a8caf78912b704e4a8964);
+        let name = new_name_graphite(b"some.metric;b=2;a=1");
+        assert_eq!(name.fingerprint(), 0xdbf893a4c9fd30f9);
+        assert_eq!(name.fingerprint128(), 0x6ebc874bceb917b6b1d605223f58c5e9);
+
+        // names with unsorted tags are hashed in canonical form too
+        let unsorted = MetricName::new_lazy(Bytes::from_static(b"some.metric;b=2;a=1"));
+        assert_eq!(unsorted.fingerprint(), name.fingerprint());
+        assert_eq!(unsorted.fingerprint128(), name.fingerprint128());
+        // i.e. the ones received from peers
+        let unsorted = MetricName::from_raw_parts(Bytes::from_static(b"some.metric;b=2;a=1"), Some(11));
+        assert!(!unsorted.is_canonical());
+        assert_eq!(unsorted.fingerprint(), name.fingerprint());
+    }
+
+    #[test]
+    fn metric_name_lazy() {
+        let mut lazy = MetricName::new_lazy(Bytes::from_static(b"gorets;a=a;b=b"));
+        assert!(!lazy.tags_found);
+        let name = new_name_graphite(b"gorets;b=b;a=a");
+        assert_eq!(lazy, name);
+        assert!(lazy.has_tags());
+        assert!(lazy.tags_found);
+        assert_eq!(lazy.tag_pos, Some(6));
+
+        let lazy = MetricName::new_lazy(Bytes::from_static(b"gorets;a=a;b=b"));
+        assert_eq!(lazy.tags_without_name(), b";a=a;b=b");
+        assert_eq!(lazy.name_without_tags(), b"gorets");
+
+        let mut set = std::collections::HashSet::new();
+        set.insert(name);
+        assert!(set.contains(&MetricName::new_lazy(Bytes::from_static(b"gorets;a=a;b=b"))));
+    }
+
+    #[test]
+    fn metric_name_deserialize() {
+        use serde::de::value::{Error, StrDeserializer};
+        use serde::de::IntoDeserializer;
+
+        let deserializer: StrDeserializer<Error> = "gorets;b=b;a=a".into_deserializer();
+        let name = MetricName::deserialize(deserializer).unwrap();
+        assert_eq!(name, new_name_graphite(b"gorets;a=a;b=b"));
+        assert_eq!(name.tag_pos(), Some(6));
+
+        let deserializer: StrDeserializer<Error> = "gorets.bobez".into_deserializer();
+        let name = deserialize_with_format(TagFormat::Graphite, deserializer).unwrap();
+        assert_eq!(name, new_name_graphite(b"gorets.bobez"));
+    }
+
+    #[test]
+    fn metric_name_unicode_policy() {
+        let mut intermediate = Vec::new();
+        let name = new_name_graphite("café.Жизнь;ü=ö;host=a".as_bytes());
+        let ascii = new_name_graphite(b"cafe.requests;host=a");
+
+        assert_eq!(name.with_unicode_policy(UnicodePolicy::Allow, &mut intermediate).unwrap(), name);
+        assert!(matches!(
+            name.with_unicode_policy(UnicodePolicy::Reject, &mut intermediate),
+            Err(MetricError::Name(_))
+        ));
+        assert_eq!(ascii.with_unicode_policy(UnicodePolicy::Reject, &mut intermediate).unwrap(), ascii);
+
+        let converted = name.with_unicode_policy(UnicodePolicy::Transliterate, &mut intermediate).unwrap();
+        // tags are sorted again after conversion
+        assert_eq!(&converted.name[..], b"cafe.Zhizn;host=a;u=o");
+        assert_eq!(converted.tag_value(b"u"), Some(&b"o"[..]));
+        assert_eq!(&UnicodePolicy::Transliterate.apply("a→b".as_bytes()).unwrap()[..], b"a_b");
+
+        let converted = name.with_unicode_policy(UnicodePolicy::PercentEncode, &mut intermediate).unwrap();
+        assert!(converted.name.starts_with(b"caf%C3%A9.%D0%96"));
+        assert_eq!(converted.tag_value(b"%C3%BC"), Some(&b"%C3%B6"[..]));
+
+        assert!(UnicodePolicy::Allow.apply(b"bad\xff").is_err());
+    }
+
+    #[test]
+    fn metric_name_make_mut() {
+        let mut intermediate = vec![0u8; 128];
+        let name = new_name_graphite(b"gorets;b=b;a=a");
+        let mut cloned = name.clone();
+        assert_eq!(cloned.name.as_ptr(), name.name.as_ptr());
+
+        let len = cloned.make_mut(TagFormat::Graphite, &mut intermediate, |buf| {
+            buf.extend_from_slice(b";c=c;0=0");
+            buf.len()
+        });
+        assert_eq!(len.unwrap(), 22);
+        assert_eq!(&cloned.name[..], b"gorets;0=0;a=a;b=b;c=c");
+        assert_eq!(cloned.tag_pos(), Some(6));
+        assert_eq!(&name.name[..], b"gorets;a=a;b=b");
+
+        let mut untagged = new_name_graphite(b"gorets");
+        untagged
+            .make_mut(TagFormat::Graphite, &mut intermediate, |buf| buf.extend_from_slice(b".bobets"))
+            .unwrap();
+        assert_eq!(&untagged.name[..], b"gorets.bobets");
+        assert_eq!(untagged.tag_pos(), None);
+
+        // the buffer is not shared, so it is changed without copying
+        let mut unique = new_name_graphite(b"gorets;b=b");
+        unique.make_mut(TagFormat::Graphite, &mut intermediate, |buf| buf.truncate(6)).unwrap();
+        let ptr = unique.name.as_ptr();
+        unique
+            .make_mut(TagFormat::Graphite, &mut intermediate, |buf| buf.extend_from_slice(b";a=a"))
+            .unwrap();
+        assert_eq!(unique.name.as_ptr(), ptr);
+        assert_eq!(&unique.name[..], b"gorets;a=a");
+
+        let mut small = [0u8; 2];
+        assert!(unique.make_mut(TagFormat::Graphite, &mut small, |buf| buf.extend_from_slice(b";0=0")).is_err());
+        assert_eq!(&unique.name[..], b"gorets;a=a;0=0");
+        assert_eq!(unique.tag_pos(), Some(6));
+    }
+
+    #[test]
+    fn metric_name_with_prefix() {
+        let name = new_name_graphite(b"requests;host=a");
+        let prefixed = name.with_prefix(b"tenant1.");
+        assert_eq!(prefixed, new_name_graphite(b"tenant1.requests;host=a"));
+        assert_eq!(prefixed.name_without_tags(), b"tenant1.requests");
+        assert_eq!(name.with_prefix(b"tenant1"), prefixed);
+        assert_eq!(name.with_prefix(b""), name);
+        assert_eq!(name.with_prefix(b".."), name);
+
+        let name = MetricName::new_lazy(Bytes::from_static(b"requests"));
+        let prefixed = name.with_prefix_separated(b"tenant1__", PrefixSeparator::Underscore);
+        assert_eq!(&prefixed.name[..], b"tenant1_requests");
+        assert_eq!(prefixed.tag_pos(), None);
+        assert_eq!(&name.with_prefix_separated(b"t.", PrefixSeparator::None).name[..], b"t.requests");
+    }
+
     #[test]
     fn metric_name_tag_position() {
         let name = Bytes::from(&b"gorets.bobez;a=b;c=d"[..]);
@@ -447,6 +1562,7 @@ mod tests {
         let name = new_name_graphite(&b"gorets.bobez;a=b;c=d"[..]);
         assert_eq!(name.name_without_tags(), &b"gorets.bobez"[..]);
         assert_eq!(name.tags_without_name(), &b";a=b;c=d"[..]);
+        assert_eq!(name.segments().collect::<Vec<_>>(), vec![&b"gorets"[..], b"bobez"]);
     }
 
     #[test]
@@ -527,6 +1643,7 @@ mod tests {
                             Bytes::copy_from_slice(agg.to_string().as_bytes())
                         },
                         destination: AggregationDestination::Both, // we can check both destination at once
+                        escape_tag_values: false,
                     },
                 );
             }
@@ -12,12 +12,12 @@ use combine::{choice, position};
 use combine::{eof, skip_many};
 use combine::{optional, skip_many1, Parser};
 
-use bytes::{Buf, BytesMut};
+use bytes::{Buf, Bytes, BytesMut};
 use lexical_core::{parse as parse_number, FromLexical};
 use num_traits::{AsPrimitive, Float};
 
-use crate::metric::{FromF64, StatsdMetric, StatsdType};
-use crate::name::{sort_tags, MetricName, TagFormat};
+use crate::metric::{sampling_valid, FromF64, MetricError, MetricTypeName, NegativeCounterPolicy, StatsdMetric, StatsdType};
+use crate::name::{sort_tags, CaseFolding, MetricName, TagFormat, UnicodePolicy};
 
 #[derive(Debug)]
 pub enum ParsedPart<F>
@@ -33,6 +33,22 @@ where
 // the signature may seem to be cryptic.
 /// Parse stream of multiple metrics in statsd format. Usage of MetricParser is recommended instead.
 pub fn metric_stream_parser<'a, I, F>(max_unparsed: usize, max_tags_len: usize) -> impl Parser<I, Output = ParsedPart<F>, PartialState = impl Default + 'a>
+where
+    I: 'a + combine::StreamOnce<Token = u8, Range = &'a [u8], Position = PointerOffset<[u8]>> + std::fmt::Debug + RangeStream,
+    I::Error: ParseError<I::Token, I::Range, I::Position>,
+    F: 'a + Float + Debug + FromStr + AsPrimitive<f64> + FromF64 + FromLexical + Sync,
+    <F as FromStr>::Err: std::error::Error + Sync + Send + 'static,
+{
+    metric_stream_parser_with_policy(max_unparsed, max_tags_len, NegativeCounterPolicy::default())
+}
+
+/// Same as `metric_stream_parser`, but applies the policy to negative counter values, so rejected
+/// counters are considered bad metrics
+pub fn metric_stream_parser_with_policy<'a, I, F>(
+    max_unparsed: usize,
+    max_tags_len: usize,
+    negative_counters: NegativeCounterPolicy,
+) -> impl Parser<I, Output = ParsedPart<F>, PartialState = impl Default + 'a>
 where
     I: 'a + combine::StreamOnce<Token = u8, Range = &'a [u8], Position = PointerOffset<[u8]>> + std::fmt::Debug + RangeStream,
     I::Error: ParseError<I::Token, I::Range, I::Position>,
@@ -71,7 +87,7 @@ where
     // This should parse metric value and separator
     let val = take_while1(|c: u8| c != b'|' && c != b'\n')
         .skip(byte(b'|'))
-        .and_then(|value| parse_number::<F>(value).map_err(|_e| StreamErrorFor::<I>::unexpected_static_message("value is not a valid number")));
+        .and_then(|value| parse_float::<F>(value).ok_or_else(|| StreamErrorFor::<I>::unexpected_static_message("value is not a valid number")));
 
     let regular_float = (
         skip_many(byte(b'+').or(byte(b'-'))),
@@ -88,9 +104,9 @@ where
     )
         .and_then(|(_, start, _, end)| {
             let start =
-                parse_number::<F>(start).map_err(|_e| StreamErrorFor::<I>::unexpected_static_message("custom histogram start value is not a valid number"))?;
+                parse_float::<F>(start).ok_or_else(|| StreamErrorFor::<I>::unexpected_static_message("custom histogram start value is not a valid number"))?;
             let end =
-                parse_number::<F>(end).map_err(|_e| StreamErrorFor::<I>::unexpected_static_message("custom histgoram end value is not a valid number"))?;
+                parse_float::<F>(end).ok_or_else(|| StreamErrorFor::<I>::unexpected_static_message("custom histgoram end value is not a valid number"))?;
             Ok::<_, StreamErrorFor<I>>(StatsdType::CustomHistogram(start, end))
         });
 
@@ -111,7 +127,7 @@ where
     );
 
     let sampling = (parse_bytes(b"|@"), recognize(unsigned_float))
-        .and_then(|(_, val)| parse_number::<f32>(val).map_err(|_e| StreamErrorFor::<I>::unexpected_static_message("sampling value is not a valid number")));
+        .and_then(|(_, val)| parse_float::<f32>(val).ok_or_else(|| StreamErrorFor::<I>::unexpected_static_message("sampling value is not a valid number")));
 
     let metric = (
         optional(sign),
@@ -119,7 +135,7 @@ where
         mtype,
         choice((sampling.map(Some), skip_many(newline()).map(|_| None), eof().map(|_| None))),
     )
-        .and_then(|(sign, mut val, mtype, sampling)| {
+        .and_then(move |(sign, mut val, mtype, sampling)| {
             let mtype = if let StatsdType::Gauge(_) = mtype {
                 StatsdType::Gauge(sign)
             } else {
@@ -130,6 +146,12 @@ where
                 mtype
             };
 
+            if let StatsdType::Counter = mtype {
+                val = negative_counters
+                    .apply(val)
+                    .map_err(|_| StreamErrorFor::<I>::unexpected_static_message("negative counter value"))?;
+            }
+
             StatsdMetric::new(val, mtype, sampling).map_err(|_| StreamErrorFor::<I>::unexpected_static_message("bad metric values"))
         });
 
@@ -144,6 +166,22 @@ where
     ))
 }
 
+/// Parses a float value. Plain integers, being the most common metric values, are parsed directly,
+/// others go to lexical's fast float algorithm, falling back to std parsing for the forms it doesn't support
+#[inline]
+fn parse_float<F>(input: &[u8]) -> Option<F>
+where
+    F: FromLexical + FromStr + FromF64,
+{
+    // any 15-digit integer is exactly representable in f64
+    if !input.is_empty() && input.len() <= 15 && input.iter().all(u8::is_ascii_digit) {
+        let value = input.iter().fold(0u64, |acc, c| acc * 10 + u64::from(c - b'0'));
+        return Some(F::from_f64(value as f64));
+    }
+
+    parse_number::<F>(input).ok().or_else(|| from_utf8(input).ok()?.parse::<F>().ok())
+}
+
 pub type MetricParsingError<'a> = easy::Errors<u8, &'a [u8], PointerOffset<[u8]>>;
 
 #[allow(unused_variables)]
@@ -156,6 +194,13 @@ pub trait ParseErrorHandler {
 pub struct DummyParseErrorHandler;
 impl ParseErrorHandler for DummyParseErrorHandler {}
 
+/// Decides if a parsed metric is kept, i.e. `WasmPlugin` with the `filter` hook
+pub trait MetricFilter<F> {
+    fn keep(&mut self, name: &MetricName, metric: &StatsdMetric<F>) -> Result<bool, MetricError>
+    where
+        F: Debug;
+}
+
 /// A high level parser to parse metric and split names from BytesMut.
 /// Follows an iterator pattern, which fires metrics until it is possible,
 /// modifying the buffer on the fly
@@ -164,6 +209,11 @@ pub struct MetricParser<'a, F, E: ParseErrorHandler> {
     skip: usize,
     max_unparsed: usize,
     max_tags_len: usize,
+    negative_counters: NegativeCounterPolicy,
+    unicode: UnicodePolicy,
+    case_folding: CaseFolding,
+    tag_escaping: bool,
+    filter: Option<&'a mut dyn MetricFilter<F>>,
     handler: E,
     sort_buf: Vec<u8>,
     _pd: PhantomData<F>,
@@ -180,11 +230,49 @@ where
             skip: 0,
             max_unparsed,
             max_tags_len,
+            negative_counters: NegativeCounterPolicy::default(),
+            unicode: UnicodePolicy::default(),
+            case_folding: CaseFolding::default(),
+            tag_escaping: false,
+            filter: None,
             handler,
             sort_buf,
             _pd: PhantomData,
         }
     }
+
+    /// Sets the policy for negative counter values, they decrement counters by default
+    pub fn with_negative_counters(mut self, policy: NegativeCounterPolicy) -> Self {
+        self.negative_counters = policy;
+        self
+    }
+
+    /// Sets the policy for names with non-ASCII characters, valid UTF-8 is allowed by default.
+    /// Rejected names are reported to the error handler
+    pub fn with_unicode(mut self, policy: UnicodePolicy) -> Self {
+        self.unicode = policy;
+        self
+    }
+
+    /// Sets lowercasing of names and tag keys, applied after the Unicode policy, nothing is changed by default
+    pub fn with_case_folding(mut self, folding: CaseFolding) -> Self {
+        self.case_folding = folding;
+        self
+    }
+
+    /// Sets escaping of tag values with `MetricName::escape_tag_values`, so values written with and without
+    /// percent-encoding make the same name. Off by default, since names having `%` in tag values are changed
+    pub fn with_tag_escaping(mut self, escaping: bool) -> Self {
+        self.tag_escaping = escaping;
+        self
+    }
+
+    /// Sets the filter called for every metric after its name is normalized, the metrics rejected are skipped.
+    /// Metrics are kept when the filter fails, the failure is reported to the error handler
+    pub fn with_filter(mut self, filter: &'a mut dyn MetricFilter<F>) -> Self {
+        self.filter = Some(filter);
+        self
+    }
 }
 
 impl<'a, F, E> Iterator for MetricParser<'a, F, E>
@@ -203,7 +291,7 @@ where
             let res = {
                 let input = &self.input[self.skip..];
 
-                let parser = metric_stream_parser(self.max_unparsed, self.max_tags_len);
+                let parser = metric_stream_parser_with_policy(self.max_unparsed, self.max_tags_len, self.negative_counters);
                 //            let res = decode(
                 //parser,
                 //combine::stream::PartialStream(input),
@@ -268,7 +356,38 @@ where
                         name.truncate(newlen);
                     }
 
-                    return Some((MetricName::from_raw_parts(name.freeze(), tag_pos), metric));
+                    let name = MetricName::from_sorted_parts(name.freeze(), tag_pos);
+                    let name = if self.unicode == UnicodePolicy::Allow || name.name.is_ascii() {
+                        name
+                    } else {
+                        match name.with_unicode_policy(self.unicode, &mut self.sort_buf) {
+                            Ok(name) => name,
+                            Err(_) => {
+                                let position = PointerOffset::new(name.name.as_ptr() as usize);
+                                let error = easy::Errors::new(position, easy::Error::Message(easy::Info::Static("name is not ascii")));
+                                self.handler.handle(&name.name, name.name.len(), error);
+                                continue;
+                            }
+                        }
+                    };
+                    let name = name.fold_case(self.case_folding, &mut self.sort_buf);
+                    let name = if self.tag_escaping {
+                        name.escape_tag_values(&mut self.sort_buf)
+                    } else {
+                        name
+                    };
+                    if let Some(filter) = self.filter.as_mut() {
+                        match filter.keep(&name, &metric) {
+                            Ok(true) => (),
+                            Ok(false) => continue,
+                            Err(_) => {
+                                let position = PointerOffset::new(name.name.as_ptr() as usize);
+                                let error = easy::Errors::new(position, easy::Error::Message(easy::Info::Static("metric filter failed")));
+                                self.handler.handle(&name.name, name.name.len(), error);
+                            }
+                        }
+                    }
+                    return Some((name, metric));
                 }
                 Ok((Some(ParsedPart::Trash(pos)), consumed)) => {
                     // trash matched
@@ -331,13 +450,199 @@ where
     }
 }
 
+/// A statsd line checked by `validate_line`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValidLine {
+    /// length of the name including tags
+    pub name_len: usize,
+    /// position of the tag separator in the name
+    pub tag_pos: Option<usize>,
+    pub mtype: MetricTypeName,
+    /// the value is below zero, which means decrement for gauges
+    pub negative: bool,
+}
+
+/// Checks the line is a valid statsd metric without making the metric itself, i.e. without
+/// allocating timer vectors or sets. The line must not include the trailing newline.
+///
+/// The check is a bit stricter than the full parser: trailing garbage after the metric makes the whole line invalid.
+pub fn validate_line(line: &[u8], max_tags_len: usize) -> Result<ValidLine, &'static str> {
+    let name_len = line.iter().position(|c| *c == b':').ok_or("no value separator")?;
+    let name = &line[..name_len];
+    let tag_pos = name.iter().position(|c| *c == b';');
+    let (base, tags) = match tag_pos {
+        Some(pos) => (&name[..pos], Some(&name[pos + 1..])),
+        None => (name, None),
+    };
+    if base.is_empty() {
+        return Err("empty name");
+    }
+    from_utf8(base).map_err(|_| "name part is not valid utf8")?;
+    if let Some(tags) = tags {
+        if tags.is_empty() {
+            return Err("empty tag part");
+        }
+        if tags.len() > max_tags_len {
+            return Err("tag part is too long");
+        }
+        from_utf8(tags).map_err(|_| "tag part is not valid utf8")?;
+    }
+
+    let mut parts = line[name_len + 1..].split(|c| *c == b'|');
+    let value = parts.next().unwrap_or_default();
+    let unsigned = match value.first() {
+        Some(b'+') | Some(b'-') => &value[1..],
+        _ => value,
+    };
+    let negative = match parse_float::<f64>(unsigned) {
+        Some(number) if !unsigned.is_empty() => number != 0f64 && value[0] == b'-',
+        _ => return Err("value is not a valid number"),
+    };
+
+    let mtype = match parts.next().ok_or("no metric type")? {
+        b"ms" => MetricTypeName::Timer,
+        b"g" => MetricTypeName::Gauge,
+        b"c" => MetricTypeName::Counter,
+        b"s" => MetricTypeName::Set,
+        [b'H', range @ ..] => {
+            let mut bounds = range.splitn(2, |c| *c == b',').map(parse_float::<f64>);
+            match (bounds.next().flatten(), bounds.next().flatten()) {
+                (Some(start), Some(end)) if start < end && start.is_finite() && end.is_finite() => MetricTypeName::CustomHistogram,
+                _ => return Err("bad custom histogram range"),
+            }
+        }
+        _ => return Err("unknown metric type"),
+    };
+
+    match parts.next() {
+        None => {}
+        Some([b'@', sampling @ ..]) if !sampling.is_empty() && sampling[0].is_ascii_digit() => match parse_float::<f32>(sampling) {
+            Some(sampling) if sampling_valid(sampling) => {}
+            _ => return Err("bad sampling"),
+        },
+        Some(_) => return Err("bad sampling"),
+    }
+    if parts.next().is_some() {
+        return Err("unexpected data after metric");
+    }
+
+    Ok(ValidLine {
+        name_len,
+        tag_pos,
+        mtype,
+        negative,
+    })
+}
+
+/// A raw valid statsd line for passing it through as is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayLine {
+    /// the line without a newline
+    pub line: Bytes,
+    pub valid: ValidLine,
+}
+
+impl RelayLine {
+    /// The name with tags as they came, i.e. not sorted
+    pub fn name(&self) -> &[u8] {
+        &self.line[..self.valid.name_len]
+    }
+}
+
+/// A parser for relays which only forward metrics without aggregating them. Lines are only validated,
+/// so the cost of making metrics and sorting tags is not paid. The last line in the buffer is considered
+/// complete even without a newline, like `MetricParser` does.
+pub struct RelayParser<'a, E: ParseErrorHandler> {
+    input: &'a mut BytesMut,
+    max_unparsed: usize,
+    max_tags_len: usize,
+    negative_counters: NegativeCounterPolicy,
+    handler: E,
+}
+
+impl<'a, E: ParseErrorHandler> RelayParser<'a, E> {
+    pub fn new(input: &'a mut BytesMut, max_unparsed: usize, max_tags_len: usize, handler: E) -> Self {
+        Self {
+            input,
+            max_unparsed,
+            max_tags_len,
+            negative_counters: NegativeCounterPolicy::default(),
+            handler,
+        }
+    }
+
+    /// Sets the policy for negative counter values, they are passed as is by default. Rejected lines
+    /// are reported to the error handler, clamped ones are passed with zero value
+    pub fn with_negative_counters(mut self, policy: NegativeCounterPolicy) -> Self {
+        self.negative_counters = policy;
+        self
+    }
+
+    // the line with the counter value replaced by zero
+    fn clamped(line: Bytes, valid: &ValidLine) -> Bytes {
+        let value_end = line[valid.name_len..]
+            .iter()
+            .position(|c| *c == b'|')
+            .map(|pos| pos + valid.name_len)
+            .unwrap_or(line.len());
+        let mut clamped = BytesMut::with_capacity(line.len());
+        clamped.extend_from_slice(&line[..=valid.name_len]);
+        clamped.extend_from_slice(b"0");
+        clamped.extend_from_slice(&line[value_end..]);
+        clamped.freeze()
+    }
+}
+
+impl<'a, E: ParseErrorHandler> Iterator for RelayParser<'a, E> {
+    type Item = RelayLine;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let skip = self.input.iter().take_while(|c| **c == b'\n').count();
+            self.input.advance(skip);
+            if self.input.is_empty() {
+                return None;
+            }
+
+            let (len, newline) = match self.input.iter().position(|c| *c == b'\n') {
+                Some(pos) => (pos, 1),
+                None => (self.input.len(), 0),
+            };
+            let result = if len > self.max_unparsed {
+                Err("line is too long")
+            } else {
+                validate_line(&self.input[..len], self.max_tags_len).and_then(|valid| match (valid.mtype, valid.negative, self.negative_counters) {
+                    (MetricTypeName::Counter, true, NegativeCounterPolicy::Reject) => Err("negative counter value"),
+                    _ => Ok(valid),
+                })
+            };
+            match result {
+                Ok(mut valid) => {
+                    let mut line = self.input.split_to(len).freeze();
+                    self.input.advance(newline);
+                    if let (MetricTypeName::Counter, true, NegativeCounterPolicy::Clamp) = (valid.mtype, valid.negative, self.negative_counters) {
+                        line = Self::clamped(line, &valid);
+                        valid.negative = false;
+                    }
+                    return Some(RelayLine { line, valid });
+                }
+                Err(reason) => {
+                    let position = PointerOffset::new(self.input.as_ptr() as usize);
+                    let error = easy::Errors::new(position, easy::Error::Message(easy::Info::Static(reason)));
+                    self.handler.handle(self.input, len, error);
+                    self.input.advance(len + newline);
+                }
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::metric::{Metric, MetricValue};
 
     use bytes::Bytes;
-    // TODO: Questioned cases:
-    //  * negative counters
 
     struct TestParseErrorHandler;
     impl ParseErrorHandler for TestParseErrorHandler {
@@ -366,6 +671,48 @@ mod tests {
         assert_eq!(parser.next(), None);
     }
 
+    #[test]
+    fn parse_float_values() {
+        for input in &[
+            "0",
+            "1",
+            "42",
+            "999999999999999",
+            "1234567890123456789",
+            "12.65",
+            "-0.5",
+            "1e10",
+            "1.5E-3",
+            ".5",
+            "inf",
+            "NaN",
+        ] {
+            let expected: f64 = input.parse().unwrap();
+            let parsed = parse_float::<f64>(input.as_bytes()).unwrap();
+            assert!(
+                parsed == expected || parsed.is_nan() && expected.is_nan(),
+                "{}: {} != {}",
+                input,
+                parsed,
+                expected
+            );
+
+            let expected: f32 = input.parse().unwrap();
+            let parsed = parse_float::<f32>(input.as_bytes()).unwrap();
+            assert!(
+                parsed == expected || parsed.is_nan() && expected.is_nan(),
+                "{}: {} != {}",
+                input,
+                parsed,
+                expected
+            );
+        }
+
+        for input in &["", "1.2.3", "abc", "1e", "--1"] {
+            assert_eq!(parse_float::<f64>(input.as_bytes()), None, "{}", input);
+        }
+    }
+
     #[test]
     fn parse_metric_good_counter_float() {
         let mut data = BytesMut::from(&b"gorets:12.65|c|@0.001"[..]);
@@ -442,6 +789,73 @@ mod tests {
         assert_eq!(parser.next(), None);
     }
 
+    #[test]
+    fn parse_negative_counters() {
+        let input = &b"gorets:-2|c\ngorets:3|c\n"[..];
+        let parse = |policy| {
+            let mut data = BytesMut::from(input);
+            make_parser(&mut data)
+                .with_negative_counters(policy)
+                .map(|(_, metric)| metric)
+                .collect::<Vec<_>>()
+        };
+        let counter = |value| StatsdMetric::<f64>::new(value, StatsdType::Counter, None).unwrap();
+
+        assert_eq!(parse(NegativeCounterPolicy::Decrement), vec![counter(-2f64), counter(3f64)]);
+        assert_eq!(parse(NegativeCounterPolicy::Clamp), vec![counter(0f64), counter(3f64)]);
+        assert_eq!(parse(NegativeCounterPolicy::Reject), vec![counter(3f64)]);
+
+        let metric = Metric::<f64>::new_checked(MetricValue::Counter(-1f64), None, 1f32, NegativeCounterPolicy::Clamp).unwrap();
+        assert_eq!(metric.as_counter(), Some(0f64));
+        assert!(Metric::<f64>::new_checked(MetricValue::Counter(-1f64), None, 1f32, NegativeCounterPolicy::Reject).is_err());
+        assert!(Metric::<f64>::new_checked(MetricValue::Gauge(-1f64), None, 1f32, NegativeCounterPolicy::Reject).is_ok());
+    }
+
+    #[test]
+    fn parse_unicode_names() {
+        let input = "café;ключ=значение:1|c\ngorets:2|c\n".as_bytes();
+        let parse = |policy| {
+            let mut data = BytesMut::from(input);
+            make_parser(&mut data).with_unicode(policy).map(|(name, _)| name.name).collect::<Vec<_>>()
+        };
+
+        assert_eq!(parse(UnicodePolicy::Allow)[0], "café;ключ=значение".as_bytes());
+        assert_eq!(parse(UnicodePolicy::Reject), vec![Bytes::from_static(b"gorets")]);
+        assert_eq!(parse(UnicodePolicy::Transliterate)[0], &b"cafe;klyuch=znachenie"[..]);
+        let encoded = &b"caf%C3%A9;%D0%BA%D0%BB%D1%8E%D1%87=%D0%B7%D0%BD%D0%B0%D1%87%D0%B5%D0%BD%D0%B8%D0%B5"[..];
+        assert_eq!(parse(UnicodePolicy::PercentEncode)[0], encoded);
+    }
+
+    #[test]
+    fn parse_tag_escaping() {
+        let mut data = BytesMut::from(&b"gorets;path=a b%3b;pct=5%:1|c\n"[..]);
+        let (name, _) = make_parser(&mut data).next().unwrap();
+        assert_eq!(&name.name[..], b"gorets;path=a b%3b;pct=5%");
+
+        let mut data = BytesMut::from(&b"gorets;path=a b%3b;pct=5%:1|c\n"[..]);
+        let (name, _) = make_parser(&mut data).with_tag_escaping(true).next().unwrap();
+        assert_eq!(&name.name[..], b"gorets;path=a%20b%3B;pct=5%25");
+        assert_eq!(name.tag_value_unescaped(b"path").as_deref(), Some(&b"a b;"[..]));
+
+        let mut buf = BytesMut::new();
+        crate::jsonlines::JsonLinesEncoder::default().encode::<f64>(&mut buf, &name, None, 1f64, None);
+        assert_eq!(
+            &buf[..],
+            &b"{\"name\":\"gorets\",\"tags\":{\"path\":\"a b;\",\"pct\":\"5%\"},\"value\":1}\n"[..]
+        );
+
+        let mut data = BytesMut::from(&b"gorets;path=a%20b%3B;pct=5%25:1|c\n"[..]);
+        assert_eq!(make_parser(&mut data).with_tag_escaping(true).next().unwrap().0, name);
+    }
+
+    #[test]
+    fn parse_case_folding() {
+        let mut data = BytesMut::from(&b"Gorets;Host=A:1|c\n"[..]);
+        let folding = CaseFolding { names: true, tag_keys: true };
+        let (name, _) = make_parser(&mut data).with_case_folding(folding).next().unwrap();
+        assert_eq!(&name.name[..], b"gorets;host=A");
+    }
+
     #[test]
     fn parse_metric_short() {
         let mut data = BytesMut::from(&b"gorets:1|c"[..]);
@@ -489,8 +903,8 @@ mod tests {
         let (name, metric) = parser.next().unwrap();
         // name is still full string, including tags
         assert_eq!(&name.name[..], &b"gorets;a=b.j.k.l;c=d"[..]);
-        assert_eq!(name.tag_pos, Some(6usize));
-        assert_eq!(&name.name[name.tag_pos.unwrap()..], &b";a=b.j.k.l;c=d"[..]);
+        assert_eq!(name.tag_pos(), Some(6usize));
+        assert_eq!(&name.name[name.tag_pos().unwrap()..], &b";a=b.j.k.l;c=d"[..]);
         assert_eq!(metric, StatsdMetric::<f64>::new(1000f64, StatsdType::Gauge(Some(1)), None).unwrap());
         let (name, metric) = parser.next().unwrap();
         assert_eq!(&name.name[..], &b"gorets"[..]);
@@ -511,8 +925,8 @@ mod tests {
         let mut parser = make_parser(&mut data);
         let (name, metric) = parser.next().unwrap();
         assert_eq!(&name.name[..], &b"bobets;c=d"[..]);
-        assert_eq!(name.tag_pos, Some(6usize));
-        assert_eq!(&name.name[name.tag_pos.unwrap()..], &b";c=d"[..]);
+        assert_eq!(name.tag_pos(), Some(6usize));
+        assert_eq!(&name.name[name.tag_pos().unwrap()..], &b";c=d"[..]);
         assert_eq!(metric, StatsdMetric::<f64>::new(1000f64, StatsdType::Gauge(None), None).unwrap());
     }
 
@@ -523,14 +937,14 @@ mod tests {
         let mut parser = make_parser(&mut data);
         let (name, metric) = parser.next().unwrap();
         assert_eq!(&name.name[..], &b"gorets1"[..]);
-        assert_eq!(name.tag_pos, None);
+        assert_eq!(name.tag_pos(), None);
         assert_eq!(metric, StatsdMetric::<f64>::new(1000f64, StatsdType::Gauge(Some(1)), None).unwrap());
 
         // parser must sort tags
         let (name, metric) = parser.next().unwrap();
         assert_eq!(&name.name[..], &b"gorets2;t2=fuck;tag3=sh.t"[..]);
-        assert_eq!(name.tag_pos, Some(7usize));
-        assert_eq!(&name.name[name.tag_pos.unwrap()..], &b";t2=fuck;tag3=sh.t"[..]);
+        assert_eq!(name.tag_pos(), Some(7usize));
+        assert_eq!(&name.name[name.tag_pos().unwrap()..], &b";t2=fuck;tag3=sh.t"[..]);
         assert_eq!(metric, StatsdMetric::<f64>::new(1000f64, StatsdType::Gauge(Some(-1)), Some(0.5)).unwrap());
     }
 
@@ -629,6 +1043,46 @@ mod tests {
         }
     }
 
+    #[test]
+    fn relay_parser_lines() {
+        let mut data = BytesMut::from(&b"\ngorets:+1|c|@0.1\nbad:1|x\nt;b=2;a=1:-2e3|ms\nh:1|H0,10\nlong;tags=abcdefghijkl:1|g\ns:1|s|@1|x\nlast:1|g"[..]);
+        let lines = RelayParser::new(&mut data, 100, 10, TestParseErrorHandler).collect::<Vec<_>>();
+        assert!(data.is_empty());
+        let names = lines.iter().map(|line| line.name()).collect::<Vec<_>>();
+        assert_eq!(names, vec![&b"gorets"[..], &b"t;b=2;a=1"[..], &b"h"[..], &b"last"[..]]);
+        assert_eq!(&lines[0].line[..], &b"gorets:+1|c|@0.1"[..]);
+        assert_eq!(lines[1].valid.tag_pos, Some(1));
+        assert_eq!(lines[1].valid.mtype, MetricTypeName::Timer);
+        assert_eq!(lines[2].valid.mtype, MetricTypeName::CustomHistogram);
+
+        for bad in [
+            &b":1|c"[..],
+            b"a;:1|c",
+            b"a:|c",
+            b"a:1",
+            b"a:1|H1,0",
+            b"a:1|c|@-1",
+            b"a:1|c|@1.5",
+            b"a:1|c|@0",
+            b"a:x|g",
+        ] {
+            assert!(validate_line(bad, 10).is_err(), "{:?}", String::from_utf8_lossy(bad));
+        }
+        assert!(validate_line(b"a:-1|g", 10).unwrap().negative);
+        assert!(!validate_line(b"a:-0|c", 10).unwrap().negative);
+
+        let relayed = |policy| {
+            let mut data = BytesMut::from(&b"a:-2.5|c|@0.5\nb:-1|g\nc:1|c"[..]);
+            RelayParser::new(&mut data, 100, 10, TestParseErrorHandler)
+                .with_negative_counters(policy)
+                .map(|line| line.line)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(relayed(NegativeCounterPolicy::Decrement), vec![&b"a:-2.5|c|@0.5"[..], b"b:-1|g", b"c:1|c"]);
+        assert_eq!(relayed(NegativeCounterPolicy::Clamp), vec![&b"a:0|c|@0.5"[..], b"b:-1|g", b"c:1|c"]);
+        assert_eq!(relayed(NegativeCounterPolicy::Reject), vec![&b"b:-1|g"[..], b"c:1|c"]);
+    }
+
     #[test]
     fn parse_bufsizes_differ() {
         // The test is based on bug, where max_unparsed < max_tags_len
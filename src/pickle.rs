@@ -0,0 +1,450 @@
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::fmt::Debug;
+use std::rc::Rc;
+
+use bytes::{Buf, Bytes, BytesMut};
+use num_traits::{AsPrimitive, Float};
+
+use crate::metric::{FromF64, Metric, MetricError, MetricValue};
+use crate::name::{MetricName, TagFormat};
+
+/// Values of the pickle subset used by carbon. Objects requiring code execution, like globals and
+/// reduce calls, are never supported, so parsing untrusted data is safe. Memoized values are shared
+/// instead of being copied, and the number of values and their nesting are limited, so crafted
+/// messages cannot exhaust memory or stack.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(Vec<u8>),
+    List(Vec<Rc<Value>>),
+    Tuple(Vec<Rc<Value>>),
+    Mark,
+}
+
+/// The limit of values created while parsing a message, including the copies of shared lists
+/// being appended to
+const MAX_NODES: usize = 1 << 20;
+
+/// The limit of container nesting, carbon messages only need 3 levels
+const MAX_DEPTH: usize = 32;
+
+impl Value {
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(v) => Some(*v as f64),
+            Value::Float(v) => Some(*v),
+            Value::Bool(v) => Some(if *v { 1f64 } else { 0f64 }),
+            _ => None,
+        }
+    }
+}
+
+// values are kept along with the depth of their nesting
+struct Machine<'a> {
+    data: &'a [u8],
+    stack: Vec<(Rc<Value>, usize)>,
+    memo: HashMap<u64, (Rc<Value>, usize)>,
+    nodes: usize,
+}
+
+const BAD: MetricError = MetricError::Pickle("malformed pickle");
+
+impl<'a> Machine<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], MetricError> {
+        if self.data.len() < len {
+            return Err(MetricError::Pickle("unexpected end of pickle"));
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn take_u8(&mut self) -> Result<u8, MetricError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn take_le(&mut self, len: usize) -> Result<u64, MetricError> {
+        Ok(self.take(len)?.iter().rev().fold(0u64, |acc, b| (acc << 8) | u64::from(*b)))
+    }
+
+    fn take_len(&mut self, len: usize) -> Result<usize, MetricError> {
+        let len = self.take_le(len)?;
+        usize::try_from(len).map_err(|_| BAD)
+    }
+
+    fn take_line(&mut self) -> Result<&'a [u8], MetricError> {
+        let pos = self.data.iter().position(|c| *c == b'\n').ok_or(BAD)?;
+        let line = self.take(pos + 1)?;
+        Ok(&line[..pos])
+    }
+
+    fn count(&mut self, nodes: usize) -> Result<(), MetricError> {
+        self.nodes += nodes;
+        if self.nodes > MAX_NODES {
+            return Err(MetricError::Pickle("pickle has too many values"));
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, value: Value, depth: usize) -> Result<(), MetricError> {
+        if depth > MAX_DEPTH {
+            return Err(MetricError::Pickle("pickle is nested too deeply"));
+        }
+        self.count(1)?;
+        self.stack.push((Rc::new(value), depth));
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<(Rc<Value>, usize), MetricError> {
+        self.stack.pop().ok_or(BAD)
+    }
+
+    fn pop_mark(&mut self) -> Result<Vec<(Rc<Value>, usize)>, MetricError> {
+        let pos = self.stack.iter().rposition(|(v, _)| **v == Value::Mark).ok_or(BAD)?;
+        let items = self.stack.split_off(pos + 1);
+        self.stack.pop();
+        Ok(items)
+    }
+
+    // the container made of items along with its depth
+    fn container(items: Vec<(Rc<Value>, usize)>) -> (Vec<Rc<Value>>, usize) {
+        let depth = items.iter().map(|(_, depth)| depth + 1).max().unwrap_or(1);
+        (items.into_iter().map(|(value, _)| value).collect(), depth)
+    }
+
+    fn append(&mut self, items: Vec<(Rc<Value>, usize)>) -> Result<(), MetricError> {
+        let (items, depth) = Self::container(items);
+        let (top, top_depth) = self.stack.pop().ok_or(BAD)?;
+        let depth = depth.max(top_depth);
+        let mut top = top;
+        // the list may be shared with the memo, it is copied then, leaving the memoized one intact
+        if Rc::strong_count(&top) > 1 {
+            if let Value::List(list) = &*top {
+                self.count(list.len())?;
+            }
+        }
+        match Rc::make_mut(&mut top) {
+            Value::List(list) => list.extend(items),
+            _ => return Err(BAD),
+        }
+        if depth > MAX_DEPTH {
+            return Err(MetricError::Pickle("pickle is nested too deeply"));
+        }
+        self.stack.push((top, depth));
+        Ok(())
+    }
+
+    fn put(&mut self, idx: u64) -> Result<(), MetricError> {
+        let top = self.stack.last().ok_or(BAD)?.clone();
+        self.memo.insert(idx, top);
+        Ok(())
+    }
+
+    fn get(&mut self, idx: u64) -> Result<(), MetricError> {
+        let value = self.memo.get(&idx).cloned().ok_or(BAD)?;
+        self.count(1)?;
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn text<T: std::str::FromStr>(line: &[u8]) -> Result<T, MetricError> {
+        std::str::from_utf8(line).ok().and_then(|s| s.trim_end_matches('L').parse().ok()).ok_or(BAD)
+    }
+
+    fn run(mut self) -> Result<Rc<Value>, MetricError> {
+        loop {
+            let mut depth = 0;
+            let value = match self.take_u8()? {
+                b'.' => return self.pop().map(|(value, _)| value),
+                0x80 => {
+                    self.take_u8()?;
+                    continue;
+                }
+                0x95 => {
+                    self.take(8)?;
+                    continue;
+                }
+                b'(' => Value::Mark,
+                b']' => {
+                    depth = 1;
+                    Value::List(Vec::new())
+                }
+                b')' => {
+                    depth = 1;
+                    Value::Tuple(Vec::new())
+                }
+                b'l' => {
+                    let (items, items_depth) = Self::container(self.pop_mark()?);
+                    depth = items_depth;
+                    Value::List(items)
+                }
+                b't' => {
+                    let (items, items_depth) = Self::container(self.pop_mark()?);
+                    depth = items_depth;
+                    Value::Tuple(items)
+                }
+                b'a' => {
+                    let item = self.pop()?;
+                    self.append(vec![item])?;
+                    continue;
+                }
+                b'e' => {
+                    let items = self.pop_mark()?;
+                    self.append(items)?;
+                    continue;
+                }
+                n @ 0x85..=0x87 => {
+                    let len = usize::from(n - 0x84);
+                    if self.stack.len() < len {
+                        return Err(BAD);
+                    }
+                    let (items, items_depth) = Self::container(self.stack.split_off(self.stack.len() - len));
+                    depth = items_depth;
+                    Value::Tuple(items)
+                }
+                b'N' => Value::None,
+                0x88 => Value::Bool(true),
+                0x89 => Value::Bool(false),
+                b'J' => Value::Int(i64::from(self.take_le(4)? as u32 as i32)),
+                b'K' => Value::Int(self.take_le(1)? as i64),
+                b'M' => Value::Int(self.take_le(2)? as i64),
+                0x8a => {
+                    let len = self.take_len(1)?;
+                    if len > 8 {
+                        return Err(MetricError::Pickle("integer is too long"));
+                    }
+                    let bytes = self.take(len)?;
+                    let mut padded = if bytes.last().map(|b| b & 0x80 != 0).unwrap_or(false) {
+                        [0xffu8; 8]
+                    } else {
+                        [0u8; 8]
+                    };
+                    padded[..len].copy_from_slice(bytes);
+                    Value::Int(i64::from_le_bytes(padded))
+                }
+                b'G' => {
+                    let bytes = self.take(8)?;
+                    // the slice is exactly 8 bytes long
+                    Value::Float(f64::from_be_bytes(bytes.try_into().map_err(|_| BAD)?))
+                }
+                b'I' => match self.take_line()? {
+                    b"01" => Value::Bool(true),
+                    b"00" => Value::Bool(false),
+                    line => Value::Int(Self::text(line)?),
+                },
+                b'L' => Value::Int(Self::text(self.take_line()?)?),
+                b'F' => Value::Float(Self::text(self.take_line()?)?),
+                b'S' => {
+                    let line = self.take_line()?;
+                    let quoted = line.len() >= 2 && line[0] == line[line.len() - 1] && (line[0] == b'\'' || line[0] == b'"');
+                    if !quoted || line[1..line.len() - 1].contains(&b'\\') {
+                        return Err(MetricError::Pickle("escaped strings are not supported"));
+                    }
+                    Value::Str(line[1..line.len() - 1].to_vec())
+                }
+                b'V' => Value::Str(self.take_line()?.to_vec()),
+                b'U' | b'C' | 0x8c => {
+                    let len = self.take_len(1)?;
+                    Value::Str(self.take(len)?.to_vec())
+                }
+                b'T' | b'B' | b'X' => {
+                    let len = self.take_len(4)?;
+                    Value::Str(self.take(len)?.to_vec())
+                }
+                0x8d | 0x8e => {
+                    let len = self.take_len(8)?;
+                    Value::Str(self.take(len)?.to_vec())
+                }
+                b'p' => {
+                    let idx = Self::text(self.take_line()?)?;
+                    self.put(idx)?;
+                    continue;
+                }
+                b'q' => {
+                    let idx = self.take_le(1)?;
+                    self.put(idx)?;
+                    continue;
+                }
+                b'r' => {
+                    let idx = self.take_le(4)?;
+                    self.put(idx)?;
+                    continue;
+                }
+                0x94 => {
+                    let idx = self.memo.len() as u64;
+                    self.put(idx)?;
+                    continue;
+                }
+                b'g' => {
+                    let idx = Self::text(self.take_line()?)?;
+                    self.get(idx)?;
+                    continue;
+                }
+                b'h' => {
+                    let idx = self.take_le(1)?;
+                    self.get(idx)?;
+                    continue;
+                }
+                b'j' => {
+                    let idx = self.take_le(4)?;
+                    self.get(idx)?;
+                    continue;
+                }
+                _ => return Err(MetricError::Pickle("unsupported pickle opcode")),
+            };
+            self.push(value, depth)?;
+        }
+    }
+}
+
+/// Takes one length-prefixed pickle message from the buffer, as they are sent by carbon-relay
+/// and other clients of carbon pickle receiver. Returns None if the message is not complete yet.
+/// Messages longer than `max_len` are rejected, since the stream cannot be recovered after that.
+pub fn pickle_frame(buf: &mut BytesMut, max_len: usize) -> Result<Option<Bytes>, MetricError> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    if len > max_len {
+        return Err(MetricError::Pickle("message is too long"));
+    }
+    if buf.len() < len + 4 {
+        buf.reserve(len + 4 - buf.len());
+        return Ok(None);
+    }
+    buf.advance(4);
+    Ok(Some(buf.split_to(len).freeze()))
+}
+
+/// Parses a carbon pickle message, which is a list of `(path, (timestamp, value))` tuples.
+/// Values become gauges timestamped in seconds, since carbon has no metric types. Tags in names are sorted
+/// using the `intermediate` buffer, which grows if needed.
+///
+/// The whole message is rejected if any of its entries is malformed.
+pub fn parse_pickle<F>(data: &[u8], intermediate: &mut Vec<u8>) -> Result<Vec<(MetricName, Metric<F>)>, MetricError>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    let machine = Machine {
+        data,
+        stack: Vec::new(),
+        memo: HashMap::new(),
+        nodes: 0,
+    };
+    let root = machine.run()?;
+    let entries = match &*root {
+        Value::List(entries) | Value::Tuple(entries) => entries,
+        _ => return Err(MetricError::Pickle("message is not a list")),
+    };
+
+    entries
+        .iter()
+        .map(|entry| {
+            let (path, ts, value) = match &**entry {
+                Value::Tuple(entry) | Value::List(entry) if entry.len() == 2 => match (&*entry[0], &*entry[1]) {
+                    (Value::Str(path), Value::Tuple(point)) | (Value::Str(path), Value::List(point)) if point.len() == 2 => {
+                        (path, point[0].as_f64(), point[1].as_f64())
+                    }
+                    _ => return Err(MetricError::Pickle("bad metric entry")),
+                },
+                _ => return Err(MetricError::Pickle("bad metric entry")),
+            };
+            let (ts, value) = match (ts, value) {
+                (Some(ts), Some(value)) if ts >= 0f64 && ts.is_finite() => (ts as u64, value),
+                _ => return Err(MetricError::Pickle("bad metric point")),
+            };
+            if intermediate.len() < path.len() {
+                intermediate.resize(path.len(), 0);
+            }
+            let name = MetricName::new(BytesMut::from(&path[..]), TagFormat::Graphite, intermediate).map_err(|()| MetricError::Pickle("bad metric name"))?;
+            Ok((name, Metric::new(MetricValue::Gauge(F::from_f64(value)), Some(ts), 1f32)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // pickle.dumps([("a.b;z=1;y=2", (1600000000, 1.5)), ("c", (1600000001.0, 2))], protocol=2)
+    const PROTO2: &[u8] = b"\x80\x02]q\x00(X\x0b\x00\x00\x00a.b;z=1;y=2q\x01J\x00\x10^_G?\xf8\x00\x00\x00\x00\x00\x00\x86q\x02\x86q\x03X\x01\x00\x00\x00cq\x04GA\xd7\xd7\x84\x00@\x00\x00K\x02\x86q\x05\x86q\x06e.";
+
+    // pickle.dumps([("a", (1600000000, 7))], protocol=0)
+    const PROTO0: &[u8] = b"(lp0\n(Va\np1\n(I1600000000\nI7\ntp2\ntp3\na.";
+
+    #[test]
+    fn parse_pickle_protocols() {
+        let mut intermediate = Vec::new();
+        let metrics = parse_pickle::<f64>(PROTO2, &mut intermediate).unwrap();
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(&metrics[0].0.name[..], b"a.b;y=2;z=1");
+        assert_eq!(metrics[0].1.value(), &MetricValue::Gauge(1.5f64));
+        assert_eq!(metrics[0].1.timestamp(), Some(1_600_000_000));
+        assert_eq!(&metrics[1].0.name[..], b"c");
+        assert_eq!(metrics[1].1.value(), &MetricValue::Gauge(2f64));
+        assert_eq!(metrics[1].1.timestamp(), Some(1_600_000_001));
+
+        let metrics = parse_pickle::<f32>(PROTO0, &mut intermediate).unwrap();
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].1.value(), &MetricValue::Gauge(7f32));
+
+        // pickle.dumps(os.system) must never be executed
+        assert!(parse_pickle::<f64>(b"\x80\x02cposix\nsystem\nq\x00.", &mut intermediate).is_err());
+        assert!(parse_pickle::<f64>(&PROTO2[..20], &mut intermediate).is_err());
+        assert!(parse_pickle::<f64>(b"\x80\x02]q\x00K\x01a.", &mut intermediate).is_err());
+    }
+
+    #[test]
+    fn pickle_limits() {
+        let mut intermediate = Vec::new();
+        // x = 1; then 30 times x = (x, x), which would take 2^30 values if memoized values were copied
+        let mut bomb = b"\x80\x02K\x01".to_vec();
+        for _ in 0..30 {
+            bomb.extend_from_slice(b"q\x00h\x00\x86");
+        }
+        bomb.push(b'.');
+        assert!(matches!(
+            parse_pickle::<f64>(&bomb, &mut intermediate),
+            Err(MetricError::Pickle("bad metric entry"))
+        ));
+
+        // a list appended to and memoized again and again, every append copying the shared one
+        let mut bomb = b"\x80\x02]".to_vec();
+        for idx in 0..2000u32 {
+            bomb.push(b'r');
+            bomb.extend_from_slice(&idx.to_le_bytes());
+            bomb.extend_from_slice(b"K\x01a");
+        }
+        bomb.push(b'.');
+        assert!(matches!(
+            parse_pickle::<f64>(&bomb, &mut intermediate),
+            Err(MetricError::Pickle("pickle has too many values"))
+        ));
+
+        let mut nested = b"\x80\x02]".to_vec();
+        nested.resize(nested.len() + 100_000, 0x85);
+        nested.push(b'.');
+        assert!(matches!(
+            parse_pickle::<f64>(&nested, &mut intermediate),
+            Err(MetricError::Pickle("pickle is nested too deeply"))
+        ));
+    }
+
+    #[test]
+    fn pickle_frames() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&(PROTO0.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&PROTO0[..10]);
+        assert_eq!(pickle_frame(&mut buf, 1024).unwrap(), None);
+        buf.extend_from_slice(&PROTO0[10..]);
+        buf.extend_from_slice(&[0, 0]);
+        assert_eq!(&pickle_frame(&mut buf, 1024).unwrap().unwrap()[..], PROTO0);
+        assert_eq!(&buf[..], &[0, 0]);
+        buf.extend_from_slice(&[0xff, 0]);
+        assert!(pickle_frame(&mut buf, 1024).is_err());
+    }
+}
@@ -0,0 +1,305 @@
+use std::fmt::Debug;
+
+use num_traits::{AsPrimitive, Float};
+use wasmi::{Config, Engine, Linker, Memory, Module, Store, TypedFunc};
+
+use crate::aggregate::{aggregates, Aggregate};
+use crate::metric::{FromF64, Metric, MetricError, MetricValue, StatsdMetric, StatsdType};
+use crate::name::MetricName;
+use crate::parser::MetricFilter;
+
+/// The default limit of instructions executed by one hook call, see `WasmPlugin::with_fuel`
+pub const DEFAULT_FUEL: u64 = 10_000_000;
+
+fn plugin_error<E: ToString>(e: E) -> MetricError {
+    MetricError::Plugin(e.to_string())
+}
+
+/// The type code passed to the `filter` hook
+fn type_code<F: Debug>(mtype: &StatsdType<F>) -> i32 {
+    match mtype {
+        StatsdType::Counter => 0,
+        StatsdType::Timer => 1,
+        StatsdType::Gauge(None) => 2,
+        StatsdType::Gauge(Some(_)) => 3,
+        StatsdType::Set => 4,
+        StatsdType::CustomHistogram(_, _) => 5,
+    }
+}
+
+/// A WASM module with hooks customizing parsing and aggregation, loaded at runtime, so the behaviour can be
+/// changed without recompiling the consumers of the crate. Modules are interpreted, and each plugin has its own
+/// instance, so threads need a plugin each. The module exports its `memory` and may export:
+///
+/// * `alloc(len: i32) -> i32` giving a place for `len` bytes of arguments, required by the hooks below,
+///   the host never frees the memory, so the module may reuse it on the next call
+/// * `filter(name: i32, name_len: i32, type: i32, value: f64) -> i32` called for every parsed metric,
+///   the metric is dropped if it gives 0. Types are 0 for counters, 1 for timers, 2 for gauges, 3 for gauge
+///   changes, 4 for sets and 5 for custom histograms, values of gauge changes are signed
+/// * `aggregate(samples: i32, count: i32) -> f64` calculating `Aggregate::Custom` of timer values, samples are
+///   little-endian f64 numbers
+///
+/// Missing hooks do nothing, i.e. all metrics are kept without `filter`. The plugin is used as a filter
+/// by `MetricParser::with_filter` and calculates aggregates with `aggregates`. Every call of a hook is limited
+/// in the number of instructions executed, so a looping module fails instead of hanging the thread.
+pub struct WasmPlugin {
+    store: Store<()>,
+    fuel: u64,
+    memory: Memory,
+    alloc: Option<TypedFunc<i32, i32>>,
+    filter: Option<TypedFunc<(i32, i32, i32, f64), i32>>,
+    aggregate: Option<TypedFunc<(i32, i32), f64>>,
+}
+
+impl WasmPlugin {
+    /// Loads the module in binary format, modules importing anything are rejected
+    pub fn new(wasm: &[u8]) -> Result<Self, MetricError> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm).map_err(plugin_error)?;
+        let mut store = Store::new(&engine, ());
+        store.set_fuel(DEFAULT_FUEL).map_err(plugin_error)?;
+        let instance = Linker::new(&engine)
+            .instantiate(&mut store, &module)
+            .and_then(|instance| instance.start(&mut store))
+            .map_err(plugin_error)?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| MetricError::Plugin("module exports no memory".into()))?;
+        let alloc = instance.get_typed_func(&store, "alloc").ok();
+        let filter = instance.get_typed_func(&store, "filter").ok();
+        let aggregate = instance.get_typed_func(&store, "aggregate").ok();
+        if alloc.is_none() && (filter.is_some() || aggregate.is_some()) {
+            return Err(MetricError::Plugin("module exports hooks but no alloc".into()));
+        }
+        Ok(Self {
+            store,
+            fuel: DEFAULT_FUEL,
+            memory,
+            alloc,
+            filter,
+            aggregate,
+        })
+    }
+
+    /// Sets the limit of instructions executed by one hook call, `DEFAULT_FUEL` by default
+    pub fn with_fuel(mut self, fuel: u64) -> Self {
+        self.fuel = fuel;
+        self
+    }
+
+    pub fn has_filter(&self) -> bool {
+        self.filter.is_some()
+    }
+
+    pub fn has_aggregate(&self) -> bool {
+        self.aggregate.is_some()
+    }
+
+    // every hook call gets the whole budget
+    fn refuel(&mut self) -> Result<(), MetricError> {
+        self.store.set_fuel(self.fuel).map_err(plugin_error)
+    }
+
+    // copies the data into a place given by alloc
+    fn write(&mut self, data: &[u8]) -> Result<i32, MetricError> {
+        let alloc = self.alloc.ok_or_else(|| MetricError::Plugin("no alloc".into()))?;
+        self.refuel()?;
+        let ptr = alloc.call(&mut self.store, data.len() as i32).map_err(plugin_error)?;
+        self.memory
+            .write(&mut self.store, ptr as u32 as usize, data)
+            .map_err(|_| MetricError::Plugin("alloc gave memory out of bounds".into()))?;
+        Ok(ptr)
+    }
+
+    /// Tells if the metric should be kept
+    pub fn filter<F>(&mut self, name: &MetricName, metric: &StatsdMetric<F>) -> Result<bool, MetricError>
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<f64>,
+    {
+        let filter = match self.filter {
+            Some(filter) => filter,
+            None => return Ok(true),
+        };
+        let ptr = self.write(&name.name)?;
+        let value = match metric.mtype() {
+            StatsdType::Gauge(Some(sign)) if *sign < 0 => -metric.value(),
+            _ => metric.value(),
+        };
+        self.refuel()?;
+        let keep = filter
+            .call(&mut self.store, (ptr, name.name.len() as i32, type_code(metric.mtype()), value.as_()))
+            .map_err(plugin_error)?;
+        Ok(keep != 0)
+    }
+
+    /// The custom aggregate of the samples, None if the module has no `aggregate` hook
+    pub fn aggregate<F>(&mut self, samples: &[F]) -> Result<Option<F>, MetricError>
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<f64>,
+    {
+        let aggregate = match self.aggregate {
+            Some(aggregate) => aggregate,
+            None => return Ok(None),
+        };
+        let data = samples
+            .iter()
+            .flat_map(|sample| AsPrimitive::<f64>::as_(*sample).to_le_bytes())
+            .collect::<Vec<_>>();
+        let ptr = self.write(&data)?;
+        self.refuel()?;
+        let value = aggregate.call(&mut self.store, (ptr, samples.len() as i32)).map_err(plugin_error)?;
+        Ok(Some(F::from_f64(value)))
+    }
+
+    /// Calculates the aggregates like `aggregate::aggregates` does, with `Aggregate::Custom` of timers given
+    /// by the `aggregate` hook
+    pub fn aggregates<F>(&mut self, metric: &mut Metric<F>, requested: &[Aggregate<F>]) -> Result<Vec<(Aggregate<F>, F)>, MetricError>
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<f64> + AsPrimitive<usize>,
+    {
+        let mut values = aggregates(metric, requested).collect::<Vec<_>>();
+        if !requested.contains(&Aggregate::Custom) {
+            return Ok(values);
+        }
+        if let MetricValue::Timer(ref samples) = metric.value() {
+            if let Some(value) = self.aggregate(samples)? {
+                values.push((Aggregate::Custom, value));
+            }
+        }
+        Ok(values)
+    }
+}
+
+impl<F> MetricFilter<F> for WasmPlugin
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    fn keep(&mut self, name: &MetricName, metric: &StatsdMetric<F>) -> Result<bool, MetricError> {
+        self.filter(name, metric)
+    }
+}
+
+impl Debug for WasmPlugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WasmPlugin")
+            .field("filter", &self.has_filter())
+            .field("aggregate", &self.has_aggregate())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{DummyParseErrorHandler, MetricParser};
+    use crate::testing::name;
+    use bytes::BytesMut;
+
+    // the modules are compiled from the text format to avoid depending on a WAT parser in tests
+
+    // keeps metrics with names not starting with `debug` and values not above 1000,
+    // aggregates samples to their maximum
+    // (module
+    //   (memory (export "memory") 1)
+    //   (func (export "alloc") (param $len i32) (result i32)
+    //     i32.const 1024)
+    //   (func (export "filter") (param $name i32) (param $len i32) (param $type i32) (param $value f64) (result i32)
+    //     (if (i32.ge_u (local.get $len) (i32.const 5))
+    //       (then
+    //         (if (i32.eq (i32.load (local.get $name)) (i32.const 0x75626564))
+    //           (then (return (i32.const 0))))))
+    //     (f64.le (local.get $value) (f64.const 1000)))
+    //   (func (export "aggregate") (param $samples i32) (param $count i32) (result f64)
+    //     (local $max f64)
+    //     (local.set $max (f64.const -inf))
+    //     (block $done
+    //       (loop $next
+    //         (br_if $done (i32.eqz (local.get $count)))
+    //         (local.set $max (f64.max (local.get $max) (f64.load (local.get $samples))))
+    //         (local.set $samples (i32.add (local.get $samples) (i32.const 8)))
+    //         (local.set $count (i32.sub (local.get $count) (i32.const 1)))
+    //         (br $next)))
+    //     (local.get $max)))
+    const PLUGIN: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x14, 0x03, 0x60, 0x01, 0x7f, 0x01, 0x7f, 0x60, 0x04, 0x7f, 0x7f, 0x7f, 0x7c, 0x01, 0x7f, 0x60,
+        0x02, 0x7f, 0x7f, 0x01, 0x7c, 0x03, 0x04, 0x03, 0x00, 0x01, 0x02, 0x05, 0x03, 0x01, 0x00, 0x01, 0x07, 0x27, 0x04, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72,
+        0x79, 0x02, 0x00, 0x05, 0x61, 0x6c, 0x6c, 0x6f, 0x63, 0x00, 0x00, 0x06, 0x66, 0x69, 0x6c, 0x74, 0x65, 0x72, 0x00, 0x01, 0x09, 0x61, 0x67, 0x67, 0x72,
+        0x65, 0x67, 0x61, 0x74, 0x65, 0x00, 0x02, 0x0a, 0x67, 0x03, 0x05, 0x00, 0x41, 0x80, 0x08, 0x0b, 0x28, 0x00, 0x20, 0x01, 0x41, 0x05, 0x4f, 0x04, 0x40,
+        0x20, 0x00, 0x28, 0x02, 0x00, 0x41, 0xe4, 0xca, 0x89, 0xab, 0x07, 0x46, 0x04, 0x40, 0x41, 0x00, 0x0f, 0x0b, 0x0b, 0x20, 0x03, 0x44, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x40, 0x8f, 0x40, 0x65, 0x0b, 0x36, 0x01, 0x01, 0x7c, 0x44, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xf0, 0xff, 0x21, 0x02, 0x02, 0x40, 0x03,
+        0x40, 0x20, 0x01, 0x45, 0x0d, 0x01, 0x20, 0x02, 0x20, 0x00, 0x2b, 0x03, 0x00, 0xa5, 0x21, 0x02, 0x20, 0x00, 0x41, 0x08, 0x6a, 0x21, 0x00, 0x20, 0x01,
+        0x41, 0x01, 0x6b, 0x21, 0x01, 0x0c, 0x00, 0x0b, 0x0b, 0x20, 0x02, 0x0b,
+    ];
+
+    // (module (memory (export "memory") 1))
+    const EMPTY: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x03, 0x01, 0x00, 0x01, 0x07, 0x0a, 0x01, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00,
+    ];
+
+    // never returns from aggregate
+    // (module
+    //   (memory (export "memory") 1)
+    //   (func (export "alloc") (param $len i32) (result i32)
+    //     i32.const 0)
+    //   (func (export "aggregate") (param $samples i32) (param $count i32) (result f64)
+    //     (loop $spin (br $spin))
+    //     f64.const 0))
+    const SPIN: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0c, 0x02, 0x60, 0x01, 0x7f, 0x01, 0x7f, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7c, 0x03, 0x03, 0x02,
+        0x00, 0x01, 0x05, 0x03, 0x01, 0x00, 0x01, 0x07, 0x1e, 0x03, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x05, 0x61, 0x6c, 0x6c, 0x6f, 0x63,
+        0x00, 0x00, 0x09, 0x61, 0x67, 0x67, 0x72, 0x65, 0x67, 0x61, 0x74, 0x65, 0x00, 0x01, 0x0a, 0x17, 0x02, 0x04, 0x00, 0x41, 0x00, 0x0b, 0x10, 0x00, 0x03,
+        0x40, 0x0c, 0x00, 0x0b, 0x44, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0b,
+    ];
+
+    #[test]
+    fn wasm_plugin_hooks() {
+        let mut plugin = WasmPlugin::new(PLUGIN).unwrap();
+        assert!(plugin.has_filter() && plugin.has_aggregate());
+
+        let metric = |value: f64| StatsdMetric::new(value, StatsdType::Timer, None).unwrap();
+        assert!(plugin.filter(&name("requests;dc=ams"), &metric(10f64)).unwrap());
+        assert!(!plugin.filter(&name("debug.requests"), &metric(10f64)).unwrap());
+        assert!(!plugin.filter(&name("requests"), &metric(5000f64)).unwrap());
+        assert!(plugin
+            .filter(&name("requests"), &StatsdMetric::new(5000f64, StatsdType::Gauge(Some(-1)), None).unwrap())
+            .unwrap());
+
+        assert_eq!(plugin.aggregate(&[1f64, 7f64, 3f64]).unwrap(), Some(7f64));
+        assert_eq!(plugin.aggregate(&[2f32]).unwrap(), Some(2f32));
+
+        let mut empty = WasmPlugin::new(EMPTY).unwrap();
+        assert!(empty.filter(&name("debug"), &metric(5000f64)).unwrap());
+        assert_eq!(empty.aggregate(&[1f64]).unwrap(), None);
+        assert!(WasmPlugin::new(b"not a module").is_err());
+    }
+
+    #[test]
+    fn wasm_plugin_parser_and_aggregates() {
+        let mut plugin = WasmPlugin::new(PLUGIN).unwrap();
+        let mut input = BytesMut::from("requests:10|ms\ndebug.requests:10|ms\nrequests:20|ms\n");
+        let parsed = MetricParser::<f64, _>::new(&mut input, 8192, 100, DummyParseErrorHandler)
+            .with_filter(&mut plugin)
+            .map(|(name, metric)| (String::from_utf8_lossy(&name.name).to_string(), metric.value()))
+            .collect::<Vec<_>>();
+        assert_eq!(parsed, vec![("requests".to_string(), 10f64), ("requests".to_string(), 20f64)]);
+
+        let mut metric = Metric::new(MetricValue::Timer(vec![3f64, 9f64, 1f64]), None, 1f32);
+        assert_eq!(
+            plugin.aggregates(&mut metric, &[Aggregate::Min, Aggregate::Custom]).unwrap(),
+            vec![(Aggregate::Min, 1f64), (Aggregate::Custom, 9f64)]
+        );
+        let mut counter = Metric::new(MetricValue::Counter(3f64), None, 1f32);
+        assert_eq!(plugin.aggregates(&mut counter, &[Aggregate::Custom]).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn wasm_plugin_fuel() {
+        let mut plugin = WasmPlugin::new(SPIN).unwrap().with_fuel(10_000);
+        assert!(plugin.aggregate(&[1f64]).is_err());
+        // the budget is given again on every call
+        assert!(plugin.aggregate(&[1f64]).is_err());
+        assert!(WasmPlugin::new(PLUGIN).unwrap().with_fuel(100).aggregate(&[1f64; 1000]).is_err());
+    }
+}
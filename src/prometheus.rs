@@ -0,0 +1,336 @@
+use std::fmt::{Debug, Write};
+
+use bytes::BytesMut;
+use num_traits::{AsPrimitive, Float};
+
+use crate::aggregate::{aggregates, percentile_from_num, quantile_from_num, Aggregate};
+use crate::metric::{FromF64, Metric, MetricUnit, MetricValue};
+use crate::name::{unescape_tag_value, MetricName, UnicodePolicy};
+
+/// Renders metrics as a Prometheus text exposition page, i.e. for a /metrics endpoint
+///
+/// * counters become counters with `_total` suffix, unless the name already has it
+/// * gauges become gauges, sets become gauges of their cardinality
+/// * timers become summaries with configured quantiles
+/// * custom histograms become histograms, note that their buckets count values strictly less than the bound,
+///   while Prometheus expects less or equal values. Histograms do not keep the sum, so it is not rendered.
+///
+/// Names are sanitized to match Prometheus rules, tags become labels. The output is sorted by name
+/// and labels, so pages are stable between scrapes. Metric units are written as OpenMetrics `# UNIT`
+/// lines for the families having the unit suffix, i.e. `response_size_bytes`, as OpenMetrics requires.
+/// A family can only have one type, so when series of different types get the same family name,
+/// i.e. a counter `requests` and a gauge `requests_total`, only the series of the type coming first
+/// in the order of counter, gauge, summary and histogram are rendered.
+#[derive(Debug, Clone)]
+pub struct PrometheusEncoder<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize>,
+{
+    timer_aggregates: Vec<Aggregate<F>>,
+    unicode: UnicodePolicy,
+}
+
+impl<F> Default for PrometheusEncoder<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize> + AsPrimitive<f64>,
+{
+    fn default() -> Self {
+        Self::new(&[50, 90, 99])
+    }
+}
+
+fn sanitize(name: &[u8], out: &mut String) {
+    for (idx, c) in name.iter().enumerate() {
+        let c = *c as char;
+        if c.is_ascii_alphabetic() || c == '_' || c == ':' || (idx > 0 && c.is_ascii_digit()) {
+            out.push(c);
+        } else {
+            out.push('_');
+        }
+    }
+}
+
+fn put_float<F: Float + AsPrimitive<f64>>(buf: &mut BytesMut, value: F) {
+    let value: f64 = value.as_();
+    // writing to BytesMut never fails
+    if value.is_nan() {
+        buf.extend_from_slice(b"NaN");
+    } else if value.is_infinite() && value > 0f64 {
+        buf.extend_from_slice(b"+Inf");
+    } else if value.is_infinite() {
+        buf.extend_from_slice(b"-Inf");
+    } else {
+        write!(buf, "{}", value).unwrap_or_default();
+    }
+}
+
+fn put_line<F: Float + AsPrimitive<f64>>(buf: &mut BytesMut, name: &str, suffix: &str, labels: &[(String, String)], extra: Option<(&str, &str)>, value: F) {
+    buf.extend_from_slice(name.as_bytes());
+    buf.extend_from_slice(suffix.as_bytes());
+    let mut labelled = false;
+    for (key, value) in labels.iter().map(|(k, v)| (k.as_str(), v.as_str())).chain(extra) {
+        buf.extend_from_slice(if labelled { b"," } else { b"{" });
+        labelled = true;
+        buf.extend_from_slice(key.as_bytes());
+        buf.extend_from_slice(b"=\"");
+        for c in value.bytes() {
+            match c {
+                b'\\' => buf.extend_from_slice(b"\\\\"),
+                b'"' => buf.extend_from_slice(b"\\\""),
+                b'\n' => buf.extend_from_slice(b"\\n"),
+                c => buf.extend_from_slice(&[c]),
+            }
+        }
+        buf.extend_from_slice(b"\"");
+    }
+    if labelled {
+        buf.extend_from_slice(b"}");
+    }
+    buf.extend_from_slice(b" ");
+    put_float(buf, value);
+    buf.extend_from_slice(b"\n");
+}
+
+struct Series<'a, F>
+where
+    F: Copy + PartialEq + Debug,
+{
+    family: String,
+    rank: usize,
+    kind: &'static str,
+    labels: Vec<(String, String)>,
+    metric: &'a Metric<F>,
+}
+
+// the family type and the rank of it, i.e. its priority when series of different types collide
+fn family_kind<F: Copy + PartialEq + Debug>(value: &MetricValue<F>) -> (usize, &'static str) {
+    match value {
+        MetricValue::Counter(_) => (0, "counter"),
+        MetricValue::Gauge(_) | MetricValue::Set(_) | MetricValue::SortedSet(_) => (1, "gauge"),
+        MetricValue::Timer(_) | MetricValue::CompactTimer(_) => (2, "summary"),
+        MetricValue::CustomHistogram(_, _) => (3, "histogram"),
+    }
+}
+
+impl<F> PrometheusEncoder<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize> + AsPrimitive<f64>,
+{
+    /// Quantiles of summaries are specified like percentiles in config, i.e. `[50, 99, 999]`
+    /// stand for 0.5, 0.99 and 0.999 quantiles
+    pub fn new(quantiles: &[u64]) -> Self {
+        let mut timer_aggregates = vec![Aggregate::Sum, Aggregate::Count];
+        timer_aggregates.extend(quantiles.iter().map(|num| percentile_from_num(*num)));
+        Self {
+            timer_aggregates,
+            unicode: UnicodePolicy::default(),
+        }
+    }
+
+    /// Apply the policy to names with non-ASCII characters before sanitizing them, the metrics with
+    /// rejected names are skipped. By default non-ASCII characters of names and label keys are replaced
+    /// with `_` by sanitizing, while label values are kept as is
+    pub fn with_unicode(mut self, policy: UnicodePolicy) -> Self {
+        self.unicode = policy;
+        self
+    }
+
+    /// Appends the page for the metrics to the buffer
+    pub fn encode<'a, I>(&self, buf: &mut BytesMut, metrics: I)
+    where
+        I: IntoIterator<Item = (&'a MetricName, &'a Metric<F>)>,
+        F: 'a,
+    {
+        let mut series = metrics
+            .into_iter()
+            .filter_map(|(name, metric)| {
+                // expired gauges are not exported at all
+                if metric.is_gauge_unset() {
+                    return None;
+                }
+                let name = self.unicode.encoded_name(name)?;
+                let mut family = String::with_capacity(name.name_without_tags().len() + 6);
+                sanitize(name.name_without_tags(), &mut family);
+                if matches!(metric.value(), MetricValue::Counter(_)) && !family.ends_with("_total") {
+                    family.push_str("_total");
+                }
+                let mut labels = name
+                    .tags()
+                    .map(|(key, value)| {
+                        let mut label = String::with_capacity(key.len());
+                        sanitize(key, &mut label);
+                        (label, String::from_utf8_lossy(&unescape_tag_value(value)).into_owned())
+                    })
+                    .collect::<Vec<_>>();
+                labels.sort();
+                let (rank, kind) = family_kind(metric.value());
+                Some(Series {
+                    family,
+                    rank,
+                    kind,
+                    labels,
+                    metric,
+                })
+            })
+            .collect::<Vec<_>>();
+        series.sort_by(|a, b| a.family.cmp(&b.family).then(a.rank.cmp(&b.rank)).then_with(|| a.labels.cmp(&b.labels)));
+
+        let mut last_family: Option<(&str, &str)> = None;
+        for s in &series {
+            if let Some((family, kind)) = last_family {
+                // the series collides with the family of another type
+                if family == s.family && kind != s.kind {
+                    continue;
+                }
+            }
+            if last_family.map(|(family, _)| family) != Some(s.family.as_str()) {
+                writeln!(buf, "# TYPE {} {}", s.family, s.kind).unwrap_or_default();
+                let base = s.family.strip_suffix("_total").unwrap_or(&s.family);
+                if let Some(unit) = s.metric.unit().and_then(MetricUnit::long_name) {
+                    if base.strip_suffix(unit).map(|rest| rest.ends_with('_')).unwrap_or(false) {
+                        writeln!(buf, "# UNIT {} {}", s.family, unit).unwrap_or_default();
+                    }
+                }
+                last_family = Some((s.family.as_str(), s.kind));
+            }
+
+            match s.metric.value() {
+                MetricValue::Counter(value) | MetricValue::Gauge(value) => put_line(buf, &s.family, "", &s.labels, None, *value),
+                MetricValue::Set(_) | MetricValue::SortedSet(_) => {
+                    let len = s.metric.set_len().unwrap_or(0);
+                    put_line(buf, &s.family, "", &s.labels, None, F::from_f64(len as f64))
+                }
+                MetricValue::Timer(_) | MetricValue::CompactTimer(_) => {
+                    let mut metric = s.metric.clone();
+                    for (aggregate, value) in aggregates(&mut metric, &self.timer_aggregates) {
+                        match aggregate {
+                            Aggregate::Sum => put_line(buf, &s.family, "_sum", &s.labels, None, value),
+                            Aggregate::Count => put_line(buf, &s.family, "_count", &s.labels, None, value),
+                            Aggregate::Percentile(_, num) => {
+                                let q = quantile_from_num(num);
+                                put_line(buf, &s.family, "", &s.labels, Some(("quantile", &q.to_string())), value)
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                MetricValue::CustomHistogram(left, buckets) => {
+                    let mut count = *left;
+                    for (bound, bucket) in buckets {
+                        let bound: f64 = bound.as_();
+                        put_line(
+                            buf,
+                            &s.family,
+                            "_bucket",
+                            &s.labels,
+                            Some(("le", &bound.to_string())),
+                            F::from_f64(count as f64),
+                        );
+                        count += bucket;
+                    }
+                    put_line(buf, &s.family, "_bucket", &s.labels, Some(("le", "+Inf")), F::from_f64(count as f64));
+                    put_line(buf, &s.family, "_count", &s.labels, None, F::from_f64(count as f64));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::name;
+
+    #[test]
+    fn prometheus_page() {
+        let metrics = [
+            (name("requests;path=/a\\\"b\n;code=200"), Metric::new(MetricValue::Counter(5f64), None, 1f32)),
+            (name("requests;code=500"), Metric::new(MetricValue::Counter(1f64), None, 1f32)),
+            (name("cpu.load"), Metric::new(MetricValue::Gauge(f64::INFINITY), None, 1f32)),
+            (name("latency"), Metric::new(MetricValue::Timer(vec![3f64, 1f64, 2f64]), None, 1f32)),
+            (
+                name("sizes"),
+                Metric::new(MetricValue::CustomHistogram(1, vec![(0f64, 2), (10f64, 3)]), None, 1f32),
+            ),
+        ];
+
+        let mut buf = BytesMut::new();
+        PrometheusEncoder::new(&[50]).encode(&mut buf, metrics.iter().map(|(name, metric)| (name, metric)));
+        let expected = r#"# TYPE cpu_load gauge
+cpu_load +Inf
+# TYPE latency summary
+latency_sum 6
+latency_count 3
+latency{quantile="0.5"} 2
+# TYPE requests_total counter
+requests_total{code="200",path="/a\\\"b\n"} 5
+requests_total{code="500"} 1
+# TYPE sizes histogram
+sizes_bucket{le="0"} 1
+sizes_bucket{le="10"} 3
+sizes_bucket{le="+Inf"} 6
+sizes_count 6
+"#;
+        assert_eq!(String::from_utf8(buf.to_vec()).unwrap(), expected);
+    }
+
+    #[test]
+    fn prometheus_units() {
+        let mut metric = Metric::new(MetricValue::Counter(5f64), None, 1f32);
+        metric.set_unit(Some(MetricUnit::Bytes));
+        let mut unsuffixed = Metric::new(MetricValue::Gauge(1f64), None, 1f32);
+        unsuffixed.set_unit(Some(MetricUnit::Seconds));
+        let metrics = [
+            (MetricName::new_untagged(BytesMut::from("sent_bytes")), metric),
+            (MetricName::new_untagged(BytesMut::from("uptime")), unsuffixed),
+        ];
+
+        let mut buf = BytesMut::new();
+        PrometheusEncoder::<f64>::default().encode(&mut buf, metrics.iter().map(|(name, metric)| (name, metric)));
+        let expected = "# TYPE sent_bytes_total counter\n# UNIT sent_bytes_total bytes\nsent_bytes_total 5\n# TYPE uptime gauge\nuptime 1\n";
+        assert_eq!(String::from_utf8(buf.to_vec()).unwrap(), expected);
+    }
+
+    #[test]
+    fn prometheus_unicode() {
+        let metric = Metric::new(MetricValue::Gauge(1f64), None, 1f32);
+        let metrics = [
+            (MetricName::new_untagged(BytesMut::from("température")), metric.clone()),
+            (MetricName::new_untagged(BytesMut::from("uptime")), metric),
+        ];
+        let encode = |policy| {
+            let mut buf = BytesMut::new();
+            PrometheusEncoder::<f64>::default()
+                .with_unicode(policy)
+                .encode(&mut buf, metrics.iter().map(|(name, metric)| (name, metric)));
+            String::from_utf8(buf.to_vec()).unwrap()
+        };
+        assert!(encode(UnicodePolicy::Allow).starts_with("# TYPE temp__rature gauge\n"));
+        assert!(encode(UnicodePolicy::Transliterate).starts_with("# TYPE temperature gauge\n"));
+        assert!(encode(UnicodePolicy::Reject).starts_with("# TYPE uptime gauge\n"));
+    }
+
+    #[test]
+    fn prometheus_colliding_families() {
+        let metrics = [
+            (name("requests_total"), Metric::new(MetricValue::Gauge(1f32), None, 1f32)),
+            (name("requests"), Metric::new(MetricValue::Counter(2f32), None, 1f32)),
+            (name("latency"), Metric::new(MetricValue::Timer(vec![1f32]), None, 1f32)),
+            (name("errors_total"), Metric::new(MetricValue::Counter(3f32), None, 1f32)),
+        ];
+
+        let mut buf = BytesMut::new();
+        PrometheusEncoder::<f32>::new(&[99, 999]).encode(&mut buf, metrics.iter().map(|(name, metric)| (name, metric)));
+        let expected = r#"# TYPE errors_total counter
+errors_total 3
+# TYPE latency summary
+latency_sum 1
+latency_count 1
+latency{quantile="0.99"} 1
+latency{quantile="0.999"} 1
+# TYPE requests_total counter
+requests_total 2
+"#;
+        assert_eq!(String::from_utf8(buf.to_vec()).unwrap(), expected);
+    }
+}
@@ -0,0 +1,1904 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fmt::Debug;
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use capnp::message::{Builder, HeapAllocator, Reader, ReaderOptions, ScratchSpaceHeapAllocator};
+use capnp::serialize::{OwnedSegments, SliceSegments};
+use num_traits::{AsPrimitive, Float};
+use serde::{Deserialize, Serialize};
+
+use crate::metric::{accumulate_all, FromF64, Metric, MetricError, MetricValue, ProtocolVersion};
+use crate::name::MetricName;
+use crate::protocol_capnp::{gauge as gauge_v1, message as message_v1, metric as cmetric_v1, metric_type};
+use crate::protocol_v2_capnp::{message, metric as cmetric, metric::metric_value, Codec as CCodec};
+
+/// Limits applied when reading capnp messages, protecting from hostile or just oversized messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct DecodeOptions {
+    /// maximum number of bytes allowed to be traversed when reading a message, `None` means no limit
+    /// note that traversing the same part of message twice is counted twice
+    pub traversal_limit: Option<usize>,
+
+    /// maximum nesting depth of message structures
+    pub nesting_limit: i32,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        Self {
+            traversal_limit: Some(64 * 1024 * 1024),
+            nesting_limit: 64,
+        }
+    }
+}
+
+impl DecodeOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn traversal_limit(mut self, bytes: Option<usize>) -> Self {
+        self.traversal_limit = bytes;
+        self
+    }
+
+    pub fn nesting_limit(mut self, limit: i32) -> Self {
+        self.nesting_limit = limit;
+        self
+    }
+
+    pub fn reader_options(&self) -> ReaderOptions {
+        let mut options = ReaderOptions::new();
+        // capnp counts limit in 8-byte words, rounding up to not make the limit stricter than requested
+        options.traversal_limit_in_words(self.traversal_limit.map(|bytes| bytes.div_ceil(8)));
+        options.nesting_limit(self.nesting_limit);
+        options
+    }
+}
+
+/// Reads a capnp message from the stream applying the limits from options
+pub fn read_message<R: Read>(read: R, options: &DecodeOptions) -> Result<Reader<OwnedSegments>, MetricError> {
+    capnp::serialize::read_message(read, options.reader_options()).map_err(MetricError::Capnp)
+}
+
+/// Reads a capnp message from the slice applying the limits from options, advancing the slice to
+/// the end of message
+pub fn read_message_from_slice<'a>(slice: &mut &'a [u8], options: &DecodeOptions) -> Result<Reader<SliceSegments<'a>>, MetricError> {
+    capnp::serialize::read_message_from_flat_slice(slice, options.reader_options()).map_err(MetricError::Capnp)
+}
+
+/// Reads a snapshot message of schema version 2 from the stream
+pub fn decode_snapshot<F, R>(read: R, options: &DecodeOptions) -> Result<Vec<(MetricName, Metric<F>)>, MetricError>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+    R: Read,
+{
+    let message = read_message(read, options)?;
+    let reader = message.get_root::<message::Reader>().map_err(MetricError::Capnp)?;
+    read_snapshot(reader)
+}
+
+/// Decodes a snapshot message on multiple threads, accumulating metrics into the map split to shards
+/// by `MetricName::shard_index`. Each thread decodes its own part of the snapshot and locks
+/// each shard only once, so the threads mostly do not wait for each other.
+///
+/// Decoding errors stop the decoding, but metrics decoded before the error may already be in
+/// the shards, the same is true for integrity check errors, since integrity can only be checked
+/// after all the metrics are decoded. Accumulation errors are returned along with the names of failed metrics.
+pub fn decode_snapshot_parallel<F>(
+    data: &[u8],
+    options: &DecodeOptions,
+    threads: usize,
+    shards: &[Mutex<HashMap<MetricName, Metric<F>>>],
+) -> Result<Vec<(MetricName, MetricError)>, MetricError>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64> + Send,
+{
+    let (len, expected) = {
+        let mut slice = data;
+        let message = read_message_from_slice(&mut slice, options)?;
+        let reader = message.get_root::<message::Reader>().map_err(MetricError::Capnp)?;
+        match reader.which().map_err(MetricError::CapnpSchema)? {
+            message::Which::Noop(()) | message::Which::Ack(_) | message::Which::ChunkAck(_) => return Ok(Vec::new()),
+            message::Which::Snapshot(metrics) => (metrics.map_err(MetricError::Capnp)?.len() as usize, SnapshotIntegrity::from_capnp(reader)?),
+        }
+    };
+    if len == 0 || shards.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let threads = threads.clamp(1, len);
+    let chunk = len.div_ceil(threads);
+    std::thread::scope(|scope| {
+        let handles = (0..threads)
+            .map(|i| {
+                let range = i * chunk..len.min((i + 1) * chunk);
+                scope.spawn(move || decode_snapshot_part(data, options, range, shards))
+            })
+            .collect::<Vec<_>>();
+
+        let mut errors = Vec::new();
+        let mut integrity = SnapshotIntegrity::default();
+        for handle in handles {
+            let (part_errors, part_integrity) = handle.join().unwrap_or_else(|e| std::panic::resume_unwind(e))?;
+            errors.extend(part_errors);
+            integrity.merge(&part_integrity);
+        }
+        if let Some(expected) = expected {
+            integrity.verify(&expected)?;
+        }
+        Ok(errors)
+    })
+}
+
+fn decode_snapshot_part<F>(
+    mut data: &[u8],
+    options: &DecodeOptions,
+    range: Range<usize>,
+    shards: &[Mutex<HashMap<MetricName, Metric<F>>>],
+) -> Result<(Vec<(MetricName, MetricError)>, SnapshotIntegrity), MetricError>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    // capnp readers cannot be shared between threads, so each thread reads the message by itself,
+    // this is cheap because nothing is copied or decoded in advance
+    let message = read_message_from_slice(&mut data, options)?;
+    let reader = message.get_root::<message::Reader>().map_err(MetricError::Capnp)?;
+    let metrics = match reader.which().map_err(MetricError::CapnpSchema)? {
+        message::Which::Noop(()) | message::Which::Ack(_) | message::Which::ChunkAck(_) => return Ok((Vec::new(), SnapshotIntegrity::default())),
+        message::Which::Snapshot(metrics) => metrics.map_err(MetricError::Capnp)?,
+    };
+    let dictionary = if reader.has_dictionary() {
+        Some(reader.get_dictionary().map_err(MetricError::Capnp)?)
+    } else {
+        None
+    };
+
+    let mut batches = shards.iter().map(|_| Vec::new()).collect::<Vec<_>>();
+    let mut integrity = SnapshotIntegrity::default();
+    for idx in range {
+        let (name, metric) = Metric::from_capnp_with_dictionary(metrics.get(idx as u32), dictionary)?;
+        integrity.add(&name, &metric);
+        batches[name.shard_index(shards.len())].push((name, metric));
+    }
+
+    let mut errors = Vec::new();
+    for (shard, batch) in shards.iter().zip(batches) {
+        if !batch.is_empty() {
+            let mut cache = shard.lock().unwrap_or_else(PoisonError::into_inner);
+            errors.extend(accumulate_all(&mut cache, batch));
+        }
+    }
+    Ok((errors, integrity))
+}
+
+/// Reads a message containing a single metric of schema version 2 from the stream
+pub fn decode_metric<F, R>(read: R, options: &DecodeOptions) -> Result<(MetricName, Metric<F>), MetricError>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+    R: Read,
+{
+    let message = read_message(read, options)?;
+    let reader = message.get_root::<cmetric::Reader>().map_err(MetricError::Capnp)?;
+    Metric::from_capnp(reader)
+}
+
+/// Reads a message containing a single metric of schema version 1 from the stream
+pub fn decode_metric_v1<F, R>(read: R, options: &DecodeOptions) -> Result<(MetricName, Metric<F>), MetricError>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+    R: Read,
+{
+    let message = read_message(read, options)?;
+    let reader = message.get_root::<cmetric_v1::Reader>().map_err(MetricError::Capnp)?;
+    Metric::from_capnp_v1(reader)
+}
+
+/// The kind of mismatch between a received message and the schema expected by this crate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// a field required for decoding is not set
+    Missing,
+    /// a union has a discriminant unknown to this version of schema, i.e. the message was
+    /// produced using a newer schema
+    UnknownVariant(u16),
+    /// a field is set, but cannot be read, i.e. because of pointing outside of the message
+    Malformed,
+    /// a field uses a feature that is not supported anymore
+    Deprecated,
+    /// message declares protocol version different from the expected one
+    Version(u64),
+}
+
+/// A single schema mismatch found in a message. `path` is a dot-separated path to the field
+/// in the message with list indexes in brackets, i.e. `snapshot[2].value.timer`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaViolation {
+    pub path: String,
+    pub kind: ViolationKind,
+}
+
+impl SchemaViolation {
+    fn new(path: &str, field: &str, kind: ViolationKind) -> Self {
+        let path = if path.is_empty() { field.to_string() } else { format!("{}.{}", path, field) };
+        Self { path, kind }
+    }
+}
+
+impl fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ViolationKind::Missing => write!(f, "{}: missing", self.path),
+            ViolationKind::UnknownVariant(d) => write!(f, "{}: unknown union variant {}", self.path, d),
+            ViolationKind::Malformed => write!(f, "{}: malformed", self.path),
+            ViolationKind::Deprecated => write!(f, "{}: deprecated", self.path),
+            ViolationKind::Version(v) => write!(f, "{}: unexpected protocol version {:#x}", self.path, v),
+        }
+    }
+}
+
+/// Accumulates violations found during the check
+#[derive(Default)]
+struct Checker {
+    violations: Vec<SchemaViolation>,
+}
+
+impl Checker {
+    fn report(&mut self, path: &str, field: &str, kind: ViolationKind) {
+        self.violations.push(SchemaViolation::new(path, field, kind))
+    }
+
+    fn into_result(self) -> Result<(), MetricError> {
+        if self.violations.is_empty() {
+            Ok(())
+        } else {
+            Err(MetricError::Schema(self.violations))
+        }
+    }
+
+    fn metric(&mut self, path: &str, reader: cmetric::Reader, dictionary_len: Option<u32>) {
+        if reader.has_name() {
+            if reader.get_name().is_err() {
+                self.report(path, "name", ViolationKind::Malformed);
+            }
+        } else if reader.has_name_refs() && dictionary_len.is_some() {
+            let max = dictionary_len.unwrap_or(0);
+            match reader.get_name_refs() {
+                Ok(refs) if refs.iter().all(|idx| idx < max) => (),
+                _ => self.report(path, "nameRefs", ViolationKind::Malformed),
+            }
+        } else {
+            self.report(path, "name", ViolationKind::Missing);
+        }
+
+        if !reader.has_value() {
+            self.report(path, "value", ViolationKind::Missing);
+        } else {
+            match reader.get_value() {
+                Ok(value) => self.metric_value(path, value),
+                Err(_) => self.report(path, "value", ViolationKind::Malformed),
+            }
+        }
+
+        if reader.has_timestamp() && reader.get_timestamp().is_err() {
+            self.report(path, "timestamp", ViolationKind::Malformed);
+        }
+
+        if !reader.has_meta() {
+            self.report(path, "meta", ViolationKind::Missing);
+        } else {
+            match reader.get_meta() {
+                Ok(meta) => {
+                    if let Err(capnp::NotInSchema(d)) = meta.get_tags().which() {
+                        self.report(path, "meta.tags", ViolationKind::UnknownVariant(d));
+                    }
+                }
+                Err(_) => self.report(path, "meta", ViolationKind::Malformed),
+            }
+        }
+    }
+
+    fn metric_value(&mut self, path: &str, reader: metric_value::Reader) {
+        let malformed = match reader.which() {
+            Ok(metric_value::Which::Gauge(_)) | Ok(metric_value::Which::Counter(_)) => None,
+            Ok(metric_value::Which::Timer(r)) => r.err().map(|_| "value.timer"),
+            Ok(metric_value::Which::Set(r)) => r.err().map(|_| "value.set"),
+            Ok(metric_value::Which::CustomHistogram(r)) => match r {
+                Ok(r) if r.get_buckets().is_ok() => None,
+                Ok(_) => Some("value.customHistogram.buckets"),
+                Err(_) => Some("value.customHistogram"),
+            },
+            Err(capnp::NotInSchema(d)) => {
+                self.report(path, "value", ViolationKind::UnknownVariant(d));
+                None
+            }
+        };
+        if let Some(field) = malformed {
+            self.report(path, field, ViolationKind::Malformed);
+        }
+    }
+
+    fn metric_v1(&mut self, path: &str, reader: cmetric_v1::Reader) {
+        if !reader.has_name() {
+            self.report(path, "name", ViolationKind::Missing);
+        } else if reader.get_name().is_err() {
+            self.report(path, "name", ViolationKind::Malformed);
+        }
+
+        if !reader.has_type() {
+            self.report(path, "type", ViolationKind::Missing);
+        } else {
+            match reader.get_type() {
+                Ok(mtype) => self.metric_type_v1(path, mtype),
+                Err(_) => self.report(path, "type", ViolationKind::Malformed),
+            }
+        }
+
+        if reader.has_timestamp() && reader.get_timestamp().is_err() {
+            self.report(path, "timestamp", ViolationKind::Malformed);
+        }
+
+        // meta is optional in v1, only check it is readable
+        if reader.has_meta() {
+            match reader.get_meta() {
+                Ok(meta) if meta.has_sampling() && meta.get_sampling().is_err() => self.report(path, "meta.sampling", ViolationKind::Malformed),
+                Ok(_) => (),
+                Err(_) => self.report(path, "meta", ViolationKind::Malformed),
+            }
+        }
+    }
+
+    fn metric_type_v1(&mut self, path: &str, reader: metric_type::Reader) {
+        let malformed = match reader.which() {
+            Ok(metric_type::Which::Counter(())) => None,
+            Ok(metric_type::Which::DiffCounter(_)) => {
+                self.report(path, "type.diffCounter", ViolationKind::Deprecated);
+                None
+            }
+            Ok(metric_type::Which::Gauge(r)) => match r.map(|r| r.which()) {
+                Ok(Ok(gauge_v1::Which::Unsigned(()))) | Ok(Ok(gauge_v1::Which::Signed(_))) => None,
+                Ok(Err(capnp::NotInSchema(d))) => {
+                    self.report(path, "type.gauge", ViolationKind::UnknownVariant(d));
+                    None
+                }
+                Err(_) => Some("type.gauge"),
+            },
+            Ok(metric_type::Which::Timer(r)) => r.err().map(|_| "type.timer"),
+            Ok(metric_type::Which::Set(r)) => r.err().map(|_| "type.set"),
+            Ok(metric_type::Which::CustomHistogram(r)) => match r {
+                Ok(r) if r.get_buckets().is_ok() => None,
+                Ok(_) => Some("type.customHistogram.buckets"),
+                Err(_) => Some("type.customHistogram"),
+            },
+            Err(capnp::NotInSchema(d)) => {
+                self.report(path, "type", ViolationKind::UnknownVariant(d));
+                None
+            }
+        };
+        if let Some(field) = malformed {
+            self.report(path, field, ViolationKind::Malformed);
+        }
+    }
+}
+
+/// Checks a single metric encoded with schema version 2, reporting all the mismatches found.
+/// A successful check guarantees `Metric::from_capnp` will not fail on the schema level.
+pub fn check_metric(reader: cmetric::Reader) -> Result<(), MetricError> {
+    let mut checker = Checker::default();
+    checker.metric("", reader, None);
+    checker.into_result()
+}
+
+/// Checks a single metric encoded with schema version 1, reporting all the mismatches found.
+pub fn check_metric_v1(reader: cmetric_v1::Reader) -> Result<(), MetricError> {
+    let mut checker = Checker::default();
+    checker.metric_v1("", reader);
+    checker.into_result()
+}
+
+/// Checks the whole message of schema version 2 including all the metrics inside it
+pub fn check_message(reader: message::Reader) -> Result<(), MetricError> {
+    let mut checker = Checker::default();
+    let version = reader.get_version();
+    if version != ProtocolVersion::V2.id() {
+        checker.report("", "version", ViolationKind::Version(version));
+    }
+
+    let dictionary_len = if reader.has_dictionary() {
+        match reader.get_dictionary() {
+            Ok(dictionary) => Some(dictionary.len()),
+            Err(_) => {
+                checker.report("", "dictionary", ViolationKind::Malformed);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    match reader.which() {
+        Ok(message::Which::Noop(())) | Ok(message::Which::Ack(_)) | Ok(message::Which::ChunkAck(_)) => (),
+        Ok(message::Which::Snapshot(Ok(metrics))) => {
+            for (idx, metric) in metrics.iter().enumerate() {
+                checker.metric(&format!("snapshot[{}]", idx), metric, dictionary_len);
+            }
+        }
+        Ok(message::Which::Snapshot(Err(_))) => checker.report("", "snapshot", ViolationKind::Malformed),
+        Err(capnp::NotInSchema(d)) => checker.report("", "message", ViolationKind::UnknownVariant(d)),
+    }
+    checker.into_result()
+}
+
+/// Checks the whole message of schema version 1 including all the metrics inside it
+pub fn check_message_v1(reader: message_v1::Reader) -> Result<(), MetricError> {
+    let mut checker = Checker::default();
+    let (field, metrics) = match reader.which() {
+        Ok(message_v1::Which::Single(Ok(metric))) => {
+            checker.metric_v1("single", metric);
+            return checker.into_result();
+        }
+        Ok(message_v1::Which::Single(Err(_))) => ("single", None),
+        Ok(message_v1::Which::Multi(metrics)) => ("multi", metrics.ok()),
+        Ok(message_v1::Which::Snapshot(metrics)) => ("snapshot", metrics.ok()),
+        Err(capnp::NotInSchema(d)) => {
+            checker.report("", "message", ViolationKind::UnknownVariant(d));
+            return checker.into_result();
+        }
+    };
+
+    match metrics {
+        Some(metrics) => {
+            for (idx, metric) in metrics.iter().enumerate() {
+                checker.metric_v1(&format!("{}[{}]", field, idx), metric);
+            }
+        }
+        None => checker.report("", field, ViolationKind::Malformed),
+    }
+    checker.into_result()
+}
+
+/// Builds capnp messages for many metrics in turn, reusing the same scratch space for all of them
+/// instead of going to the allocator for each metric. Metrics not fitting into the scratch space
+/// are still built, with the rest of the message allocated on the heap.
+///
+/// The scratch space is zeroed once on creation, then only the used part of it is zeroed after each
+/// message, so it's better to keep the builder for as long as possible.
+pub struct ScratchBuilder<'a> {
+    allocator: ScratchSpaceHeapAllocator<'a>,
+}
+
+impl<'a> ScratchBuilder<'a> {
+    /// Creates a builder over the scratch space, that can be allocated with `capnp::Word::allocate_zeroed_vec`
+    pub fn new(scratch_space: &'a mut [capnp::Word]) -> Self {
+        Self {
+            allocator: ScratchSpaceHeapAllocator::new(capnp::Word::words_to_bytes_mut(scratch_space)),
+        }
+    }
+
+    /// Builds a message for the metric, passing it to the closure. The message is only valid inside the closure
+    /// because the scratch space is reused right after it.
+    /// The boolean in name tuple has the same meaning as in `Metric::as_capnp`
+    pub fn build<F, C, R>(&mut self, metric: &Metric<F>, name: Option<(&MetricName, bool)>, f: C) -> R
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<f64>,
+        C: FnOnce(&Builder<&mut ScratchSpaceHeapAllocator<'a>>) -> R,
+    {
+        let builder = metric.as_capnp(&mut self.allocator, name);
+        f(&builder)
+    }
+
+    /// Serializes metric to the writer using scratch space for building the message
+    pub fn write_metric<F, W>(&mut self, metric: &Metric<F>, name: Option<(&MetricName, bool)>, write: W) -> Result<(), MetricError>
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<f64>,
+        W: Write,
+    {
+        self.build(metric, name, |builder| capnp::serialize::write_message(write, builder))
+            .map_err(MetricError::Capnp)
+    }
+}
+
+/// A shared string table for metric names in a snapshot message.
+///
+/// Names are split into parts right after each `.`, `;` and `=`, so repeated name prefixes
+/// and tag keys are only stored once per message
+#[derive(Debug, Default)]
+pub struct NameDictionary {
+    index: HashMap<Bytes, u32>,
+    entries: Vec<Bytes>,
+}
+
+impl NameDictionary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds all parts of the name to the dictionary, returning the references to them
+    pub fn add(&mut self, name: &MetricName) -> Vec<u32> {
+        let mut refs = Vec::new();
+        let mut start = 0;
+        for part in name.name.split_inclusive(|c| *c == b'.' || *c == b';' || *c == b'=') {
+            let end = start + part.len();
+            let entries = &mut self.entries;
+            let idx = *self.index.entry(name.name.slice(start..end)).or_insert_with_key(|key| {
+                entries.push(key.clone());
+                (entries.len() - 1) as u32
+            });
+            refs.push(idx);
+            start = end;
+        }
+        refs
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Puts the dictionary into the message
+    pub fn fill_capnp(&self, builder: &mut message::Builder) {
+        let mut d_builder = builder.reborrow().init_dictionary(self.entries.len() as u32);
+        for (idx, entry) in self.entries.iter().enumerate() {
+            d_builder.set(idx as u32, &String::from_utf8_lossy(entry));
+        }
+    }
+}
+
+fn fnv_bytes(mut hash: u64, value: &[u8]) -> u64 {
+    for b in value {
+        hash ^= u64::from(*b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+fn fnv_u64(hash: u64, value: u64) -> u64 {
+    fnv_bytes(hash, &value.to_le_bytes())
+}
+
+/// Totals of the metrics in a snapshot, written along with them by `fill_snapshot` and checked on reading,
+/// so truncated or partially written snapshot files and streams are detected. The totals do not depend
+/// on the order of metrics. Only sizes of timers and sets are hashed, since their values may change
+/// slightly on encoding
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SnapshotIntegrity {
+    pub metrics: u64,
+    pub updates: u64,
+    pub hash: u64,
+}
+
+impl SnapshotIntegrity {
+    pub fn add<F>(&mut self, name: &MetricName, metric: &Metric<F>)
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<f64>,
+    {
+        let updates = metric.updates().as_() as u64;
+        // the name is hashed as it is sent, tags of names from some sources may be not sorted
+        let mut hash = fnv_u64(fnv_bytes(0xcbf2_9ce4_8422_2325, &name.name), updates);
+        hash = match metric.value() {
+            MetricValue::Gauge(v) => fnv_u64(fnv_u64(hash, 0), v.as_().to_bits()),
+            MetricValue::Counter(v) => fnv_u64(fnv_u64(hash, 1), v.as_().to_bits()),
+            MetricValue::Timer(v) => fnv_u64(fnv_u64(hash, 2), v.len() as u64),
+            MetricValue::CompactTimer(v) => fnv_u64(fnv_u64(hash, 2), v.len() as u64),
+            MetricValue::Set(_) | MetricValue::SortedSet(_) => fnv_u64(fnv_u64(hash, 3), metric.set_len().unwrap_or(0) as u64),
+            MetricValue::CustomHistogram(left, buckets) => buckets.iter().fold(fnv_u64(fnv_u64(hash, 4), *left), |hash, (_, count)| fnv_u64(hash, *count)),
+        };
+        self.metrics += 1;
+        self.updates = self.updates.wrapping_add(updates);
+        self.hash = self.hash.wrapping_add(hash);
+    }
+
+    /// Adds the totals of another part of the same snapshot
+    pub fn merge(&mut self, other: &Self) {
+        self.metrics += other.metrics;
+        self.updates = self.updates.wrapping_add(other.updates);
+        self.hash = self.hash.wrapping_add(other.hash);
+    }
+
+    /// Checks the totals of metrics read against the ones written by sender
+    pub fn verify(&self, expected: &Self) -> Result<(), MetricError> {
+        if self.metrics != expected.metrics {
+            Err(MetricError::Integrity("number of metrics does not match"))
+        } else if self.updates != expected.updates {
+            Err(MetricError::Integrity("number of updates does not match"))
+        } else if self.hash != expected.hash {
+            Err(MetricError::Integrity("content hash does not match"))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn fill_capnp(&self, builder: &mut message::Builder) {
+        let mut integrity = builder.reborrow().init_integrity();
+        integrity.set_metrics(self.metrics);
+        integrity.set_updates(self.updates);
+        integrity.set_hash(self.hash);
+    }
+
+    /// Reads the totals, gives None for messages without them, i.e. from older senders
+    pub fn from_capnp(reader: message::Reader) -> Result<Option<Self>, MetricError> {
+        if !reader.has_integrity() {
+            return Ok(None);
+        }
+        let integrity = reader.get_integrity().map_err(MetricError::Capnp)?;
+        Ok(Some(Self {
+            metrics: integrity.get_metrics(),
+            updates: integrity.get_updates(),
+            hash: integrity.get_hash(),
+        }))
+    }
+}
+
+/// Fills the snapshot message with metrics. With `use_dictionary` set, names are stored
+/// as references to the shared dictionary, which makes snapshots with deep name hierarchies
+/// a lot smaller. The `SnapshotIntegrity` of the metrics is written too
+pub fn fill_snapshot<'m, F, I>(builder: &mut message::Builder, metrics: I, use_dictionary: bool)
+where
+    F: 'm + Float + Debug + FromF64 + AsPrimitive<f64>,
+    I: IntoIterator<Item = (&'m MetricName, &'m Metric<F>)>,
+    I::IntoIter: ExactSizeIterator,
+{
+    builder.set_version(ProtocolVersion::V2.id());
+    let metrics = metrics.into_iter();
+    let mut dictionary = NameDictionary::new();
+    let mut integrity = SnapshotIntegrity::default();
+    {
+        let mut snapshot = builder.reborrow().init_snapshot(metrics.len() as u32);
+        for (idx, (name, metric)) in metrics.enumerate() {
+            integrity.add(name, metric);
+            let mut m_builder = snapshot.reborrow().get(idx as u32);
+            metric.fill_capnp(&mut m_builder);
+            if use_dictionary {
+                metric.fill_capnp_tags(&mut m_builder, name);
+                let refs = dictionary.add(name);
+                let mut r_builder = m_builder.init_name_refs(refs.len() as u32);
+                for (ridx, r) in refs.into_iter().enumerate() {
+                    r_builder.set(ridx as u32, r);
+                }
+            } else {
+                metric.fill_capnp_name(&mut m_builder, name, false);
+            }
+        }
+    }
+
+    if use_dictionary {
+        dictionary.fill_capnp(builder);
+    }
+    integrity.fill_capnp(builder);
+}
+
+// size of a capnp text or list of bytes, padded to words
+fn padded(len: usize) -> usize {
+    len.div_ceil(8) * 8
+}
+
+/// An upper estimate of bytes the metric takes in a snapshot message built by `fill_snapshot`,
+/// including space for far pointers, which capnp uses when a message grows over several segments.
+/// With dictionary, names are considered to share no parts with other names.
+pub fn estimate_snapshot_size<F>(name: &MetricName, metric: &Metric<F>, use_dictionary: bool) -> usize
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    // metric struct with 5 pointers, value, timestamp and meta structs, meta with 4 data words
+    // and 3 pointers, and 7 far pointer landing pads
+    let mut size = 48 + 24 + 16 + 56 + 7 * 8;
+    size += match metric.value() {
+        MetricValue::Gauge(_) | MetricValue::Counter(_) => 0,
+        MetricValue::Timer(v) => v.len() * 8,
+        MetricValue::CompactTimer(v) => v.len() * 8,
+        MetricValue::Set(_) | MetricValue::SortedSet(_) => metric.set_len().unwrap_or(0) * 8,
+        MetricValue::CustomHistogram(_, buckets) => 16 + 8 + buckets.len() * 16,
+    };
+    // texts in meta, each with a landing pad
+    let text = |text: &str| padded(text.len() + 1) + 8;
+    if let Some(unit) = metric.unit() {
+        size += text(unit.as_str());
+    }
+    if let Some(vector) = metric.vector() {
+        // a list tag and a struct with a value and a name pointer for each component
+        size += 16 + vector.components().iter().map(|(name, _)| 16 + text(name)).sum::<usize>();
+    }
+    if let Some(info) = metric.info() {
+        size += 16 + info.labels().iter().map(|(key, value)| 16 + text(key) + text(value)).sum::<usize>();
+    }
+    if use_dictionary {
+        // a reference, and a new dictionary entry with a pointer and a landing pad for each part
+        let (refs, entries) = name
+            .name
+            .split_inclusive(|c| *c == b'.' || *c == b';' || *c == b'=')
+            .fold((0, 0), |(refs, entries), part| (refs + 1, entries + padded(part.len() + 1) + 16));
+        size += padded(refs * 4) + entries;
+    } else {
+        size += padded(name.name.len() + 1);
+    }
+    size
+}
+
+/// Splits metrics into groups for separate snapshot messages, so every message built from a group
+/// by `fill_snapshot` is not larger than the budget, i.e. when peers are behind a proxy limiting
+/// message sizes. Sizes are estimated by `estimate_snapshot_size` without building the messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotBatcher {
+    budget: usize,
+    use_dictionary: bool,
+}
+
+impl SnapshotBatcher {
+    // message root, list tags and segment table
+    const MESSAGE_OVERHEAD: usize = 256;
+
+    pub fn new(budget: usize, use_dictionary: bool) -> Self {
+        Self { budget, use_dictionary }
+    }
+
+    /// Groups the metrics in order they are given, a metric too large for the budget fails the whole split
+    #[allow(clippy::type_complexity)]
+    pub fn split<'m, F, I>(&self, metrics: I) -> Result<Vec<Vec<(&'m MetricName, &'m Metric<F>)>>, MetricError>
+    where
+        F: 'm + Float + Debug + FromF64 + AsPrimitive<f64>,
+        I: IntoIterator<Item = (&'m MetricName, &'m Metric<F>)>,
+    {
+        self.batches(metrics).collect()
+    }
+
+    /// Same as `split`, but groups are made lazily, one per `next` call, so the metrics are not
+    /// collected in advance. The iteration stops after the error
+    pub fn batches<'m, F, I>(&self, metrics: I) -> SnapshotBatches<'m, F, I::IntoIter>
+    where
+        F: 'm + Float + Debug + FromF64 + AsPrimitive<f64>,
+        I: IntoIterator<Item = (&'m MetricName, &'m Metric<F>)>,
+    {
+        SnapshotBatches {
+            batcher: *self,
+            metrics: metrics.into_iter(),
+            pending: None,
+            failed: false,
+        }
+    }
+
+    /// Builds snapshot messages for the metrics
+    pub fn build<'m, F, I>(&self, metrics: I) -> Result<Vec<Builder<HeapAllocator>>, MetricError>
+    where
+        F: 'm + Float + Debug + FromF64 + AsPrimitive<f64>,
+        I: IntoIterator<Item = (&'m MetricName, &'m Metric<F>)>,
+    {
+        Ok(self.split(metrics)?.into_iter().map(|batch| self.build_batch(batch)).collect())
+    }
+
+    /// Builds a snapshot message for one of the groups
+    pub fn build_batch<'m, F>(&self, batch: Vec<(&'m MetricName, &'m Metric<F>)>) -> Builder<HeapAllocator>
+    where
+        F: 'm + Float + Debug + FromF64 + AsPrimitive<f64>,
+    {
+        let mut builder = Builder::new_default();
+        fill_snapshot(&mut builder.init_root::<message::Builder>(), batch, self.use_dictionary);
+        builder
+    }
+}
+
+/// Lazy groups of metrics made by `SnapshotBatcher::batches`
+pub struct SnapshotBatches<'m, F, I>
+where
+    F: Copy + PartialEq + Debug,
+{
+    batcher: SnapshotBatcher,
+    metrics: I,
+    // the metric not fitting into the previous group along with its size
+    pending: Option<(&'m MetricName, &'m Metric<F>, usize)>,
+    failed: bool,
+}
+
+impl<'m, F, I> Iterator for SnapshotBatches<'m, F, I>
+where
+    F: 'm + Float + Debug + FromF64 + AsPrimitive<f64>,
+    I: Iterator<Item = (&'m MetricName, &'m Metric<F>)>,
+{
+    type Item = Result<Vec<(&'m MetricName, &'m Metric<F>)>, MetricError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let mut batch = Vec::new();
+        let mut batch_size = SnapshotBatcher::MESSAGE_OVERHEAD;
+        if let Some((name, metric, size)) = self.pending.take() {
+            batch.push((name, metric));
+            batch_size += size;
+        }
+        for (name, metric) in &mut self.metrics {
+            let size = estimate_snapshot_size(name, metric, self.batcher.use_dictionary);
+            if SnapshotBatcher::MESSAGE_OVERHEAD + size > self.batcher.budget {
+                self.failed = true;
+                return Some(Err(MetricError::BatchSize(size)));
+            }
+            if batch_size + size > self.batcher.budget {
+                self.pending = Some((name, metric, size));
+                return Some(Ok(batch));
+            }
+            batch.push((name, metric));
+            batch_size += size;
+        }
+        if batch.is_empty() {
+            None
+        } else {
+            Some(Ok(batch))
+        }
+    }
+}
+
+/// Reads all metrics from the snapshot message, resolving names from the dictionary if required.
+/// The integrity of the snapshot is verified if the sender has written it.
+/// Noop and ack messages, including chunk acks, give no metrics.
+pub fn read_snapshot<F>(reader: message::Reader) -> Result<Vec<(MetricName, Metric<F>)>, MetricError>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    let metrics = match reader.which().map_err(MetricError::CapnpSchema)? {
+        message::Which::Noop(()) | message::Which::Ack(_) | message::Which::ChunkAck(_) => return Ok(Vec::new()),
+        message::Which::Snapshot(metrics) => metrics.map_err(MetricError::Capnp)?,
+    };
+
+    let dictionary = if reader.has_dictionary() {
+        Some(reader.get_dictionary().map_err(MetricError::Capnp)?)
+    } else {
+        None
+    };
+
+    let metrics = metrics
+        .iter()
+        .map(|metric| Metric::from_capnp_with_dictionary(metric, dictionary))
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(expected) = SnapshotIntegrity::from_capnp(reader)? {
+        let mut integrity = SnapshotIntegrity::default();
+        metrics.iter().for_each(|(name, metric)| integrity.add(name, metric));
+        integrity.verify(&expected)?;
+    }
+    Ok(metrics)
+}
+
+/// Fills the message acknowledging the snapshot with the sequence number
+pub fn fill_ack(builder: &mut message::Builder, sequence: u64) {
+    builder.set_version(ProtocolVersion::V2.id());
+    builder.set_ack(sequence);
+}
+
+/// The acknowledged sequence number if the message is an ack
+pub fn read_ack(reader: message::Reader) -> Result<Option<u64>, MetricError> {
+    match reader.which().map_err(MetricError::CapnpSchema)? {
+        message::Which::Ack(sequence) => Ok(Some(sequence)),
+        _ => Ok(None),
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Unacked<T> {
+    sequence: u64,
+    sent: Instant,
+    attempts: u32,
+    payload: T,
+}
+
+/// Sender side bookkeeping of sequenced snapshots. Snapshots are numbered when sent and kept until
+/// acknowledged, the ones not acknowledged in time are given back for retransmission. The payload
+/// is usually the encoded message, with the sequence number set by `message::Builder::set_sequence`
+#[derive(Debug, Clone)]
+pub struct RetransmitQueue<T> {
+    next: u64,
+    pending: Vec<Unacked<T>>,
+}
+
+impl<T> Default for RetransmitQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> RetransmitQueue<T> {
+    pub fn new() -> Self {
+        Self { next: 1, pending: Vec::new() }
+    }
+
+    /// The sequence number the next snapshot will get
+    pub fn next_sequence(&self) -> u64 {
+        self.next
+    }
+
+    /// Remembers the snapshot sent at `now`, giving its sequence number
+    pub fn push(&mut self, payload: T, now: Instant) -> u64 {
+        let sequence = self.next;
+        self.next += 1;
+        self.pending.push(Unacked {
+            sequence,
+            sent: now,
+            attempts: 1,
+            payload,
+        });
+        sequence
+    }
+
+    /// Forgets the acknowledged snapshot, giving it back. Acks of unknown or already acknowledged
+    /// snapshots give None
+    pub fn ack(&mut self, sequence: u64) -> Option<T> {
+        let idx = self.pending.iter().position(|unacked| unacked.sequence == sequence)?;
+        Some(self.pending.remove(idx).payload)
+    }
+
+    /// The snapshots not acknowledged within `timeout` since they were sent last time, they are
+    /// considered sent again at `now`
+    pub fn due(&mut self, now: Instant, timeout: Duration) -> Vec<(u64, &T)> {
+        self.pending
+            .iter_mut()
+            .filter(|unacked| now.saturating_duration_since(unacked.sent) >= timeout)
+            .map(|unacked| {
+                unacked.sent = now;
+                unacked.attempts += 1;
+                (unacked.sequence, &unacked.payload)
+            })
+            .collect()
+    }
+
+    /// Drops the snapshots sent `max_attempts` times without acknowledgement, giving their sequence
+    /// numbers, so the loss can be reported
+    pub fn expire(&mut self, max_attempts: u32) -> Vec<u64> {
+        let expired = self
+            .pending
+            .iter()
+            .filter(|unacked| unacked.attempts >= max_attempts)
+            .map(|unacked| unacked.sequence)
+            .collect();
+        self.pending.retain(|unacked| unacked.attempts < max_attempts);
+        expired
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+/// The result of receiving a sequenced snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SequenceCheck {
+    /// the next expected snapshot or a not sequenced one
+    InOrder,
+    /// the snapshots in the range were not received before this one
+    Gap(Range<u64>),
+    /// the snapshot was reported missing in a gap before, i.e. it was retransmitted
+    Recovered,
+    /// the snapshot was received already, i.e. retransmitted because of a lost ack
+    Stale,
+}
+
+/// The number of gaps remembered by `SequenceTracker`, the oldest ones are forgotten first,
+/// their snapshots are most probably expired by the sender anyway
+const MAX_SEQUENCE_GAPS: usize = 1024;
+
+/// Receiver side detection of lost snapshots by their sequence numbers
+#[derive(Debug, Clone, Default)]
+pub struct SequenceTracker {
+    last: u64,
+    missing: VecDeque<Range<u64>>,
+}
+
+impl SequenceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The last sequence number received
+    pub fn last(&self) -> u64 {
+        self.last
+    }
+
+    /// The sequence numbers reported in gaps and not received yet
+    pub fn missing(&self) -> impl Iterator<Item = &Range<u64>> {
+        self.missing.iter()
+    }
+
+    pub fn observe(&mut self, sequence: u64) -> SequenceCheck {
+        if sequence == 0 {
+            return SequenceCheck::InOrder;
+        }
+        if sequence <= self.last {
+            let idx = match self.missing.iter().position(|gap| gap.contains(&sequence)) {
+                Some(idx) => idx,
+                None => return SequenceCheck::Stale,
+            };
+            let gap = self.missing[idx].clone();
+            match (gap.start < sequence, sequence + 1 < gap.end) {
+                (true, true) => {
+                    self.missing[idx] = gap.start..sequence;
+                    self.missing.insert(idx + 1, sequence + 1..gap.end);
+                }
+                (true, false) => self.missing[idx] = gap.start..sequence,
+                (false, true) => self.missing[idx] = sequence + 1..gap.end,
+                (false, false) => {
+                    self.missing.remove(idx);
+                }
+            }
+            return SequenceCheck::Recovered;
+        }
+        let expected = self.last + 1;
+        self.last = sequence;
+        if sequence == expected {
+            SequenceCheck::InOrder
+        } else {
+            if self.missing.len() == MAX_SEQUENCE_GAPS {
+                self.missing.pop_front();
+            }
+            self.missing.push_back(expected..sequence);
+            SequenceCheck::Gap(expected..sequence)
+        }
+    }
+
+    /// Starts counting from scratch, i.e. when the sender has restarted
+    pub fn reset(&mut self) {
+        self.last = 0;
+        self.missing.clear();
+    }
+}
+
+/// The manifest of a chunked snapshot, carried by every chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkManifest {
+    pub snapshot: u64,
+    pub count: u32,
+    pub total_metrics: u64,
+}
+
+impl ChunkManifest {
+    /// Reads the manifest and the chunk index, gives None for messages not being chunks
+    pub fn from_capnp(reader: message::Reader) -> Result<Option<(Self, u32)>, MetricError> {
+        if !reader.has_chunk() {
+            return Ok(None);
+        }
+        let chunk = reader.get_chunk().map_err(MetricError::Capnp)?;
+        let manifest = Self {
+            snapshot: chunk.get_snapshot(),
+            count: chunk.get_count(),
+            total_metrics: chunk.get_total_metrics(),
+        };
+        Ok(Some((manifest, chunk.get_index())))
+    }
+}
+
+/// Fills the message acknowledging the chunks of the snapshot up to and including the index
+pub fn fill_chunk_ack(builder: &mut message::Builder, snapshot: u64, index: u32) {
+    builder.set_version(ProtocolVersion::V2.id());
+    let mut chunk = builder.reborrow().init_chunk_ack();
+    chunk.set_snapshot(snapshot);
+    chunk.set_index(index);
+}
+
+/// The snapshot and the chunk index acknowledged if the message is a chunk ack
+pub fn read_chunk_ack(reader: message::Reader) -> Result<Option<(u64, u32)>, MetricError> {
+    match reader.which().map_err(MetricError::CapnpSchema)? {
+        message::Which::ChunkAck(chunk) => {
+            let chunk = chunk.map_err(MetricError::Capnp)?;
+            Ok(Some((chunk.get_snapshot(), chunk.get_index())))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Sender side of a snapshot too large for one message, split into chunks by `SnapshotBatcher`.
+/// Chunks are kept until acknowledged, so after a reconnect sending resumes from the chunk following
+/// the last acknowledged one instead of starting the whole snapshot over.
+pub struct ChunkedSnapshot {
+    manifest: ChunkManifest,
+    chunks: Vec<Builder<HeapAllocator>>,
+    acked: u32,
+}
+
+impl ChunkedSnapshot {
+    /// Builds the chunks of the snapshot numbered `snapshot`, which should differ from the previous
+    /// snapshot sent to the same receiver. An empty snapshot still has one chunk
+    pub fn new<'m, F, I>(snapshot: u64, batcher: &SnapshotBatcher, metrics: I) -> Result<Self, MetricError>
+    where
+        F: 'm + Float + Debug + FromF64 + AsPrimitive<f64>,
+        I: IntoIterator<Item = (&'m MetricName, &'m Metric<F>)>,
+    {
+        let mut batches = batcher.split(metrics)?;
+        if batches.is_empty() {
+            batches.push(Vec::new());
+        }
+        let manifest = ChunkManifest {
+            snapshot,
+            count: batches.len() as u32,
+            total_metrics: batches.iter().map(Vec::len).sum::<usize>() as u64,
+        };
+        let chunks = batches
+            .into_iter()
+            .enumerate()
+            .map(|(index, batch)| {
+                let mut builder = Builder::new_default();
+                let mut message = builder.init_root::<message::Builder>();
+                fill_snapshot(&mut message, batch, batcher.use_dictionary);
+                let mut chunk = message.init_chunk();
+                chunk.set_snapshot(manifest.snapshot);
+                chunk.set_index(index as u32);
+                chunk.set_count(manifest.count);
+                chunk.set_total_metrics(manifest.total_metrics);
+                builder
+            })
+            .collect();
+        Ok(Self { manifest, chunks, acked: 0 })
+    }
+
+    pub fn manifest(&self) -> &ChunkManifest {
+        &self.manifest
+    }
+
+    /// The number of chunks acknowledged
+    pub fn acked(&self) -> u32 {
+        self.acked
+    }
+
+    /// Acks are cumulative, acknowledging the chunk acknowledges all the chunks before it.
+    /// Acks of other snapshots and of chunks out of range are ignored
+    pub fn ack(&mut self, snapshot: u64, index: u32) {
+        if snapshot == self.manifest.snapshot && index < self.manifest.count {
+            self.acked = self.acked.max(index + 1);
+        }
+    }
+
+    /// The chunks to be sent, starting after the last acknowledged one, the index of the first one is `acked`
+    pub fn remaining(&self) -> &[Builder<HeapAllocator>] {
+        &self.chunks[self.acked as usize..]
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.acked == self.manifest.count
+    }
+}
+
+/// Receiver side of chunked snapshots, collecting the chunks until the snapshot is complete.
+/// Chunks must come in order, the retransmitted ones received already are ignored, so the sender
+/// can resume from any chunk not after the one following `last_received`.
+#[derive(Debug, Clone)]
+pub struct ChunkAssembler<F>
+where
+    F: Copy + PartialEq + Debug,
+{
+    manifest: Option<ChunkManifest>,
+    received: u32,
+    metrics: Vec<(MetricName, Metric<F>)>,
+}
+
+impl<F> Default for ChunkAssembler<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F> ChunkAssembler<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    pub fn new() -> Self {
+        Self {
+            manifest: None,
+            received: 0,
+            metrics: Vec::new(),
+        }
+    }
+
+    /// The snapshot and the index of the last chunk received, to be acknowledged with `fill_chunk_ack`
+    pub fn last_received(&self) -> Option<(u64, u32)> {
+        match self.manifest {
+            Some(manifest) if self.received > 0 => Some((manifest.snapshot, self.received - 1)),
+            _ => None,
+        }
+    }
+
+    /// Adds the chunk, giving all metrics of the snapshot when it is complete. A chunk of another
+    /// snapshot starts it over, dropping the incomplete one
+    #[allow(clippy::type_complexity)]
+    pub fn push(
+        &mut self,
+        manifest: ChunkManifest,
+        index: u32,
+        metrics: Vec<(MetricName, Metric<F>)>,
+    ) -> Result<Option<Vec<(MetricName, Metric<F>)>>, MetricError> {
+        if index >= manifest.count {
+            return Err(MetricError::Chunk("chunk index is out of range"));
+        }
+        match self.manifest {
+            Some(current) if current.snapshot == manifest.snapshot => {
+                if current != manifest {
+                    return Err(MetricError::Chunk("chunk manifest has changed"));
+                }
+            }
+            _ => {
+                if index != 0 {
+                    return Err(MetricError::Chunk("snapshot does not start from the first chunk"));
+                }
+                self.manifest = Some(manifest);
+                self.received = 0;
+                self.metrics.clear();
+            }
+        }
+
+        if index < self.received {
+            return Ok(None);
+        }
+        if index > self.received {
+            return Err(MetricError::Chunk("chunk is missing"));
+        }
+        self.metrics.extend(metrics);
+        self.received += 1;
+        if self.received < manifest.count {
+            return Ok(None);
+        }
+        // the completed snapshot is remembered, so its retransmitted chunks are still ignored
+        let metrics = std::mem::take(&mut self.metrics);
+        if metrics.len() as u64 != manifest.total_metrics {
+            return Err(MetricError::Chunk("number of metrics does not match the manifest"));
+        }
+        Ok(Some(metrics))
+    }
+
+    /// Reads the message, see `push`. The messages not being chunks are complete snapshots
+    #[allow(clippy::type_complexity)]
+    pub fn read(&mut self, reader: message::Reader) -> Result<Option<Vec<(MetricName, Metric<F>)>>, MetricError> {
+        match ChunkManifest::from_capnp(reader)? {
+            Some((manifest, index)) => self.push(manifest, index, read_snapshot(reader)?),
+            None => Ok(Some(read_snapshot(reader)?)),
+        }
+    }
+}
+
+/// Compression of the serialized snapshot messages. Uncompressed snapshots are supported by every peer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapshotCodec {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl SnapshotCodec {
+    fn from_capnp(codec: CCodec) -> Self {
+        match codec {
+            CCodec::None => SnapshotCodec::None,
+            CCodec::Lz4 => SnapshotCodec::Lz4,
+            CCodec::Zstd => SnapshotCodec::Zstd,
+        }
+    }
+
+    fn to_capnp(self) -> CCodec {
+        match self {
+            SnapshotCodec::None => CCodec::None,
+            SnapshotCodec::Lz4 => CCodec::Lz4,
+            SnapshotCodec::Zstd => CCodec::Zstd,
+        }
+    }
+}
+
+/// Advertises the codecs the peer can decompress snapshots with
+pub fn fill_codecs(builder: &mut message::Builder, codecs: &[SnapshotCodec]) {
+    let mut list = builder.reborrow().init_codecs(codecs.len() as u32);
+    for (idx, codec) in codecs.iter().enumerate() {
+        list.set(idx as u32, codec.to_capnp());
+    }
+}
+
+/// The codecs advertised by the peer. Codecs unknown to this version are skipped, the peers not advertising
+/// anything only support uncompressed snapshots
+pub fn read_codecs(reader: message::Reader) -> Result<Vec<SnapshotCodec>, MetricError> {
+    if !reader.has_codecs() {
+        return Ok(vec![SnapshotCodec::None]);
+    }
+    let codecs = reader.get_codecs().map_err(MetricError::Capnp)?;
+    Ok(codecs.iter().filter_map(|codec| codec.ok()).map(SnapshotCodec::from_capnp).collect())
+}
+
+/// Picks the codec to send snapshots to the peer with: the first of ours, given in the order of
+/// preference, supported by the peer. Falls back to no compression, so peers can upgrade one by one
+pub fn negotiate_codec(ours: &[SnapshotCodec], theirs: &[SnapshotCodec]) -> SnapshotCodec {
+    ours.iter().copied().find(|codec| theirs.contains(codec)).unwrap_or(SnapshotCodec::None)
+}
+
+/// Sets the authentication token of the sender, should be called after filling everything else,
+/// so the token can be computed over `auth_payload` of the message
+pub fn fill_auth(builder: &mut message::Builder, token: &[u8]) {
+    builder.set_auth(token);
+}
+
+/// The canonical bytes of the message without its token, the same for the sender and the receiver,
+/// so tokens can sign the content of the message, i.e. be HMACs of it
+pub fn auth_payload(reader: message::Reader) -> Result<Vec<u8>, MetricError> {
+    let mut copy = Builder::new_default();
+    copy.set_root(reader).map_err(MetricError::Capnp)?;
+    copy.get_root::<message::Builder>().map_err(MetricError::Capnp)?.set_auth(&[]);
+    let words = copy.into_reader().canonicalize().map_err(MetricError::Capnp)?;
+    Ok(capnp::Word::words_to_bytes(&words).to_vec())
+}
+
+/// Checks the authentication token of peer messages, so the snapshots from unknown senders
+/// can be rejected. Implemented for the token schemes of a cluster, like signed expiring tokens
+pub trait AuthVerifier {
+    /// Gives `MetricError::Auth` if the sender is not allowed, the token is None for messages without it.
+    /// The payload is `auth_payload` of the message, for the tokens signing the content
+    fn verify(&self, token: Option<&[u8]>, payload: &[u8]) -> Result<(), MetricError>;
+}
+
+/// Allows the senders having any of the pre-shared tokens
+#[derive(Debug, Clone, Default)]
+pub struct StaticTokens {
+    tokens: Vec<Vec<u8>>,
+}
+
+impl StaticTokens {
+    pub fn new<I, T>(tokens: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        Self {
+            tokens: tokens.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+// the time of comparing tokens of the same length does not depend on the position of the first difference
+fn tokens_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+impl AuthVerifier for StaticTokens {
+    fn verify(&self, token: Option<&[u8]>, _payload: &[u8]) -> Result<(), MetricError> {
+        let token = token.ok_or(MetricError::Auth("no token"))?;
+        if self.tokens.iter().any(|known| tokens_eq(known, token)) {
+            Ok(())
+        } else {
+            Err(MetricError::Auth("unknown token"))
+        }
+    }
+}
+
+/// Verifies the token of the message, should be called before reading anything else from it
+pub fn verify_message<V: AuthVerifier + ?Sized>(reader: message::Reader, verifier: &V) -> Result<(), MetricError> {
+    let token = if reader.has_auth() {
+        Some(reader.get_auth().map_err(MetricError::Capnp)?)
+    } else {
+        None
+    };
+    verifier.verify(token, &auth_payload(reader)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::info::Info;
+    use crate::metric::{Metric, MetricUnit, MetricValue};
+    use crate::monotonic::{MonotonicCounter, MonotonicUpdate};
+    use crate::name::{MetricName, TagFormat};
+    use crate::testing::name;
+    use crate::vector::VectorGauge;
+    use bytes::BytesMut;
+
+    #[test]
+    fn check_good_message() {
+        let name = name("some.metric;tag=value");
+        let metric = Metric::new(MetricValue::Timer(vec![1f64, 2f64]), Some(10), 1f32);
+
+        let mut builder = capnp::message::Builder::new_default();
+        {
+            let mut message = builder.init_root::<message::Builder>();
+            message.set_version(ProtocolVersion::V2.id());
+            let mut snapshot = message.init_snapshot(1);
+            let mut m_builder = snapshot.reborrow().get(0);
+            metric.fill_capnp(&mut m_builder);
+            metric.fill_capnp_name(&mut m_builder, &name, false);
+        }
+        let reader = builder.get_root_as_reader::<message::Reader>().unwrap();
+        check_message(reader).unwrap();
+    }
+
+    #[test]
+    fn check_reports_all_violations() {
+        let mut builder = capnp::message::Builder::new_default();
+        {
+            let mut message = builder.init_root::<message::Builder>();
+            message.set_version(1);
+            let mut snapshot = message.init_snapshot(2);
+            // the first metric has only name, the second one has nothing at all
+            snapshot.reborrow().get(0).set_name("some.metric");
+        }
+        let reader = builder.get_root_as_reader::<message::Reader>().unwrap();
+        let violations = match check_message(reader) {
+            Err(MetricError::Schema(v)) => v,
+            other => panic!("expected schema error, got {:?}", other),
+        };
+
+        let expected = vec![
+            SchemaViolation::new("", "version", ViolationKind::Version(1)),
+            SchemaViolation::new("snapshot[0]", "value", ViolationKind::Missing),
+            SchemaViolation::new("snapshot[0]", "meta", ViolationKind::Missing),
+            SchemaViolation::new("snapshot[1]", "name", ViolationKind::Missing),
+            SchemaViolation::new("snapshot[1]", "value", ViolationKind::Missing),
+            SchemaViolation::new("snapshot[1]", "meta", ViolationKind::Missing),
+        ];
+        assert_eq!(violations, expected);
+        assert_eq!(&violations[1].to_string(), "snapshot[0].value: missing");
+    }
+
+    #[test]
+    fn snapshot_retransmits() {
+        let start = Instant::now();
+        let timeout = Duration::from_secs(5);
+        let mut queue = RetransmitQueue::new();
+        assert_eq!(queue.push("first", start), 1);
+        assert_eq!(queue.push("second", start + Duration::from_secs(3)), 2);
+        assert_eq!(queue.next_sequence(), 3);
+
+        assert!(queue.due(start + Duration::from_secs(4), timeout).is_empty());
+        assert_eq!(queue.due(start + timeout, timeout), vec![(1, &"first")]);
+        assert_eq!(queue.ack(2), Some("second"));
+        assert_eq!(queue.ack(2), None);
+        assert!(queue.expire(3).is_empty());
+        assert_eq!(queue.due(start + timeout * 2, timeout).len(), 1);
+        assert_eq!(queue.expire(3), vec![1]);
+        assert!(queue.is_empty());
+
+        let mut tracker = SequenceTracker::new();
+        assert_eq!(tracker.observe(1), SequenceCheck::InOrder);
+        assert_eq!(tracker.observe(0), SequenceCheck::InOrder);
+        assert_eq!(tracker.observe(4), SequenceCheck::Gap(2..4));
+        assert_eq!(tracker.observe(3), SequenceCheck::Recovered);
+        assert_eq!(tracker.observe(3), SequenceCheck::Stale);
+        assert_eq!(tracker.observe(5), SequenceCheck::InOrder);
+        assert_eq!(tracker.last(), 5);
+        assert_eq!(tracker.observe(10), SequenceCheck::Gap(6..10));
+        assert_eq!(tracker.observe(8), SequenceCheck::Recovered);
+        assert_eq!(tracker.missing().cloned().collect::<Vec<_>>(), vec![2..3, 6..8, 9..10]);
+        assert_eq!(tracker.observe(4), SequenceCheck::Stale);
+        tracker.reset();
+        assert_eq!(tracker.missing().count(), 0);
+    }
+
+    #[test]
+    fn ack_messages() {
+        let mut builder = capnp::message::Builder::new_default();
+        fill_ack(&mut builder.init_root::<message::Builder>(), 42);
+        let reader = builder.get_root_as_reader::<message::Reader>().unwrap();
+        assert_eq!(read_ack(reader).unwrap(), Some(42));
+        assert!(read_snapshot::<f64>(reader).unwrap().is_empty());
+
+        let mut builder = capnp::message::Builder::new_default();
+        builder.init_root::<message::Builder>().set_noop(());
+        let reader = builder.get_root_as_reader::<message::Reader>().unwrap();
+        assert_eq!(read_ack(reader).unwrap(), None);
+    }
+
+    #[test]
+    fn chunk_assembler() {
+        let metric = |n: &str| (name(n), Metric::<f64>::new(MetricValue::Counter(1f64), None, 1f32));
+        let manifest = ChunkManifest {
+            snapshot: 1,
+            count: 3,
+            total_metrics: 3,
+        };
+        let mut assembler = ChunkAssembler::new();
+        assert!(assembler.push(manifest, 1, vec![metric("b")]).is_err());
+        assert_eq!(assembler.push(manifest, 0, vec![metric("a")]).unwrap(), None);
+        assert_eq!(assembler.push(manifest, 1, vec![metric("b")]).unwrap(), None);
+        // the sender has reconnected and resumed not knowing the last chunk was received
+        assert_eq!(assembler.last_received(), Some((1, 1)));
+        assert_eq!(assembler.push(manifest, 1, vec![metric("b")]).unwrap(), None);
+        let snapshot = assembler.push(manifest, 2, vec![metric("c")]).unwrap().unwrap();
+        assert_eq!(snapshot, vec![metric("a"), metric("b"), metric("c")]);
+        assert_eq!(assembler.push(manifest, 2, vec![metric("c")]).unwrap(), None);
+
+        let next = ChunkManifest { snapshot: 2, ..manifest };
+        assert_eq!(assembler.push(next, 0, vec![metric("a")]).unwrap(), None);
+        assert!(assembler.push(next, 2, vec![metric("c")]).is_err());
+        assert!(assembler.push(ChunkManifest { count: 2, ..next }, 1, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn chunked_snapshot_resume() {
+        let metrics = (0..20)
+            .map(|idx| {
+                let name = name(&format!("some.metric.{}", idx));
+                (name, Metric::new(MetricValue::Gauge(idx as f64), None, 1f32))
+            })
+            .collect::<Vec<_>>();
+        let batcher = SnapshotBatcher::new(512, false);
+        let mut snapshot = ChunkedSnapshot::new(7, &batcher, metrics.iter().map(|(n, m)| (n, m))).unwrap();
+        let count = snapshot.manifest().count;
+        assert!(count > 2);
+        assert_eq!(snapshot.manifest().total_metrics, 20);
+
+        let mut assembler = ChunkAssembler::<f64>::new();
+        for chunk in &snapshot.remaining()[..2] {
+            let reader = chunk.get_root_as_reader::<message::Reader>().unwrap();
+            assert_eq!(assembler.read(reader).unwrap(), None);
+        }
+
+        // the connection is lost before the second chunk is acknowledged
+        let (id, index) = assembler.last_received().unwrap();
+        let mut ack = capnp::message::Builder::new_default();
+        fill_chunk_ack(&mut ack.init_root::<message::Builder>(), id, index - 1);
+        snapshot.ack(id, index - 1);
+        assert_eq!(read_chunk_ack(ack.get_root_as_reader::<message::Reader>().unwrap()).unwrap(), Some((7, 0)));
+        assert_eq!(snapshot.acked(), 1);
+        assert_eq!(snapshot.remaining().len() as u32, count - 1);
+
+        let mut received = None;
+        for chunk in snapshot.remaining() {
+            let reader = chunk.get_root_as_reader::<message::Reader>().unwrap();
+            received = assembler.read(reader).unwrap();
+        }
+        assert_eq!(received.unwrap(), metrics);
+        snapshot.ack(7, count - 1);
+        assert!(snapshot.is_done());
+    }
+
+    #[test]
+    fn codec_negotiation() {
+        use SnapshotCodec::*;
+        assert_eq!(negotiate_codec(&[Zstd, Lz4], &[Lz4, Zstd, None]), Zstd);
+        assert_eq!(negotiate_codec(&[Zstd, Lz4], &[None, Lz4]), Lz4);
+        // an old peer
+        assert_eq!(negotiate_codec(&[Zstd, Lz4], &[None]), None);
+        assert_eq!(negotiate_codec(&[], &[Zstd]), None);
+    }
+
+    #[test]
+    fn static_token_auth() {
+        let verifier = StaticTokens::new(vec!["first", "second"]);
+        assert!(verifier.verify(Some(b"second"), b"").is_ok());
+        assert!(matches!(verifier.verify(Some(b"secon"), b""), Err(MetricError::Auth(_))));
+        assert!(matches!(verifier.verify(None, b""), Err(MetricError::Auth(_))));
+        assert!(StaticTokens::default().verify(Some(b""), b"").is_err());
+    }
+
+    // the token is a keyed hash of the payload, like HMAC
+    struct SignedTokens(u64);
+
+    impl SignedTokens {
+        fn sign(&self, payload: &[u8]) -> Vec<u8> {
+            fnv_bytes(self.0, payload).to_le_bytes().to_vec()
+        }
+    }
+
+    impl AuthVerifier for SignedTokens {
+        fn verify(&self, token: Option<&[u8]>, payload: &[u8]) -> Result<(), MetricError> {
+            match token {
+                Some(token) if tokens_eq(token, &self.sign(payload)) => Ok(()),
+                _ => Err(MetricError::Auth("bad signature")),
+            }
+        }
+    }
+
+    #[test]
+    fn message_auth() {
+        let name = name("requests;dc=ams");
+        let metrics = [(name, Metric::<f64>::new(MetricValue::Counter(1f64), None, 1f32))];
+        let message = |sequence: u64, sign: &dyn Fn(&[u8]) -> Option<Vec<u8>>| {
+            let mut builder = Builder::new_default();
+            {
+                let mut message = builder.init_root::<message::Builder>();
+                fill_snapshot(&mut message, metrics.iter().map(|(name, metric)| (name, metric)), false);
+                message.set_sequence(sequence);
+            }
+            let payload = auth_payload(builder.get_root_as_reader::<message::Reader>().unwrap()).unwrap();
+            if let Some(token) = sign(&payload) {
+                fill_auth(&mut builder.get_root::<message::Builder>().unwrap(), &token);
+            }
+            builder
+        };
+
+        let static_tokens = StaticTokens::new(vec!["first"]);
+        let signed = SignedTokens(42);
+        let builder = message(1, &|_| Some(b"first".to_vec()));
+        let reader = builder.get_root_as_reader::<message::Reader>().unwrap();
+        assert!(verify_message(reader, &static_tokens).is_ok());
+        assert!(verify_message(reader, &signed).is_err());
+
+        let builder = message(1, &|payload| Some(signed.sign(payload)));
+        let reader = builder.get_root_as_reader::<message::Reader>().unwrap();
+        assert!(verify_message(reader, &signed).is_ok());
+        assert!(verify_message(reader, &SignedTokens(43)).is_err());
+        assert!(verify_message(reader, &static_tokens).is_err());
+
+        // the token of one message does not fit the other one
+        let token = reader.get_auth().unwrap().to_vec();
+        let builder = message(2, &|_| Some(token.clone()));
+        assert!(verify_message(builder.get_root_as_reader::<message::Reader>().unwrap(), &signed).is_err());
+        let builder = message(1, &|_| None);
+        assert!(verify_message(builder.get_root_as_reader::<message::Reader>().unwrap(), &static_tokens).is_err());
+    }
+
+    #[test]
+    fn snapshot_integrity() {
+        let metrics = vec![
+            (name("gauge"), Metric::<f64>::new(MetricValue::Gauge(1f64), None, 1f32)),
+            (name("timer"), Metric::new(MetricValue::Timer(vec![1f64, 2f64]), None, 1f32)),
+            (
+                name("hist"),
+                Metric::new(MetricValue::CustomHistogram(1, vec![(1f64, 2), (2f64, 0)]), None, 1f32),
+            ),
+        ];
+        let integrity = |metrics: &[(MetricName, Metric<f64>)]| {
+            let mut integrity = SnapshotIntegrity::default();
+            metrics.iter().for_each(|(name, metric)| integrity.add(name, metric));
+            integrity
+        };
+        let expected = integrity(&metrics);
+        assert_eq!(expected.metrics, 3);
+        assert_eq!(expected.updates, 3);
+
+        let mut reversed = metrics.clone();
+        reversed.reverse();
+        integrity(&reversed).verify(&expected).unwrap();
+        let mut parts = integrity(&metrics[..1]);
+        parts.merge(&integrity(&metrics[1..]));
+        assert_eq!(parts, expected);
+
+        assert!(matches!(integrity(&metrics[..2]).verify(&expected), Err(MetricError::Integrity(_))));
+        let mut changed = metrics.clone();
+        changed[0].1 = Metric::new(MetricValue::Gauge(2f64), None, 1f32);
+        assert!(integrity(&changed).verify(&expected).is_err());
+    }
+
+    #[test]
+    fn snapshot_integrity_unsorted_names() {
+        // names decoded from v1 messages are not sorted
+        let metrics = [(
+            MetricName::new_lazy(Bytes::from_static(b"some.metric;b=2;a=1")),
+            Metric::<f64>::new(MetricValue::Counter(1f64), None, 1f32),
+        )];
+        for use_dictionary in [true, false] {
+            let mut builder = capnp::message::Builder::new_default();
+            {
+                let mut message = builder.init_root::<message::Builder>();
+                fill_snapshot(&mut message, metrics.iter().map(|(n, m)| (n, m)), use_dictionary);
+            }
+            let reader = builder.get_root_as_reader::<message::Reader>().unwrap();
+            let received = read_snapshot::<f64>(reader).unwrap();
+            assert_eq!(&received[0].0.name[..], b"some.metric;b=2;a=1");
+        }
+    }
+
+    #[test]
+    fn name_dictionary_parts() {
+        let mut dictionary = NameDictionary::new();
+        let name1 = name("some.metric.first;tag=value");
+        let name2 = name("some.metric.second;tag=other");
+        let name3 = name("some.metric.first");
+        assert_eq!(dictionary.add(&name1), vec![0, 1, 2, 3, 4]);
+        assert_eq!(dictionary.add(&name2), vec![0, 1, 5, 3, 6]);
+        assert_eq!(dictionary.add(&name3), vec![0, 1, 7]);
+        let entries = dictionary.entries.iter().map(|e| &e[..]).collect::<Vec<_>>();
+        let expected: Vec<&[u8]> = vec![b"some.", b"metric.", b"first;", b"tag=", b"value", b"second;", b"other", b"first"];
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn snapshot_with_dictionary() {
+        let names = vec!["some.metric.first;tag=value", "some.metric.second;tag=value", "some.other"];
+        let metrics = names
+            .into_iter()
+            .enumerate()
+            .map(|(idx, n)| (name(n), Metric::new(MetricValue::Counter(idx as f64), None, 1f32)))
+            .collect::<Vec<_>>();
+
+        for use_dictionary in [true, false] {
+            let mut builder = capnp::message::Builder::new_default();
+            {
+                let mut message = builder.init_root::<message::Builder>();
+                fill_snapshot(&mut message, metrics.iter().map(|(n, m)| (n, m)), use_dictionary);
+            }
+            let reader = builder.get_root_as_reader::<message::Reader>().unwrap();
+            check_message(reader).unwrap();
+            assert_eq!(read_snapshot::<f64>(reader).unwrap(), metrics);
+        }
+    }
+
+    #[test]
+    fn snapshot_batches() {
+        let mut intermediate = vec![0u8; 128];
+        let metrics = (0..100)
+            .map(|idx| {
+                let name = MetricName::new(
+                    BytesMut::from(&format!("some.metric.{};tag=value", idx)[..]),
+                    TagFormat::Graphite,
+                    &mut intermediate,
+                )
+                .unwrap();
+                (name, Metric::new(MetricValue::Timer(vec![idx as f64; idx % 10 + 1]), Some(idx as u64), 1f32))
+            })
+            .collect::<Vec<_>>();
+
+        for use_dictionary in [true, false] {
+            let batcher = SnapshotBatcher::new(4096, use_dictionary);
+            let batches = batcher.split(metrics.iter().map(|(n, m)| (n, m))).unwrap();
+            assert!(batches.len() > 1);
+            assert_eq!(batches.iter().map(Vec::len).sum::<usize>(), metrics.len());
+            for batch in &batches {
+                let size = batch.iter().map(|(n, m)| estimate_snapshot_size(n, m, use_dictionary)).sum::<usize>();
+                assert!(size + SnapshotBatcher::MESSAGE_OVERHEAD <= 4096);
+            }
+        }
+
+        let small = SnapshotBatcher::new(300, false);
+        assert!(matches!(small.split(metrics.iter().map(|(n, m)| (n, m))), Err(MetricError::BatchSize(_))));
+        assert!(small.split(Vec::<(&MetricName, &Metric<f64>)>::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn snapshot_batches_fit_budget() {
+        let metrics = (0..300)
+            .map(|idx| {
+                let name = name(&format!("s.m{}.x;t=v{}", idx, idx));
+                let value = match idx % 3 {
+                    0 => MetricValue::Counter(idx as f64),
+                    1 => MetricValue::Timer(vec![idx as f64; idx % 20 + 1]),
+                    _ => MetricValue::CustomHistogram(1, vec![(0f64, 1), (10f64, 2)]),
+                };
+                (name, Metric::new(value, Some(idx as u64), 1f32))
+            })
+            .collect::<Vec<_>>();
+
+        for use_dictionary in [true, false] {
+            let builders = SnapshotBatcher::new(2048, use_dictionary).build(metrics.iter().map(|(n, m)| (n, m))).unwrap();
+            let mut decoded = Vec::new();
+            for builder in &builders {
+                let mut buf = Vec::new();
+                capnp::serialize::write_message(&mut buf, builder).unwrap();
+                assert!(buf.len() <= 2048, "message of {} bytes", buf.len());
+                decoded.extend(read_snapshot::<f64>(builder.get_root_as_reader::<message::Reader>().unwrap()).unwrap());
+            }
+            assert_eq!(decoded, metrics);
+        }
+    }
+
+    #[test]
+    fn snapshot_size_estimate() {
+        let name = MetricName::new_untagged(BytesMut::from("some.metric"));
+        let labels = vec![("version", "1.0.0-rc1"), ("commit", "0123456789abcdef")];
+        let mut gauge = VectorGauge::new(vec![("load1", 1f64), ("load5", 2f64), ("load15", 3f64)])
+            .unwrap()
+            .to_metric(Some(10))
+            .with_info(Info::new(labels).unwrap());
+        gauge.set_unit(Some(MetricUnit::Custom("requests per second".into())));
+        let mut counter = MonotonicCounter::default();
+        counter.update(MonotonicUpdate::Delta(u64::MAX)).unwrap();
+        let mut counter = counter.to_metric::<f64>(Some(10));
+        counter.set_unit(Some(MetricUnit::Bytes));
+        let mut unset = Metric::<f64>::gauge_unset(Some(10));
+        unset.set_unit(Some(MetricUnit::Percent));
+
+        for metric in [gauge, counter, unset] {
+            for use_dictionary in [true, false] {
+                let mut builder = capnp::message::Builder::new_default();
+                fill_snapshot(&mut builder.init_root::<message::Builder>(), std::iter::once((&name, &metric)), use_dictionary);
+                let mut buf = Vec::new();
+                capnp::serialize::write_message(&mut buf, &builder).unwrap();
+                let estimate = SnapshotBatcher::MESSAGE_OVERHEAD + estimate_snapshot_size(&name, &metric, use_dictionary);
+                assert!(buf.len() <= estimate, "message of {} bytes, {} estimated", buf.len(), estimate);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_parallel() {
+        let metrics = (0..100)
+            .map(|idx| {
+                let name = name(&format!("some.metric.{}", idx % 30));
+                (name, Metric::new(MetricValue::Counter(idx as f64), None, 1f32))
+            })
+            .collect::<Vec<_>>();
+
+        let mut builder = capnp::message::Builder::new_default();
+        {
+            let mut message = builder.init_root::<message::Builder>();
+            fill_snapshot(&mut message, metrics.iter().map(|(n, m)| (n, m)), true);
+        }
+        let mut buf = Vec::new();
+        capnp::serialize::write_message(&mut buf, &builder).unwrap();
+
+        let mut expected = HashMap::new();
+        assert!(accumulate_all(&mut expected, metrics).is_empty());
+
+        for threads in [1, 3, 200] {
+            let shards = (0..4).map(|_| Mutex::new(HashMap::new())).collect::<Vec<_>>();
+            let errors = decode_snapshot_parallel::<f64>(&buf, &DecodeOptions::default(), threads, &shards).unwrap();
+            assert!(errors.is_empty());
+
+            let mut decoded = HashMap::new();
+            for (idx, shard) in shards.into_iter().enumerate() {
+                for (name, metric) in shard.into_inner().unwrap() {
+                    assert_eq!(name.shard_index(4), idx);
+                    decoded.insert(name, metric);
+                }
+            }
+            assert_eq!(decoded.len(), 30);
+            for (name, metric) in &expected {
+                assert_eq!(decoded[name].value(), metric.value());
+            }
+        }
+    }
+
+    #[test]
+    fn decode_options() {
+        let options = DecodeOptions::new().traversal_limit(Some(1001)).nesting_limit(10);
+        let reader_options = options.reader_options();
+        assert_eq!(reader_options.traversal_limit_in_words, Some(126));
+        assert_eq!(reader_options.nesting_limit, 10);
+        assert_eq!(DecodeOptions::new().traversal_limit(None).reader_options().traversal_limit_in_words, None);
+    }
+
+    #[test]
+    fn decode_with_limits() {
+        let metric = Metric::new(MetricValue::Timer(vec![1f64; 1000]), None, 1f32);
+        let name = name("some.timer");
+        let mut buf = Vec::new();
+        capnp::serialize::write_message(&mut buf, &metric.as_capnp_heap(Some((&name, false)))).unwrap();
+
+        let (rname, rmetric) = decode_metric::<f64, _>(&buf[..], &DecodeOptions::default()).unwrap();
+        assert_eq!(rname, name);
+        assert_eq!(rmetric, metric);
+
+        // 1000 timer values cannot fit into 1000 bytes
+        let options = DecodeOptions::new().traversal_limit(Some(1000));
+        assert!(decode_metric::<f64, _>(&buf[..], &options).is_err());
+    }
+
+    #[test]
+    fn scratch_builder() {
+        let mut space = capnp::Word::allocate_zeroed_vec(16);
+        let mut scratch = ScratchBuilder::new(&mut space);
+        let mut buf = Vec::new();
+        let mut expected = Vec::new();
+        // the second metric does not fit into the scratch space
+        for (n, size) in &[("some.timer", 3), ("some.big.timer", 100), ("some.other.timer", 1)] {
+            let name = name(n);
+            let metric = Metric::new(MetricValue::Timer(vec![1f64; *size]), Some(*size as u64), 1f32);
+            scratch.write_metric(&metric, Some((&name, false)), &mut buf).unwrap();
+            expected.push((name, metric));
+        }
+
+        let mut slice = &buf[..];
+        for (name, metric) in expected {
+            let reader = read_message_from_slice(&mut slice, &DecodeOptions::default()).unwrap();
+            let decoded = Metric::<f64>::from_capnp(reader.get_root().unwrap()).unwrap();
+            assert_eq!(decoded, (name, metric));
+        }
+        assert!(slice.is_empty());
+    }
+
+    #[test]
+    fn check_v1_deprecated() {
+        let mut builder = capnp::message::Builder::new_default();
+        {
+            let mut metric = builder.init_root::<cmetric_v1::Builder>();
+            metric.set_name("some.metric");
+            metric.reborrow().init_type().set_diff_counter(1f64);
+        }
+        let reader = builder.get_root_as_reader::<cmetric_v1::Reader>().unwrap();
+        match check_metric_v1(reader) {
+            Err(MetricError::Schema(v)) => assert_eq!(v, vec![SchemaViolation::new("", "type.diffCounter", ViolationKind::Deprecated)]),
+            other => panic!("expected schema error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use std::fmt::Debug;
+use std::str::FromStr;
+
+use num_traits::{AsPrimitive, Float};
+
+use crate::aggregate::{aggregates, Aggregate};
+use crate::cache::SnapshotView;
+use crate::metric::{FromF64, Metric, MetricError, MetricTypeName};
+use crate::name::MetricName;
+use crate::router::glob_match;
+
+/// The way a tag value is matched
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagMatch {
+    /// `key=value`
+    Equal(Vec<u8>),
+    /// `key!=value`, also matches metrics without the tag
+    NotEqual(Vec<u8>),
+    /// `key=*glob`, globs are the same as in routing rules
+    Glob(Vec<u8>),
+    /// `key!*glob`, also matches metrics without the tag
+    NotGlob(Vec<u8>),
+    /// `key=~regex`, the expression must match the whole value, like in Prometheus
+    #[cfg(feature = "scrub-regex")]
+    Regex(TagRegex),
+    /// `key!~regex`, also matches metrics without the tag
+    #[cfg(feature = "scrub-regex")]
+    NotRegex(TagRegex),
+}
+
+/// A regular expression anchored to match whole tag values, compared by its source
+#[cfg(feature = "scrub-regex")]
+#[derive(Debug, Clone)]
+pub struct TagRegex(regex::bytes::Regex);
+
+#[cfg(feature = "scrub-regex")]
+impl TagRegex {
+    pub fn new(re: &str) -> Result<Self, MetricError> {
+        regex::bytes::Regex::new(&format!("^(?:{})$", re))
+            .map(Self)
+            .map_err(|_| MetricError::Query("bad regular expression in tag selector"))
+    }
+
+    pub fn is_match(&self, value: &[u8]) -> bool {
+        self.0.is_match(value)
+    }
+}
+
+#[cfg(feature = "scrub-regex")]
+impl PartialEq for TagRegex {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+#[cfg(feature = "scrub-regex")]
+impl Eq for TagRegex {}
+
+/// A condition on the value of a tag, parsed from strings like `dc=ams`, `host=*web*` or `host=~web.*`,
+/// regular expressions require `scrub-regex` feature
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagSelector {
+    pub key: Vec<u8>,
+    pub matcher: TagMatch,
+}
+
+impl TagSelector {
+    pub fn matches(&self, name: &MetricName) -> bool {
+        let value = name.tag_value(&self.key);
+        match (&self.matcher, value) {
+            (TagMatch::Equal(expected), Some(value)) => expected[..] == *value,
+            (TagMatch::Glob(glob), Some(value)) => glob_match(glob, value),
+            (TagMatch::Equal(_), None) | (TagMatch::Glob(_), None) => false,
+            (TagMatch::NotEqual(expected), value) => value != Some(&expected[..]),
+            (TagMatch::NotGlob(glob), value) => !value.map(|value| glob_match(glob, value)).unwrap_or(false),
+            #[cfg(feature = "scrub-regex")]
+            (TagMatch::Regex(re), value) => value.map(|value| re.is_match(value)).unwrap_or(false),
+            #[cfg(feature = "scrub-regex")]
+            (TagMatch::NotRegex(re), value) => !value.map(|value| re.is_match(value)).unwrap_or(false),
+        }
+    }
+}
+
+impl FromStr for TagSelector {
+    type Err = MetricError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pos = s.find(['=', '!']).ok_or(MetricError::Query("tag selector has no operator"))?;
+        let (key, rest) = s.split_at(pos);
+        if key.is_empty() {
+            return Err(MetricError::Query("tag selector has no key"));
+        }
+        let matcher = if let Some(value) = rest.strip_prefix("=*") {
+            TagMatch::Glob(value.into())
+        } else if let Some(value) = rest.strip_prefix("!*") {
+            TagMatch::NotGlob(value.into())
+        } else if rest.starts_with("=~") || rest.starts_with("!~") {
+            regex_match(rest)?
+        } else if let Some(value) = rest.strip_prefix("!=") {
+            TagMatch::NotEqual(value.into())
+        } else if let Some(value) = rest.strip_prefix('=') {
+            TagMatch::Equal(value.into())
+        } else {
+            return Err(MetricError::Query("unknown tag selector operator"));
+        };
+        Ok(Self { key: key.into(), matcher })
+    }
+}
+
+#[cfg(feature = "scrub-regex")]
+fn regex_match(rest: &str) -> Result<TagMatch, MetricError> {
+    let re = TagRegex::new(&rest[2..])?;
+    Ok(if rest.starts_with('=') { TagMatch::Regex(re) } else { TagMatch::NotRegex(re) })
+}
+
+#[cfg(not(feature = "scrub-regex"))]
+fn regex_match(_: &str) -> Result<TagMatch, MetricError> {
+    Err(MetricError::Query("regular expressions in tag selectors require scrub-regex feature"))
+}
+
+/// A metric found by the query
+#[derive(Debug, Clone)]
+pub struct QueryItem<'a, F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize>,
+{
+    pub name: &'a MetricName,
+    pub metric: &'a Metric<F>,
+    /// the selected aggregates applicable to the metric along with their values
+    pub aggregates: Vec<(Aggregate<F>, F)>,
+}
+
+/// Filters a snapshot for inspection, i.e. in debug endpoints. Made by `SnapshotView::query`.
+/// A metric is found if its name matches any of the name globs, all of the tag selectors
+/// and any of the types, a query without conditions finds all the metrics
+#[derive(Debug, Clone)]
+pub struct SnapshotQuery<'a, F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64> + AsPrimitive<usize>,
+{
+    snapshot: &'a SnapshotView<F>,
+    names: Vec<Vec<u8>>,
+    tags: Vec<TagSelector>,
+    types: Vec<MetricTypeName>,
+    aggregates: Vec<Aggregate<F>>,
+}
+
+impl<'a, F> SnapshotQuery<'a, F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64> + AsPrimitive<usize>,
+{
+    pub fn new(snapshot: &'a SnapshotView<F>) -> Self {
+        Self {
+            snapshot,
+            names: Vec::new(),
+            tags: Vec::new(),
+            types: Vec::new(),
+            aggregates: Vec::new(),
+        }
+    }
+
+    /// Adds a glob for the name without tags
+    pub fn name(mut self, glob: &str) -> Self {
+        self.names.push(glob.as_bytes().to_vec());
+        self
+    }
+
+    pub fn tag(mut self, selector: TagSelector) -> Self {
+        self.tags.push(selector);
+        self
+    }
+
+    pub fn metric_type(mut self, mtype: MetricTypeName) -> Self {
+        self.types.push(mtype);
+        self
+    }
+
+    /// Aggregates to calculate for the metrics found, the metrics are cloned for calculation
+    pub fn aggregates(mut self, aggregates: &[Aggregate<F>]) -> Self {
+        self.aggregates.extend_from_slice(aggregates);
+        self
+    }
+
+    pub fn matches(&self, name: &MetricName, metric: &Metric<F>) -> bool {
+        (self.names.is_empty() || self.names.iter().any(|glob| glob_match(glob, name.name_without_tags())))
+            && self.tags.iter().all(|selector| selector.matches(name))
+            && (self.types.is_empty() || self.types.contains(&MetricTypeName::from_metric(metric)))
+    }
+
+    /// The metrics found, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = QueryItem<'a, F>> + '_ {
+        self.snapshot
+            .iter()
+            .filter(move |(name, metric)| self.matches(name, metric))
+            .map(move |(name, metric)| {
+                let aggregates = if self.aggregates.is_empty() {
+                    Vec::new()
+                } else {
+                    let mut metric = metric.clone();
+                    aggregates(&mut metric, &self.aggregates).collect()
+                };
+                QueryItem { name, metric, aggregates }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metric::MetricValue;
+    use crate::testing::name;
+    use std::collections::HashMap;
+
+    #[test]
+    fn snapshot_query() {
+        let metrics = vec![
+            (name("requests;dc=ams;host=web1"), Metric::<f64>::new(MetricValue::Counter(1f64), None, 1f32)),
+            (name("requests;dc=fra;host=web2"), Metric::new(MetricValue::Counter(2f64), None, 1f32)),
+            (
+                name("latency;dc=ams;host=db1"),
+                Metric::new(MetricValue::Timer(vec![3f64, 1f64, 2f64]), None, 1f32),
+            ),
+            (name("latency"), Metric::new(MetricValue::Timer(vec![1f64]), None, 1f32)),
+        ];
+        let snapshot = SnapshotView::from(metrics.into_iter().collect::<HashMap<_, _>>());
+        let found = |query: SnapshotQuery<f64>| {
+            let mut names = query
+                .iter()
+                .map(|item| String::from_utf8_lossy(&item.name.name).to_string())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        assert_eq!(found(snapshot.query()).len(), 4);
+        assert_eq!(
+            found(snapshot.query().tag("dc=ams".parse().unwrap())),
+            vec!["latency;dc=ams;host=db1", "requests;dc=ams;host=web1"]
+        );
+        assert_eq!(found(snapshot.query().tag("host=*web*".parse().unwrap())).len(), 2);
+        assert_eq!(
+            found(snapshot.query().tag("dc!=ams".parse().unwrap())),
+            vec!["latency", "requests;dc=fra;host=web2"]
+        );
+        assert_eq!(found(snapshot.query().tag("host!*web*".parse().unwrap()).name("req*")).len(), 0);
+        assert_eq!(found(snapshot.query().metric_type(MetricTypeName::Timer).name("lat*")).len(), 2);
+
+        let query = snapshot
+            .query()
+            .tag("host=db1".parse().unwrap())
+            .aggregates(&[Aggregate::Max, Aggregate::Median]);
+        let items = query.iter().collect::<Vec<_>>();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].aggregates, vec![(Aggregate::Max, 3f64), (Aggregate::Median, 2f64)]);
+        // the snapshot itself is not sorted
+        assert_eq!(items[0].metric.value(), &MetricValue::Timer(vec![3f64, 1f64, 2f64]));
+
+        assert!("dc".parse::<TagSelector>().is_err());
+        assert!("=ams".parse::<TagSelector>().is_err());
+        assert!("dc!ams".parse::<TagSelector>().is_err());
+
+        #[cfg(feature = "scrub-regex")]
+        {
+            assert_eq!(
+                found(snapshot.query().tag("host=~web.*".parse().unwrap())),
+                vec!["requests;dc=ams;host=web1", "requests;dc=fra;host=web2"]
+            );
+            // the whole value must match
+            assert_eq!(found(snapshot.query().tag("host=~eb.".parse().unwrap())).len(), 0);
+            assert_eq!(
+                found(snapshot.query().tag("host!~web[0-9]".parse().unwrap())),
+                vec!["latency", "latency;dc=ams;host=db1"]
+            );
+            assert_eq!("host=~web.*".parse::<TagSelector>().unwrap(), "host=~web.*".parse().unwrap());
+            assert!("host=~web(".parse::<TagSelector>().is_err());
+        }
+        #[cfg(not(feature = "scrub-regex"))]
+        assert!("host=~web.*".parse::<TagSelector>().is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use serde::{Deserialize, Serialize};
+
+use crate::name::MetricName;
+use crate::router::glob_match;
+
+/// What to do with the updates over the limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QuotaAction {
+    #[default]
+    Deny,
+    /// keep every n-th update over the limit
+    Downsample(u32),
+}
+
+/// A quota on updates per interval for series having the tag
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct QuotaRule {
+    /// glob for the name without tags, like in routing rules
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// key of the tag the quota is counted by
+    pub tag: String,
+
+    /// value of the tag, `*` gives every distinct value a quota of its own
+    #[serde(default = "QuotaRule::any_value")]
+    pub value: String,
+
+    /// number of updates allowed per interval
+    pub limit: u64,
+
+    #[serde(default)]
+    pub action: QuotaAction,
+}
+
+impl QuotaRule {
+    fn any_value() -> String {
+        "*".into()
+    }
+}
+
+/// The decision about a single update
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuotaDecision {
+    Allow,
+    Deny,
+    /// the update is kept for the ones dropped, the value is the sampling rate to account it with
+    Downsample(f32),
+}
+
+/// A tag value that has been over quota in the interval
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    /// index of the rule in the list passed to `Quotas::new`
+    pub rule: usize,
+    pub value: Vec<u8>,
+    pub updates: u64,
+}
+
+#[derive(Debug)]
+struct CompiledQuota {
+    name: Option<Vec<u8>>,
+    tag: Vec<u8>,
+    value: Option<Vec<u8>>,
+    limit: u64,
+    action: QuotaAction,
+    counters: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+impl CompiledQuota {
+    fn counters(&self) -> MutexGuard<'_, HashMap<Vec<u8>, u64>> {
+        self.counters.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn decide(&self, updates: u64) -> QuotaDecision {
+        if updates <= self.limit {
+            return QuotaDecision::Allow;
+        }
+        match self.action {
+            QuotaAction::Deny => QuotaDecision::Deny,
+            QuotaAction::Downsample(every) => {
+                let every = every.max(1);
+                if (updates - self.limit).is_multiple_of(every as u64) {
+                    QuotaDecision::Downsample(1f32 / every as f32)
+                } else {
+                    QuotaDecision::Deny
+                }
+            }
+        }
+    }
+}
+
+/// Limits the number of updates per interval by tag values, so a single tenant flooding with updates
+/// cannot starve the others. The rules are evaluated in order, only the first one matching the name
+/// counts the update. Counters are kept until `reset`, which should be called on each aggregation interval.
+#[derive(Debug)]
+pub struct Quotas {
+    rules: Vec<CompiledQuota>,
+}
+
+impl Quotas {
+    pub fn new(rules: Vec<QuotaRule>) -> Self {
+        let rules = rules
+            .into_iter()
+            .map(|rule| CompiledQuota {
+                name: rule.name.map(String::into_bytes),
+                tag: rule.tag.into_bytes(),
+                value: if rule.value == "*" { None } else { Some(rule.value.into_bytes()) },
+                limit: rule.limit,
+                action: rule.action,
+                counters: Mutex::new(HashMap::new()),
+            })
+            .collect();
+        Self { rules }
+    }
+
+    /// Counts the update of the series and decides if it should be aggregated
+    pub fn check(&self, name: &MetricName) -> QuotaDecision {
+        for rule in &self.rules {
+            if let Some(ref glob) = rule.name {
+                if !glob_match(glob, name.name_without_tags()) {
+                    continue;
+                }
+            }
+            let value = match (name.tag_value(&rule.tag), &rule.value) {
+                (Some(found), Some(value)) if found == &value[..] => found,
+                (Some(found), None) => found,
+                _ => continue,
+            };
+
+            let mut counters = rule.counters();
+            let updates = match counters.get_mut(value) {
+                Some(updates) => {
+                    *updates += 1;
+                    *updates
+                }
+                None => {
+                    counters.insert(value.to_vec(), 1);
+                    1
+                }
+            };
+            return rule.decide(updates);
+        }
+        QuotaDecision::Allow
+    }
+
+    /// Starts a new interval, giving the tag values that have been over quota in the previous one,
+    /// ordered by rule and value
+    pub fn reset(&self) -> Vec<QuotaExceeded> {
+        let mut exceeded = Vec::new();
+        for (idx, rule) in self.rules.iter().enumerate() {
+            let counters = std::mem::take(&mut *rule.counters());
+            let start = exceeded.len();
+            exceeded.extend(
+                counters
+                    .into_iter()
+                    .filter(|(_, updates)| *updates > rule.limit)
+                    .map(|(value, updates)| QuotaExceeded { rule: idx, value, updates }),
+            );
+            exceeded[start..].sort_unstable_by(|a, b| a.value.cmp(&b.value));
+        }
+        exceeded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::name;
+
+    #[test]
+    fn quota_decisions() {
+        let quotas = Quotas::new(vec![
+            QuotaRule {
+                name: Some("debug.*".into()),
+                tag: "tenant".into(),
+                value: "noisy".into(),
+                limit: 1,
+                action: QuotaAction::Downsample(2),
+            },
+            QuotaRule {
+                name: None,
+                tag: "tenant".into(),
+                value: "*".into(),
+                limit: 2,
+                action: QuotaAction::Deny,
+            },
+        ]);
+
+        let (a, b, noisy, untagged) = (
+            name("requests;tenant=a"),
+            name("errors;tenant=b"),
+            name("debug.x;tenant=noisy"),
+            name("requests"),
+        );
+
+        let decisions = (0..5).map(|_| quotas.check(&noisy)).collect::<Vec<_>>();
+        use QuotaDecision::*;
+        assert_eq!(decisions, [Allow, Deny, Downsample(0.5), Deny, Downsample(0.5)]);
+
+        assert_eq!(quotas.check(&a), Allow);
+        assert_eq!(quotas.check(&a), Allow);
+        assert_eq!(quotas.check(&a), Deny);
+        // other tenants are not affected
+        assert_eq!(quotas.check(&b), Allow);
+        assert_eq!(quotas.check(&untagged), Allow);
+
+        let exceeded = quotas.reset();
+        assert_eq!(
+            exceeded,
+            vec![
+                QuotaExceeded {
+                    rule: 0,
+                    value: b"noisy".to_vec(),
+                    updates: 5
+                },
+                QuotaExceeded {
+                    rule: 1,
+                    value: b"a".to_vec(),
+                    updates: 3
+                },
+            ]
+        );
+        assert_eq!(quotas.check(&a), Allow);
+        assert!(quotas.reset().is_empty());
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+use num_traits::{AsPrimitive, Float};
+use serde::{Deserialize, Serialize};
+
+use crate::aggregate::Aggregate;
+use crate::cache::Snapshot;
+use crate::metric::{FromF64, Metric, MetricError, MetricValue};
+use crate::name::{parse_name, MetricName};
+use crate::query::TagSelector;
+
+/// The way values of the series in a group are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RecordingFunction {
+    Sum,
+    Min,
+    Max,
+    Mean,
+    /// the number of series in the group
+    Count,
+}
+
+/// A rule like the Prometheus recording one, i.e. `sum by (dc) (requests)`, written as
+/// `{ "metrics": ["requests"], "aggregate": "value", "function": "sum", "by": ["dc"], "name": "requests:sum;dc={dc}" }`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RecordingRuleOptions<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize>,
+{
+    /// globs for the name without tags, all the metrics are selected without them
+    #[serde(default)]
+    pub metrics: Vec<String>,
+
+    /// tag selectors like `dc=ams`, `host=*web*` or `host=~web.*`, see `TagSelector`
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// the aggregate of every selected series to be combined
+    pub aggregate: Aggregate<F>,
+
+    pub function: RecordingFunction,
+
+    /// tags to group the series by, a metric is recorded for every distinct combination of
+    /// their values, series without any of the tags are not counted
+    #[serde(default)]
+    pub by: Vec<String>,
+
+    /// the name of recorded metrics, may include tags, `{tag}` is replaced with the value of the
+    /// grouping tag
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TemplatePart {
+    Literal(Vec<u8>),
+    // the index in grouping tags
+    Tag(usize),
+}
+
+fn parse_template(template: &str, by: &[String]) -> Result<Vec<TemplatePart>, MetricError> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(pos) = rest.find('{') {
+        if pos > 0 {
+            parts.push(TemplatePart::Literal(rest[..pos].into()));
+        }
+        let end = rest[pos..]
+            .find('}')
+            .ok_or_else(|| MetricError::Recording(format!("unclosed brace in '{}'", template)))?
+            + pos;
+        let key = &rest[pos + 1..end];
+        let idx = by
+            .iter()
+            .position(|tag| tag == key)
+            .ok_or_else(|| MetricError::Recording(format!("'{}' is not a grouping tag in '{}'", key, template)))?;
+        parts.push(TemplatePart::Tag(idx));
+        rest = &rest[end + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(TemplatePart::Literal(rest.into()));
+    }
+    Ok(parts)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Group<F> {
+    value: F,
+    count: usize,
+}
+
+/// A recording rule evaluated on every interval snapshot, giving synthetic metrics to be sent
+/// along with the ones of the snapshot
+#[derive(Debug, Clone)]
+pub struct RecordingRule<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize>,
+{
+    names: Vec<String>,
+    tags: Vec<TagSelector>,
+    aggregate: Aggregate<F>,
+    function: RecordingFunction,
+    by: Vec<Vec<u8>>,
+    template: Vec<TemplatePart>,
+}
+
+impl<F> RecordingRule<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64> + AsPrimitive<usize>,
+{
+    pub fn new(options: &RecordingRuleOptions<F>) -> Result<Self, MetricError> {
+        let tags = options.tags.iter().map(|selector| selector.parse()).collect::<Result<Vec<_>, _>>()?;
+        let template = parse_template(&options.name, &options.by)?;
+        // a name with all the tags set to some value must be valid for the names to be valid at all
+        let example = template.iter().fold(String::new(), |mut name, part| {
+            match part {
+                TemplatePart::Literal(literal) => name.push_str(&String::from_utf8_lossy(literal)),
+                TemplatePart::Tag(_) => name.push('x'),
+            }
+            name
+        });
+        if parse_name(&example).is_none() {
+            return Err(MetricError::Recording(format!("bad name '{}'", options.name)));
+        }
+        Ok(Self {
+            names: options.metrics.clone(),
+            tags,
+            aggregate: options.aggregate,
+            function: options.function,
+            by: options.by.iter().map(|tag| tag.as_bytes().to_vec()).collect(),
+            template,
+        })
+    }
+
+    /// Computes the metrics of all the groups found in the snapshot, giving them as gauges.
+    /// Series without the aggregate or with a NaN value of it are skipped
+    pub fn eval_snapshot(&self, snapshot: &Snapshot<F>) -> Vec<(MetricName, Metric<F>)> {
+        let query = self.names.iter().fold(snapshot.query(), |query, glob| query.name(glob));
+        let query = self.tags.iter().fold(query, |query, selector| query.tag(selector.clone()));
+        let query = query.aggregates(&[self.aggregate]);
+
+        let mut groups: HashMap<Vec<&[u8]>, Group<F>> = HashMap::new();
+        for item in query.iter() {
+            let value = match item.aggregates.first() {
+                Some((_, value)) if !value.is_nan() => *value,
+                _ => continue,
+            };
+            let key = match self.by.iter().map(|tag| item.name.tag_value(tag)).collect::<Option<Vec<_>>>() {
+                Some(key) => key,
+                None => continue,
+            };
+            groups
+                .entry(key)
+                .and_modify(|group| {
+                    group.value = match self.function {
+                        RecordingFunction::Sum | RecordingFunction::Mean => group.value + value,
+                        RecordingFunction::Min => group.value.min(value),
+                        RecordingFunction::Max => group.value.max(value),
+                        RecordingFunction::Count => group.value,
+                    };
+                    group.count += 1;
+                })
+                .or_insert(Group { value, count: 1 });
+        }
+
+        groups
+            .into_iter()
+            .filter_map(|(key, group)| {
+                let value = match self.function {
+                    RecordingFunction::Sum | RecordingFunction::Min | RecordingFunction::Max => group.value,
+                    RecordingFunction::Mean => group.value / F::from_f64(group.count as f64),
+                    RecordingFunction::Count => F::from_f64(group.count as f64),
+                };
+                let mut name = Vec::new();
+                for part in &self.template {
+                    match part {
+                        TemplatePart::Literal(literal) => name.extend_from_slice(literal),
+                        TemplatePart::Tag(idx) => name.extend_from_slice(key[*idx]),
+                    }
+                }
+                // tag values are taken from valid names, so only the empty ones could break the name
+                let name = parse_name(std::str::from_utf8(&name).ok()?)?;
+                Some((name, Metric::new(MetricValue::Gauge(value), None, 1f32)))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::SnapshotView;
+    use crate::testing::name;
+
+    fn options(function: RecordingFunction, by: &[&str], name: &str) -> RecordingRuleOptions<f64> {
+        RecordingRuleOptions {
+            metrics: vec!["requests".into()],
+            tags: vec!["host!=canary".into()],
+            aggregate: Aggregate::Value,
+            function,
+            by: by.iter().map(|tag| tag.to_string()).collect(),
+            name: name.into(),
+        }
+    }
+
+    #[test]
+    fn recording_rules() {
+        let mut metrics = HashMap::new();
+        for (series, value) in [
+            ("requests;dc=ams;host=web1", 1f64),
+            ("requests;dc=ams;host=web2", 3f64),
+            ("requests;dc=fra;host=web3", 10f64),
+            ("requests;dc=fra;host=canary", 100f64),
+            ("requests;host=web4", 1000f64),
+            ("errors;dc=ams;host=web1", 10000f64),
+        ] {
+            metrics.insert(name(series), Metric::<f64>::new(MetricValue::Counter(value), None, 1f32));
+        }
+        let snapshot = SnapshotView::from(metrics);
+        let recorded = |options: RecordingRuleOptions<f64>| {
+            let mut recorded = RecordingRule::new(&options)
+                .unwrap()
+                .eval_snapshot(&snapshot)
+                .into_iter()
+                .map(|(name, metric)| (String::from_utf8_lossy(&name.name).to_string(), metric.value().clone()))
+                .collect::<Vec<_>>();
+            recorded.sort_by(|a, b| a.0.cmp(&b.0));
+            recorded
+        };
+
+        assert_eq!(
+            recorded(options(RecordingFunction::Sum, &["dc"], "requests:sum;dc={dc}")),
+            vec![
+                ("requests:sum;dc=ams".to_string(), MetricValue::Gauge(4f64)),
+                ("requests:sum;dc=fra".to_string(), MetricValue::Gauge(10f64)),
+            ]
+        );
+        assert_eq!(
+            recorded(options(RecordingFunction::Mean, &["dc"], "requests.{dc}.mean")),
+            vec![
+                ("requests.ams.mean".to_string(), MetricValue::Gauge(2f64)),
+                ("requests.fra.mean".to_string(), MetricValue::Gauge(10f64)),
+            ]
+        );
+        assert_eq!(
+            recorded(options(RecordingFunction::Count, &[], "requests:hosts")),
+            vec![("requests:hosts".to_string(), MetricValue::Gauge(4f64))]
+        );
+
+        assert!(RecordingRule::new(&options(RecordingFunction::Max, &["dc"], "requests;host={host}")).is_err());
+        assert!(RecordingRule::new(&options(RecordingFunction::Max, &["dc"], "requests;dc={dc")).is_err());
+        assert!(RecordingRule::new(&options(RecordingFunction::Max, &[], "")).is_err());
+    }
+}
@@ -0,0 +1,231 @@
+use std::fmt::{Debug, Write};
+
+use bytes::BytesMut;
+use num_traits::{AsPrimitive, Float};
+use serde::{Deserialize, Serialize};
+
+use crate::aggregate::Aggregate;
+use crate::metric::FromF64;
+use crate::name::{MetricName, UnicodePolicy};
+
+/// Appends a bulk string
+fn put_bulk(buf: &mut BytesMut, value: &[u8]) {
+    // writing to BytesMut never fails
+    write!(buf, "${}\r\n", value.len()).unwrap_or_default();
+    buf.extend_from_slice(value);
+    buf.extend_from_slice(b"\r\n");
+}
+
+/// Appends a command as RESP array of bulk strings, i.e. `["RPUSH", "key", "value"]`
+pub fn put_command(buf: &mut BytesMut, args: &[&[u8]]) {
+    write!(buf, "*{}\r\n", args.len()).unwrap_or_default();
+    for arg in args {
+        put_bulk(buf, arg);
+    }
+}
+
+/// Where to put metrics in Redis
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum RespTarget {
+    /// XADD each value to the stream as an entry with `name`, `value` and optional `ts` and `aggregate` fields,
+    /// the stream is capped approximately when the length is set
+    Stream { key: String, max_len: Option<u64> },
+    /// RPUSH values to the list as `<name> <value> [<timestamp>]` lines, up to batch size values per command
+    List { key: String, batch_size: usize },
+}
+
+/// Encodes metrics as Redis commands. All commands of a batch are written to the buffer back to back,
+/// so they can be sent as a single pipeline.
+#[derive(Debug, Clone)]
+pub struct RespEncoder {
+    target: RespTarget,
+    unicode: UnicodePolicy,
+}
+
+impl RespEncoder {
+    pub fn new(target: RespTarget) -> Self {
+        Self {
+            target,
+            unicode: UnicodePolicy::default(),
+        }
+    }
+
+    /// Apply the policy to names with non-ASCII characters, the values of rejected names are skipped
+    pub fn with_unicode(mut self, policy: UnicodePolicy) -> Self {
+        self.unicode = policy;
+        self
+    }
+
+    /// Starts a batch of commands written to the buffer
+    pub fn batch<'a>(&'a self, buf: &'a mut BytesMut) -> RespBatch<'a> {
+        RespBatch {
+            target: &self.target,
+            unicode: self.unicode,
+            buf,
+            values: BytesMut::new(),
+            pending: 0,
+            commands: 0,
+        }
+    }
+}
+
+/// A batch of commands. The last RPUSH command is written by `finish`, which also gives the number
+/// of replies to be read, or when the batch is dropped
+#[derive(Debug)]
+pub struct RespBatch<'a> {
+    target: &'a RespTarget,
+    unicode: UnicodePolicy,
+    buf: &'a mut BytesMut,
+    // bulk strings of the list command not written yet
+    values: BytesMut,
+    pending: usize,
+    commands: usize,
+}
+
+impl<'a> RespBatch<'a> {
+    pub fn push<F>(&mut self, name: &MetricName, aggregate: Option<&Aggregate<F>>, value: F, timestamp: Option<u64>)
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<usize> + AsPrimitive<f64>,
+    {
+        let name = match self.unicode.encoded_name(name) {
+            Some(name) => name,
+            None => return,
+        };
+        let value: f64 = value.as_();
+        match self.target {
+            RespTarget::Stream { key, max_len } => {
+                let value = value.to_string();
+                let timestamp = timestamp.map(|ts| ts.to_string());
+                let aggregate = aggregate.map(|agg| agg.to_string()).filter(|agg| !agg.is_empty());
+                let max_len = max_len.map(|len| len.to_string());
+
+                let mut args: Vec<&[u8]> = vec![b"XADD", key.as_bytes()];
+                if let Some(ref max_len) = max_len {
+                    args.extend_from_slice(&[b"MAXLEN", b"~", max_len.as_bytes()]);
+                }
+                args.extend_from_slice(&[b"*", b"name", &name.name[..], b"value", value.as_bytes()]);
+                if let Some(ref timestamp) = timestamp {
+                    args.extend_from_slice(&[b"ts", timestamp.as_bytes()]);
+                }
+                if let Some(ref aggregate) = aggregate {
+                    args.extend_from_slice(&[b"aggregate", aggregate.as_bytes()]);
+                }
+                put_command(self.buf, &args);
+                self.commands += 1;
+            }
+            RespTarget::List { batch_size, .. } => {
+                let mut line = String::with_capacity(name.name.len() + 32);
+                line.push_str(&String::from_utf8_lossy(&name.name[..]));
+                write!(line, " {}", value).unwrap_or_default();
+                if let Some(timestamp) = timestamp {
+                    write!(line, " {}", timestamp).unwrap_or_default();
+                }
+                put_bulk(&mut self.values, line.as_bytes());
+                self.pending += 1;
+                if self.pending >= *batch_size {
+                    self.flush();
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if let RespTarget::List { key, .. } = self.target {
+            if self.pending == 0 {
+                return;
+            }
+            write!(self.buf, "*{}\r\n", self.pending + 2).unwrap_or_default();
+            put_bulk(self.buf, b"RPUSH");
+            put_bulk(self.buf, key.as_bytes());
+            self.buf.extend_from_slice(&self.values.split());
+            self.pending = 0;
+            self.commands += 1;
+        }
+    }
+
+    /// Writes the pending values and returns the number of commands in the batch,
+    /// which is the number of replies to be read
+    pub fn finish(mut self) -> usize {
+        self.flush();
+        self.commands
+    }
+}
+
+impl<'a> Drop for RespBatch<'a> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::name;
+
+    #[test]
+    fn resp_commands() {
+        let (name, unicode) = (name("cpu;host=h1"), name("température"));
+
+        let mut buf = BytesMut::new();
+        let encoder = RespEncoder::new(RespTarget::Stream {
+            key: "metrics".into(),
+            max_len: Some(1000),
+        });
+        let mut batch = encoder.batch(&mut buf);
+        batch.push(&name, Some(&Aggregate::Max), 1.5f64, Some(10));
+        batch.push(&name, None, 2f64, None);
+        assert_eq!(batch.finish(), 2);
+        assert_eq!(
+            String::from_utf8(buf.to_vec()).unwrap(),
+            concat!(
+                "*14\r\n$4\r\nXADD\r\n$7\r\nmetrics\r\n$6\r\nMAXLEN\r\n$1\r\n~\r\n$4\r\n1000\r\n$1\r\n*\r\n",
+                "$4\r\nname\r\n$11\r\ncpu;host=h1\r\n$5\r\nvalue\r\n$3\r\n1.5\r\n$2\r\nts\r\n$2\r\n10\r\n$9\r\naggregate\r\n$3\r\nmax\r\n",
+                "*10\r\n$4\r\nXADD\r\n$7\r\nmetrics\r\n$6\r\nMAXLEN\r\n$1\r\n~\r\n$4\r\n1000\r\n$1\r\n*\r\n",
+                "$4\r\nname\r\n$11\r\ncpu;host=h1\r\n$5\r\nvalue\r\n$1\r\n2\r\n",
+            )
+        );
+
+        let mut buf = BytesMut::new();
+        let encoder = RespEncoder::new(RespTarget::List {
+            key: "q".into(),
+            batch_size: 2,
+        });
+        let mut batch = encoder.batch(&mut buf);
+        for i in 0..3 {
+            batch.push::<f64>(&name, None, i as f64, Some(5));
+        }
+        assert_eq!(batch.finish(), 2);
+        assert_eq!(
+            String::from_utf8(buf.to_vec()).unwrap(),
+            concat!(
+                "*4\r\n$5\r\nRPUSH\r\n$1\r\nq\r\n$15\r\ncpu;host=h1 0 5\r\n$15\r\ncpu;host=h1 1 5\r\n",
+                "*3\r\n$5\r\nRPUSH\r\n$1\r\nq\r\n$15\r\ncpu;host=h1 2 5\r\n",
+            )
+        );
+
+        let mut buf = BytesMut::new();
+        let encoder = RespEncoder::new(RespTarget::List {
+            key: "q".into(),
+            batch_size: 10,
+        })
+        .with_unicode(UnicodePolicy::PercentEncode);
+        let mut batch = encoder.batch(&mut buf);
+        batch.push::<f64>(&unicode, None, 1f64, None);
+        assert_eq!(batch.finish(), 1);
+        assert_eq!(
+            String::from_utf8(buf.to_vec()).unwrap(),
+            "*3\r\n$5\r\nRPUSH\r\n$1\r\nq\r\n$18\r\ntemp%C3%A9rature 1\r\n"
+        );
+
+        // the pending values are written when the batch is dropped without finishing
+        let mut buf = BytesMut::new();
+        let mut batch = encoder.batch(&mut buf);
+        batch.push::<f64>(&name, None, 1f64, None);
+        drop(batch);
+        assert_eq!(
+            String::from_utf8(buf.to_vec()).unwrap(),
+            "*3\r\n$5\r\nRPUSH\r\n$1\r\nq\r\n$13\r\ncpu;host=h1 1\r\n"
+        );
+    }
+}
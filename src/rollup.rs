@@ -0,0 +1,179 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+
+use num_traits::{AsPrimitive, Float};
+use serde::{Deserialize, Serialize};
+
+use crate::aggregate::Aggregate;
+use crate::metric::{FromF64, MetricTypeName};
+
+/// The way values of an aggregate are merged into a coarser interval
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RollupRule {
+    Sum,
+    Min,
+    Max,
+    /// the value with the latest timestamp
+    Last,
+    /// mean weighted by the point weight, i.e. by the count of values for means of timers,
+    /// or by interval duration for rates
+    WeightedMean,
+}
+
+impl RollupRule {
+    /// The rule giving exact results for the aggregate where possible. Medians, percentiles and custom
+    /// aggregates cannot be merged exactly, so the weighted mean is used as an approximation for them.
+    /// Values of counters are summed, while for other types the last value is taken.
+    pub fn for_aggregate<F>(aggregate: &Aggregate<F>, mtype: MetricTypeName) -> Self
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<usize>,
+    {
+        match aggregate {
+            Aggregate::Value if mtype == MetricTypeName::Counter => RollupRule::Sum,
+            Aggregate::Value | Aggregate::Last => RollupRule::Last,
+            Aggregate::Count | Aggregate::Sum | Aggregate::UpdateCount | Aggregate::Bucket(_) => RollupRule::Sum,
+            Aggregate::Min => RollupRule::Min,
+            Aggregate::Max => RollupRule::Max,
+            Aggregate::Mean | Aggregate::Median | Aggregate::Percentile(_, _) | Aggregate::Rate(_) | Aggregate::Custom => RollupRule::WeightedMean,
+        }
+    }
+}
+
+/// A point of an aggregated series
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RollupPoint<F> {
+    pub timestamp: u64,
+    pub value: F,
+    /// only used by `RollupRule::WeightedMean`, points with zero weight are not counted then
+    pub weight: F,
+}
+
+impl<F: Float> RollupPoint<F> {
+    /// A point with weight of 1
+    pub fn new(timestamp: u64, value: F) -> Self {
+        Self {
+            timestamp,
+            value,
+            weight: F::one(),
+        }
+    }
+
+    pub fn weighted(timestamp: u64, value: F, weight: F) -> Self {
+        Self { timestamp, value, weight }
+    }
+}
+
+/// Merges the values of one coarse interval
+#[derive(Debug, Clone)]
+pub struct RollupAccumulator<F> {
+    rule: RollupRule,
+    value: Option<F>,
+    last_ts: u64,
+    weight: F,
+}
+
+impl<F: Float> RollupAccumulator<F> {
+    pub fn new(rule: RollupRule) -> Self {
+        Self {
+            rule,
+            value: None,
+            last_ts: 0,
+            weight: F::zero(),
+        }
+    }
+
+    pub fn push(&mut self, point: &RollupPoint<F>) {
+        let value = match (self.rule, self.value) {
+            (RollupRule::WeightedMean, _) if point.weight <= F::zero() => return,
+            (_, None) => {
+                self.value = Some(point.value);
+                self.last_ts = point.timestamp;
+                self.weight = point.weight;
+                return;
+            }
+            (_, Some(value)) => value,
+        };
+
+        self.value = Some(match self.rule {
+            RollupRule::Sum => value + point.value,
+            RollupRule::Min => value.min(point.value),
+            RollupRule::Max => value.max(point.value),
+            RollupRule::Last if point.timestamp >= self.last_ts => point.value,
+            RollupRule::Last => value,
+            RollupRule::WeightedMean => {
+                // the running mean is kept to avoid overflows on large sums
+                let weight = self.weight + point.weight;
+                value + (point.value - value) * point.weight / weight
+            }
+        });
+        self.weight = self.weight + point.weight;
+        self.last_ts = self.last_ts.max(point.timestamp);
+    }
+
+    /// The merged value, None if there were no points
+    pub fn result(&self) -> Option<F> {
+        self.value
+    }
+}
+
+/// Re-aggregates the series into the coarser intervals of `interval` timestamp units, i.e. 60 for
+/// minutes from timestamps in seconds. Resulting points are stamped with the start of the interval,
+/// intervals are aligned to multiples of `interval`. Points may come in any order.
+pub fn rollup<F, I>(points: I, interval: u64, rule: RollupRule) -> Vec<(u64, F)>
+where
+    F: Float,
+    I: IntoIterator<Item = RollupPoint<F>>,
+{
+    let interval = interval.max(1);
+    let mut intervals: BTreeMap<u64, RollupAccumulator<F>> = BTreeMap::new();
+    for point in points {
+        let start = point.timestamp - point.timestamp % interval;
+        intervals.entry(start).or_insert_with(|| RollupAccumulator::new(rule)).push(&point);
+    }
+    intervals
+        .into_iter()
+        .filter_map(|(start, acc)| acc.result().map(|value| (start, value)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rollup_rules() {
+        let points = || (0..12).map(|i| RollupPoint::new(i * 10, i as f64)).collect::<Vec<_>>();
+        assert_eq!(rollup(points(), 60, RollupRule::Sum), vec![(0, 15f64), (60, 51f64)]);
+        assert_eq!(rollup(points(), 60, RollupRule::Min), vec![(0, 0f64), (60, 6f64)]);
+        assert_eq!(rollup(points(), 60, RollupRule::Max), vec![(0, 5f64), (60, 11f64)]);
+
+        let mut reversed = points();
+        reversed.reverse();
+        assert_eq!(rollup(reversed, 60, RollupRule::Last), vec![(0, 5f64), (60, 11f64)]);
+
+        // means of 10 values of 1 and 30 values of 5 give the mean of all 40 values
+        let means = vec![
+            RollupPoint::weighted(0, 1f64, 10f64),
+            RollupPoint::weighted(10, 5f64, 30f64),
+            RollupPoint::weighted(20, 100f64, 0f64),
+        ];
+        assert_eq!(rollup(means, 60, RollupRule::WeightedMean), vec![(0, 4f64)]);
+
+        // rolling up twice gives the same result as rolling up once
+        let minutes = rollup(points(), 60, RollupRule::Sum).into_iter().map(|(ts, v)| RollupPoint::new(ts, v));
+        assert_eq!(rollup(minutes, 600, RollupRule::Sum), vec![(0, 66f64)]);
+    }
+
+    #[test]
+    fn rollup_rule_for_aggregate() {
+        assert_eq!(RollupRule::for_aggregate(&Aggregate::<f64>::Value, MetricTypeName::Counter), RollupRule::Sum);
+        assert_eq!(RollupRule::for_aggregate(&Aggregate::<f64>::Value, MetricTypeName::Gauge), RollupRule::Last);
+        assert_eq!(RollupRule::for_aggregate(&Aggregate::<f64>::Count, MetricTypeName::Timer), RollupRule::Sum);
+        assert_eq!(RollupRule::for_aggregate(&Aggregate::<f64>::Max, MetricTypeName::Timer), RollupRule::Max);
+        assert_eq!(
+            RollupRule::for_aggregate(&Aggregate::<f64>::Mean, MetricTypeName::Timer),
+            RollupRule::WeightedMean
+        );
+    }
+}
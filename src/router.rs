@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+use num_traits::{AsPrimitive, Float};
+use serde::{Deserialize, Serialize};
+
+use crate::metric::{FromF64, Metric, MetricTypeName};
+use crate::name::MetricName;
+
+/// A routing rule, all the specified conditions must match for metric to be routed
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RouteRule {
+    /// glob for the name without tags, `*` matches any number of any characters, `?` matches exactly one
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// tags that must be present with the specified values, a value of `*` matches any value
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+
+    /// metric types to match, empty list matches any type
+    #[serde(default)]
+    pub types: Vec<MetricTypeName>,
+
+    /// names of destinations to route the matched metrics to
+    pub destinations: Vec<String>,
+
+    /// do not evaluate further rules if this one matched
+    #[serde(default)]
+    pub last: bool,
+}
+
+#[derive(Debug, Clone)]
+struct CompiledRule {
+    name: Option<Vec<u8>>,
+    tags: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    types: Vec<MetricTypeName>,
+    destinations: Vec<usize>,
+    last: bool,
+}
+
+impl CompiledRule {
+    fn matches(&self, name: &MetricName, mtype: MetricTypeName) -> bool {
+        if !self.types.is_empty() && !self.types.contains(&mtype) {
+            return false;
+        }
+        if let Some(ref glob) = self.name {
+            if !glob_match(glob, name.name_without_tags()) {
+                return false;
+            }
+        }
+        self.tags.iter().all(|(key, value)| match (name.tag_value(key), value) {
+            (Some(_), None) => true,
+            (Some(found), Some(value)) => found == &value[..],
+            (None, _) => false,
+        })
+    }
+}
+
+/// Assigns metrics to destinations by evaluating the rules in order. A metric may be routed
+/// to many destinations by one or many rules. Destinations are referred to by index,
+/// in order of the first appearance in rules.
+#[derive(Debug, Clone)]
+pub struct Router {
+    rules: Vec<CompiledRule>,
+    destinations: Vec<String>,
+}
+
+impl Router {
+    pub fn new(rules: Vec<RouteRule>) -> Self {
+        let mut destinations: Vec<String> = Vec::new();
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                let indexes = rule
+                    .destinations
+                    .into_iter()
+                    .map(|dest| match destinations.iter().position(|d| d == &dest) {
+                        Some(idx) => idx,
+                        None => {
+                            destinations.push(dest);
+                            destinations.len() - 1
+                        }
+                    })
+                    .collect();
+                CompiledRule {
+                    name: rule.name.map(String::into_bytes),
+                    tags: rule
+                        .tags
+                        .into_iter()
+                        .map(|(key, value)| (key.into_bytes(), if value == "*" { None } else { Some(value.into_bytes()) }))
+                        .collect(),
+                    types: rule.types,
+                    destinations: indexes,
+                    last: rule.last,
+                }
+            })
+            .collect();
+        Self { rules, destinations }
+    }
+
+    /// Names of all destinations, index in this list is the index returned from routing
+    pub fn destinations(&self) -> &[String] {
+        &self.destinations
+    }
+
+    pub fn destination_index(&self, name: &str) -> Option<usize> {
+        self.destinations.iter().position(|d| d == name)
+    }
+
+    /// Fills `out` with indexes of destinations the metric is routed to, each destination is
+    /// given once, in order of the rules matched. The vector is cleared before, so it can
+    /// be reused between calls to avoid allocations
+    pub fn route<F>(&self, name: &MetricName, metric: &Metric<F>, out: &mut Vec<usize>)
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<f64>,
+    {
+        self.route_type(name, MetricTypeName::from_metric(metric), out)
+    }
+
+    /// Same as `route`, but only needs a type of metric
+    pub fn route_type(&self, name: &MetricName, mtype: MetricTypeName, out: &mut Vec<usize>) {
+        out.clear();
+        for rule in &self.rules {
+            if !rule.matches(name, mtype) {
+                continue;
+            }
+            for dest in &rule.destinations {
+                if !out.contains(dest) {
+                    out.push(*dest);
+                }
+            }
+            if rule.last {
+                break;
+            }
+        }
+    }
+}
+
+// iterative glob matching with backtracking to the last star only
+pub(crate) fn glob_match(glob: &[u8], input: &[u8]) -> bool {
+    let (mut g, mut i) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while i < input.len() {
+        match glob.get(g) {
+            Some(b'*') => {
+                star = Some((g, i));
+                g += 1;
+            }
+            Some(c) if *c == b'?' || *c == input[i] => {
+                g += 1;
+                i += 1;
+            }
+            _ => match star {
+                Some((star_g, star_i)) => {
+                    g = star_g + 1;
+                    i = star_i + 1;
+                    star = Some((star_g, star_i + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|c| *c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metric::MetricValue;
+    use crate::testing::name;
+
+    #[test]
+    fn glob_matching() {
+        assert!(glob_match(b"some.*.metric", b"some.long.name.metric"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"s?me.*", b"same.metric"));
+        assert!(glob_match(b"*.count*", b"a.b.counter"));
+        assert!(!glob_match(b"some.*.metric", b"some.metric"));
+        assert!(!glob_match(b"some", b"some.metric"));
+        assert!(!glob_match(b"?", b""));
+    }
+
+    #[test]
+    fn route_metrics() {
+        let rules: Vec<RouteRule> = vec![
+            RouteRule {
+                name: Some("debug.*".into()),
+                destinations: vec!["blackhole".into()],
+                last: true,
+                ..Default::default()
+            },
+            RouteRule {
+                tags: vec![("env".to_string(), "prod".to_string())].into_iter().collect(),
+                destinations: vec!["prod".into(), "archive".into()],
+                ..Default::default()
+            },
+            RouteRule {
+                types: vec![MetricTypeName::Timer],
+                tags: vec![("service".to_string(), "*".to_string())].into_iter().collect(),
+                destinations: vec!["archive".into(), "latency".into()],
+                ..Default::default()
+            },
+        ];
+        let router = Router::new(rules);
+        assert_eq!(router.destinations(), &["blackhole", "prod", "archive", "latency"]);
+        assert_eq!(router.destination_index("latency"), Some(3));
+
+        let timer = Metric::<f64>::new(MetricValue::Timer(vec![1f64]), None, 1f32);
+        let counter = Metric::<f64>::new(MetricValue::Counter(1f64), None, 1f32);
+
+        let mut out = Vec::new();
+        router.route(&name("debug.timer;env=prod"), &timer, &mut out);
+        assert_eq!(out, vec![0]);
+        router.route(&name("some.timer;service=web;env=prod"), &timer, &mut out);
+        assert_eq!(out, vec![1, 2, 3]);
+        router.route(&name("some.counter;service=web;env=prod"), &counter, &mut out);
+        assert_eq!(out, vec![1, 2]);
+        router.route(&name("some.timer;service=web;env=dev"), &timer, &mut out);
+        assert_eq!(out, vec![2, 3]);
+        router.route(&name("some.timer"), &timer, &mut out);
+        assert!(out.is_empty());
+    }
+}
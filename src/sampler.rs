@@ -0,0 +1,105 @@
+use std::fmt::Debug;
+
+use num_traits::{AsPrimitive, Float};
+
+use crate::metric::{FromF64, Metric, MetricError};
+use crate::name::MetricName;
+
+/// Makes keep or drop decisions by name fingerprint, so a series is either always kept or always
+/// dropped, and so is on every host using the same rate and seed. Unlike random sampling, this
+/// keeps the kept series complete, while the number of series is reduced.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sampler {
+    rate: f32,
+    threshold: u64,
+    seed: u64,
+}
+
+// the finalizer of splitmix64, spreads the fingerprint bits over the whole range
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+impl Sampler {
+    /// The rate must be in (0, 1] range
+    pub fn new(rate: f32) -> Result<Self, MetricError> {
+        if !(rate > 0f32 && rate <= 1f32) {
+            return Err(MetricError::Sampling);
+        }
+        let threshold = if rate >= 1f32 { u64::MAX } else { (f64::from(rate) * u64::MAX as f64) as u64 };
+        Ok(Self { rate, threshold, seed: 0 })
+    }
+
+    /// Samplers with different seeds make independent decisions
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn rate(&self) -> f32 {
+        self.rate
+    }
+
+    /// Decides by a fingerprint given by `MetricName::fingerprint`
+    pub fn keep_fingerprint(&self, fingerprint: u64) -> bool {
+        mix(fingerprint ^ self.seed) <= self.threshold
+    }
+
+    pub fn keep(&self, name: &MetricName) -> bool {
+        self.keep_fingerprint(name.fingerprint())
+    }
+
+    /// Decides if the metric is kept, stamping the rate into sampling of the kept one. The rate
+    /// multiplies the sampling metric already has, so a metric sampled by 0.5 on client and by 0.1
+    /// here ends up with sampling of 0.05.
+    pub fn sample<F>(&self, name: &MetricName, metric: &mut Metric<F>) -> bool
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<f64>,
+    {
+        if !self.keep(name) {
+            return false;
+        }
+        let current: f64 = metric.sampling().as_();
+        // the product of two rates in (0, 1] is in range too, unless it is too small for f32
+        metric.set_sampling((current * f64::from(self.rate)) as f32).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metric::MetricValue;
+    use crate::testing::name;
+
+    #[test]
+    fn deterministic_sampling() {
+        assert!(Sampler::new(0f32).is_err());
+        assert!(Sampler::new(1.5f32).is_err());
+
+        let names = (0..1000).map(|idx| name(&format!("some.metric;id={}", idx))).collect::<Vec<_>>();
+
+        let sampler = Sampler::new(0.25f32).unwrap();
+        let kept = names.iter().filter(|name| sampler.keep(name)).count();
+        assert!(kept > 200 && kept < 300, "kept {}", kept);
+        // decisions are stable
+        assert!(names.iter().all(|name| sampler.keep(name) == Sampler::new(0.25f32).unwrap().keep(name)));
+        // and differ with another seed
+        let seeded = sampler.seed(42);
+        assert!(names.iter().any(|name| sampler.keep(name) != seeded.keep(name)));
+
+        let all = Sampler::new(1f32).unwrap();
+        assert!(names.iter().all(|name| all.keep(name)));
+
+        let name = names.iter().find(|name| sampler.keep(name)).unwrap();
+        let mut metric = Metric::new(MetricValue::Counter(1f64), None, 0.5f32);
+        assert!(sampler.sample(name, &mut metric));
+        assert_eq!(metric.sampling(), 0.125f64);
+
+        let name = names.iter().find(|name| !sampler.keep(name)).unwrap();
+        let mut metric = Metric::new(MetricValue::Counter(1f64), None, 1f32);
+        assert!(!sampler.sample(name, &mut metric));
+        assert_eq!(metric.sampling(), 1f64);
+    }
+}
@@ -0,0 +1,346 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use bytes::BytesMut;
+use serde::{Deserialize, Serialize};
+
+use crate::metric::MetricError;
+use crate::name::MetricName;
+
+/// Kind of sensitive data to look for in tag values
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScrubPattern {
+    Email,
+    Ipv4,
+    Ipv6,
+    Uuid,
+    /// a regular expression, requires `scrub-regex` feature
+    Regex(String),
+}
+
+impl ScrubPattern {
+    fn default_placeholder(&self) -> &'static str {
+        match self {
+            ScrubPattern::Email => "EMAIL",
+            ScrubPattern::Ipv4 | ScrubPattern::Ipv6 => "IP",
+            ScrubPattern::Uuid => "UUID",
+            ScrubPattern::Regex(_) => "REDACTED",
+        }
+    }
+}
+
+/// A redaction rule, every part of tag value matching the pattern is replaced with the placeholder
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ScrubRule {
+    pub pattern: ScrubPattern,
+
+    /// keys of tags to scrub, empty list means all tags
+    #[serde(default)]
+    pub keys: Vec<String>,
+
+    /// the replacement, defaults to `EMAIL`, `IP`, `UUID` or `REDACTED` depending on pattern
+    #[serde(default)]
+    pub placeholder: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Email,
+    Ipv4,
+    Ipv6,
+    Uuid,
+    #[cfg(feature = "scrub-regex")]
+    Regex(regex::bytes::Regex),
+}
+
+fn is_word(c: u8) -> bool {
+    c.is_ascii_alphanumeric()
+}
+
+// the match must not be a part of a longer word
+fn bounded(value: &[u8], start: usize, end: usize) -> bool {
+    (start == 0 || !is_word(value[start - 1])) && (end == value.len() || !is_word(value[end]))
+}
+
+fn find_run<P: Fn(u8) -> bool>(value: &[u8], from: usize, allowed: P, check: impl Fn(&[u8]) -> bool) -> Option<(usize, usize)> {
+    let mut pos = from;
+    while pos < value.len() {
+        if !allowed(value[pos]) {
+            pos += 1;
+            continue;
+        }
+        let start = pos;
+        while pos < value.len() && allowed(value[pos]) {
+            pos += 1;
+        }
+        if bounded(value, start, pos) && check(&value[start..pos]) {
+            return Some((start, pos));
+        }
+    }
+    None
+}
+
+fn find_uuid(value: &[u8], from: usize) -> Option<(usize, usize)> {
+    const LEN: usize = 36;
+    if value.len() < LEN {
+        return None;
+    }
+    (from..=value.len() - LEN)
+        .find(|start| {
+            value[*start..*start + LEN].iter().enumerate().all(|(idx, c)| {
+                if idx == 8 || idx == 13 || idx == 18 || idx == 23 {
+                    *c == b'-'
+                } else {
+                    c.is_ascii_hexdigit()
+                }
+            }) && bounded(value, *start, *start + LEN)
+        })
+        .map(|start| (start, start + LEN))
+}
+
+fn find_email(value: &[u8], from: usize) -> Option<(usize, usize)> {
+    let local = |c: u8| c.is_ascii_alphanumeric() || b"._%+-".contains(&c);
+    let domain = |c: u8| c.is_ascii_alphanumeric() || c == b'.' || c == b'-';
+    let mut at = from;
+    while let Some(pos) = value[at..].iter().position(|c| *c == b'@') {
+        let pos = at + pos;
+        let start = pos - value[from..pos].iter().rev().take_while(|c| local(**c)).count();
+        let mut end = pos + 1 + value[pos + 1..].iter().take_while(|c| domain(**c)).count();
+        while end > pos + 1 && (value[end - 1] == b'.' || value[end - 1] == b'-') {
+            end -= 1;
+        }
+        let host = &value[pos + 1..end];
+        if start < pos && host.contains(&b'.') && host.split(|c| *c == b'.').all(|label| !label.is_empty()) {
+            return Some((start, end));
+        }
+        at = pos + 1;
+    }
+    None
+}
+
+impl Matcher {
+    fn new(pattern: &ScrubPattern) -> Result<Self, MetricError> {
+        Ok(match pattern {
+            ScrubPattern::Email => Matcher::Email,
+            ScrubPattern::Ipv4 => Matcher::Ipv4,
+            ScrubPattern::Ipv6 => Matcher::Ipv6,
+            ScrubPattern::Uuid => Matcher::Uuid,
+            #[cfg(feature = "scrub-regex")]
+            ScrubPattern::Regex(re) => Matcher::Regex(regex::bytes::Regex::new(re).map_err(|e| MetricError::Scrub(e.to_string()))?),
+            #[cfg(not(feature = "scrub-regex"))]
+            ScrubPattern::Regex(_) => return Err(MetricError::Scrub("regular expressions require scrub-regex feature".into())),
+        })
+    }
+
+    // gives the position of the first non-empty match starting at `from` or later
+    fn find(&self, value: &[u8], from: usize) -> Option<(usize, usize)> {
+        match self {
+            Matcher::Email => find_email(value, from),
+            Matcher::Uuid => find_uuid(value, from),
+            Matcher::Ipv4 => find_run(
+                value,
+                from,
+                |c| c.is_ascii_digit() || c == b'.',
+                |run| std::str::from_utf8(run).ok().and_then(|s| s.parse::<Ipv4Addr>().ok()).is_some(),
+            ),
+            Matcher::Ipv6 => find_run(
+                value,
+                from,
+                |c| c.is_ascii_hexdigit() || c == b':' || c == b'.',
+                |run| run.iter().filter(|c| **c == b':').count() >= 2 && std::str::from_utf8(run).ok().and_then(|s| s.parse::<Ipv6Addr>().ok()).is_some(),
+            ),
+            #[cfg(feature = "scrub-regex")]
+            Matcher::Regex(re) => {
+                let mut from = from;
+                while from <= value.len() {
+                    let found = re.find_at(value, from)?;
+                    if found.end() > found.start() {
+                        return Some((found.start(), found.end()));
+                    }
+                    from = found.end() + 1;
+                }
+                None
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CompiledScrub {
+    matcher: Matcher,
+    keys: Vec<Vec<u8>>,
+    placeholder: Vec<u8>,
+}
+
+/// Redacts sensitive data like emails, IP addresses or UUIDs in tag values, so it does not reach
+/// the long term storage. Names are not scrubbed, only tag values are. Rules are applied in order,
+/// each one to the result of the previous.
+#[derive(Debug, Clone)]
+pub struct Scrubber {
+    rules: Vec<CompiledScrub>,
+}
+
+impl Scrubber {
+    pub fn new(rules: Vec<ScrubRule>) -> Result<Self, MetricError> {
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                let placeholder = rule.placeholder.clone().unwrap_or_else(|| rule.pattern.default_placeholder().to_string());
+                if placeholder.is_empty() || placeholder.chars().any(|c| c == ';' || c == '=' || c == '~' || c.is_whitespace()) {
+                    return Err(MetricError::Scrub(format!("'{}' cannot be a tag value", placeholder)));
+                }
+                Ok(CompiledScrub {
+                    matcher: Matcher::new(&rule.pattern)?,
+                    keys: rule.keys.into_iter().map(String::into_bytes).collect(),
+                    placeholder: placeholder.into_bytes(),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Gives the value with all the rules for the key applied, None if nothing was replaced
+    pub fn scrub_value(&self, key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+        let mut scrubbed: Option<Vec<u8>> = None;
+        for rule in &self.rules {
+            if !rule.keys.is_empty() && !rule.keys.iter().any(|k| &k[..] == key) {
+                continue;
+            }
+            let current = scrubbed.as_deref().unwrap_or(value);
+            let mut out = Vec::new();
+            let mut pos = 0;
+            while let Some((start, end)) = rule.matcher.find(current, pos) {
+                out.extend_from_slice(&current[pos..start]);
+                out.extend_from_slice(&rule.placeholder);
+                pos = end;
+            }
+            if pos > 0 {
+                out.extend_from_slice(&current[pos..]);
+                scrubbed = Some(out);
+            }
+        }
+        scrubbed
+    }
+
+    /// Gives the name with tag values scrubbed. The new name is built in `buf`, which should be
+    /// reused between calls. Order of tags is kept, names not needing scrubbing are only cloned.
+    pub fn scrub(&self, name: &MetricName, buf: &mut BytesMut) -> MetricName {
+        if self.rules.is_empty() {
+            return name.clone();
+        }
+
+        // every value is scrubbed once, while the new name is built along the way
+        let base = name.name_without_tags();
+        let start = buf.len();
+        buf.reserve(name.name_with_tags().len());
+        buf.extend_from_slice(base);
+        let mut scrubbed = false;
+        for tag in name.tags_without_name().split(|c| *c == b';').filter(|tag| !tag.is_empty()) {
+            buf.extend_from_slice(b";");
+            let found = tag
+                .iter()
+                .position(|c| *c == b'=')
+                .and_then(|pos| self.scrub_value(&tag[..pos], &tag[pos + 1..]).map(|value| (pos, value)));
+            match found {
+                Some((pos, value)) => {
+                    buf.extend_from_slice(&tag[..=pos]);
+                    buf.extend_from_slice(&value);
+                    scrubbed = true;
+                }
+                None => buf.extend_from_slice(tag),
+            }
+        }
+        if !scrubbed {
+            buf.truncate(start);
+            return name.clone();
+        }
+        MetricName::from_raw_parts(buf.split_off(start).freeze(), Some(base.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::name::TagFormat;
+    use crate::testing::name;
+
+    fn rule(pattern: ScrubPattern) -> ScrubRule {
+        ScrubRule {
+            pattern,
+            keys: Vec::new(),
+            placeholder: None,
+        }
+    }
+
+    fn scrub(scrubber: &Scrubber, value: &str) -> String {
+        String::from_utf8(scrubber.scrub_value(b"key", value.as_bytes()).unwrap_or_else(|| value.as_bytes().to_vec())).unwrap()
+    }
+
+    #[test]
+    fn scrub_values() {
+        let scrubber = Scrubber::new(vec![
+            rule(ScrubPattern::Email),
+            rule(ScrubPattern::Uuid),
+            rule(ScrubPattern::Ipv4),
+            rule(ScrubPattern::Ipv6),
+        ])
+        .unwrap();
+        assert_eq!(scrub(&scrubber, "inbox/john.doe+x@mail.example.com"), "inbox/EMAIL");
+        assert_eq!(scrub(&scrubber, "a@b@c.org"), "a@EMAIL");
+        assert_eq!(scrub(&scrubber, "not@email"), "not@email");
+        assert_eq!(scrub(&scrubber, "req-123e4567-e89b-12d3-a456-426614174000"), "req-UUID");
+        assert_eq!(
+            scrub(&scrubber, "123e4567-e89b-12d3-a456-4266141740001"),
+            "123e4567-e89b-12d3-a456-4266141740001"
+        );
+        assert_eq!(scrub(&scrubber, "10.0.0.1-192.168.1.20"), "IP-IP");
+        assert_eq!(scrub(&scrubber, "1.2.3.4.5"), "1.2.3.4.5");
+        assert_eq!(scrub(&scrubber, "v1.2.3"), "v1.2.3");
+        assert_eq!(scrub(&scrubber, "fe80::1"), "IP");
+        assert_eq!(scrub(&scrubber, "12:30:45"), "12:30:45");
+        assert_eq!(scrubber.scrub_value(b"key", b"plain"), None);
+    }
+
+    #[test]
+    fn scrub_names() {
+        let scrubber = Scrubber::new(vec![ScrubRule {
+            pattern: ScrubPattern::Ipv4,
+            keys: vec!["client".into()],
+            placeholder: Some("x".into()),
+        }])
+        .unwrap();
+        let mut buf = BytesMut::new();
+
+        let scrubbed = scrubber.scrub(&name("requests;server=10.0.0.2;client=10.0.0.1;flag"), &mut buf);
+        assert_eq!(&scrubbed.name[..], b"requests;client=x;flag;server=10.0.0.2");
+        assert_eq!(scrubbed.name_without_tags(), b"requests");
+        let untouched = name("requests;client=local");
+        assert_eq!(scrubber.scrub(&untouched, &mut buf), untouched);
+
+        assert!(Scrubber::new(vec![ScrubRule {
+            pattern: ScrubPattern::Email,
+            keys: Vec::new(),
+            placeholder: Some("a=b".into()),
+        }])
+        .is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "scrub-regex")]
+    fn scrub_regex() {
+        let scrubber = Scrubber::new(vec![rule(ScrubPattern::Regex("[0-9]{4,}".into()))]).unwrap();
+        assert_eq!(scrub(&scrubber, "order_12345_item_12"), "order_REDACTED_item_12");
+        assert!(Scrubber::new(vec![rule(ScrubPattern::Regex("(".into()))]).is_err());
+
+        // the rule is not idempotent, so scrubbing twice would be visible
+        let scrubber = Scrubber::new(vec![ScrubRule {
+            pattern: ScrubPattern::Regex("x+".into()),
+            keys: Vec::new(),
+            placeholder: Some("xx".into()),
+        }])
+        .unwrap();
+        let name = MetricName::new(BytesMut::from("requests;id=axb"), TagFormat::Graphite, &mut [0u8; 32]).unwrap();
+        assert_eq!(&scrubber.scrub(&name, &mut BytesMut::new()).name[..], b"requests;id=axxb");
+    }
+}
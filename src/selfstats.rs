@@ -0,0 +1,142 @@
+use std::convert::TryFrom;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use num_traits::{AsPrimitive, Float};
+
+use crate::metric::{FromF64, Metric, MetricValue};
+use crate::name::MetricName;
+
+/// Internal counters of a metric server, can be updated from many threads at once
+#[derive(Debug, Default)]
+pub struct IngestCounters {
+    ingress: AtomicU64,
+    parse_errors: AtomicU64,
+    agg_duration: AtomicU64,
+}
+
+impl IngestCounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts metrics received
+    pub fn add_ingress(&self, count: u64) {
+        self.ingress.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn add_parse_errors(&self, count: u64) {
+        self.parse_errors.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Counts time spent for aggregation, durations are summed within an interval
+    pub fn add_agg_duration(&self, duration: Duration) {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        self.agg_duration.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    /// Takes the values counted so far, resetting the counters
+    pub fn take(&self) -> IngestStats {
+        IngestStats {
+            ingress: self.ingress.swap(0, Ordering::Relaxed),
+            parse_errors: self.parse_errors.swap(0, Ordering::Relaxed),
+            agg_duration: Duration::from_nanos(self.agg_duration.swap(0, Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Internal stats of a metric server for an interval
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IngestStats {
+    pub ingress: u64,
+    pub parse_errors: u64,
+    pub agg_duration: Duration,
+}
+
+/// Makes the standard set of self-monitoring metrics, so all servers built on this crate expose
+/// their internals the same way:
+///
+/// * `<prefix>.ingress.count` - counter of metrics received
+/// * `<prefix>.parse.errors` - counter of metrics failed to parse
+/// * `<prefix>.agg.duration` - gauge of time spent for aggregation, in milliseconds
+#[derive(Debug, Clone)]
+pub struct SelfMetrics {
+    ingress: MetricName,
+    parse_errors: MetricName,
+    agg_duration: MetricName,
+}
+
+impl SelfMetrics {
+    /// The prefix is a dotted untagged name, i.e. `resources.monitoring.bioyino`, it can be empty
+    pub fn new(prefix: &[u8]) -> Self {
+        let name = |suffix: &[u8]| {
+            let mut name = BytesMut::with_capacity(prefix.len() + suffix.len() + 1);
+            if !prefix.is_empty() {
+                name.extend_from_slice(prefix);
+                name.extend_from_slice(b".");
+            }
+            name.extend_from_slice(suffix);
+            MetricName::new_untagged(name)
+        };
+
+        Self {
+            ingress: name(b"ingress.count"),
+            parse_errors: name(b"parse.errors"),
+            agg_duration: name(b"agg.duration"),
+        }
+    }
+
+    pub fn metrics<F>(&self, stats: &IngestStats, timestamp: Option<u64>) -> Vec<(MetricName, Metric<F>)>
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<f64>,
+    {
+        let metric = |value| Metric::new(value, timestamp, 1f32);
+        vec![
+            (self.ingress.clone(), metric(MetricValue::Counter(F::from_f64(stats.ingress as f64)))),
+            (self.parse_errors.clone(), metric(MetricValue::Counter(F::from_f64(stats.parse_errors as f64)))),
+            (
+                self.agg_duration.clone(),
+                metric(MetricValue::Gauge(F::from_f64(stats.agg_duration.as_secs_f64() * 1000f64))),
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn self_metrics() {
+        let counters = IngestCounters::new();
+        counters.add_ingress(10);
+        counters.add_ingress(5);
+        counters.add_parse_errors(1);
+        counters.add_agg_duration(Duration::from_micros(1500));
+        let stats = counters.take();
+        assert_eq!(counters.take(), IngestStats::default());
+
+        let metrics = SelfMetrics::new(b"resources.bioyino").metrics::<f64>(&stats, Some(100));
+        let names = metrics
+            .iter()
+            .map(|(name, _)| String::from_utf8_lossy(&name.name[..]).to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "resources.bioyino.ingress.count",
+                "resources.bioyino.parse.errors",
+                "resources.bioyino.agg.duration"
+            ]
+        );
+        assert_eq!(metrics[0].1.value(), &MetricValue::Counter(15f64));
+        assert_eq!(metrics[1].1.value(), &MetricValue::Counter(1f64));
+        assert_eq!(metrics[2].1.value(), &MetricValue::Gauge(1.5f64));
+        assert_eq!(metrics[2].1.timestamp(), Some(100));
+
+        let metrics = SelfMetrics::new(b"").metrics::<f32>(&stats, None);
+        assert_eq!(&metrics[1].0.name[..], b"parse.errors");
+    }
+}
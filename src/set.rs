@@ -0,0 +1,177 @@
+use serde::{Deserialize, Serialize};
+
+/// Storage to use for set metrics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SetStorage {
+    /// `HashSet<u64>`, best for high cardinalities
+    #[default]
+    Hash,
+    /// `SortedSet`, more compact and cache friendly for small and medium cardinalities
+    Sorted,
+}
+
+/// A set of u64 values stored as a sorted vector without duplicates
+///
+/// Single values are inserted using binary search, while batches (i.e. other sets or iterators) are
+/// appended, then sorted and deduplicated at once. Deserialized values are sorted and deduplicated too,
+/// so sets from other sources cannot break the order.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "SortedSetValues")]
+pub struct SortedSet {
+    values: Vec<u64>,
+}
+
+// the serialized form of the set with the values in any order
+#[derive(Deserialize)]
+struct SortedSetValues {
+    values: Vec<u64>,
+}
+
+impl From<SortedSetValues> for SortedSet {
+    fn from(SortedSetValues { mut values }: SortedSetValues) -> Self {
+        values.sort_unstable();
+        values.dedup();
+        Self { values }
+    }
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        Self { values: Vec::new() }
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            values: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Inserts a value, returns false if it was already present
+    pub fn insert(&mut self, value: u64) -> bool {
+        match self.values.binary_search(&value) {
+            Ok(_) => false,
+            Err(pos) => {
+                self.values.insert(pos, value);
+                true
+            }
+        }
+    }
+
+    pub fn contains(&self, value: &u64) -> bool {
+        self.values.binary_search(value).is_ok()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.values.capacity()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Values in ascending order
+    pub fn as_slice(&self) -> &[u64] {
+        &self.values
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, u64> {
+        self.values.iter()
+    }
+
+    pub fn into_vec(self) -> Vec<u64> {
+        self.values
+    }
+
+    /// Merges another set into self
+    pub fn merge(&mut self, other: &SortedSet) {
+        if other.is_empty() {
+            return;
+        }
+        // fast path for non-overlapping sets which is very common for
+        // the sets filled with sequental identifiers
+        if self.values.last().map(|last| *last < other.values[0]).unwrap_or(true) {
+            self.values.extend_from_slice(&other.values);
+            return;
+        }
+        self.values.extend_from_slice(&other.values);
+        self.values.sort_unstable();
+        self.values.dedup();
+    }
+}
+
+impl Extend<u64> for SortedSet {
+    fn extend<I: IntoIterator<Item = u64>>(&mut self, iter: I) {
+        let len = self.values.len();
+        self.values.extend(iter);
+        if self.values.len() != len {
+            self.values.sort_unstable();
+            self.values.dedup();
+        }
+    }
+}
+
+impl std::iter::FromIterator<u64> for SortedSet {
+    fn from_iter<I: IntoIterator<Item = u64>>(iter: I) -> Self {
+        let mut set = Self::new();
+        set.extend(iter);
+        set
+    }
+}
+
+impl<'a> IntoIterator for &'a SortedSet {
+    type Item = &'a u64;
+    type IntoIter = std::slice::Iter<'a, u64>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.values.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sorted_set_insert() {
+        let mut set = SortedSet::new();
+        assert!(set.insert(5));
+        assert!(set.insert(1));
+        assert!(set.insert(3));
+        assert!(!set.insert(3));
+        assert_eq!(set.as_slice(), &[1, 3, 5]);
+        assert!(set.contains(&5));
+        assert!(!set.contains(&2));
+    }
+
+    #[test]
+    fn sorted_set_batches() {
+        let mut set: SortedSet = vec![4, 2, 4, 1].into_iter().collect();
+        assert_eq!(set.as_slice(), &[1, 2, 4]);
+
+        set.merge(&vec![5, 6].into_iter().collect());
+        assert_eq!(set.as_slice(), &[1, 2, 4, 5, 6]);
+
+        set.merge(&vec![0, 3, 4].into_iter().collect());
+        assert_eq!(set.as_slice(), &[0, 1, 2, 3, 4, 5, 6]);
+
+        set.extend(vec![7, 7, 1]);
+        assert_eq!(set.len(), 8);
+    }
+
+    #[test]
+    fn sorted_set_deserialize() {
+        use serde::de::value::{Error, MapDeserializer};
+        use serde::de::IntoDeserializer;
+
+        let values: Vec<u64> = vec![4, 2, 4, 1];
+        let fields = vec![("values", values.into_deserializer())];
+        let set = SortedSet::deserialize(MapDeserializer::<_, Error>::new(fields.into_iter())).unwrap();
+        assert_eq!(set.as_slice(), &[1, 2, 4]);
+        assert!(set.contains(&4));
+    }
+}
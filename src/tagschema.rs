@@ -0,0 +1,228 @@
+use std::collections::BTreeMap;
+
+use bytes::BytesMut;
+use serde::{Deserialize, Serialize};
+
+use crate::name::MetricName;
+use crate::router::glob_match;
+
+/// Tagging standard for the metrics with names matching the glob
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct TagRule {
+    /// glob for the name without tags, like in routing rules
+    pub name: String,
+
+    /// tag keys every metric must have
+    #[serde(default)]
+    pub required: Vec<String>,
+
+    /// the only tag keys allowed in addition to the required ones, any keys are allowed if not set
+    #[serde(default)]
+    pub allowed: Option<Vec<String>>,
+
+    /// globs the values of tags must match, by tag key
+    #[serde(default)]
+    pub values: BTreeMap<String, String>,
+
+    /// values to add when a required tag is missing, only used when fixing
+    #[serde(default)]
+    pub defaults: BTreeMap<String, String>,
+}
+
+/// What to do with metrics not conforming to the schema
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TagSchemaAction {
+    #[default]
+    Reject,
+    /// drop not allowed tags and tags with bad values, add defaults for missing required tags,
+    /// metrics missing required tags without defaults are still rejected
+    Fix,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct TagSchemaOptions {
+    /// the first rule matching the name is applied, names matching no rules are not checked
+    pub rules: Vec<TagRule>,
+
+    #[serde(default)]
+    pub action: TagSchemaAction,
+}
+
+/// A broken tagging rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagViolation {
+    Missing(String),
+    NotAllowed(Vec<u8>),
+    BadValue { key: Vec<u8>, value: Vec<u8> },
+}
+
+/// The result of checking a metric name against the schema
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagCheck {
+    Valid,
+    /// the name with tags fixed according to the schema
+    Fixed(MetricName),
+    Rejected(Vec<TagViolation>),
+}
+
+/// Enforces tagging standards, like requiring `team` and `env` tags, at ingest
+#[derive(Debug, Clone)]
+pub struct TagSchema {
+    options: TagSchemaOptions,
+}
+
+impl TagSchema {
+    pub fn new(options: TagSchemaOptions) -> Self {
+        Self { options }
+    }
+
+    fn rule(&self, name: &MetricName) -> Option<&TagRule> {
+        self.options
+            .rules
+            .iter()
+            .find(|rule| glob_match(rule.name.as_bytes(), name.name_without_tags()))
+    }
+
+    fn tag_violation(rule: &TagRule, key: &[u8], value: &[u8]) -> Option<TagViolation> {
+        let required = rule.required.iter().any(|required| required.as_bytes() == key);
+        let allowed = match rule.allowed {
+            Some(ref allowed) => required || allowed.iter().any(|allowed| allowed.as_bytes() == key),
+            None => true,
+        };
+        if !allowed {
+            return Some(TagViolation::NotAllowed(key.to_vec()));
+        }
+        match rule.values.iter().find(|(k, _)| k.as_bytes() == key) {
+            Some((_, glob)) if !glob_match(glob.as_bytes(), value) => Some(TagViolation::BadValue {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// All the violations of the rule matching the name
+    pub fn violations(&self, name: &MetricName) -> Vec<TagViolation> {
+        let rule = match self.rule(name) {
+            Some(rule) => rule,
+            None => return Vec::new(),
+        };
+        let mut violations = rule
+            .required
+            .iter()
+            .filter(|key| name.tag_value(key.as_bytes()).is_none())
+            .map(|key| TagViolation::Missing(key.clone()))
+            .collect::<Vec<_>>();
+        violations.extend(name.tags().filter_map(|(key, value)| Self::tag_violation(rule, key, value)));
+        violations
+    }
+
+    /// Checks the name, fixing it if the schema allows. The fixed name is built in `buf`,
+    /// `intermediate` is used for sorting tags and grows if required, so both should be reused between calls
+    pub fn check(&self, name: &MetricName, buf: &mut BytesMut, intermediate: &mut Vec<u8>) -> TagCheck {
+        let violations = self.violations(name);
+        if violations.is_empty() {
+            return TagCheck::Valid;
+        }
+        let rule = match (self.options.action, self.rule(name)) {
+            (TagSchemaAction::Fix, Some(rule)) => rule,
+            _ => return TagCheck::Rejected(violations),
+        };
+        // required tags dropped because of bad values are replaced by defaults too
+        let kept = name
+            .tags()
+            .filter(|(key, value)| Self::tag_violation(rule, key, value).is_none())
+            .collect::<Vec<_>>();
+        let missing = rule
+            .required
+            .iter()
+            .filter(|required| !kept.iter().any(|(key, _)| *key == required.as_bytes()))
+            .collect::<Vec<_>>();
+        let unfixable = missing
+            .iter()
+            .filter(|key| !rule.defaults.contains_key(key.as_str()))
+            .map(|key| TagViolation::Missing(key.to_string()))
+            .collect::<Vec<_>>();
+        if !unfixable.is_empty() {
+            return TagCheck::Rejected(unfixable);
+        }
+
+        let base = name.name_without_tags();
+        let start = buf.len();
+        buf.extend_from_slice(base);
+        let defaults = missing.iter().map(|key| (key.as_bytes(), rule.defaults[key.as_str()].as_bytes()));
+        for (key, value) in kept.into_iter().chain(defaults) {
+            buf.extend_from_slice(b";");
+            buf.extend_from_slice(key);
+            buf.extend_from_slice(b"=");
+            buf.extend_from_slice(value);
+        }
+        let tag_pos = if buf.len() - start == base.len() { None } else { Some(base.len()) };
+        TagCheck::Fixed(MetricName::from_unsorted_tags(buf.split_off(start), tag_pos, intermediate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::name;
+
+    fn rule() -> TagRule {
+        TagRule {
+            name: "app.*".into(),
+            required: vec!["team".into(), "env".into()],
+            allowed: Some(vec!["host".into()]),
+            values: vec![("env".to_string(), "prod*".to_string())].into_iter().collect(),
+            defaults: vec![("env".to_string(), "prod".to_string())].into_iter().collect(),
+        }
+    }
+
+    #[test]
+    fn tag_schema() {
+        let (valid, bad, missing, other) = (
+            name("app.requests;env=prod;host=a;team=core"),
+            name("app.requests;env=dev;pod=x;team=core"),
+            name("app.requests;host=a"),
+            name("other;pod=x"),
+        );
+
+        let mut schema = TagSchema::new(TagSchemaOptions {
+            rules: vec![rule()],
+            action: TagSchemaAction::Reject,
+        });
+        // some data is left before the name in the reused buffer
+        let mut buf = BytesMut::from(&b"prefix"[..]);
+        let mut intermediate = Vec::new();
+        assert_eq!(schema.check(&valid, &mut buf, &mut intermediate), TagCheck::Valid);
+        assert_eq!(schema.check(&other, &mut buf, &mut intermediate), TagCheck::Valid);
+        assert_eq!(
+            schema.violations(&bad),
+            vec![
+                TagViolation::BadValue {
+                    key: b"env".to_vec(),
+                    value: b"dev".to_vec()
+                },
+                TagViolation::NotAllowed(b"pod".to_vec())
+            ]
+        );
+        assert!(matches!(schema.check(&bad, &mut buf, &mut intermediate), TagCheck::Rejected(v) if v.len() == 2));
+
+        schema.options.action = TagSchemaAction::Fix;
+        match schema.check(&bad, &mut buf, &mut intermediate) {
+            TagCheck::Fixed(fixed) => {
+                assert_eq!(&fixed.name[..], b"app.requests;env=prod;team=core");
+                assert_eq!(fixed.tag_value(b"team"), Some(&b"core"[..]));
+            }
+            other => panic!("bad check result: {:?}", other),
+        }
+        assert_eq!(&buf[..], b"prefix");
+        // team has no default
+        assert_eq!(
+            schema.check(&missing, &mut buf, &mut intermediate),
+            TagCheck::Rejected(vec![TagViolation::Missing("team".into())])
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use std::fmt::Debug;
+
+use num_traits::{AsPrimitive, Float};
+
+use crate::aggregate::Aggregate;
+use crate::metric::{FromF64, Metric, MetricValue, TimestampPrecision};
+use crate::name::{parse_name, MetricName};
+
+/// Relative difference small enough to consider floats equal, suitable for both f32 and f64
+pub const DEFAULT_EPSILON: f64 = 1e-5;
+
+/// Makes a name with tags in Graphite format, panicking on a bad one
+pub fn name(name: &str) -> MetricName {
+    parse_name(name).unwrap_or_else(|| panic!("bad metric name '{}'", name))
+}
+
+/// Equality allowing some difference in floats, so comparisons don't depend on float
+/// precision and platform specific float calculations
+pub trait ApproxEq {
+    /// Floats are compared using relative difference, except the values close to zero,
+    /// where `epsilon` becomes the absolute difference
+    fn approx_eq(&self, other: &Self, epsilon: f64) -> bool;
+}
+
+impl ApproxEq for f64 {
+    fn approx_eq(&self, other: &Self, epsilon: f64) -> bool {
+        if self == other {
+            // covers infinities too
+            return true;
+        }
+        (self - other).abs() <= epsilon * self.abs().max(other.abs()).max(1f64)
+    }
+}
+
+impl ApproxEq for f32 {
+    fn approx_eq(&self, other: &Self, epsilon: f64) -> bool {
+        f64::from(*self).approx_eq(&f64::from(*other), epsilon)
+    }
+}
+
+impl<T: ApproxEq> ApproxEq for Option<T> {
+    fn approx_eq(&self, other: &Self, epsilon: f64) -> bool {
+        match (self, other) {
+            (Some(v1), Some(v2)) => v1.approx_eq(v2, epsilon),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+impl<T: ApproxEq> ApproxEq for [T] {
+    fn approx_eq(&self, other: &Self, epsilon: f64) -> bool {
+        self.len() == other.len() && self.iter().zip(other.iter()).all(|(v1, v2)| v1.approx_eq(v2, epsilon))
+    }
+}
+
+impl<T: ApproxEq> ApproxEq for Vec<T> {
+    fn approx_eq(&self, other: &Self, epsilon: f64) -> bool {
+        self[..].approx_eq(&other[..], epsilon)
+    }
+}
+
+/// Aggregates with their values, as given by `aggregate::aggregates`
+impl<F> ApproxEq for (Aggregate<F>, F)
+where
+    F: Float + Debug + FromF64 + AsPrimitive<usize> + ApproxEq,
+{
+    fn approx_eq(&self, other: &Self, epsilon: f64) -> bool {
+        self.0 == other.0 && self.1.approx_eq(&other.1, epsilon)
+    }
+}
+
+/// Values of the same type only are equal, samples of timers are compared in order
+impl<F> ApproxEq for MetricValue<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64> + ApproxEq,
+{
+    fn approx_eq(&self, other: &Self, epsilon: f64) -> bool {
+        match (self, other) {
+            (MetricValue::Gauge(v1), MetricValue::Gauge(v2)) | (MetricValue::Counter(v1), MetricValue::Counter(v2)) => v1.approx_eq(v2, epsilon),
+            (MetricValue::Timer(t1), MetricValue::Timer(t2)) => t1.approx_eq(t2, epsilon),
+            (MetricValue::CompactTimer(t1), MetricValue::CompactTimer(t2)) => {
+                t1.len() == t2.len() && t1.iter().zip(t2.iter()).all(|(v1, v2)| v1.approx_eq(&v2, epsilon))
+            }
+            (MetricValue::CustomHistogram(l1, b1), MetricValue::CustomHistogram(l2, b2)) => {
+                l1 == l2 && b1.len() == b2.len() && b1.iter().zip(b2.iter()).all(|((s1, c1), (s2, c2))| c1 == c2 && s1.approx_eq(s2, epsilon))
+            }
+            (v1, v2) => v1 == v2,
+        }
+    }
+}
+
+/// Metrics are equal when their values are approximately equal and all other fields are equal,
+/// timestamps are compared regardless of their units
+impl<F> ApproxEq for Metric<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64> + ApproxEq,
+{
+    fn approx_eq(&self, other: &Self, epsilon: f64) -> bool {
+        self.value().approx_eq(other.value(), epsilon)
+            && self.timestamp_as(TimestampPrecision::Nanos) == other.timestamp_as(TimestampPrecision::Nanos)
+            && self.updates() == other.updates()
+            && self.sampling() == other.sampling()
+    }
+}
+
+/// Asserts two values are approximately equal using `ApproxEq`, with the default
+/// or specified epsilon
+#[macro_export]
+macro_rules! assert_metric_approx_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        $crate::assert_metric_approx_eq!($left, $right, $crate::testing::DEFAULT_EPSILON)
+    };
+    ($left:expr, $right:expr, $epsilon:expr $(,)?) => {
+        match (&$left, &$right, $epsilon) {
+            (left, right, epsilon) => {
+                if !$crate::testing::ApproxEq::approx_eq(left, right, epsilon) {
+                    panic!(
+                        "assertion failed: `left ~= right` with epsilon {}\n  left: `{:?}`\n right: `{:?}`",
+                        epsilon, left, right
+                    )
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn approx_eq_floats() {
+        assert_metric_approx_eq!(0.1f64 + 0.2f64, 0.3f64);
+        assert_metric_approx_eq!(1e10f32 + 1f32, 1e10f32);
+        assert_metric_approx_eq!(f64::INFINITY, f64::INFINITY);
+        assert!(!1f64.approx_eq(&1.001f64, DEFAULT_EPSILON));
+        assert!(!f64::NAN.approx_eq(&f64::NAN, DEFAULT_EPSILON));
+        assert_metric_approx_eq!(1f64, 1.001f64, 0.01);
+    }
+
+    #[test]
+    fn approx_eq_metrics() {
+        let m1 = Metric::<f32>::new(MetricValue::Timer(vec![0.1f32 + 0.2f32, 1f32]), Some(1), 1f32);
+        let m2 = Metric::<f32>::new(MetricValue::Timer(vec![0.3f32, 1f32]), Some(1), 1f32);
+        assert_metric_approx_eq!(m1, m2);
+        assert!(!m1.approx_eq(&Metric::new(MetricValue::Timer(vec![1f32, 0.3f32]), Some(1), 1f32), DEFAULT_EPSILON));
+        assert!(!m1.approx_eq(&Metric::new(MetricValue::Timer(vec![0.3f32, 1f32]), Some(2), 1f32), DEFAULT_EPSILON));
+
+        let aggregates = vec![(Aggregate::Mean, 0.1f64 + 0.2f64), (Aggregate::Percentile(0.99, 99), 1f64)];
+        assert_metric_approx_eq!(aggregates, vec![(Aggregate::Mean, 0.3f64), (Aggregate::Percentile(0.99, 99), 1f64)]);
+        assert!(!aggregates.approx_eq(&vec![(Aggregate::Median, 0.3f64), (Aggregate::Percentile(0.99, 99), 1f64)], DEFAULT_EPSILON));
+    }
+}
@@ -0,0 +1,277 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::metric::MetricError;
+
+/// Storage to use for timer metrics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TimerStorage {
+    /// Plain vector of floats
+    #[default]
+    Vec,
+    /// `CompactTimer` with the specified quantum
+    Compact(f64),
+}
+
+impl TimerStorage {
+    /// Checks the quantum of compact storage is a positive finite number
+    pub fn validate(self) -> Result<(), MetricError> {
+        match self {
+            TimerStorage::Compact(quantum) if !(quantum > 0f64 && quantum.is_finite()) => Err(MetricError::TimerQuantum(quantum)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The way sampling rate of timers is handled
+///
+/// All samples of a timer share the same sampling rate, so percentiles are not affected by it as long
+/// as the timer is not mixed with timers sampled at another rate, which gives wrong percentiles or errors.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TimerSampling {
+    /// Samples are stored as is, with sampling only considered for count, sum and rate
+    #[default]
+    Ignore,
+    /// Each sample is replicated `1/rate` times, rounded to the nearest integer, and the sampling
+    /// is reset to 1, so timers with any sampling rates can be mixed. Note that this multiplies
+    /// the memory used by the timer
+    Replicate,
+}
+
+/// Units of timer values. Timers are stored in milliseconds as statsd `ms` type implies,
+/// the unit is used to convert durations on input and aggregates on output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TimerUnit {
+    #[default]
+    #[serde(rename = "ms")]
+    Millis,
+    #[serde(rename = "us")]
+    Micros,
+    #[serde(rename = "s")]
+    Seconds,
+}
+
+impl TimerUnit {
+    /// Converts milliseconds to these units
+    pub fn convert_millis(self, value: f64) -> f64 {
+        match self {
+            TimerUnit::Millis => value,
+            TimerUnit::Micros => value * 1_000f64,
+            // division is more precise than multiplication by 0.001
+            TimerUnit::Seconds => value / 1_000f64,
+        }
+    }
+
+    /// Converts the duration to these units without rounding to whole units
+    pub fn convert_duration(self, duration: Duration) -> f64 {
+        match self {
+            TimerUnit::Millis => duration.as_nanos() as f64 / 1_000_000f64,
+            TimerUnit::Micros => duration.as_nanos() as f64 / 1_000f64,
+            TimerUnit::Seconds => duration.as_secs_f64(),
+        }
+    }
+}
+
+/// A timer storing samples quantized to integer number of `quantum`s and delta-encoded as
+/// zigzag varints in the order of insertion.
+///
+/// For the typical latency values, like milliseconds with microsecond precision, each sample
+/// takes 1-3 bytes instead of 8 for f64. Precision below quantum is lost and values
+/// are saturated to i64 range, NaN is stored as zero.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompactTimer {
+    quantum: f64,
+    last: i64,
+    len: usize,
+    data: Vec<u8>,
+}
+
+impl CompactTimer {
+    /// Creates an empty timer, quantum must be a positive finite number
+    pub fn new(quantum: f64) -> Result<Self, MetricError> {
+        TimerStorage::Compact(quantum).validate()?;
+        Ok(Self {
+            quantum,
+            last: 0,
+            len: 0,
+            data: Vec::new(),
+        })
+    }
+
+    pub fn quantum(&self) -> f64 {
+        self.quantum
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of bytes allocated for samples
+    pub fn capacity(&self) -> usize {
+        self.data.capacity()
+    }
+
+    pub fn push(&mut self, value: f64) {
+        let quantized = (value / self.quantum).round() as i64;
+        put_varint(&mut self.data, zigzag(quantized.wrapping_sub(self.last)));
+        self.last = quantized;
+        self.len += 1;
+    }
+
+    /// Removes all samples, keeping the quantum
+    pub fn clear(&mut self) {
+        self.last = 0;
+        self.len = 0;
+        self.data.clear();
+    }
+
+    /// Appends all samples from other timer
+    pub fn merge(&mut self, other: &CompactTimer) {
+        if other.is_empty() {
+            return;
+        }
+
+        // deltas can only be copied if quanta are exactly the same
+        if other.quantum != self.quantum {
+            for value in other.iter() {
+                self.push(value);
+            }
+            return;
+        }
+
+        // only the first delta of other timer is relative to zero and should be recalculated,
+        // all others can be copied as is
+        let (first, offset) = get_varint(&other.data).unwrap_or((0, other.data.len()));
+        let first = unzigzag(first);
+        put_varint(&mut self.data, zigzag(first.wrapping_sub(self.last)));
+        self.data.extend_from_slice(&other.data[offset..]);
+        self.last = other.last;
+        self.len += other.len;
+    }
+
+    /// Iterates over dequantized samples in the order of insertion
+    pub fn iter(&self) -> CompactTimerIter<'_> {
+        CompactTimerIter {
+            quantum: self.quantum,
+            current: 0,
+            data: &self.data,
+        }
+    }
+}
+
+pub struct CompactTimerIter<'a> {
+    quantum: f64,
+    current: i64,
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for CompactTimerIter<'a> {
+    type Item = f64;
+
+    fn next(&mut self) -> Option<f64> {
+        let (delta, offset) = get_varint(self.data)?;
+        self.data = &self.data[offset..];
+        self.current = self.current.wrapping_add(unzigzag(delta));
+        Some(self.current as f64 * self.quantum)
+    }
+}
+
+#[inline]
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+#[inline]
+fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+#[inline]
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+// returns the value and the number of bytes read
+#[inline]
+fn get_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (idx, byte) in buf.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * idx);
+        if byte & 0x80 == 0 {
+            return Some((value, idx + 1));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_timer_roundtrip() {
+        let values = [1.5, 0.001, 1000.0, -3.25, 0.0, 1e12, -1e12, 0.5];
+        let mut timer = CompactTimer::new(0.001).unwrap();
+        for v in &values {
+            timer.push(*v);
+        }
+        assert_eq!(timer.len(), values.len());
+
+        let decoded: Vec<f64> = timer.iter().collect();
+        for (v, d) in values.iter().zip(decoded.iter()) {
+            assert!((v - d).abs() < 0.0005, "{} != {}", v, d);
+        }
+    }
+
+    #[test]
+    fn compact_timer_size() {
+        let mut timer = CompactTimer::new(0.001).unwrap();
+        for i in 0..1000 {
+            timer.push(100f64 + (i % 50) as f64 * 0.123);
+        }
+        // at least 4x smaller than a Vec<f64>, the first value may take one byte more
+        assert!(timer.data.len() <= 2 * timer.len() + 1);
+    }
+
+    #[test]
+    fn compact_timer_merge() {
+        let mut timer1 = CompactTimer::new(1f64).unwrap();
+        timer1.push(10f64);
+        timer1.push(20f64);
+
+        let mut timer2 = CompactTimer::new(1f64).unwrap();
+        timer2.push(5f64);
+        timer2.push(-7f64);
+        timer2.push(3f64);
+
+        let mut timer3 = CompactTimer::new(0.5).unwrap();
+        timer3.push(1.5);
+
+        timer1.merge(&timer2);
+        timer1.merge(&timer3);
+        timer1.merge(&CompactTimer::new(1f64).unwrap());
+        timer1.push(1f64);
+        assert_eq!(timer1.iter().collect::<Vec<_>>(), vec![10f64, 20f64, 5f64, -7f64, 3f64, 2f64, 1f64]);
+        assert_eq!(timer1.len(), 7);
+
+        // quanta too small for an epsilon comparison are still different
+        let mut tiny1 = CompactTimer::new(1e-20).unwrap();
+        tiny1.push(5e-20);
+        let mut tiny2 = CompactTimer::new(2e-20).unwrap();
+        tiny2.push(6e-20);
+        tiny1.merge(&tiny2);
+        let merged = tiny1.iter().collect::<Vec<_>>();
+        assert!((merged[1] - 6e-20).abs() < 1e-21, "{:?}", merged);
+    }
+}
@@ -0,0 +1,182 @@
+use std::convert::TryFrom;
+use std::fmt::Debug;
+
+use num_traits::{AsPrimitive, Float};
+
+use crate::metric::{FromF64, Metric, MetricError, MetricTypeName};
+
+macro_rules! typed_metric {
+    ($(#[$doc:meta])* $name:ident, $type_name:expr) => {
+        $(#[$doc])*
+        #[derive(Debug, Clone, PartialEq)]
+        pub struct $name<F>(Metric<F>)
+        where
+            F: Copy + PartialEq + Debug;
+
+        impl<F> $name<F>
+        where
+            F: Float + Debug + FromF64 + AsPrimitive<f64>,
+        {
+            pub fn as_metric(&self) -> &Metric<F> {
+                &self.0
+            }
+
+            pub fn into_metric(self) -> Metric<F> {
+                self.0
+            }
+
+            /// Accumulates the metric of the same type, see `Metric::accumulate`
+            pub fn accumulate(&mut self, other: Self) -> Result<(), MetricError> {
+                self.0.accumulate(other.0)
+            }
+        }
+
+        /// Gives the metric back if it is of another type
+        impl<F> TryFrom<Metric<F>> for $name<F>
+        where
+            F: Float + Debug + FromF64 + AsPrimitive<f64>,
+        {
+            type Error = Metric<F>;
+
+            fn try_from(metric: Metric<F>) -> Result<Self, Metric<F>> {
+                if MetricTypeName::from_metric(&metric) == $type_name {
+                    Ok(Self(metric))
+                } else {
+                    Err(metric)
+                }
+            }
+        }
+
+        impl<F> From<$name<F>> for Metric<F>
+        where
+            F: Float + Debug + FromF64 + AsPrimitive<f64>,
+        {
+            fn from(metric: $name<F>) -> Self {
+                metric.0
+            }
+        }
+    };
+}
+
+typed_metric!(
+    /// A metric guaranteed to be a counter
+    CounterMetric,
+    MetricTypeName::Counter
+);
+
+typed_metric!(
+    /// A metric guaranteed to be a gauge
+    GaugeMetric,
+    MetricTypeName::Gauge
+);
+
+typed_metric!(
+    /// A metric guaranteed to be a timer with any storage
+    TimerMetric,
+    MetricTypeName::Timer
+);
+
+typed_metric!(
+    /// A metric guaranteed to be a set with any storage
+    SetMetric,
+    MetricTypeName::Set
+);
+
+typed_metric!(
+    /// A metric guaranteed to be a custom histogram
+    HistogramMetric,
+    MetricTypeName::CustomHistogram
+);
+
+impl<F> CounterMetric<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    pub fn value(&self) -> F {
+        self.0.as_counter().unwrap()
+    }
+}
+
+impl<F> GaugeMetric<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    pub fn value(&self) -> F {
+        self.0.as_gauge().unwrap()
+    }
+}
+
+impl<F> TimerMetric<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    pub fn len(&self) -> usize {
+        self.0.timer_len().unwrap()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<F> SetMetric<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    pub fn len(&self) -> usize {
+        self.0.set_len().unwrap()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<F> HistogramMetric<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    /// Counter of the left bucket and the list of other buckets with their start values
+    pub fn buckets(&self) -> (u64, &[(F, u64)]) {
+        self.0.histogram_buckets().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metric::MetricValue;
+    use crate::timer::TimerStorage;
+
+    fn timers_only(timer: &TimerMetric<f64>) -> usize {
+        timer.len()
+    }
+
+    #[test]
+    fn typed_metrics() {
+        let counter = Metric::<f64>::new(MetricValue::Counter(1f64), None, 1f32);
+        let counter = TimerMetric::try_from(counter).unwrap_err();
+        let mut counter = CounterMetric::try_from(counter).unwrap();
+        counter
+            .accumulate(CounterMetric::try_from(Metric::new(MetricValue::Counter(2f64), None, 1f32)).unwrap())
+            .unwrap();
+        assert_eq!(counter.value(), 3f64);
+        let metric = Metric::from(counter);
+        assert_eq!(metric.as_counter(), Some(3f64));
+        assert_eq!(metric.updates(), 2f64);
+
+        let mut metric = Metric::<f64>::new(MetricValue::Timer(vec![1f64, 2f64]), None, 1f32);
+        metric.timer_storage(TimerStorage::Compact(1f64)).unwrap();
+        let timer = TimerMetric::try_from(metric).unwrap();
+        assert_eq!(timers_only(&timer), 2);
+
+        let set = Metric::<f64>::default_for(MetricTypeName::Set, Some(1f64)).unwrap();
+        assert_eq!(SetMetric::try_from(set).unwrap().len(), 1);
+
+        let gauge = Metric::<f64>::new(MetricValue::Gauge(5f64), None, 1f32);
+        assert_eq!(GaugeMetric::try_from(gauge).unwrap().value(), 5f64);
+
+        let histogram = Metric::<f64>::new(MetricValue::CustomHistogram(1, vec![(0f64, 2)]), None, 1f32);
+        assert_eq!(HistogramMetric::try_from(histogram).unwrap().buckets(), (1, &[(0f64, 2)][..]));
+    }
+}
@@ -0,0 +1,186 @@
+use std::fmt::Debug;
+
+use bytes::BytesMut;
+use num_traits::{AsPrimitive, Float};
+use serde::{Deserialize, Serialize};
+
+use crate::metric::{FromF64, Metric, MetricError, MetricValue};
+use crate::name::{AggregationDestination, MetricName};
+use crate::protocol_v2_capnp::metric::metric_meta;
+
+/// The maximal number of components in a vector gauge
+pub const MAX_COMPONENTS: usize = 16;
+
+/// A gauge made of a small fixed set of named components, like `load1`, `load5` and `load15` of
+/// the load average, which are always updated together. Unlike separate gauges, the components
+/// are never seen by receivers from different updates.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VectorGauge<F>
+where
+    F: Copy + PartialEq + Debug,
+{
+    components: Vec<(String, F)>,
+}
+
+impl<F> VectorGauge<F>
+where
+    F: Float + Debug + FromF64 + AsPrimitive<f64>,
+{
+    /// Components keep the order given, names must be unique and non-empty
+    pub fn new<I, S>(components: I) -> Result<Self, MetricError>
+    where
+        I: IntoIterator<Item = (S, F)>,
+        S: Into<String>,
+    {
+        let components = components.into_iter().map(|(name, value)| (name.into(), value)).collect::<Vec<_>>();
+        if components.is_empty() || components.len() > MAX_COMPONENTS {
+            return Err(MetricError::VectorGauge("bad number of components"));
+        }
+        if components.iter().any(|(name, _)| name.is_empty()) {
+            return Err(MetricError::VectorGauge("empty component name"));
+        }
+        if components
+            .iter()
+            .enumerate()
+            .any(|(idx, (name, _))| components[..idx].iter().any(|(other, _)| other == name))
+        {
+            return Err(MetricError::VectorGauge("duplicate component name"));
+        }
+        Ok(Self { components })
+    }
+
+    pub fn components(&self) -> &[(String, F)] {
+        &self.components
+    }
+
+    pub fn get(&self, name: &str) -> Option<F> {
+        self.components.iter().find(|(component, _)| component == name).map(|(_, value)| *value)
+    }
+
+    /// Replaces all the components with the ones of the newer update. Both gauges must have the same
+    /// components, otherwise none of them are changed
+    pub fn accumulate(&mut self, new: &VectorGauge<F>) -> Result<(), MetricError> {
+        if self.components.len() != new.components.len() || self.components.iter().zip(new.components.iter()).any(|((a, _), (b, _))| a != b) {
+            return Err(MetricError::Aggregating);
+        }
+        self.components
+            .iter_mut()
+            .zip(new.components.iter())
+            .for_each(|((_, value), (_, new))| *value = *new);
+        Ok(())
+    }
+
+    /// The gauge with the value of the first component for receivers not supporting vector gauges,
+    /// the components are kept in `Metric::vector` while no regular gauge updates come
+    pub fn to_metric(&self, timestamp: Option<u64>) -> Metric<F> {
+        Metric::new(MetricValue::Gauge(self.components[0].1), timestamp, 1f32).with_vector(self.clone())
+    }
+
+    /// Approximate number of bytes occupied by the gauge, including heap buffers
+    pub fn approx_mem_size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self
+                .components
+                .iter()
+                .map(|(name, _)| std::mem::size_of::<(String, F)>() + name.capacity())
+                .sum::<usize>()
+    }
+
+    pub fn fill_capnp(&self, builder: metric_meta::Builder) {
+        let mut c_builder = builder.init_components(self.components.len() as u32);
+        for (idx, (name, value)) in self.components.iter().enumerate() {
+            let mut component = c_builder.reborrow().get(idx as u32);
+            component.set_name(name);
+            component.set_value(value.as_());
+        }
+    }
+
+    /// Reads the components from metric meta, None if it is not a vector gauge
+    pub fn from_capnp(reader: metric_meta::Reader) -> Result<Option<Self>, MetricError> {
+        if !reader.has_components() {
+            return Ok(None);
+        }
+        let components = reader
+            .get_components()
+            .map_err(MetricError::Capnp)?
+            .iter()
+            .map(|component| {
+                Ok((
+                    component.get_name().map_err(MetricError::Capnp)?.to_string(),
+                    F::from_f64(component.get_value()),
+                ))
+            })
+            .collect::<Result<Vec<_>, MetricError>>()?;
+        Self::new(components).map(Some)
+    }
+
+    /// Splits the gauge into separate ones for the backends not supporting vectors, the component
+    /// name is placed like an aggregate: as a postfix `load.load1` and/or as `component=load1` tag
+    pub fn split(&self, name: &MetricName, dest: AggregationDestination, timestamp: Option<u64>) -> Vec<(MetricName, Metric<F>)> {
+        let mut buf = BytesMut::new();
+        self.components
+            .iter()
+            .map(|(component, value)| {
+                name.put_full(&mut buf, dest, component.as_bytes(), b"", b"component", component.as_bytes());
+                let mut name = MetricName::new_lazy(buf.split().freeze());
+                name.find_tags();
+                (name, Metric::new(MetricValue::Gauge(*value), timestamp, 1f32))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{fill_snapshot, read_snapshot};
+    use crate::protocol_v2_capnp::message;
+    use crate::testing::name;
+
+    #[test]
+    fn vector_gauge() {
+        assert!(VectorGauge::<f64>::new(Vec::<(String, f64)>::new()).is_err());
+        assert!(VectorGauge::new(vec![("load1", 1f64), ("load1", 2f64)]).is_err());
+        assert!(VectorGauge::new(vec![("", 1f64)]).is_err());
+
+        let mut load = VectorGauge::new(vec![("load1", 1f64), ("load5", 0.5), ("load15", 0.25)]).unwrap();
+        load.accumulate(&VectorGauge::new(vec![("load1", 2f64), ("load5", 1f64), ("load15", 0.5)]).unwrap())
+            .unwrap();
+        assert_eq!(load.get("load5"), Some(1f64));
+        assert_eq!(load.get("load30"), None);
+        assert!(load.accumulate(&VectorGauge::new(vec![("load1", 3f64)]).unwrap()).is_err());
+        assert_eq!(load.get("load1"), Some(2f64));
+
+        let name = name("system.load;host=a");
+        let split = load.split(&name, AggregationDestination::Tag, None);
+        assert_eq!(split.len(), 3);
+        assert_eq!(&split[0].0.name[..], b"system.load;component=load1;host=a");
+        assert_eq!(split[0].0.tag_value(b"host"), Some(&b"a"[..]));
+        assert_eq!(split[2].1.value(), &MetricValue::Gauge(0.5));
+
+        let split = load.split(&MetricName::new_untagged(BytesMut::from("load")), AggregationDestination::Name, None);
+        assert_eq!(&split[1].0.name[..], b"load.load5");
+    }
+
+    #[test]
+    fn vector_gauge_metric() {
+        let load = VectorGauge::new(vec![("load1", 1f64), ("load5", 0.5)]).unwrap();
+        let mut metric = load.to_metric(Some(10));
+        assert_eq!(metric.value(), &MetricValue::Gauge(1f64));
+        let newer = VectorGauge::new(vec![("load1", 2f64), ("load5", 1f64)]).unwrap();
+        metric.accumulate(newer.to_metric(Some(20))).unwrap();
+        assert_eq!(metric.vector(), Some(&newer));
+        assert_eq!(metric.value(), &MetricValue::Gauge(2f64));
+
+        let name = MetricName::new_untagged(BytesMut::from("system.load"));
+        let mut builder = capnp::message::Builder::new_default();
+        fill_snapshot(&mut builder.init_root::<message::Builder>(), std::iter::once((&name, &metric)), false);
+        let reader = builder.get_root_as_reader::<message::Reader>().unwrap();
+        let read = read_snapshot::<f64>(reader).unwrap();
+        assert_eq!(read, vec![(name, metric.clone())]);
+        assert_eq!(read[0].1.vector(), Some(&newer));
+
+        metric.accumulate(Metric::new(MetricValue::Gauge(3f64), None, 1f32)).unwrap();
+        assert_eq!(metric.vector(), None);
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Write};
+
+use bytes::{BufMut, BytesMut};
+use num_traits::{AsPrimitive, Float};
+
+use crate::aggregate::Aggregate;
+use crate::metric::{FromF64, MetricError, MetricTypeName};
+use crate::name::{find_tag_pos, split_tag, tag_parts, unescape_tag_value, MetricName, NamingOptions, TagFormat, UnicodePolicy};
+
+/// Encodes aggregated metrics into Wavefront data format lines, which is also accepted by Librato
+/// and some other SaaS backends:
+///
+/// `"<name>" <value> [<timestamp>] source="<source>" ["<tag>"="<value>" ...]`
+///
+/// Graphite tags of a name become point tags. The source is mandatory in the format, it is taken
+/// from a tag if one is configured and found, or the default one is used otherwise. Wavefront
+/// rejects the points with non-finite values or empty tag values, so they are not written.
+#[derive(Debug, Clone)]
+pub struct WavefrontEncoder {
+    source: Vec<u8>,
+    source_tag: Option<Vec<u8>>,
+    unicode: UnicodePolicy,
+}
+
+fn put_quoted(buf: &mut BytesMut, value: &[u8]) {
+    buf.reserve(value.len() + 2);
+    buf.put_u8(b'"');
+    for c in value {
+        if *c == b'"' || *c == b'\\' {
+            buf.put_u8(b'\\');
+        }
+        buf.put_u8(*c);
+    }
+    buf.put_u8(b'"');
+}
+
+impl WavefrontEncoder {
+    pub fn new(source: &str) -> Self {
+        Self {
+            source: source.as_bytes().to_vec(),
+            source_tag: None,
+            unicode: UnicodePolicy::default(),
+        }
+    }
+
+    /// Take the source from the tag with this key, i.e. `host`, the tag is not repeated as a point tag then
+    pub fn source_tag(mut self, key: &str) -> Self {
+        self.source_tag = Some(key.as_bytes().to_vec());
+        self
+    }
+
+    /// Apply the policy to names with non-ASCII characters, the lines for rejected names are skipped
+    pub fn with_unicode(mut self, policy: UnicodePolicy) -> Self {
+        self.unicode = policy;
+        self
+    }
+
+    /// Appends a line for the full name in Graphite format, i.e. the one made by `MetricName::put_with_options`.
+    /// Timestamp is in seconds. Nothing is written for the names rejected by the Unicode policy
+    pub fn encode<F>(&self, buf: &mut BytesMut, name: &[u8], value: F, timestamp: Option<u64>) -> Result<(), MetricError>
+    where
+        F: Float + AsPrimitive<f64>,
+    {
+        let name = match self.unicode.apply(name) {
+            Ok(name) => name,
+            Err(_) => return Ok(()),
+        };
+        let value: f64 = value.as_();
+        if !value.is_finite() {
+            return Err(MetricError::Wavefront("value is not finite"));
+        }
+        let name = &name[..];
+        let tag_pos = find_tag_pos(name, TagFormat::Graphite).unwrap_or(name.len());
+        let tags = || tag_parts(&name[tag_pos..]).map(split_tag);
+        if tags().any(|(_, value)| value.is_empty()) {
+            return Err(MetricError::Wavefront("empty tag value"));
+        }
+        let source_tag = self
+            .source_tag
+            .as_ref()
+            .and_then(|key| tags().find(|(k, _)| k == key))
+            .map(|(key, value)| (key, unescape_tag_value(value)));
+
+        put_quoted(buf, &name[..tag_pos]);
+        // writing to BytesMut never fails
+        write!(buf, " {}", value).unwrap_or_default();
+        if let Some(timestamp) = timestamp {
+            write!(buf, " {}", timestamp).unwrap_or_default();
+        }
+
+        buf.extend_from_slice(b" source=");
+        put_quoted(buf, source_tag.as_ref().map(|(_, value)| &value[..]).unwrap_or(&self.source));
+        for (key, value) in tags() {
+            if Some(key) == source_tag.as_ref().map(|(key, _)| *key) {
+                continue;
+            }
+            buf.put_u8(b' ');
+            put_quoted(buf, key);
+            buf.put_u8(b'=');
+            put_quoted(buf, &unescape_tag_value(value));
+        }
+        buf.put_u8(b'\n');
+        Ok(())
+    }
+
+    /// Appends a line for the aggregate of the metric, named according to options.
+    /// `scratch` is used to build the full name, so it should be reused between calls.
+    /// Fails if there are no naming options for the aggregate or the line cannot be written, see `encode`
+    #[allow(clippy::too_many_arguments)]
+    pub fn encode_aggregate<F>(
+        &self,
+        buf: &mut BytesMut,
+        scratch: &mut BytesMut,
+        name: &MetricName,
+        mtype: MetricTypeName,
+        aggregate: Aggregate<F>,
+        value: F,
+        timestamp: Option<u64>,
+        options: &HashMap<(MetricTypeName, Aggregate<F>), NamingOptions>,
+    ) -> Result<(), MetricError>
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<usize> + AsPrimitive<f64>,
+    {
+        scratch.clear();
+        name.put_with_options(scratch, mtype, aggregate, options)
+            .map_err(|()| MetricError::Wavefront("no naming options for the aggregate"))?;
+        self.encode(buf, &scratch[..], value, timestamp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::name::AggregationDestination;
+    use crate::testing::name;
+    use bytes::Bytes;
+
+    #[test]
+    fn wavefront_lines() {
+        let encoder = WavefrontEncoder::new("agent1").source_tag("host");
+        let mut buf = BytesMut::new();
+        encoder.encode(&mut buf, b"cpu.idle", 1.5f64, Some(100)).unwrap();
+        encoder.encode(&mut buf, b"requests;env=pr\"od;host=h1", 2f32, None).unwrap();
+        assert!(matches!(encoder.encode(&mut buf, b"cpu.idle", f64::NAN, None), Err(MetricError::Wavefront(_))));
+        assert!(matches!(
+            encoder.encode(&mut buf, b"requests;env=;host=h1", 1f64, None),
+            Err(MetricError::Wavefront(_))
+        ));
+        assert_eq!(
+            &buf[..],
+            &b"\"cpu.idle\" 1.5 100 source=\"agent1\"\n\"requests\" 2 source=\"h1\" \"env\"=\"pr\\\"od\"\n"[..]
+        );
+
+        let name = name("requests;env=prod");
+        let mut options = HashMap::new();
+        options.insert(
+            (MetricTypeName::Timer, Aggregate::Max),
+            NamingOptions {
+                prefix: Bytes::new(),
+                tag: Bytes::from_static(b"aggregate"),
+                tag_value: Bytes::from_static(b"max"),
+                postfix: Bytes::from_static(b"max"),
+                destination: AggregationDestination::Tag,
+                escape_tag_values: false,
+            },
+        );
+        let mut buf = BytesMut::new();
+        let mut scratch = BytesMut::new();
+        encoder
+            .encode_aggregate(&mut buf, &mut scratch, &name, MetricTypeName::Timer, Aggregate::Max, 10f64, Some(1), &options)
+            .unwrap();
+        assert_eq!(&buf[..], &b"\"requests\" 10 1 source=\"agent1\" \"aggregate\"=\"max\" \"env\"=\"prod\"\n"[..]);
+        assert!(encoder
+            .encode_aggregate(&mut buf, &mut scratch, &name, MetricTypeName::Timer, Aggregate::Min, 10f64, Some(1), &options)
+            .is_err());
+
+        let mut buf = BytesMut::new();
+        let encoder = WavefrontEncoder::new("agent1").with_unicode(UnicodePolicy::Transliterate);
+        encoder.encode(&mut buf, "température;ville=Zürich".as_bytes(), 1f64, None).unwrap();
+        encoder
+            .with_unicode(UnicodePolicy::Reject)
+            .encode(&mut buf, "température".as_bytes(), 1f64, None)
+            .unwrap();
+        assert_eq!(&buf[..], &b"\"temperature\" 1 source=\"agent1\" \"ville\"=\"Zurich\"\n"[..]);
+    }
+}
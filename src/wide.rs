@@ -0,0 +1,120 @@
+use std::fmt::{self, Debug};
+
+use num_traits::{AsPrimitive, Float};
+
+use crate::metric::{FromF64, Metric, MetricError, MetricValue};
+
+// the sum and the rounding error of a + b
+#[inline]
+fn two_sum(a: f64, b: f64) -> (f64, f64) {
+    let sum = a + b;
+    let b_virtual = sum - a;
+    let a_virtual = sum - b_virtual;
+    (sum, (a - a_virtual) + (b - b_virtual))
+}
+
+/// A counter accumulator keeping the sum as a double-double, i.e. an unevaluated sum of two f64,
+/// giving about 106 bits of precision. Integer sums are exact up to 2^106, far beyond 2^53
+/// where `MetricValue::Counter` starts losing increments, like adding 1 to a byte counter
+/// of 10 PB. The value is only narrowed to float when converted to a metric, `Display` keeps
+/// the integer sums exact.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct WideCounter {
+    hi: f64,
+    lo: f64,
+}
+
+impl WideCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, value: f64) {
+        let (sum, err) = two_sum(self.hi, value);
+        let (hi, lo) = two_sum(sum, err + self.lo);
+        self.hi = hi;
+        self.lo = lo;
+    }
+
+    /// Adds the value of the counter metric, other types give `MetricError::Aggregating`
+    pub fn add_metric<F>(&mut self, metric: &Metric<F>) -> Result<(), MetricError>
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<f64>,
+    {
+        match metric.value() {
+            MetricValue::Counter(value) => {
+                self.add(value.as_());
+                Ok(())
+            }
+            _ => Err(MetricError::Aggregating),
+        }
+    }
+
+    pub fn accumulate(&mut self, other: &WideCounter) {
+        self.add(other.hi);
+        self.add(other.lo);
+    }
+
+    /// The sum narrowed to f64
+    pub fn value(&self) -> f64 {
+        self.hi + self.lo
+    }
+
+    /// The exact sum if it is integral and fits into i128
+    pub fn to_i128(&self) -> Option<i128> {
+        let integral = |v: f64| v.is_finite() && v.fract() == 0f64 && v.abs() < 2f64.powi(126);
+        if integral(self.hi) && integral(self.lo) {
+            Some(self.hi as i128 + self.lo as i128)
+        } else {
+            None
+        }
+    }
+
+    /// The counter metric with the narrowed sum
+    pub fn to_metric<F>(&self, timestamp: Option<u64>) -> Metric<F>
+    where
+        F: Float + Debug + FromF64 + AsPrimitive<f64>,
+    {
+        Metric::new(MetricValue::Counter(F::from_f64(self.value())), timestamp, 1f32)
+    }
+}
+
+impl fmt::Display for WideCounter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.to_i128() {
+            Some(value) => write!(f, "{}", value),
+            None => write!(f, "{}", self.value()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wide_counter() {
+        let base = 2f64.powi(53);
+        let mut narrow = Metric::new(MetricValue::Counter(base), None, 1f32);
+        let mut wide = WideCounter::new();
+        wide.add_metric(&narrow).unwrap();
+        for _ in 0..10 {
+            narrow.accumulate(Metric::new(MetricValue::Counter(1f64), None, 1f32)).unwrap();
+            wide.add(1f64);
+        }
+        // each increment is lost in f64
+        assert_eq!(narrow.value(), &MetricValue::Counter(base));
+        assert_eq!(wide.to_i128(), Some((1i128 << 53) + 10));
+        assert_eq!(wide.to_string(), "9007199254741002");
+
+        let mut other = WideCounter::new();
+        other.add(2f64.powi(80));
+        other.add(-0.5);
+        wide.accumulate(&other);
+        assert_eq!(wide.to_i128(), None);
+        assert_eq!(wide.value(), 2f64.powi(80) + base);
+        assert_eq!(wide.to_metric::<f64>(None).value(), &MetricValue::Counter(2f64.powi(80) + base));
+
+        assert!(wide.add_metric(&Metric::new(MetricValue::Gauge(1f64), None, 1f32)).is_err());
+    }
+}
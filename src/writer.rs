@@ -0,0 +1,151 @@
+use std::fmt::Debug;
+
+use num_traits::{AsPrimitive, Float};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::metric::{FromF64, Metric, MetricError};
+use crate::name::MetricName;
+use crate::protocol::SnapshotBatcher;
+
+/// Writes snapshots to an async stream, i.e. a peer socket, as a sequence of messages not larger
+/// than the budget of the batcher. The next message is only serialized after the stream has accepted
+/// the previous one, so a slow peer makes the writer wait instead of the whole serialized snapshot
+/// being kept in memory. Receivers read the messages one by one, like the ones made by `SnapshotBatcher::build`
+pub struct SnapshotWriter<W> {
+    inner: W,
+    batcher: SnapshotBatcher,
+    buf: Vec<u8>,
+}
+
+impl<W> SnapshotWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    pub fn new(inner: W, batcher: SnapshotBatcher) -> Self {
+        Self {
+            inner,
+            batcher,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Writes the snapshot and flushes the stream, giving the number of messages written, which is 0 for empty snapshots.
+    /// On errors some of the messages may have been written already
+    pub async fn write_snapshot<'m, F, I>(&mut self, metrics: I) -> Result<usize, MetricError>
+    where
+        F: 'm + Float + Debug + FromF64 + AsPrimitive<f64>,
+        I: IntoIterator<Item = (&'m MetricName, &'m Metric<F>)>,
+    {
+        let mut messages = 0;
+        for batch in self.batcher.batches(metrics) {
+            self.buf.clear();
+            {
+                // the message is dropped before waiting for the stream, only the serialized one is kept
+                let builder = self.batcher.build_batch(batch?);
+                capnp::serialize::write_message(&mut self.buf, &builder).map_err(MetricError::Capnp)?;
+            }
+            self.inner.write_all(&self.buf).await.map_err(MetricError::Io)?;
+            messages += 1;
+        }
+        self.inner.flush().await.map_err(MetricError::Io)?;
+        Ok(messages)
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metric::MetricValue;
+    use crate::protocol::{read_message_from_slice, read_snapshot, DecodeOptions};
+    use crate::protocol_v2_capnp::message;
+    use crate::testing::name;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    // accepts up to 100 bytes at a time, making the writer wait before every write
+    #[derive(Default)]
+    struct SlowStream {
+        data: Vec<u8>,
+        ready: bool,
+        waits: usize,
+        // the largest serialized data kept by the writer while waiting
+        max_buffered: usize,
+        broken: bool,
+    }
+
+    impl AsyncWrite for SlowStream {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+            if !self.ready {
+                self.ready = true;
+                self.waits += 1;
+                self.max_buffered = self.max_buffered.max(buf.len());
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            self.ready = false;
+            if self.broken {
+                return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+            }
+            let len = buf.len().min(100);
+            self.data.extend_from_slice(&buf[..len]);
+            Poll::Ready(Ok(len))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn async_snapshot_writer() {
+        let metrics = (0..50)
+            .map(|idx| {
+                let name = name(&format!("some.metric.{}", idx));
+                (name, Metric::new(MetricValue::Counter(idx as f64), None, 1f32))
+            })
+            .collect::<Vec<_>>();
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let mut writer = SnapshotWriter::new(Vec::new(), SnapshotBatcher::new(1024, false));
+        let messages = runtime.block_on(writer.write_snapshot(metrics.iter().map(|(n, m)| (n, m)))).unwrap();
+        assert!(messages > 1);
+
+        let data = writer.into_inner();
+        let mut slice = &data[..];
+        let mut received = Vec::new();
+        while !slice.is_empty() {
+            let message = read_message_from_slice(&mut slice, &DecodeOptions::default()).unwrap();
+            let reader = message.get_root::<message::Reader>().unwrap();
+            received.extend(read_snapshot::<f64>(reader).unwrap());
+        }
+        assert_eq!(received, metrics);
+
+        let mut writer = SnapshotWriter::new(SlowStream::default(), SnapshotBatcher::new(1024, false));
+        let messages = runtime.block_on(writer.write_snapshot(metrics.iter().map(|(n, m)| (n, m)))).unwrap();
+        let stream = writer.into_inner();
+        assert!(stream.waits > messages);
+        // only one message is kept serialized while the stream is not ready
+        assert!(stream.max_buffered <= 1024);
+        assert_eq!(stream.data, data);
+
+        let broken = SlowStream {
+            broken: true,
+            ..SlowStream::default()
+        };
+        let mut writer = SnapshotWriter::new(broken, SnapshotBatcher::new(1024, false));
+        let result = runtime.block_on(writer.write_snapshot(metrics.iter().map(|(n, m)| (n, m))));
+        assert!(matches!(result, Err(MetricError::Io(e)) if e.kind() == std::io::ErrorKind::BrokenPipe));
+    }
+}